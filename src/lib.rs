@@ -0,0 +1,8 @@
+// Ferromic: population genetics statistics from VCF files
+//
+// process: shared types, VCF processing and the config, single-region and sliding-window modes
+// parse: input files (VCF/BCF, config TSV, BED regions, reference FASTA, GFF)
+// stats: diversity and neutrality statistics
+pub mod parse;
+pub mod process;
+pub mod stats;
@@ -1,3 +1,34 @@
-fn main() {
-    println!("Welcome to Ferromic");
+use clap::Parser;
+use ferromic::process::{resolve_thread_count, run, run_check, run_compare, run_merge, Cli, Command, LogLevel, VcfError};
+use rayon::ThreadPoolBuilder;
+use std::io::IsTerminal;
+
+fn main() -> Result<(), VcfError> {
+    let cli = Cli::parse();
+    let log_level = cli.args.as_ref().map_or(LogLevel::Info, |args| args.log_level);
+
+    // Diagnostics go to stderr so that reports on stdout stay clean
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::from(log_level))
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let args = match cli.command {
+        Some(Command::Check { vcf }) => return run_check(&vcf),
+        Some(Command::Compare { file1, file2, tolerance }) => return run_compare(&file1, &file2, tolerance),
+        Some(Command::Merge { inputs, output }) => return run_merge(&inputs, &output),
+        None => cli.args.expect("clap requires the analysis arguments without a subcommand"),
+    };
+
+    // Set Rayon to use --threads, FERROMIC_THREADS or all logical CPUs
+    let env_threads = std::env::var("FERROMIC_THREADS").ok();
+    let num_threads = resolve_thread_count(args.threads, env_threads.as_deref());
+    ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .unwrap();
+
+    run(args)
 }
@@ -9,8 +9,9 @@ use flate2::read::MultiGzDecoder;
 use clap::Parser;
 use human_bytes::human_bytes;
 use std::sync::Mutex;
-use log::{info, debug, error, LevelFilter};
-use env_logger::Builder;
+use tracing::{info, debug};
+use ferromic::process::LogLevel;
+use std::io::IsTerminal;
 use num_cpus;
 use memmap2::MmapOptions;
 use sysinfo::{System, SystemExt, ProcessExt, Pid};
@@ -51,6 +52,10 @@ struct Args {
 
     #[arg(short = 'g', long, help = "Memory limit in GB")]
     memory_limit: Option<u64>,
+
+    // Verbosity of progress and diagnostic messages on stderr
+    #[arg(long = "log_level", value_enum, default_value = "info")]
+    log_level: LogLevel,
 }
 
 #[derive(Debug)]
@@ -83,9 +88,16 @@ enum VcfError {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Builder::new().filter_level(LevelFilter::Info).init();
-
     let args = Args::parse();
+
+    // Diagnostics go to stderr, as in the main binary
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::from(args.log_level))
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr)
+        .init();
+
     info!("VCF Concatenator");
     info!("Input directory: {}", args.input);
     info!("Output file: {}", args.output);
@@ -167,7 +179,7 @@ async fn process_vcf_files(args: &Args) -> Result<(), VcfError> {
         progress.entry(file.chromosome.clone()).or_default().total_bytes += file.path.metadata()?.len();
     }
 
-    println!("About to start processing {} files", vcf_files.len());
+    info!("About to start processing {} files", vcf_files.len());
 
     let progress_bars: HashMap<String, ProgressBar> = vcf_files.iter().map(|file| {
         let mut progress = chromosome_progress.lock().unwrap();
@@ -191,7 +203,7 @@ async fn process_vcf_files(args: &Args) -> Result<(), VcfError> {
         let memory_usage = memory_usage.clone();
         let chromosome_progress = chromosome_progress.clone();
         let progress_bars = progress_bars.clone();
-        println!("Started processing file: {:?}", file.path);
+        debug!("Started processing file: {:?}", file.path);
         if file.is_compressed {
             process_compressed_file(&file, args.chunk_size * 1024 * 1024, memory_usage, max_memory_usage, chunk_sender, chromosome_progress, progress_bars)
         } else {
@@ -360,9 +372,9 @@ fn process_compressed_file(
     chromosome_progress: Arc<Mutex<HashMap<String, ChromosomeProgress>>>,
     progress_bars: Arc<Mutex<HashMap<String, ProgressBar>>>
 ) -> Result<(), VcfError> {
-    println!("Starting to process compressed file: {:?}", file.path);
+    debug!("Starting to process compressed file: {:?}", file.path);
     let mut reader = create_reader(&file.path)?;
-    println!("Reader created for file: {:?}", file.path);
+    debug!("Reader created for file: {:?}", file.path);
     let mut buffer = vec![0; chunk_size];
     let mut line_buffer = Vec::new();
 
@@ -460,7 +472,7 @@ fn chunk_writer(
     progress_bars: Arc<Mutex<HashMap<String, ProgressBar>>>,
     memory_usage: Arc<AtomicUsize>
 ) -> Result<(), VcfError> {
-    println!("Chunk writer started and waiting for chunks");
+    debug!("Chunk writer started and waiting for chunks");
     let mut total_bytes_written = 0;
     let mut sys = System::new_all();
 
@@ -527,7 +539,7 @@ fn chunk_writer(
     }
     overall_pb.finish_with_message("Concatenation completed");
 
-    println!("Chunk writer finished. Total data processed: {}", human_bytes(total_bytes_written as f64));
+    info!("Chunk writer finished. Total data processed: {}", human_bytes(total_bytes_written as f64));
     Ok(())
 }
 
@@ -0,0 +1,2302 @@
+use crate::process::*;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use rust_htslib::bcf::{self, Read as BcfRead};
+use rust_htslib::htslib;
+use rust_htslib::tbx::{self, Read as TbxRead};
+use tracing::{debug, error, info, warn};
+
+// Function to parse regions file (mask or allow)
+pub fn parse_regions_file(
+    path: &Path,
+) -> Result<HashMap<String, Vec<(i64, i64)>>, VcfError> {
+    let is_bed_file = path.extension().and_then(|s| s.to_str()) == Some("bed");
+
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut regions: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+
+    for (line_num, line_result) in reader.lines().enumerate() {
+        let line = line_result?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            warn!("Skipping invalid line {}: '{}'", line_num + 1, line);
+            continue; // Skip invalid lines
+        }
+
+        let chr = canonical_chr(fields[0]); // Normalize chromosome name
+        let start: i64 = match fields[1].trim().parse() {
+            Ok(val) => val,
+            Err(_) => {
+                warn!("Invalid start position on line {}: '{}'", line_num + 1, fields[1]);
+                continue;
+            }
+        };
+        let end: i64 = match fields[2].trim().parse() {
+            Ok(val) => val,
+            Err(_) => {
+                warn!("Invalid end position on line {}: '{}'", line_num + 1, fields[2]);
+                continue;
+            }
+        };
+
+        // Adjust positions based on file type
+        let (start, end) = if is_bed_file {
+            // BED files are zero-based, half-open intervals [start, end)
+            (start, end)
+        } else {
+            // Other files are one-based, inclusive intervals [start-1, end)
+            (start - 1, end)
+        };
+
+        regions.entry(chr.clone()).or_default().push((start, end));
+    }
+
+    // Sort the intervals for each chromosome
+    for intervals in regions.values_mut() {
+        intervals.sort_by_key(|&(start, _)| start);
+    }
+
+    Ok(regions)
+}
+
+// Function to check if a position is within any of the regions
+pub fn position_in_regions(pos: i64, regions: &[(i64, i64)]) -> bool {
+    // pos is zero-based
+    // regions are sorted by start position
+    let mut left = 0;
+    let mut right = regions.len();
+
+    while left < right {
+        let mid = (left + right) / 2;
+        let (start, end) = regions[mid];
+        if pos < start {
+            right = mid;
+        } else if pos >= end {
+            left = mid + 1;
+        } else {
+            // pos in [start, end)
+            return true;
+        }
+    }
+    false
+}
+
+// Pairs of config entries (by index) that overlap on the same chromosome, found by sorting on start
+pub fn find_overlapping_entries(entries: &[ConfigEntry]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by(|&a, &b| {
+        (&entries[a].seqname, entries[a].start).cmp(&(&entries[b].seqname, entries[b].start))
+    });
+
+    let mut overlaps = Vec::new();
+    // Entry reaching furthest so far on the current chromosome
+    let mut furthest: Option<usize> = None;
+    for &i in &order {
+        match furthest {
+            Some(f) if entries[f].seqname == entries[i].seqname => {
+                if entries[i].start <= entries[f].end {
+                    overlaps.push((f.min(i), f.max(i)));
+                }
+                if entries[i].end > entries[f].end {
+                    furthest = Some(i);
+                }
+            }
+            _ => furthest = Some(i),
+        }
+    }
+    overlaps
+}
+
+// Warn about overlapping config regions, or fail on them in strict mode
+pub fn check_overlapping_entries(entries: &[ConfigEntry], strict: bool) -> Result<(), VcfError> {
+    let overlaps = find_overlapping_entries(entries);
+    if overlaps.is_empty() {
+        return Ok(());
+    }
+
+    for &(a, b) in &overlaps {
+        let message = format!(
+            "Config regions {}:{}-{} and {}:{}-{} overlap",
+            entries[a].seqname, entries[a].start, entries[a].end,
+            entries[b].seqname, entries[b].start, entries[b].end
+        );
+        if strict {
+            return Err(VcfError::Parse(message));
+        }
+        warn!("{}", message);
+    }
+    Ok(())
+}
+
+// Regions of a 3-column BED file, converted from 0-based half-open to 1-based inclusive
+pub fn parse_bed_file(path: &Path) -> Result<Vec<(String, i64, i64)>, VcfError> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut regions = Vec::new();
+
+    for (line_num, line_result) in reader.lines().enumerate() {
+        let line = line_result?;
+        let trimmed = line.trim();
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with("track")
+            || trimmed.starts_with("browser")
+        {
+            continue;
+        }
+
+        let fields: Vec<&str> = trimmed.split_whitespace().collect();
+        if fields.len() < 3 {
+            return Err(VcfError::Parse(format!(
+                "BED line {} has fewer than 3 columns: '{}'",
+                line_num + 1,
+                line
+            )));
+        }
+        let chr = canonical_chr(fields[0]);
+        let start: i64 = fields[1].parse().map_err(|_| {
+            VcfError::Parse(format!("Invalid start on BED line {}: '{}'", line_num + 1, fields[1]))
+        })?;
+        let end: i64 = fields[2].parse().map_err(|_| {
+            VcfError::Parse(format!("Invalid end on BED line {}: '{}'", line_num + 1, fields[2]))
+        })?;
+        if start < 0 || end <= start {
+            return Err(VcfError::Parse(format!(
+                "Invalid BED interval on line {}: {}-{}",
+                line_num + 1,
+                start,
+                end
+            )));
+        }
+
+        regions.push((chr, start + 1, end));
+    }
+
+    Ok(regions)
+}
+
+// Add BED intervals to a mask map (0-based half-open), merging overlaps so lookups stay exact
+pub fn merge_bed_regions(
+    regions: &mut HashMap<String, Vec<(i64, i64)>>,
+    bed_regions: &[(String, i64, i64)],
+) {
+    for (chr, start, end) in bed_regions {
+        regions.entry(chr.clone()).or_default().push((start - 1, *end));
+    }
+    for intervals in regions.values_mut() {
+        intervals.sort_by_key(|&(start, _)| start);
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(intervals.len());
+        for &(start, end) in intervals.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *intervals = merged;
+    }
+}
+
+// Callable sites from an --accessible_mask BED, merged per chromosome (0-based half-open)
+pub fn parse_accessible_mask(path: &Path) -> Result<HashMap<String, Vec<(i64, i64)>>, VcfError> {
+    let mut regions = HashMap::new();
+    merge_bed_regions(&mut regions, &parse_bed_file(path)?);
+    Ok(regions)
+}
+
+// Bases of the 1-based inclusive region start-end covered by merged 0-based half-open intervals
+pub fn accessible_length(intervals: &[(i64, i64)], start: i64, end: i64) -> i64 {
+    intervals
+        .iter()
+        .map(|&(mask_start, mask_end)| (mask_end.min(end) - mask_start.max(start - 1)).max(0))
+        .sum()
+}
+
+// Bases of chr:start-end in a parsed --accessible_mask
+pub fn count_accessible_bases(regions: &HashMap<String, Vec<(i64, i64)>>, chr: &str, start: i64, end: i64) -> i64 {
+    regions
+        .get(&canonical_chr(chr))
+        .map_or(0, |intervals| accessible_length(intervals, start, end))
+}
+
+pub fn parse_config_file(path: &Path) -> Result<Vec<ConfigEntry>, VcfError> {
+    let file = File::open(path)?;
+    let input: Box<dyn io::Read> = if path.extension().and_then(|s| s.to_str()) == Some("gz") {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(input);
+
+    let headers = reader.headers().map_err(|e| VcfError::Io(e.into()))?.clone();
+    // An optional 8th column named "label" comes before the sample columns
+    let has_label = headers.get(7).is_some_and(|name| name.trim().eq_ignore_ascii_case("label"));
+    let prefix_columns = if has_label { 8 } else { 7 };
+    let sample_names: Vec<String> = headers.iter().skip(prefix_columns).map(String::from).collect();
+
+    // Check if the number of sample names is consistent
+    if sample_names.is_empty() {
+        error!("No sample names found in the configuration file header after skipping the first {} columns. Tabs must separate all columns, including sample names.", prefix_columns);
+        return Err(VcfError::Parse("No sample names found in config file header.".to_string()));
+    }
+
+    let mut entries = Vec::new();
+    let mut invalid_genotypes = 0;
+    let mut total_genotypes = 0;
+
+    for (line_num, result) in reader.records().enumerate() {
+        let record = result.map_err(|e| VcfError::Io(e.into()))?;
+
+        // Check if the record has the expected number of fields
+        if record.len() != headers.len() {
+            error!("Record on line {} does not have the same number of fields as the header. Expected {}, found {}. Please check for missing tabs in the config file.", line_num + 2, headers.len(), record.len());
+            return Err(VcfError::Parse(format!("Mismatched number of fields in record on line {}", line_num + 2)));
+        }
+
+        // Normalize chromosome name by removing "chr" prefix
+        let file_line = line_num + 2;
+        let seqname = record
+            .get(0)
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(canonical_chr)
+            .ok_or(VcfError::MissingField { line: file_line, field: "seqname" })?;
+        let start: i64 = parse_field(record.get(1), file_line, "start")?;
+        let end: i64 = parse_field(record.get(2), file_line, "end")?;
+        let label = record
+            .get(7)
+            .filter(|_| has_label)
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(String::from);
+
+        let mut samples_unfiltered = HashMap::new();
+        let mut samples_filtered = HashMap::new();
+
+        for (i, field) in record.iter().enumerate().skip(prefix_columns) {
+            total_genotypes += 1;
+            if i < sample_names.len() + prefix_columns {
+                let sample_name = &sample_names[i - prefix_columns];
+                match parse_config_genotype(field) {
+                    Some((groups, exact)) => {
+                        samples_unfiltered.insert(sample_name.clone(), groups);
+                        if exact {
+                            samples_filtered.insert(sample_name.clone(), groups);
+                        }
+                    }
+                    None => invalid_genotypes += 1,
+                }
+            } else {
+                warn!("More genotype fields than sample names at line {}.", line_num + 2);
+            }
+        }
+
+        if samples_unfiltered.is_empty() {
+            warn!("No valid genotypes found for region {}:{}-{}", seqname, start, end);
+            continue;
+        }
+
+        entries.push(ConfigEntry {
+            seqname,
+            start,
+            end,
+            label,
+            samples_unfiltered,
+            samples_filtered,
+        });
+    }
+
+    let invalid_percentage = (invalid_genotypes as f64 / total_genotypes as f64) * 100.0;
+    info!("Number of invalid genotypes: {} ({:.2}%)", invalid_genotypes, invalid_percentage);
+
+    Ok(entries)
+}
+
+// Haplotype group labels of one config genotype such as "0|1" or "0|1_lowconf", and whether the
+// genotype is exactly "a|b". The part before '_' counts for unfiltered analyses; only exact
+// genotypes count for filtered ones.
+pub fn parse_config_genotype(field: &str) -> Option<((u8, u8), bool)> {
+    let genotype_str_unfiltered = field.split('_').next().unwrap_or("");
+    let mut chars = genotype_str_unfiltered.chars();
+    // Each digit is the haplotype group label; 0 and 1 are the inversion orientations
+    let groups = match (chars.next(), chars.next(), chars.next()) {
+        (Some(left), Some('|'), Some(right)) => (left.to_digit(10)? as u8, right.to_digit(10)? as u8),
+        _ => return None,
+    };
+    Some((groups, field.len() == 3))
+}
+
+// Parse a YAML config, the structured alternative to the TSV config file:
+//
+// regions:
+//   - seqname: chr1
+//     start: 1000
+//     end: 2000
+//     samples:
+//       NA12878: "0|1"
+pub fn parse_yaml_config(path: &Path) -> Result<Vec<ConfigEntry>, VcfError> {
+    let contents = fs::read_to_string(path)?;
+    let config: YamlConfig = serde_yaml::from_str(&contents)
+        .map_err(|e| VcfError::Parse(format!("Invalid YAML config {}: {}", path.display(), e)))?;
+
+    let mut entries = Vec::new();
+    for region in config.regions {
+        let seqname = canonical_chr(&region.seqname);
+        if region.start > region.end {
+            return Err(VcfError::Parse(format!(
+                "Region {}:{}-{} in {} has start after end",
+                seqname, region.start, region.end, path.display()
+            )));
+        }
+
+        let mut samples_unfiltered = HashMap::new();
+        let mut samples_filtered = HashMap::new();
+        for (sample_name, genotype) in &region.samples {
+            match parse_config_genotype(genotype.trim()) {
+                Some((groups, exact)) => {
+                    samples_unfiltered.insert(sample_name.clone(), groups);
+                    if exact {
+                        samples_filtered.insert(sample_name.clone(), groups);
+                    }
+                }
+                None => warn!(
+                    "Invalid genotype '{}' for sample {} in region {}:{}-{}",
+                    genotype, sample_name, seqname, region.start, region.end
+                ),
+            }
+        }
+
+        if samples_unfiltered.is_empty() {
+            warn!("No valid genotypes found for region {}:{}-{}", seqname, region.start, region.end);
+            continue;
+        }
+
+        entries.push(ConfigEntry {
+            seqname,
+            start: region.start,
+            end: region.end,
+            label: region.label.clone(),
+            samples_unfiltered,
+            samples_filtered,
+        });
+    }
+    Ok(entries)
+}
+
+
+// Parse a required field, telling an absent or empty value apart from one of the wrong type
+pub fn parse_field<T: std::str::FromStr>(value: Option<&str>, line: usize, field: &'static str) -> Result<T, VcfError> {
+    let value = value
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .ok_or(VcfError::MissingField { line, field })?;
+    value.parse().map_err(|_| VcfError::BadValue { line, field, value: value.to_string() })
+}
+
+// One or more comma-separated start-end intervals, e.g. "1000-2000,5000-6000"
+// Returned sorted, with overlapping intervals merged
+pub fn parse_region(region: &str) -> Result<Vec<(i64, i64)>, VcfError> {
+    let mut intervals = Vec::new();
+    for interval in region.split(',') {
+        let parts: Vec<&str> = interval.split('-').collect();
+        if parts.len() != 2 {
+            return Err(VcfError::InvalidRegion(format!(
+                "Invalid region format '{}'. Use start-end, or several separated by commas",
+                interval
+            )));
+        }
+        // Not read from a file, so there is no line number
+        let start: i64 = parse_field(Some(parts[0]), 0, "start")?;
+        let end: i64 = parse_field(Some(parts[1]), 0, "end")?;
+        if start >= end {
+            return Err(VcfError::InvalidRegion(format!(
+                "Start position must be less than end position in '{}'",
+                interval
+            )));
+        }
+        intervals.push((start, end));
+    }
+    Ok(merge_intervals(intervals))
+}
+
+// Sort 1-based inclusive intervals and merge the ones that overlap
+pub fn merge_intervals(mut intervals: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(i64, i64)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// (UCSC name without "chr", Ensembl name) for chromosomes whose names differ beyond the prefix
+pub const CHR_NAME_MAP: &[(&str, &str)] = &[("M", "MT")];
+
+// Name used to match chromosomes across files: no "chr" prefix, Ensembl spelling
+pub fn canonical_chr(name: &str) -> String {
+    let bare = name.trim().trim_start_matches("chr");
+    CHR_NAME_MAP
+        .iter()
+        .find(|(ucsc, ensembl)| bare == *ucsc || bare == *ensembl)
+        .map_or(bare, |(_, ensembl)| ensembl)
+        .to_string()
+}
+
+// Every spelling of a chromosome without the "chr" prefix, e.g. M and MT
+pub fn chr_aliases(name: &str) -> Vec<String> {
+    let canonical = canonical_chr(name);
+    let mut aliases = vec![canonical.clone()];
+    aliases.extend(
+        CHR_NAME_MAP
+            .iter()
+            .filter(|(_, ensembl)| *ensembl == canonical)
+            .map(|(ucsc, _)| ucsc.to_string()),
+    );
+    aliases
+}
+
+// Write a chromosome name in the --chr_prefix_mode convention
+pub fn normalize_chr(name: &str, mode: ChrPrefixMode) -> String {
+    let canonical = canonical_chr(name);
+    match mode {
+        ChrPrefixMode::Auto => name.trim().to_string(),
+        ChrPrefixMode::Ensembl => canonical,
+        ChrPrefixMode::Ucsc => {
+            let ucsc = CHR_NAME_MAP
+                .iter()
+                .find(|(_, ensembl)| *ensembl == canonical)
+                .map_or(canonical.as_str(), |(ucsc, _)| ucsc);
+            format!("chr{}", ucsc)
+        }
+    }
+}
+
+pub fn find_vcf_file(folder: &str, chr: &str) -> Result<PathBuf, VcfError> {
+    let path = Path::new(folder);
+    let aliases = chr_aliases(chr);
+    // The file name starts with the chromosome, with or without "chr", and no further digit
+    let names_chr = |file_name: &str| {
+        aliases.iter().any(|alias| {
+            [format!("chr{}", alias), alias.clone()].iter().any(|prefix| {
+                file_name.starts_with(prefix.as_str())
+                    && file_name.chars().nth(prefix.len()).map_or(false, |c| !c.is_ascii_digit())
+            })
+        })
+    };
+    let mut chr_specific_files: Vec<_> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let path = entry.path();
+            let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            names_chr(file_name) &&
+                [".vcf", ".vcf.gz", ".bgz", ".bcf"].iter().any(|suffix| file_name.ends_with(suffix))
+        })
+        .map(|entry| entry.path())
+        .collect();
+
+    // read_dir order is unspecified, so ties go to a .vcf.gz and otherwise to the first name in sorted order
+    chr_specific_files.sort();
+    let preferred = chr_specific_files
+        .iter()
+        .position(|file| file.to_str().is_some_and(|name| name.ends_with(".vcf.gz")))
+        .unwrap_or(0);
+    if chr_specific_files.is_empty() {
+        Err(VcfError::NoVcfFiles)
+    } else {
+        Ok(chr_specific_files.swap_remove(preferred))
+    }
+}
+
+// Stands for standard input wherever a VCF path is expected
+pub const STDIN_PATH: &str = "-";
+
+// Gzip or BGZF compressed, by extension; some pipelines name bgzip output .bgz instead of .gz
+pub fn has_gzip_extension(path: &Path) -> bool {
+    matches!(path.extension().and_then(|s| s.to_str()), Some("gz") | Some("bgz"))
+}
+
+// Plain-text VCF piped in, e.g. from bcftools view
+pub fn open_vcf_stdin() -> Result<Box<dyn BufRead + Send>, VcfError> {
+    // A StdinLock cannot be sent to the reader thread, so the handle itself is buffered
+    Ok(Box::new(BufReader::new(io::stdin())))
+}
+
+pub fn open_vcf_reader(path: &Path) -> Result<Box<dyn BufRead + Send>, VcfError> {
+    if path == Path::new(STDIN_PATH) {
+        return open_vcf_stdin();
+    }
+    if path.extension().and_then(|s| s.to_str()) == Some("bcf") {
+        return Ok(Box::new(BcfTextReader::new(path)?));
+    }
+
+    let file = File::open(path)?;
+    
+    if has_gzip_extension(path) {
+        // bgzip output is a series of independent blocks that can be inflated in parallel
+        if is_bgzf(path)? {
+            return Ok(Box::new(BgzfParallelReader::new(BufReader::new(file))));
+        }
+        let decoder = MultiGzDecoder::new(file);
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        // Pipes, empty files and filesystems without mmap support fall back to buffered reads
+        #[cfg(feature = "mmap")]
+        if let Ok(reader) = open_mmap_reader(path) {
+            return Ok(reader);
+        }
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// Plain-text VCF read straight from a memory map
+#[cfg(feature = "mmap")]
+pub fn open_mmap_reader(path: &Path) -> Result<Box<dyn BufRead + Send>, VcfError> {
+    let file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(VcfError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not a regular file and cannot be memory-mapped", path.display()),
+        )));
+    }
+    // Safety: the map is read-only; as with any mmap, the file must not be truncated while it is read
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(Box::new(io::Cursor::new(mmap)))
+}
+
+pub type VcfLineIter = Box<dyn Iterator<Item = Result<String, VcfError>> + Send>;
+
+// Iterate over only the VCF lines within chr:start-end (1-based, inclusive) using a .tbi or .csi index
+// Returns None when the file is not indexed or the index does not know the chromosome, so the caller scans linearly
+pub fn try_open_tabix_reader(
+    path: &Path,
+    chr: &str,
+    start: i64,
+    end: i64,
+) -> Result<Option<VcfLineIter>, VcfError> {
+    if !has_gzip_extension(path) {
+        return Ok(None);
+    }
+    let has_index = ["tbi", "csi"].iter().any(|ext| {
+        let mut index_path = path.as_os_str().to_owned();
+        index_path.push(format!(".{}", ext));
+        Path::new(&index_path).exists()
+    });
+    if !has_index {
+        return Ok(None);
+    }
+
+    let mut reader = match tbx::Reader::from_path(path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            warn!("Could not load index for {}, scanning whole file: {}", path.display(), e);
+            return Ok(None);
+        }
+    };
+
+    // The config may name chromosomes with or without the "chr" prefix, or as M rather than MT
+    let names = std::iter::once(chr.to_string())
+        .chain(chr_aliases(chr).into_iter().flat_map(|alias| [format!("chr{}", alias), alias]));
+    let tid = match names
+        .into_iter()
+        .find_map(|name| reader.tid(&name).ok())
+    {
+        Some(tid) => tid,
+        None => return Ok(None),
+    };
+
+    // Tabix queries are 0-based and half-open
+    reader
+        .fetch(tid, (start - 1).max(0) as u64, end.max(0) as u64)
+        .map_err(|e| VcfError::Parse(format!("Failed to query index of {}: {}", path.display(), e)))?;
+
+    let mut record = Vec::new();
+    Ok(Some(Box::new(std::iter::from_fn(move || match reader.read(&mut record) {
+        Ok(true) => Some(Ok(String::from_utf8_lossy(&record).into_owned())),
+        Ok(false) => None,
+        Err(e) => Some(Err(VcfError::Parse(format!("Failed to read indexed VCF record: {}", e)))),
+    }))))
+}
+
+// Reads a BCF file as VCF text, one line at a time, so it goes through the same parsing as VCF input
+struct BcfTextReader {
+    reader: bcf::Reader,
+    record: bcf::Record,
+    line: htslib::kstring_t,
+    offset: usize,
+    header_pending: bool,
+}
+
+// The kstring buffer is owned by this reader alone
+unsafe impl Send for BcfTextReader {}
+
+impl BcfTextReader {
+    fn new(path: &Path) -> Result<Self, VcfError> {
+        let reader = bcf::Reader::from_path(path).map_err(|e| {
+            VcfError::Parse(format!("Failed to open BCF file {}: {}", path.display(), e))
+        })?;
+        let record = reader.empty_record();
+        Ok(BcfTextReader {
+            reader,
+            record,
+            line: htslib::kstring_t { l: 0, m: 0, s: std::ptr::null_mut() },
+            offset: 0,
+            header_pending: true,
+        })
+    }
+
+    // Format the header on the first call, then one record per call; false at end of file
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.line.l = 0;
+        self.offset = 0;
+        let ret = if self.header_pending {
+            self.header_pending = false;
+            unsafe { htslib::bcf_hdr_format(self.reader.header().inner, 0, &mut self.line) }
+        } else {
+            match self.reader.read(&mut self.record) {
+                None => return Ok(false),
+                Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                Some(Ok(())) => unsafe {
+                    htslib::vcf_format(self.reader.header().inner, self.record.inner, &mut self.line)
+                },
+            }
+        };
+        if ret < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Failed to format BCF record as VCF"));
+        }
+        Ok(true)
+    }
+
+    fn buffered(&self) -> &[u8] {
+        if self.line.s.is_null() {
+            return &[];
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(self.line.s as *const u8, self.line.l as usize) };
+        &bytes[self.offset..]
+    }
+}
+
+impl io::Read for BcfTextReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for BcfTextReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffered().is_empty() && !self.next_line()? {
+            return Ok(&[]);
+        }
+        Ok(self.buffered())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset = (self.offset + amt).min(self.line.l as usize);
+    }
+}
+
+impl Drop for BcfTextReader {
+    fn drop(&mut self) {
+        if !self.line.s.is_null() {
+            unsafe { htslib::hts_free(self.line.s as *mut std::os::raw::c_void) };
+        }
+    }
+}
+
+// Blocks inflated per batch, spread over the Rayon pool (each holds at most 64 KB of text)
+const BGZF_BLOCKS_PER_BATCH: usize = 64;
+
+// Whether the file starts with a BGZF block: a gzip member whose extra field has a BC subfield
+pub fn is_bgzf(path: &Path) -> Result<bool, VcfError> {
+    let mut header = Vec::with_capacity(18);
+    File::open(path)?.take(18).read_to_end(&mut header)?;
+    Ok(bgzf_block_size(&header).is_some())
+}
+
+// Total size of the BGZF block starting with this header (the BSIZE field + 1)
+// None unless the header is gzip with FEXTRA and a BC subfield of length 2
+fn bgzf_block_size(header: &[u8]) -> Option<usize> {
+    if header.len() < 12 || header[..4] != [31, 139, 8, 4] {
+        return None;
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let extra = header.get(12..12 + xlen)?;
+    let mut offset = 0;
+    while offset + 4 <= extra.len() {
+        let slen = u16::from_le_bytes([extra[offset + 2], extra[offset + 3]]) as usize;
+        if extra[offset] == 66 && extra[offset + 1] == 67 && slen == 2 {
+            let bsize = extra.get(offset + 4..offset + 6)?;
+            return Some(u16::from_le_bytes([bsize[0], bsize[1]]) as usize + 1);
+        }
+        offset += 4 + slen;
+    }
+    None
+}
+
+// Reads BGZF blocks in batches and inflates the blocks of a batch concurrently
+pub struct BgzfParallelReader<R: io::Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    offset: usize,
+    done: bool,
+}
+
+impl<R: io::Read> BgzfParallelReader<R> {
+    pub fn new(inner: R) -> Self {
+        BgzfParallelReader { inner, buffer: Vec::new(), offset: 0, done: false }
+    }
+
+    // One compressed block, or None at a clean end of file
+    fn read_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut header = vec![0u8; 12];
+        let first = self.inner.read(&mut header[..1])?;
+        if first == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut header[1..])?;
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        header.resize(12 + xlen, 0);
+        self.inner.read_exact(&mut header[12..])?;
+        let block_size = bgzf_block_size(&header)
+            .filter(|&size| size > header.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid BGZF block header"))?;
+        let mut block = header;
+        block.resize(block_size, 0);
+        let header_len = 12 + xlen;
+        self.inner.read_exact(&mut block[header_len..])?;
+        Ok(Some(block))
+    }
+
+    // Replace the buffer with the text of the next batch; false at end of file
+    fn next_batch(&mut self) -> io::Result<bool> {
+        let mut blocks = Vec::with_capacity(BGZF_BLOCKS_PER_BATCH);
+        while blocks.len() < BGZF_BLOCKS_PER_BATCH {
+            match self.read_block()? {
+                Some(block) => blocks.push(block),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        let inflated = blocks
+            .par_iter()
+            .map(|block| {
+                let mut text = Vec::new();
+                GzDecoder::new(block.as_slice()).read_to_end(&mut text)?;
+                Ok(text)
+            })
+            .collect::<io::Result<Vec<Vec<u8>>>>()?;
+        self.buffer = inflated.concat();
+        self.offset = 0;
+        Ok(!self.buffer.is_empty() || !self.done)
+    }
+}
+
+impl<R: io::Read> io::Read for BgzfParallelReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: io::Read> BufRead for BgzfParallelReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Empty blocks, such as the end-of-file marker, leave a batch without text
+        while self.offset >= self.buffer.len() && !self.done {
+            if !self.next_batch()? {
+                break;
+            }
+        }
+        Ok(&self.buffer[self.offset..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset = (self.offset + amt).min(self.buffer.len());
+    }
+}
+
+// Function to collect all unique chromosome names from VCF files in the folder
+pub fn collect_vcf_chromosomes(vcf_folder: &str) -> Result<Vec<String>, VcfError> {
+    let path = Path::new(vcf_folder);
+    let mut chromosomes = HashSet::new();
+
+    // Iterate over all files in the VCF folder
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_path = entry.path();
+
+        // Process only .vcf, .vcf.gz (or .vcf.bgz) and .bcf files
+        if let Some(ext) = file_path.extension().and_then(|s| s.to_str()) {
+            if ext != "vcf" && ext != "gz" && ext != "bgz" && ext != "bcf" {
+                continue;
+            }
+        } else {
+            continue;
+        }
+
+        // Open the VCF file (handle gzipped and BCF files)
+        let mut reader = open_vcf_reader(&file_path)?;
+
+        // Read lines until header is found
+        let mut buffer = String::new();
+        while reader.read_line(&mut buffer)? > 0 {
+            if buffer.starts_with("#CHROM") {
+                break;
+            }
+            buffer.clear();
+        }
+
+        // Read variant records to collect chromosome names
+        for line_result in reader.lines() {
+            let line = line_result?;
+            if line.starts_with("#") {
+                continue; // Skip header lines
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 1 {
+                continue; // Skip invalid lines
+            }
+            let vcf_chr = canonical_chr(fields[0]);
+            if !vcf_chr.is_empty() {
+                chromosomes.insert(vcf_chr);
+            }
+        }
+    }
+
+    let chromosomes: Vec<String> = chromosomes.into_iter().collect();
+    Ok(chromosomes)
+}
+
+
+// One sample name per line; blank lines and lines starting with '#' are skipped
+pub fn parse_sample_list(path: &Path) -> Result<Vec<String>, VcfError> {
+    let contents = fs::read_to_string(path)?;
+    let samples: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    if samples.is_empty() {
+        return Err(VcfError::Parse(format!("No sample names found in {}", path.display())));
+    }
+    Ok(samples)
+}
+
+// Sample-to-group assignments from a two-column TSV: sample_id, then any string as the group label
+// Blank lines, '#' lines and a leading "sample_id" header line are skipped
+pub fn parse_group_file(path: &Path) -> Result<HashMap<String, String>, VcfError> {
+    let contents = fs::read_to_string(path)?;
+    let mut groups = HashMap::new();
+
+    for (line_num, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = trimmed.split('\t').map(str::trim).collect();
+        if fields.len() < 2 || fields[0].is_empty() || fields[1].is_empty() {
+            return Err(VcfError::Parse(format!(
+                "Group file line {} needs a sample ID and a group label separated by a tab: '{}'",
+                line_num + 1,
+                line
+            )));
+        }
+        if groups.is_empty() && fields[0] == "sample_id" {
+            continue;
+        }
+
+        if let Some(previous) = groups.insert(fields[0].to_string(), fields[1].to_string()) {
+            if previous != fields[1] {
+                return Err(VcfError::Parse(format!(
+                    "Sample {} is assigned to both group {} and group {} in {}",
+                    fields[0],
+                    previous,
+                    fields[1],
+                    path.display()
+                )));
+            }
+        }
+    }
+
+    if groups.is_empty() {
+        return Err(VcfError::Parse(format!("No sample groups found in {}", path.display())));
+    }
+    Ok(groups)
+}
+
+// Haplotype group of each sample in a PLINK FAM (or the first six columns of a PED) file, from the
+// phenotype column: 1 is group 0, 2 is group 1. Samples with a missing phenotype (-9 or 0) are left out.
+pub fn parse_fam_file(path: &Path) -> Result<HashMap<String, u8>, VcfError> {
+    let contents = fs::read_to_string(path)?;
+    let mut groups = HashMap::new();
+
+    for (line_num, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        // family_id, sample_id, father_id, mother_id, sex, phenotype
+        let fields: Vec<&str> = trimmed.split_whitespace().collect();
+        if fields.len() < 6 {
+            return Err(VcfError::Parse(format!(
+                "FAM line {} needs six columns (family, sample, father, mother, sex, phenotype): '{}'",
+                line_num + 1,
+                line
+            )));
+        }
+        let group = match fields[5] {
+            "1" => 0,
+            "2" => 1,
+            "-9" | "0" => continue,
+            other => {
+                return Err(VcfError::Parse(format!(
+                    "FAM line {} has phenotype '{}'; expected 1, 2 or -9",
+                    line_num + 1,
+                    other
+                )))
+            }
+        };
+
+        if let Some(previous) = groups.insert(fields[1].to_string(), group) {
+            if previous != group {
+                return Err(VcfError::Parse(format!(
+                    "Sample {} has both phenotype 1 and 2 in {}",
+                    fields[1],
+                    path.display()
+                )));
+            }
+        }
+    }
+
+    if groups.is_empty() {
+        return Err(VcfError::Parse(format!("No samples with phenotype 1 or 2 in {}", path.display())));
+    }
+    Ok(groups)
+}
+
+// Indices of the VCF samples named in the list, matched by full name or by extract_sample_id
+// Warns about listed samples that are not in the VCF
+pub fn select_sample_columns(vcf_samples: &[String], sample_list: &[String]) -> Result<Vec<usize>, VcfError> {
+    let wanted: HashSet<&str> = sample_list.iter().map(String::as_str).collect();
+    let columns: Vec<usize> = vcf_samples
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| wanted.contains(name.as_str()) || wanted.contains(extract_sample_id(name)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let present: HashSet<&str> = columns
+        .iter()
+        .flat_map(|&i| [vcf_samples[i].as_str(), extract_sample_id(&vcf_samples[i])])
+        .collect();
+    let missing: Vec<&str> = sample_list
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !present.contains(name))
+        .collect();
+    if !missing.is_empty() {
+        warn!("The following samples from the sample list are missing in the VCF: {:?}", missing);
+    }
+
+    if columns.is_empty() {
+        return Err(VcfError::Parse("None of the samples in the sample list are in the VCF".to_string()));
+    }
+    Ok(columns)
+}
+
+// The --exclude_samples names that match no VCF column, by full name or sample ID
+pub fn missing_excluded_samples<'a>(vcf_samples: &[String], exclude_samples: &'a [String]) -> Vec<&'a str> {
+    let present: HashSet<&str> = vcf_samples
+        .iter()
+        .flat_map(|name| [name.as_str(), extract_sample_id(name)])
+        .collect();
+    exclude_samples
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !present.contains(name))
+        .collect()
+}
+
+// Drop the --exclude_samples samples from the kept VCF columns, matching names like select_sample_columns
+pub fn exclude_sample_columns(
+    vcf_samples: &[String],
+    columns: Vec<usize>,
+    exclude_samples: &[String],
+) -> Result<Vec<usize>, VcfError> {
+    let excluded: HashSet<&str> = exclude_samples.iter().map(String::as_str).collect();
+    let kept: Vec<usize> = columns
+        .into_iter()
+        .filter(|&i| {
+            let name = vcf_samples[i].as_str();
+            !excluded.contains(name) && !excluded.contains(extract_sample_id(name))
+        })
+        .collect();
+    if kept.is_empty() {
+        return Err(VcfError::Parse("No samples are left after --exclude_samples".to_string()));
+    }
+    Ok(kept)
+}
+
+// Function to validate VCF header
+pub fn validate_vcf_header(header: &str) -> Result<(), VcfError> {
+    let fields: Vec<&str> = header.split('\t').collect();
+    let required_fields = vec!["#CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO", "FORMAT"];
+
+    if fields.len() < required_fields.len() || fields[..required_fields.len()] != required_fields[..] {
+        return Err(VcfError::InvalidVcfFormat("Invalid VCF header format".to_string()));
+    }
+    Ok(())
+}
+
+// Count header and variant lines, multi-allelic sites and per-sample missing genotypes of a VCF
+pub fn vcf_audit(path: &Path) -> Result<VcfAuditReport, VcfError> {
+    let reader = open_vcf_reader(path)?;
+    let mut report = VcfAuditReport::default();
+    let mut seen_header = false;
+
+    for (line_num, line_result) in reader.lines().enumerate() {
+        let line = line_result?;
+        report.total_lines += 1;
+        if line.starts_with("##") {
+            report.header_lines += 1;
+            continue;
+        }
+        if line.starts_with("#CHROM") {
+            validate_vcf_header(&line)?;
+            report.header_lines += 1;
+            report.sample_names = line.split('\t').skip(9).map(String::from).collect();
+            report.missing_per_sample = vec![0; report.sample_names.len()];
+            seen_header = true;
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !seen_header {
+            return Err(VcfError::InvalidVcfFormat(format!(
+                "Variant line {} appears before the #CHROM header",
+                line_num + 1
+            )));
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 + report.sample_names.len() {
+            return Err(VcfError::Parse(format!(
+                "Line {} has {} fields, expected {}",
+                line_num + 1,
+                fields.len(),
+                9 + report.sample_names.len()
+            )));
+        }
+        report.variant_lines += 1;
+        *report.variants_per_chromosome.entry(fields[0].to_string()).or_insert(0) += 1;
+        if fields[4].contains(',') {
+            report.multiallelic_sites += 1;
+        }
+        for (i, sample_field) in fields[9..9 + report.sample_names.len()].iter().enumerate() {
+            let gt = sample_field.split(':').next().unwrap_or("");
+            if gt.is_empty() || gt.split(['|', '/']).any(|allele| allele == ".") {
+                report.missing_per_sample[i] += 1;
+            }
+        }
+    }
+
+    if !seen_header {
+        return Err(VcfError::InvalidVcfFormat("No #CHROM header line found".to_string()));
+    }
+    Ok(report)
+}
+
+// Contig name and length from a `##contig=<ID=...,length=...>` meta-information line
+pub fn parse_contig_length(line: &str) -> Option<(String, i64)> {
+    let fields = line.trim_end().strip_prefix("##contig=<")?.strip_suffix('>')?;
+    let mut id = None;
+    let mut length = None;
+    for field in fields.split(',') {
+        match field.split_once('=') {
+            Some(("ID", value)) => id = Some(value.to_string()),
+            Some(("length", value)) => length = value.parse::<i64>().ok(),
+            _ => {}
+        }
+    }
+    Some((id?, length?))
+}
+
+
+// 1-based positions in [start, end] that belong to a CG dinucleotide of the reference, in either case.
+// Both the C and the G are included, since the G is the C of a CpG on the reverse strand.
+pub fn build_cpg_set(fasta: &Path, chr: &str, start: i64, end: i64) -> Result<HashSet<i64>, VcfError> {
+    let mut reader = bio::io::fasta::IndexedReader::from_file(&fasta)
+        .map_err(|e| VcfError::Io(io::Error::other(format!("Failed to open FASTA file: {}", e))))?;
+    let seq_info = reader
+        .index
+        .sequences()
+        .into_iter()
+        .find(|seq| canonical_chr(&seq.name) == canonical_chr(chr))
+        .ok_or_else(|| VcfError::Parse(format!("Chromosome {} not found in reference", chr)))?;
+
+    // One extra base on each side catches dinucleotides that straddle the region boundaries
+    let fetch_start = (start - 2).max(0) as u64;
+    let fetch_end = (end + 1).min(seq_info.len as i64).max(0) as u64;
+    let mut sequence = Vec::new();
+    if fetch_start < fetch_end {
+        reader
+            .fetch(&seq_info.name, fetch_start, fetch_end)
+            .and_then(|_| reader.read(&mut sequence))
+            .map_err(|e| VcfError::Io(io::Error::other(format!("Failed to read {}: {}", seq_info.name, e))))?;
+    }
+
+    let mut cpg_sites = HashSet::new();
+    for (i, pair) in sequence.windows(2).enumerate() {
+        if pair[0].eq_ignore_ascii_case(&b'C') && pair[1].eq_ignore_ascii_case(&b'G') {
+            let c_position = fetch_start as i64 + i as i64 + 1;
+            cpg_sites.extend([c_position, c_position + 1].into_iter().filter(|pos| (start..=end).contains(pos)));
+        }
+    }
+    Ok(cpg_sites)
+}
+
+// Fraction of G and C among the A, C, G and T bases of a sequence; NaN if it has none (e.g. all N)
+pub fn gc_fraction(sequence: &[u8]) -> f64 {
+    let (gc, acgt) = sequence.iter().fold((0usize, 0usize), |(gc, acgt), base| match base.to_ascii_uppercase() {
+        b'G' | b'C' => (gc + 1, acgt + 1),
+        b'A' | b'T' => (gc, acgt + 1),
+        _ => (gc, acgt),
+    });
+    if acgt == 0 {
+        return f64::NAN;
+    }
+    gc as f64 / acgt as f64
+}
+
+// GC content of chr:start-end (1-based, inclusive) in the indexed reference FASTA
+pub fn calculate_gc_content(fasta: &Path, chr: &str, start: i64, end: i64) -> Result<f64, VcfError> {
+    Ok(gc_fraction(&read_reference_sequence(fasta, chr, start, end)?))
+}
+
+// The reference sequence of a chromosome span, read once so that windows and regions can be sliced from it
+pub struct ReferenceSpan {
+    pub start: i64,
+    pub sequence: Vec<u8>,
+}
+
+impl ReferenceSpan {
+    pub fn read(fasta: &Path, chr: &str, start: i64, end: i64) -> Result<Self, VcfError> {
+        Ok(ReferenceSpan { start, sequence: read_reference_sequence(fasta, chr, start, end)? })
+    }
+
+    // GC content of start-end (1-based, inclusive), or None if the span holds none of it
+    pub fn gc_content(&self, start: i64, end: i64) -> Option<f64> {
+        let from = usize::try_from(start - self.start).ok()?;
+        let to = usize::try_from(end - self.start + 1).ok()?.min(self.sequence.len());
+        (from < to).then(|| gc_fraction(&self.sequence[from..to]))
+    }
+}
+
+pub fn read_reference_sequence(
+    fasta_path: &Path,
+    chr: &str, 
+    start: i64,
+    end: i64
+) -> Result<Vec<u8>, VcfError> {
+    // Input validation
+    if start < 0 || end < 0 {
+        return Err(VcfError::Parse(format!(
+            "Invalid coordinates: start ({}) and end ({}) must be non-negative",
+            start, end
+        )));
+    }
+    
+    if start > end {
+        return Err(VcfError::Parse(format!(
+            "Invalid coordinates: start ({}) must be less than or equal to end ({})",
+            start, end
+        )));
+    }
+
+    // Create reader for the FASTA file and its index
+    let mut reader = bio::io::fasta::IndexedReader::from_file(&fasta_path)
+        .map_err(|e| VcfError::Io(io::Error::new(
+            io::ErrorKind::Other, 
+            format!("Failed to open FASTA file: {}", e)
+        )))?;
+
+    // Match with and without the "chr" prefix, and M to MT
+    let sequences = reader.index.sequences();
+    let seq_info = sequences
+        .iter()
+        .find(|seq| canonical_chr(&seq.name) == canonical_chr(chr))
+        .ok_or_else(|| VcfError::Parse(format!(
+            "Chromosome {} (or chr{}) not found in reference", chr, canonical_chr(chr)
+        )))?;
+
+    let seq_length = seq_info.len;
+    let actual_chr_name = seq_info.name.as_str(); // Get a reference to the name
+
+    // Validate start position
+    if start as u64 >= seq_length {
+        return Err(VcfError::Parse(format!(
+            "Start position {} exceeds sequence length {} for chromosome {}",
+            start, seq_length, actual_chr_name
+        )));
+    }
+
+    // Clamp end position to sequence length
+    let adjusted_end = std::cmp::min(end as u64, seq_length - 1);
+    if adjusted_end as i64 != end {
+        warn!("End position {} exceeds sequence length {}. Clamping to {}", end, seq_length, adjusted_end);
+    }
+
+    // Calculate region length and allocate buffer
+    let region_length = (adjusted_end - (start as u64) + 1) as usize;
+    let mut sequence = Vec::with_capacity(region_length);
+
+    // Fetch and read the sequence with proper error handling
+    reader.fetch(actual_chr_name, (start - 1) as u64, end as u64)
+        .map_err(|e| VcfError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to fetch region {}:{}-{}: {}", actual_chr_name, start, adjusted_end, e)
+        )))?;
+
+    reader.read(&mut sequence)
+        .map_err(|e| VcfError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to read sequence for region {}:{}-{}: {}", 
+                    actual_chr_name, start, adjusted_end, e)
+        )))?;
+
+    // Verify sequence length
+    if sequence.len() != region_length {
+        return Err(VcfError::Parse(format!(
+            "Expected sequence length {} but got {} for region {}:{}-{}",
+            region_length, sequence.len(), actual_chr_name, start, adjusted_end
+        )));
+    }
+
+    // Verify sequence content
+    let invalid_chars: Vec<(usize, u8)> = sequence.iter()
+        .enumerate()
+        .filter(|(_, &b)| !matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+        .take(10)  // Show first 10 invalid chars
+        .map(|(i, &b)| (i, b))  // Dereference here
+        .collect();
+    
+    if !invalid_chars.is_empty() {
+        error!("Found invalid characters:");
+        for (pos, ch) in invalid_chars {
+            error!("Position {}: '{}' (ASCII: {})", pos, String::from_utf8_lossy(&[ch]), ch);
+        }
+        return Err(VcfError::Parse(format!(
+            "Invalid nucleotides found in sequence for region {}:{}-{}",
+            actual_chr_name, start, adjusted_end
+        )));
+    }
+
+    Ok(sequence)
+}
+
+
+
+// IN PROGRESS
+// Helper function to parse GFF file and extract CDS regions
+// GTF and GFF use 1-based coordinate system
+pub fn parse_gff_file(
+    gff_path: &Path, 
+    chr: &str,
+    region_start: i64,
+    region_end: i64,
+) -> Result<Vec<CdsRegion>, VcfError> {
+    info!("Parsing GFF file for {}:{}-{}", chr, region_start, region_end);
+
+    let file = File::open(gff_path).map_err(|e| {
+        VcfError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("GFF file not found: {:?}", e),
+        ))
+    })?;
+    let reader = BufReader::new(file);
+    
+    // Change to use transcript ID as key since CDS belongs to transcripts
+    let mut transcript_cdss: HashMap<String, Vec<(i64, i64, i64)>> = HashMap::new();
+    let mut skipped_lines = 0;
+    let mut processed_lines = 0;
+    let mut transcripts_found = HashSet::new();
+    let mut malformed_attributes = 0;
+
+    debug!("Reading GFF entries...");
+
+    for (line_num, line_result) in reader.lines().enumerate() {
+        let line = line_result?;
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            skipped_lines += 1;
+            continue;
+        }
+
+        if canonical_chr(fields[0]) != canonical_chr(chr) {
+            continue;
+        }
+
+        if fields[2] != "CDS" {
+            continue;
+        }
+
+        processed_lines += 1;
+        if processed_lines % 10000 == 0 {
+            debug!("Processed {} CDS entries...", processed_lines);
+        }
+
+        let start: i64 = match fields[3].parse() {
+            Ok(s) => s,
+            Err(_) => {
+                warn!("Invalid start position at line {}, skipping", line_num + 1);
+                skipped_lines += 1;
+                continue;
+            }
+        };
+
+        let end: i64 = match fields[4].parse() {
+            Ok(e) => e,
+            Err(_) => {
+                warn!("Invalid end position at line {}, skipping", line_num + 1);
+                skipped_lines += 1;
+                continue;
+            }
+        };
+
+        if end < region_start || start > region_end {
+            continue;
+        }
+
+        let frame: i64 = fields[7].parse().unwrap_or_else(|_| {
+            warn!("Invalid frame at line {}, using 0", line_num + 1);
+            0
+        });
+
+        // Parse attributes to get transcript_id and gene_name
+        let attributes = fields[8];
+        let mut transcript_id = None;
+        let mut gene_name = None;
+        
+        for attr in attributes.split(';') {
+            let attr = attr.trim();
+            let parts: Vec<&str> = if attr.contains('=') {
+                attr.splitn(2, '=').collect()
+            } else {
+                attr.splitn(2, ' ').collect()
+            };
+
+            if parts.len() != 2 {
+                continue;
+            }
+
+            let key = parts[0].trim();
+            let value = parts[1].trim().trim_matches('"').trim_matches('\'');
+
+            match key {
+                "transcript_id" => transcript_id = Some(value.to_string()),
+                "gene_name" => gene_name = Some(value.to_string()),
+                _ => continue,
+            }
+        }
+
+        let transcript_id = match transcript_id {
+            Some(id) => id,
+            None => {
+                malformed_attributes += 1;
+                if malformed_attributes <= 5 {
+                    warn!("Could not find transcript_id in attributes at line {}: {}", line_num + 1, attributes);
+                }
+                continue;
+            }
+        };
+
+        // Store for later reporting
+        if let Some(gene) = gene_name {
+            transcripts_found.insert(format!("{}:{}", gene, transcript_id));
+        } else {
+            transcripts_found.insert(transcript_id.clone());
+        }
+
+        // Store CDS segment with frame exactly as given in GFF
+        transcript_cdss.entry(transcript_id)
+            .or_default()
+            .push((start, end, frame));
+    }
+
+    info!(
+        "GFF parsing: {} CDS entries processed, {} lines skipped, {} unique transcripts",
+        processed_lines, skipped_lines, transcripts_found.len()
+    );
+    if malformed_attributes > 0 {
+        warn!("Entries with missing transcript IDs: {}", malformed_attributes);
+    }
+
+    debug!("Processing CDS regions by transcript...");
+    let mut cds_regions = Vec::new();
+    let transcripts_processed = 0;
+
+    #[derive(Default)]
+    struct TranscriptStats {
+        total_transcripts: usize,
+        non_divisible_by_three: usize,
+        total_cds_segments: usize,
+        single_cds_transcripts: usize,
+        multi_cds_transcripts: usize,
+        total_coding_length: i64,
+        shortest_transcript_length: Option<i64>,
+        longest_transcript_length: Option<i64>,
+        transcripts_with_gaps: usize,
+    }
+
+    let mut stats = TranscriptStats::default();
+
+    for (transcript_id, mut segments) in transcript_cdss {
+        segments.sort_by_key(|&(start, _, _)| start);
+        
+        debug!("Processing transcript {} with {} CDS segments", transcript_id, segments.len());
+
+        stats.total_transcripts += 1;
+        stats.total_cds_segments += segments.len();
+
+        if segments.len() == 1 {
+            stats.single_cds_transcripts += 1;
+        } else {
+            stats.multi_cds_transcripts += 1;
+        }
+
+        // Check for gaps between segments
+        let has_gaps = segments.windows(2)
+            .any(|w| w[1].0 - w[0].1 > 1);
+        if has_gaps {
+            stats.transcripts_with_gaps += 1;
+        }
+
+        // Calculate total coding length and check individual segments
+        let mut coding_segments = Vec::new();
+        for (i, &(start, end, frame)) in segments.iter().enumerate() {
+            let segment_length = end - start + 1;
+            debug!("  Segment {}: {}-{} (length: {}, frame: {})", i + 1, start, end, segment_length, frame);
+            coding_segments.push((start, end));
+        }
+
+        if segments.is_empty() {
+            warn!("No valid segments for transcript {}", transcript_id);
+            continue;
+        }
+
+        let min_start = segments.iter().map(|&(s, _, _)| s).min().unwrap();
+        let max_end = segments.iter().map(|&(_, e, _)| e).max().unwrap();
+        let transcript_span = max_end - min_start + 1;
+        
+        // Calculate actual coding length (sum of CDS lengths)
+        let total_coding_length: i64 = segments.iter()
+            .map(|&(s, e, _)| e - s + 1)
+            .sum();
+
+        stats.total_coding_length += total_coding_length;
+
+        // Update length stats
+        match stats.shortest_transcript_length {
+            None => stats.shortest_transcript_length = Some(total_coding_length),
+            Some(current) => if total_coding_length < current {
+                stats.shortest_transcript_length = Some(total_coding_length)
+            }
+        }
+        
+        match stats.longest_transcript_length {
+            None => stats.longest_transcript_length = Some(total_coding_length),
+            Some(current) => if total_coding_length > current {
+                stats.longest_transcript_length = Some(total_coding_length)
+            }
+        }
+
+        if total_coding_length % 3 != 0 {
+            stats.non_divisible_by_three += 1;
+            debug!(
+                "Total CDS length {} of transcript {} not divisible by 3 (remainder {}, segment lengths {:?})",
+                total_coding_length,
+                transcript_id,
+                total_coding_length % 3,
+                segments.iter().map(|&(s, e, _)| e - s + 1).collect::<Vec<_>>()
+            );
+        }
+
+        let segs: Vec<(i64,i64)> = segments.iter().map(|&(s,e,_)| (s,e)).collect();
+        let cds_region = CdsRegion {
+            transcript_id: transcript_id.clone(),
+            segments: segs,
+        };
+
+        let cds_start = cds_region.segments.iter().map(|(s, _)| *s).min().unwrap();
+        let cds_end = cds_region.segments.iter().map(|(_, e)| *e).max().unwrap();
+
+        // Create a local variable holding the cloned segments
+        let cloned_segments = cds_region.segments.clone();
+        
+        let min_start_for_print = cloned_segments.iter().map(|(s,_)| s).min().unwrap();
+        let max_end_for_print = cloned_segments.iter().map(|(_,e)| e).max().unwrap();
+        
+        debug!(
+            "  CDS region: {}-{} (genomic span {}, total coding length {})",
+            min_start_for_print, max_end_for_print, transcript_span, total_coding_length
+        );
+
+        cds_regions.push(cds_region);
+    }
+
+    if stats.total_transcripts > 0 {
+        info!("CDS processing summary:");
+        info!("Total transcripts processed: {}", stats.total_transcripts);
+        info!("Total CDS segments: {}", stats.total_cds_segments);
+        info!("Average segments per transcript: {:.2}",
+              stats.total_cds_segments as f64 / stats.total_transcripts as f64);
+        info!("Single-cds transcripts: {} ({:.1}%)",
+              stats.single_cds_transcripts,
+              100.0 * stats.single_cds_transcripts as f64 / stats.total_transcripts as f64);
+        info!("Multi-cds transcripts: {} ({:.1}%)",
+              stats.multi_cds_transcripts,
+              100.0 * stats.multi_cds_transcripts as f64 / stats.total_transcripts as f64);
+        info!("Transcripts with gaps: {} ({:.1}%)",
+              stats.transcripts_with_gaps,
+              100.0 * stats.transcripts_with_gaps as f64 / stats.total_transcripts as f64);
+        info!("Non-divisible by three: {} ({:.1}%)",
+              stats.non_divisible_by_three,
+              100.0 * stats.non_divisible_by_three as f64 / stats.total_transcripts as f64);
+        info!("Total coding bases: {}", stats.total_coding_length);
+
+        if let Some(shortest) = stats.shortest_transcript_length {
+            info!("Shortest transcript: {} bp", shortest);
+        }
+        if let Some(longest) = stats.longest_transcript_length {
+            info!("Longest transcript: {} bp", longest);
+        }
+        info!("Average transcript length: {:.1} bp",
+              stats.total_coding_length as f64 / stats.total_transcripts as f64);
+    }
+
+    if cds_regions.is_empty() {
+        warn!("No valid CDS regions found!");
+    }
+
+    Ok(cds_regions)
+}
+
+// Coding class of a single-base substitution, for the McDonald-Kreitman table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteClass {
+    Synonymous,
+    Nonsynonymous,
+    NonCoding,
+}
+
+// Coding sequence of one --annotation_gff transcript, in the direction of transcription
+#[derive(Debug, Clone)]
+pub struct CodingTranscript {
+    pub gene: String,
+    // CDS segments, 1-based inclusive and sorted by position
+    pub segments: Vec<(i64, i64)>,
+    pub reverse: bool,
+    // Bases before the first complete codon, from the phase of the first CDS segment
+    pub phase: usize,
+    pub sequence: Vec<u8>,
+}
+
+impl CodingTranscript {
+    // Index of a genomic position in the coding sequence
+    fn coding_index(&self, pos: i64) -> Option<usize> {
+        let mut offset = 0;
+        for &(start, end) in &self.segments {
+            if (start..=end).contains(&pos) {
+                let forward = (offset + pos - start) as usize;
+                if forward >= self.sequence.len() {
+                    return None;
+                }
+                return Some(if self.reverse { self.sequence.len() - 1 - forward } else { forward });
+            }
+            offset += end - start + 1;
+        }
+        None
+    }
+}
+
+// CDS features of a region, keeping the longest transcript of each gene
+#[derive(Debug, Clone, Default)]
+pub struct GffIndex {
+    pub transcripts: Vec<CodingTranscript>,
+}
+
+impl GffIndex {
+    pub fn transcript_at(&self, pos: i64) -> Option<&CodingTranscript> {
+        self.transcripts.iter().find(|t| t.coding_index(pos).is_some())
+    }
+}
+
+fn complement_base(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+// Amino acid of a codon in the standard genetic code ('*' for stop); None if it has a base other than ACGT
+pub fn translate_codon(codon: &[u8]) -> Option<u8> {
+    const AMINO_ACIDS: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+    let mut index = 0;
+    for &base in codon {
+        let value = match base.to_ascii_uppercase() {
+            b'T' => 0,
+            b'C' => 1,
+            b'A' => 2,
+            b'G' => 3,
+            _ => return None,
+        };
+        index = index * 4 + value;
+    }
+    (codon.len() == 3).then(|| AMINO_ACIDS[index])
+}
+
+// Whether replacing ref_ by alt at pos changes the encoded amino acid.
+// Indels, MNPs and sites outside a complete codon are NonCoding and stay out of the MK table.
+pub fn classify_site(pos: i64, ref_: &str, alt: &str, gff_features: &GffIndex) -> SiteClass {
+    let (&[ref_base], &[alt_base]) = (ref_.as_bytes(), alt.as_bytes()) else {
+        return SiteClass::NonCoding;
+    };
+    let Some(transcript) = gff_features.transcript_at(pos) else {
+        return SiteClass::NonCoding;
+    };
+    let Some(index) = transcript.coding_index(pos).filter(|&index| index >= transcript.phase) else {
+        return SiteClass::NonCoding;
+    };
+    let codon_start = index - (index - transcript.phase) % 3;
+    let Some(codon) = transcript.sequence.get(codon_start..codon_start + 3) else {
+        return SiteClass::NonCoding;
+    };
+    let strand_base = |base: u8| if transcript.reverse { complement_base(base) } else { base };
+    let mut ref_codon = codon.to_vec();
+    let mut alt_codon = codon.to_vec();
+    ref_codon[index - codon_start] = strand_base(ref_base);
+    alt_codon[index - codon_start] = strand_base(alt_base);
+    match (translate_codon(&ref_codon), translate_codon(&alt_codon)) {
+        (Some(a), Some(b)) if a == b => SiteClass::Synonymous,
+        (Some(_), Some(_)) => SiteClass::Nonsynonymous,
+        _ => SiteClass::NonCoding,
+    }
+}
+
+// A transcript's gene, whether it is on the reverse strand, and its CDS segments with their phase
+type TranscriptCds = (Option<String>, bool, Vec<(i64, i64, usize)>);
+
+// CDS features of an --annotation_gff (GFF3 or GTF) overlapping chr:start-end, with their reference sequence.
+// Transcripts are keyed by transcript_id, Parent or ID, and genes by gene_name, gene_id or gene.
+pub fn parse_annotation_gff(
+    gff_path: &Path,
+    fasta_path: &Path,
+    chr: &str,
+    start: i64,
+    end: i64,
+) -> Result<GffIndex, VcfError> {
+    let reader = BufReader::new(File::open(gff_path)?);
+    // Keyed by transcript ID
+    let mut transcripts: HashMap<String, TranscriptCds> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if line.starts_with('#') || fields.len() < 9 || fields[2] != "CDS" {
+            continue;
+        }
+        if canonical_chr(fields[0]) != canonical_chr(chr) {
+            continue;
+        }
+        let (Ok(cds_start), Ok(cds_end)) = (fields[3].parse::<i64>(), fields[4].parse::<i64>()) else {
+            continue;
+        };
+        if cds_end < start || cds_start > end {
+            continue;
+        }
+        let attributes: HashMap<&str, &str> = fields[8]
+            .split(';')
+            .filter_map(|attr| {
+                let attr = attr.trim();
+                attr.split_once('=').or_else(|| attr.split_once(' '))
+            })
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+            .collect();
+        let Some(transcript) = ["transcript_id", "Parent", "ID"].iter().find_map(|key| attributes.get(key)) else {
+            continue;
+        };
+        let gene = ["gene_name", "gene_id", "gene"].iter().find_map(|key| attributes.get(key));
+        let entry = transcripts
+            .entry(transcript.to_string())
+            .or_insert_with(|| (gene.map(|g| g.to_string()), fields[6] == "-", Vec::new()));
+        entry.2.push((cds_start, cds_end, fields[7].parse().unwrap_or(0)));
+    }
+
+    let mut longest: HashMap<String, CodingTranscript> = HashMap::new();
+    for (transcript_id, (gene, reverse, mut segments)) in transcripts {
+        segments.sort_by_key(|&(segment_start, _, _)| segment_start);
+        let mut sequence = Vec::new();
+        for &(segment_start, segment_end, _) in &segments {
+            match read_reference_sequence(fasta_path, chr, segment_start, segment_end) {
+                Ok(bases) => sequence.extend(bases),
+                Err(e) => {
+                    warn!("Skipping transcript {} in the MK table: {}", transcript_id, e);
+                    sequence.clear();
+                    break;
+                }
+            }
+        }
+        if sequence.is_empty() {
+            continue;
+        }
+        if reverse {
+            sequence = sequence.iter().rev().map(|&base| complement_base(base)).collect();
+        }
+        let first = if reverse { segments[segments.len() - 1] } else { segments[0] };
+        let gene = gene.unwrap_or_else(|| transcript_id.clone());
+        let transcript = CodingTranscript {
+            gene: gene.clone(),
+            segments: segments.iter().map(|&(s, e, _)| (s, e)).collect(),
+            reverse,
+            phase: first.2,
+            sequence,
+        };
+        if longest.get(&gene).is_none_or(|current| current.sequence.len() < transcript.sequence.len()) {
+            longest.insert(gene, transcript);
+        }
+    }
+
+    let mut transcripts: Vec<CodingTranscript> = longest.into_values().collect();
+    transcripts.sort_by(|a, b| a.gene.cmp(&b.gene));
+    Ok(GffIndex { transcripts })
+}
+
+
+// Function to parse a variant line
+// FILTER may hold several tags separated by ';'; "." means no filters were applied
+pub fn fails_filter_column(filter: &str, pass_only: bool, exclude_filters: &[String]) -> bool {
+    let filter = filter.trim();
+    if pass_only && filter != "." && filter != "PASS" {
+        return true;
+    }
+    filter
+        .split(';')
+        .any(|tag| exclude_filters.iter().any(|excluded| excluded == tag))
+}
+
+// Parse an --info_filter expression of the form KEY OP VALUE, with OP one of >= <= > < == !=
+pub fn parse_info_filter(expr: &str) -> Result<InfoFilter, VcfError> {
+    let invalid = || VcfError::Parse(format!("Invalid --info_filter '{}': expected KEY OP VALUE, e.g. DP>=10", expr));
+    let op_start = expr.find(['<', '>', '=', '!']).ok_or_else(invalid)?;
+    let rest = &expr[op_start..];
+    let (op, op_len) = if rest.starts_with(">=") {
+        (InfoOp::Ge, 2)
+    } else if rest.starts_with("<=") {
+        (InfoOp::Le, 2)
+    } else if rest.starts_with("==") {
+        (InfoOp::Eq, 2)
+    } else if rest.starts_with("!=") {
+        (InfoOp::Ne, 2)
+    } else if rest.starts_with('>') {
+        (InfoOp::Gt, 1)
+    } else if rest.starts_with('<') {
+        (InfoOp::Lt, 1)
+    } else {
+        return Err(invalid());
+    };
+    let key = expr[..op_start].trim();
+    let value = rest[op_len..].trim();
+    if key.is_empty() || value.is_empty() || key.contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+    Ok(InfoFilter {
+        key: key.to_string(),
+        op,
+        value: value.to_string(),
+    })
+}
+
+// Whether an INFO column satisfies a filter. Absent keys fail; for per-allele lists the first value is used.
+// Numbers are compared numerically, anything else only with == and !=.
+pub fn apply_info_filter(info_str: &str, filter: &InfoFilter) -> bool {
+    let value = info_str.split(';').find_map(|entry| {
+        let mut parts = entry.splitn(2, '=');
+        (parts.next() == Some(filter.key.as_str())).then(|| parts.next().unwrap_or("").split(',').next().unwrap_or(""))
+    });
+    let Some(value) = value else {
+        return false;
+    };
+    match (value.parse::<f64>(), filter.value.parse::<f64>()) {
+        (Ok(actual), Ok(expected)) => match filter.op {
+            InfoOp::Ge => actual >= expected,
+            InfoOp::Le => actual <= expected,
+            InfoOp::Gt => actual > expected,
+            InfoOp::Lt => actual < expected,
+            InfoOp::Eq => actual == expected,
+            InfoOp::Ne => actual != expected,
+        },
+        _ => match filter.op {
+            InfoOp::Eq => value == filter.value,
+            InfoOp::Ne => value != filter.value,
+            _ => false,
+        },
+    }
+}
+
+// Names of the fixed VCF columns, in order
+const VCF_FIXED_FIELDS: [&str; 9] = ["CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO", "FORMAT"];
+
+// What parse_variant checks each record against, set up once per VCF by process_vcf
+#[derive(Clone, Copy)]
+pub struct RecordContext<'a> {
+    pub chr: &'a str,
+    pub start: i64,
+    pub end: i64,
+    pub sample_names: &'a [String],
+    // With a sample subset, the VCF columns of sample_names
+    pub sample_columns: Option<&'a [usize]>,
+    pub masks: &'a RegionMasks,
+    pub cpg_sites: Option<&'a HashSet<i64>>,
+    pub options: &'a VcfOptions,
+}
+
+pub fn parse_variant(
+    line: &str,
+    line_num: usize,
+    context: &RecordContext,
+    position_allele_map: &Mutex<HashMap<i64, (char, char)>>,
+    missing_data_info: &mut MissingDataInfo,
+    _filtering_stats: &mut FilteringStats,
+) -> Result<Option<(Variant, bool)>, VcfError> {
+    let RecordContext { chr, start, end, sample_names, sample_columns, masks, cpg_sites, options } = *context;
+    let VcfOptions {
+        min_gq,
+        min_dp,
+        max_dp,
+        qual_threshold,
+        multiallelic,
+        filter_pass_only,
+        ref exclude_filters,
+        ref info_filters,
+        require_phased,
+        include_symbolic,
+        snps_only,
+        variant_type,
+        ..
+    } = *options;
+    let allow_regions = masks.allow.as_deref();
+    let mask_regions = masks.mask.as_deref();
+    let repeat_regions = masks.repeat.as_deref();
+
+    // Lines from the reader keep their line ending, which would otherwise stick to the last sample's GQ
+    let fields: Vec<&str> = line.trim_end_matches(['\n', '\r']).split('\t').collect();
+
+    // With a sample subset, sample_names holds the kept samples and sample_columns their VCF columns
+    let required_fixed_fields = 9;
+    let required_samples = sample_columns
+        .and_then(|columns| columns.iter().max())
+        .map_or(sample_names.len(), |&last| last + 1);
+    if fields.len() < required_fixed_fields + required_samples {
+        let field = VCF_FIXED_FIELDS.get(fields.len()).copied().unwrap_or("sample genotype");
+        return Err(VcfError::MissingField { line: line_num, field });
+    }
+
+    let vcf_chr = canonical_chr(fields[0]);
+
+    if vcf_chr != canonical_chr(chr) {
+        return Ok(None);
+    }
+
+    let pos: i64 = parse_field(Some(fields[1]), line_num, "POS")?;
+    if pos < start || pos > end {
+        return Ok(None);
+    }
+
+    _filtering_stats.total_variants += 1; // DO NOT MOVE THIS LINE ABOVE THE CHECK FOR WITHIN RANGE
+    // Only variants within the range get passed the collector which increments statistics.
+    // For variants outside the range, the consumer thread does not send any result to the collector.
+    // If this line is moved above the early return return Ok(None) in the range check, then it would increment all variants, not just those in the regions
+    // This would mean that the maximum number of variants filtered could be below the maximum number of variants, in the case that there are variants outside of the ranges (which would not even get far enough to need to be filtered, but would be included in the total).
+
+    let adjusted_pos = pos - 1; // Adjust VCF position (one-based) to zero-based
+
+    // Check allow regions
+    if let Some(allow_regions_chr) = allow_regions.and_then(|ar| ar.get(&vcf_chr)) {
+        if !position_in_regions(adjusted_pos, allow_regions_chr) {
+            _filtering_stats._filtered_variants += 1;
+            _filtering_stats.filtered_due_to_allow += 1;
+            _filtering_stats.filtered_positions.insert(pos);
+            _filtering_stats.add_example(format!("{}: Filtered due to allow", line.trim()));
+            return Ok(None);
+        }
+    } else if allow_regions.is_some() {
+        // If allow_regions is provided, but there are no allowed regions for this chromosome, filter it
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_due_to_allow += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to allow", line.trim()));
+        return Ok(None);
+    }
+
+    // Check mask regions
+    if let Some(mask_regions_chr) = mask_regions.and_then(|mr| mr.get(&vcf_chr)) {
+        if position_in_regions(adjusted_pos, mask_regions_chr) {
+            _filtering_stats._filtered_variants += 1;
+            _filtering_stats.filtered_positions.insert(pos);
+            // Repeats are merged into the mask but counted on their own
+            let in_repeat = repeat_regions
+                .and_then(|rr| rr.get(&vcf_chr))
+                .is_some_and(|repeats| position_in_regions(adjusted_pos, repeats));
+            if in_repeat {
+                _filtering_stats.repeat_masked_count += 1;
+                _filtering_stats.add_example(format!("{}: Filtered due to repeat mask", line.trim()));
+            } else {
+                _filtering_stats.filtered_due_to_mask += 1;
+                _filtering_stats.add_example(format!("{}: Filtered due to mask", line.trim()));
+            }
+            return Ok(None);
+        }
+    } else if mask_regions.is_some() {
+        // If mask_regions is provided but there are no mask regions for this chromosome,
+        // we do not filter the variant since it's not masked.
+        // This is separate from the allow file behavior, which restricts anything not explicitly allowed.
+        // No action needed here; we proceed with processing.
+    }
+
+    // Check the FILTER column
+    if fails_filter_column(fields[6], filter_pass_only, exclude_filters) {
+        missing_data_info.filtered_by_qual += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to FILTER {}", line.trim(), fields[6]));
+        return Ok(None);
+    }
+
+    // Check the INFO expressions
+    if let Some(failed) = info_filters.iter().find(|filter| !apply_info_filter(fields[7], filter)) {
+        _filtering_stats.info_filtered_count += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to INFO {}", line.trim(), failed.key));
+        return Ok(None);
+    }
+
+    // Check the QUAL column; a missing QUAL (.) counts as 0
+    if qual_threshold > 0.0 {
+        let qual: f64 = if fields[5] == "." {
+            _filtering_stats.missing_qual_count += 1;
+            0.0
+        } else {
+            parse_field(Some(fields[5]), line_num, "QUAL")?
+        };
+        if qual < qual_threshold {
+            _filtering_stats.qual_filtered_count += 1;
+            _filtering_stats._filtered_variants += 1;
+            _filtering_stats.filtered_positions.insert(pos);
+            _filtering_stats.add_example(format!("{}: Filtered due to QUAL {}", line.trim(), fields[5]));
+            return Ok(None);
+        }
+    }
+
+    let alt_alleles: Vec<&str> = fields[4].split(',').collect();
+
+    // Symbolic and spanning-deletion ALTs carry no sequence to compare
+    let is_symbolic = alt_alleles.iter().any(|alt| is_symbolic_allele(alt));
+    if is_symbolic && !include_symbolic {
+        _filtering_stats.structural_variant_skipped += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to symbolic ALT {}", line.trim(), fields[4]));
+        return Ok(None);
+    }
+
+    if snps_only && (fields[3].len() != 1 || alt_alleles.iter().any(|alt| alt.len() != 1)) {
+        _filtering_stats.indels_skipped += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to indel", line.trim()));
+        return Ok(None);
+    }
+
+    // A site is kept only if every ALT is of the requested type
+    let alt_types: Vec<VariantType> = alt_alleles.iter().map(|alt| classify_variant(fields[3], alt)).collect();
+    let site_type = alt_types.first().copied().unwrap_or(VariantType::Snp);
+    *_filtering_stats.variant_type_counts.entry(site_type).or_insert(0) += 1;
+    if !alt_types.iter().all(|&alt_type| variant_type.matches(alt_type)) {
+        _filtering_stats.variant_type_skipped += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to variant type {}", line.trim(), site_type.as_str()));
+        return Ok(None);
+    }
+
+    if cpg_sites.is_some_and(|sites| sites.contains(&pos)) {
+        _filtering_stats.cpg_excluded += 1;
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to CpG site", line.trim()));
+        return Ok(None);
+    }
+
+    // Store reference and alternate alleles
+    if !fields[3].is_empty() && !fields[4].is_empty() {
+        let ref_allele = fields[3].chars().next().unwrap_or('N');
+        let alt_allele = if is_symbolic { 'N' } else { fields[4].chars().next().unwrap_or('N') };
+        position_allele_map.lock().insert(pos, (ref_allele, alt_allele));
+    }
+
+    let ancestral_allele = parse_ancestral_allele(fields[7], fields[3], &alt_alleles);
+    // With --include_symbolic every ALT is coded as allele 1, so the site is biallelic
+    let ancestral_allele = if is_symbolic { ancestral_allele.map(|allele| allele.min(1)) } else { ancestral_allele };
+    let is_multiallelic = alt_alleles.len() > 1 && !is_symbolic;
+    let ref_allele = snp_base(fields[3]);
+    let alt_allele = if is_multiallelic || is_symbolic { b'N' } else { snp_base(fields[4]) };
+    // Only in skip mode is a multi-allelic site excluded from the filtered set
+    let drop_multiallelic = is_multiallelic && multiallelic == MultiallelicMode::Skip;
+    if is_multiallelic {
+        _filtering_stats.multi_allelic_variants += 1;
+    }
+    if drop_multiallelic {
+        warn!("Multi-allelic site detected at position {}, skipped in filtered output (see --multiallelic).", pos);
+        _filtering_stats.add_example(format!("{}: Filtered due to multi-allelic variant", line.trim()));
+    }
+
+    // Parse the FORMAT field to get the indices of the subfields
+    let format_fields: Vec<&str> = fields[8].split(':').collect();
+
+    // Find the index of GQ; without it every genotype has an unknown GQ
+    let gq_index = format_fields.iter().position(|&s| s == "GQ");
+
+    // Find the index of DP; it is only required when depth bounds are set
+    let check_dp = min_dp > 0 || max_dp < u32::MAX;
+    let dp_index = format_fields.iter().position(|&s| s == "DP");
+    if dp_index.is_none() && check_dp {
+        return Err(VcfError::MissingField { line: line_num, field: "FORMAT/DP" });
+    }
+
+    let sample_fields: Vec<&str> = match sample_columns {
+        Some(columns) => columns.iter().map(|&i| fields[required_fixed_fields + i]).collect(),
+        None => fields[required_fixed_fields..].to_vec(),
+    };
+
+    let mut has_unphased = false;
+    let mut has_low_gq = false;
+    let genotypes: Vec<Option<Vec<u8>>> = sample_fields.iter()
+        .map(|gt| {
+            missing_data_info.total_data_points += 1;
+            let alleles_str = gt.split(':').next().unwrap_or(".");
+            if alleles_str == "." || alleles_str == "./." || alleles_str == ".|." {
+                missing_data_info.missing_data_points += 1;
+                missing_data_info.positions_with_missing.insert(pos);
+                return None;
+            }
+            // A genotype below --min_gq, or with an absent or '.' GQ, is masked like one outside the DP bounds
+            if min_gq > 0 {
+                let gq = gq_index.and_then(|i| gt.split(':').nth(i)).and_then(|s| s.parse::<u8>().ok());
+                if gq.map_or(true, |gq| gq < min_gq) {
+                    has_low_gq = true;
+                    missing_data_info.low_gq_count += 1;
+                    missing_data_info.missing_data_points += 1;
+                    missing_data_info.positions_with_missing.insert(pos);
+                    return None;
+                }
+            }
+            // Genotypes with an unknown depth ('.') are kept
+            if let Some(dp) = dp_index.filter(|_| check_dp).and_then(|i| gt.split(':').nth(i)).and_then(|s| s.parse::<u32>().ok()) {
+                let out_of_range = if dp < u32::from(min_dp) {
+                    missing_data_info.low_dp_count += 1;
+                    true
+                } else if dp > max_dp {
+                    missing_data_info.high_dp_count += 1;
+                    true
+                } else {
+                    false
+                };
+                if out_of_range {
+                    missing_data_info.missing_data_points += 1;
+                    missing_data_info.positions_with_missing.insert(pos);
+                    return None;
+                }
+            }
+            // Without phase, which allele sits on which haplotype is arbitrary
+            if alleles_str.contains('/') {
+                has_unphased = true;
+                if require_phased {
+                    missing_data_info.missing_data_points += 1;
+                    missing_data_info.positions_with_missing.insert(pos);
+                    return None;
+                }
+            }
+            let alleles = alleles_str.split(|c| c == '|' || c == '/')
+                .map(|allele| allele.parse::<u8>().ok().map(|index| if is_symbolic { index.min(1) } else { index }))
+                .collect::<Option<Vec<u8>>>();
+            if alleles.is_none() {
+                missing_data_info.missing_data_points += 1;
+                missing_data_info.positions_with_missing.insert(pos);
+            }
+            alleles
+        })
+        .collect();
+    record_sample_missing(missing_data_info, &genotypes);
+
+    if has_unphased {
+        _filtering_stats.unphased_variants += 1;
+    }
+
+    if has_low_gq {
+        _filtering_stats.low_gq_variants += 1;
+        _filtering_stats.add_example(format!("{}: Genotypes masked due to low GQ", line.trim()));
+    }
+
+    // Do not exclude the variant; update the missing data info
+    if genotypes.iter().any(|gt| gt.is_none()) {
+        _filtering_stats.missing_data_variants += 1;
+        _filtering_stats.add_example(format!("{}: Filtered due to missing data", line.trim()));
+        // Continue processing
+    }
+
+    let has_missing_genotypes = genotypes.iter().any(|gt| gt.is_none());
+    let passes_filters = !has_missing_genotypes && !drop_multiallelic;
+    
+    // Update filtering stats if variant is filtered out
+    if !passes_filters {
+        _filtering_stats._filtered_variants += 1;
+        _filtering_stats.filtered_positions.insert(pos);
+        
+        if genotypes.iter().any(|gt| gt.is_none()) {
+            _filtering_stats.missing_data_variants += 1;
+            _filtering_stats.add_example(format!("{}: Filtered due to missing data", line.trim()));
+        }
+    }
+
+    let variant = Variant {
+        position: pos,
+        genotypes: genotypes.clone(),
+        ancestral_allele,
+        ref_allele,
+        alt_allele,
+        variant_type: site_type,
+    };
+    
+    // Return the parsed variant and whether it passes filters
+    Ok(Some((variant, passes_filters)))
+}
+
+
+// Symbolic ALT alleles such as <DEL> and the spanning deletion *
+pub fn is_symbolic_allele(alt: &str) -> bool {
+    alt.starts_with('<') || alt == "*"
+}
+
+// Classify one ALT against REF by allele lengths
+pub fn classify_variant(ref_: &str, alt: &str) -> VariantType {
+    if is_symbolic_allele(alt) {
+        return VariantType::Sv;
+    }
+    match (ref_.len(), alt.len()) {
+        (1, 1) => VariantType::Snp,
+        (r, a) if r == a => VariantType::Mnp,
+        (r, a) if r < a => VariantType::Insertion,
+        _ => VariantType::Deletion,
+    }
+}
+
+// Upper-case base of a single-nucleotide allele, b'N' for indels, symbolic and missing alleles
+pub fn snp_base(allele: &str) -> u8 {
+    match allele.as_bytes() {
+        [base] if b"ACGTacgt".contains(base) => base.to_ascii_uppercase(),
+        _ => b'N',
+    }
+}
+
+// Resolve the INFO AA tag to a VCF allele index (0 = REF, 1.. = ALT)
+// Returns None when the tag is absent or matches none of the alleles
+pub fn parse_ancestral_allele(info: &str, ref_allele: &str, alt_alleles: &[&str]) -> Option<u8> {
+    let aa = info
+        .split(';')
+        .find_map(|entry| entry.strip_prefix("AA="))?
+        .split('|')
+        .next()?
+        .trim();
+    if aa.is_empty() || aa == "." {
+        return None;
+    }
+
+    if aa.eq_ignore_ascii_case(ref_allele) {
+        return Some(0);
+    }
+    alt_alleles
+        .iter()
+        .position(|alt| aa.eq_ignore_ascii_case(alt))
+        .map(|i| (i + 1) as u8)
+}
+
+// Config sample ID of a VCF sample name: everything after the last '_', or the whole name without one.
+// So "EAS_JPT_NA18939" -> "NA18939", but also "HG00096_1" -> "1". Names that end in the same suffix map to
+// the same ID, and when matching config samples the later VCF column wins (see find_duplicate_sample_ids).
+pub fn extract_sample_id(name: &str) -> &str {
+    name.rsplit('_').next().unwrap_or(name)
+}
+
+// Error unless two VCFs hold the same sample IDs; their column order may differ
+pub fn check_sample_consistency(file1_samples: &[String], file2_samples: &[String]) -> Result<(), VcfError> {
+    let ids1: HashSet<&str> = file1_samples.iter().map(|name| extract_sample_id(name)).collect();
+    let ids2: HashSet<&str> = file2_samples.iter().map(|name| extract_sample_id(name)).collect();
+    if ids1 == ids2 {
+        return Ok(());
+    }
+    let mut only_first: Vec<&str> = ids1.difference(&ids2).copied().collect();
+    let mut only_second: Vec<&str> = ids2.difference(&ids1).copied().collect();
+    only_first.sort_unstable();
+    only_second.sort_unstable();
+    Err(VcfError::Parse(format!(
+        "VCF files have different samples: {} only in the first ({}), {} only in the second ({})",
+        only_first.len(),
+        only_first.join(", "),
+        only_second.len(),
+        only_second.join(", ")
+    )))
+}
+
+// (ID, first column, later column) for every VCF column whose sample ID an earlier column already has
+pub fn find_duplicate_sample_ids(sample_names: &[String]) -> Vec<(String, usize, usize)> {
+    let mut first_column: HashMap<&str, usize> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for (i, name) in sample_names.iter().enumerate() {
+        let sample_id = extract_sample_id(name);
+        match first_column.get(sample_id) {
+            Some(&first) => duplicates.push((sample_id.to_string(), first, i)),
+            None => {
+                first_column.insert(sample_id, i);
+            }
+        }
+    }
+    duplicates
+}
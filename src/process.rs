@@ -0,0 +1,5806 @@
+use crate::parse::*;
+use crate::stats::*;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use csv::{WriterBuilder};
+use crossbeam_channel::bounded;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::thread;
+use prettytable::{Table, row};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sysinfo::{System, SystemExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::{debug, error, info, warn};
+
+// Define command-line arguments using clap
+#[derive(Parser, Debug, Serialize)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    // Folder containing VCF files
+    #[arg(short, long = "vcf_folder", required_unless_present = "stdin", default_value = "")]
+    pub vcf_folder: String,
+
+    // Read the VCF of the --chr chromosome from standard input instead of --vcf_folder
+    #[arg(
+        long = "stdin",
+        requires = "chr",
+        conflicts_with_all = ["config_file", "yaml_config", "bed_file", "group_file", "ped_file"]
+    )]
+    pub stdin: bool,
+
+    // Chromosome to process
+    #[arg(short, long = "chr")]
+    pub chr: Option<String>,
+
+    // Region to process (start-end)
+    #[arg(short, long = "region")]
+    pub region: Option<String>,
+
+    // Configuration file
+    #[arg(long = "config_file")]
+    pub config_file: Option<String>,
+
+    // YAML configuration file, used in place of --config_file
+    #[arg(long = "yaml_config", conflicts_with = "config_file")]
+    pub yaml_config: Option<String>,
+
+    // Output file
+    #[arg(short, long = "output_file")]
+    pub output_file: Option<String>,
+
+    // Minimum genotype quality
+    #[arg(long = "min_gq", default_value = "0")]
+    pub min_gq: u8,
+
+    // Per-genotype read depth bounds; genotypes outside them are treated as missing
+    #[arg(long = "min_dp", default_value = "0")]
+    pub min_dp: u8,
+
+    // Per-site, per-generation mutation rate for effective population size estimates from theta and pi
+    #[arg(long = "mutation_rate", value_name = "MU")]
+    pub mutation_rate: Option<f64>,
+
+    // Drop variants whose QUAL is below this; a missing QUAL (.) counts as 0 (0 = no filter)
+    #[arg(long = "qual_threshold", default_value = "0.0")]
+    pub qual_threshold: f64,
+
+    #[arg(long = "max_dp", default_value_t = u32::MAX)]
+    pub max_dp: u32,
+
+    // Minimum minor allele frequency
+    #[arg(long = "min_maf", default_value = "0.0")]
+    pub min_maf: f64,
+
+    // Drop variants whose Hardy-Weinberg exact test p-value is below this (0 = disabled)
+    #[arg(long = "hwe_filter", default_value = "0.0")]
+    pub hwe_filter: f64,
+
+    // Mask file (regions to exclude)
+    #[arg(long = "mask_file")]
+    pub mask_file: Option<String>,
+
+    // BED file of regions to exclude, e.g. centromeres and segmental duplications
+    #[arg(long = "exclude_bed", alias = "exclude_regions")]
+    pub exclude_bed: Option<String>,
+
+    // BED file of repeats (e.g. RepeatMasker output) to exclude; counted apart from the other masks
+    #[arg(long = "repeat_mask", value_name = "PATH")]
+    pub repeat_mask: Option<String>,
+
+    // BED file of callable sites; only these count toward the sequence length of theta and pi
+    #[arg(long = "accessible_mask", value_name = "PATH")]
+    pub accessible_mask: Option<String>,
+
+    // Allow file (regions to include)
+    #[arg(long = "allow_file")]
+    pub allow_file: Option<String>,
+
+    #[arg(long = "reference")]
+    pub reference_path: String,
+
+    #[arg(long = "gff")]
+    pub gff_path: String,
+
+    // Where to take the ancestral allele from for Fay and Wu's H
+    #[arg(long = "ancestral_source", value_enum, default_value = "ref")]
+    pub ancestral_source: AncestralSource,
+
+    // Window size in bp for sliding-window mode (without a config file)
+    #[arg(long = "window_size")]
+    pub window_size: Option<i64>,
+
+    // Step between window starts in bp; defaults to the window size (non-overlapping)
+    #[arg(long = "step_size")]
+    pub step_size: Option<i64>,
+
+    // Number of bootstrap replicates for theta and pi confidence intervals (0 = disabled)
+    #[arg(long = "bootstrap", default_value = "0")]
+    pub bootstrap: usize,
+
+    // Number of group-label permutations for the FST p-value in config mode (0 = disabled)
+    #[arg(long = "fst_permutations", default_value = "0")]
+    pub fst_permutations: usize,
+
+    // Random seed for bootstrapping, permutations and --max_variants subsampling
+    #[arg(long = "seed")]
+    pub seed: Option<u64>,
+
+    // Count fixed differences at sites with missing calls, using the called haplotypes only
+    #[arg(long = "fixed_allow_missing")]
+    pub fixed_allow_missing: bool,
+
+    // Config mode: GFF3 or GTF of coding sequences for a McDonald-Kreitman table per gene in <output>_mk_table.csv
+    #[arg(long = "annotation_gff", value_name = "PATH")]
+    pub annotation_gff: Option<String>,
+
+    // Config mode: mark regions where any statistic lies more than this many standard deviations from the mean
+    #[arg(long = "flag_outliers", value_name = "Z")]
+    pub flag_outliers: Option<f64>,
+
+    // Config mode: append chr:start:end of every region written to the CSV to this file, for --resume
+    #[arg(long = "checkpoint_file", value_name = "PATH", conflicts_with_all = ["flag_outliers", "compress_output"])]
+    pub checkpoint_file: Option<String>,
+
+    // Skip the regions already in --checkpoint_file and append the rest to the existing output
+    #[arg(long = "resume", requires = "checkpoint_file")]
+    pub resume: bool,
+
+    // Config mode: write rows as regions finish instead of sorted by chromosome and position
+    #[arg(long = "output_unsorted")]
+    pub output_unsorted: bool,
+
+    // Randomly keep at most this many variants per VCF read, for quick exploratory runs (0 = no limit)
+    #[arg(long = "max_variants", default_value = "0")]
+    pub max_variants: usize,
+
+    // How to handle sites with more than one ALT allele
+    #[arg(long = "multiallelic", value_enum, default_value = "skip")]
+    pub multiallelic: MultiallelicMode,
+
+    // Keep sites with symbolic (<DEL>) or spanning-deletion (*) ALTs, coding every ALT as allele 1
+    #[arg(long = "include_symbolic")]
+    pub include_symbolic: bool,
+
+    // Skip indels: sites whose REF or any ALT is not a single base
+    #[arg(long = "snps_only")]
+    pub snps_only: bool,
+
+    // Report the genotype concordance of VCF samples that share a config sample ID
+    #[arg(long = "concordance_check")]
+    pub concordance_check: bool,
+
+    // Keep only variants of this type, judged by the lengths of REF and each ALT
+    #[arg(long = "variant_type", value_enum, default_value = "all")]
+    pub variant_type: VariantTypeFilter,
+
+    // How chromosome names are written: as given, always with "chr", or always without
+    #[arg(long = "chr_prefix_mode", value_enum, default_value = "auto")]
+    pub chr_prefix_mode: ChrPrefixMode,
+
+    // Skip variants at CpG sites of the --reference sequence
+    #[arg(long = "exclude_cpg")]
+    pub exclude_cpg: bool,
+
+    // Write the positions with missing data to <output>_missing_positions.txt instead of only counting them
+    #[arg(long = "verbose_positions")]
+    pub verbose_positions: bool,
+
+    // Also write per-position pi to <output>_per_site.csv
+    #[arg(long = "per_site")]
+    pub per_site: bool,
+
+    // Restrict the analysis to the samples listed in this file, one name per line
+    #[arg(long = "sample_list")]
+    pub sample_list: Option<String>,
+
+    // Leave out the samples listed in this file, one name per line; wins over --sample_list
+    #[arg(long = "exclude_samples", value_name = "PATH")]
+    pub exclude_samples: Option<String>,
+
+    // Verbosity of progress and diagnostic messages on stderr
+    #[arg(long = "log_level", value_enum, default_value = "info")]
+    pub log_level: LogLevel,
+
+    // Gzip the main CSV output, adding .gz to the file name if needed
+    #[arg(long = "compress_output")]
+    pub compress_output: bool,
+
+    // Write the site-frequency spectrum of each region (and haplotype group) to .sfs files
+    #[arg(long = "sfs")]
+    pub sfs: bool,
+
+    // Fold the SFS by REF; unfolded spectra are polarised by the AA INFO tag
+    #[arg(long = "sfs_folded", default_value_t = true, action = ArgAction::Set)]
+    pub sfs_folded: bool,
+
+    // Write SFS proportions that sum to 1 instead of counts
+    #[arg(long = "sfs_normalized")]
+    pub sfs_normalized: bool,
+
+    // Count sites with missing calls too, projecting each onto a common sample size
+    #[arg(long = "sfs_exclude_missing")]
+    pub sfs_exclude_missing: bool,
+
+    // Write AC, AN, AF and its 95% Clopper-Pearson interval for every variant to this CSV
+    #[arg(long = "allele_freq_output", value_name = "PATH")]
+    pub allele_freq_output: Option<String>,
+
+    // Write the composite likelihood ratio of the SFS against neutrality per window of this many bp to <output>_clr.csv
+    #[arg(long = "clr_window_size", value_name = "BP")]
+    pub clr_window_size: Option<i64>,
+
+    // Write both haplotype sequences of every sample over the region to this FASTA file
+    #[arg(long = "fasta_output", value_name = "PATH")]
+    pub fasta_output: Option<String>,
+
+    // Write the number of differing sites between every pair of samples over the region to this file
+    #[arg(long = "pairwise_matrix", value_name = "PATH")]
+    pub pairwise_matrix: Option<String>,
+
+    // Layout of the --pairwise_matrix file
+    #[arg(long = "pairwise_matrix_format", value_enum, default_value = "csv")]
+    pub pairwise_matrix_format: MatrixFormat,
+
+    // Write the positions at which each pair of samples differs to this CSV
+    #[arg(long = "diff_positions_output", value_name = "PATH")]
+    pub diff_positions_output: Option<String>,
+
+    // Only write pairs of --diff_positions_output with more than this many differing sites
+    #[arg(long = "min_diff_count", default_value = "0")]
+    pub min_diff_count: usize,
+
+    // Write r^2 and D' for variant pairs at most this many bp apart to <output>_ld.csv
+    #[arg(long = "ld_window_bp")]
+    pub ld_window_bp: Option<i64>,
+
+    // Fraction of haplotypes that must be called at both sites of an LD pair
+    #[arg(long = "ld_min_complete", default_value = "1.0")]
+    pub ld_min_complete: f64,
+
+    // Also write mean r^2 in this many log-spaced distance bins to <output>_ld_decay.csv
+    #[arg(long = "ld_decay_bins")]
+    pub ld_decay_bins: Option<usize>,
+
+    // BED file of regions to analyse over all samples, instead of a config file
+    #[arg(long = "bed_file")]
+    pub bed_file: Option<String>,
+
+    // Skip variants whose FILTER is neither PASS nor "."
+    #[arg(long = "filter_pass_only")]
+    pub filter_pass_only: bool,
+
+    // Skip variants carrying this FILTER tag (repeatable)
+    #[arg(long = "exclude_filter", value_name = "TAG")]
+    pub exclude_filter: Vec<String>,
+
+    // Keep only variants whose INFO field satisfies KEY OP VALUE, e.g. "DP>=10" (repeatable)
+    #[arg(long = "info_filter", value_name = "EXPR")]
+    pub info_filter: Vec<String>,
+
+    // Format of the statistics written to --output_file
+    #[arg(long = "output_format", value_enum, default_value = "csv")]
+    pub output_format: OutputFormat,
+
+    // Worker threads; 0 falls back to FERROMIC_THREADS, then to all logical CPUs
+    #[arg(long = "threads", default_value = "0")]
+    pub threads: usize,
+
+    // Capacity of the queue of VCF lines waiting for the worker threads
+    #[arg(
+        long = "channel_buffer",
+        default_value = "1000",
+        help = "Number of VCF lines queued for the worker threads. Larger buffers use more memory but can improve throughput on fast SSDs"
+    )]
+    pub channel_buffer: usize,
+
+    // Capacity of the queue of parsed variants waiting to be collected
+    #[arg(
+        long = "result_buffer",
+        default_value = "1000",
+        help = "Number of parsed variants queued for collection. Larger buffers use more memory but can improve throughput on fast SSDs"
+    )]
+    pub result_buffer: usize,
+
+    // Fail instead of warning on overlapping config regions
+    #[arg(long = "strict")]
+    pub strict: bool,
+
+    // Stop at the first failing config region instead of recording it in <output>_errors.csv
+    #[arg(long = "fail_on_region_error")]
+    pub fail_on_region_error: bool,
+
+    // Treat unphased (0/1) genotypes as missing
+    #[arg(long = "require_phased")]
+    pub require_phased: bool,
+
+    // Drop sites genotyped in fewer than this many samples of a haplotype group
+    #[arg(long = "min_samples", default_value = "1")]
+    pub min_samples: usize,
+
+    // Report NA statistics for regions whose fraction of missing genotypes exceeds this
+    #[arg(long = "max_missing", default_value = "1.0")]
+    pub max_missing: f64,
+
+    // Config mode: report NA statistics for regions shorter than this many bp
+    #[arg(long = "min_seq_length", default_value = "1")]
+    pub min_seq_length: i64,
+
+    // Write per-sample missing genotype counts to this CSV
+    #[arg(long = "per_sample_missing", value_name = "PATH")]
+    pub per_sample_missing: Option<String>,
+
+    // Config mode: write the heterozygosity of every sample to this CSV
+    #[arg(long = "sample_stats_output", value_name = "PATH")]
+    pub sample_stats_output: Option<String>,
+
+    // Decimal places of the statistics in the CSV output
+    #[arg(long = "output_precision", default_value = "6")]
+    pub output_precision: usize,
+
+    // TSV of sample_id and group_label: statistics per group and per pair of groups, without a config file
+    #[arg(long = "group_file", value_name = "PATH", conflicts_with_all = ["config_file", "yaml_config"])]
+    pub group_file: Option<String>,
+
+    // PLINK FAM file whose phenotype column (1, 2, -9 to leave out) sets the haplotype groups 0 and 1 of
+    // both haplotypes of each sample, in place of the config genotypes or for --bed_file/--chr regions
+    #[arg(long = "ped_file", value_name = "PATH", conflicts_with = "group_file")]
+    pub ped_file: Option<String>,
+
+    // Also write the resolved arguments, version, time and host to <output>.params.json
+    #[arg(long = "write_params")]
+    pub write_params: bool,
+}
+
+// Command line: the diversity analysis by default, or a subcommand
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    // Validate a VCF file and summarise its contents without computing any statistics
+    Check {
+        // VCF file to check (plain or gzipped)
+        vcf: PathBuf,
+    },
+    // Compare two output CSV files and list the regions whose statistics changed
+    Compare {
+        #[arg(long = "file1")]
+        file1: PathBuf,
+        #[arg(long = "file2")]
+        file2: PathBuf,
+        // Largest relative change that still counts as equal
+        #[arg(long = "tolerance", default_value = "0.01")]
+        tolerance: f64,
+    },
+    // Concatenate output CSV files with identical headers, keeping a single header line
+    Merge {
+        // Input files or glob patterns (e.g. "out/*.csv")
+        #[arg(long = "inputs", num_args = 1.., required = true)]
+        inputs: Vec<String>,
+        #[arg(long = "output")]
+        output: PathBuf,
+    },
+}
+
+// Number of worker threads: --threads, else FERROMIC_THREADS, else every logical CPU
+pub fn resolve_thread_count(cli_threads: usize, env_threads: Option<&str>) -> usize {
+    if cli_threads > 0 {
+        return cli_threads;
+    }
+    env_threads
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&threads| threads > 0)
+        .unwrap_or_else(num_cpus::get)
+}
+
+// Source of the ancestral (unmutated) allele at each site
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AncestralSource {
+    // Treat REF as ancestral
+    #[value(name = "ref")]
+    Ref,
+    // Use the AA= tag in the INFO column
+    #[value(name = "aa_tag")]
+    AaTag,
+}
+
+// Handling of sites with more than one ALT allele
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiallelicMode {
+    // Keep them in the unfiltered set only, as parsed
+    #[value(name = "skip")]
+    Skip,
+    // Decompose into one biallelic record per ALT allele
+    #[value(name = "split")]
+    Split,
+    // Treat every non-REF allele as the same derived allele
+    #[value(name = "keep")]
+    Keep,
+}
+
+// Variant class from the lengths of REF and ALT; symbolic and spanning-deletion ALTs are structural
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum VariantType {
+    Snp,
+    Mnp,
+    Insertion,
+    Deletion,
+    Sv,
+}
+
+impl VariantType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VariantType::Snp => "snp",
+            VariantType::Mnp => "mnp",
+            VariantType::Insertion => "insertion",
+            VariantType::Deletion => "deletion",
+            VariantType::Sv => "sv",
+        }
+    }
+}
+
+// Variant types kept by --variant_type
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariantTypeFilter {
+    #[value(name = "all")]
+    All,
+    #[value(name = "snp")]
+    Snp,
+    #[value(name = "mnp")]
+    Mnp,
+    #[value(name = "insertion")]
+    Insertion,
+    #[value(name = "deletion")]
+    Deletion,
+    #[value(name = "sv")]
+    Sv,
+}
+
+impl VariantTypeFilter {
+    pub fn matches(self, variant_type: VariantType) -> bool {
+        match self {
+            VariantTypeFilter::All => true,
+            VariantTypeFilter::Snp => variant_type == VariantType::Snp,
+            VariantTypeFilter::Mnp => variant_type == VariantType::Mnp,
+            VariantTypeFilter::Insertion => variant_type == VariantType::Insertion,
+            VariantTypeFilter::Deletion => variant_type == VariantType::Deletion,
+            VariantTypeFilter::Sv => variant_type == VariantType::Sv,
+        }
+    }
+}
+
+// Naming convention for chromosomes in the outputs
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChrPrefixMode {
+    // Keep names as given; "chr1" and "1" still match each other
+    #[value(name = "auto")]
+    Auto,
+    // UCSC style: chr1, chrX, chrM
+    #[value(name = "ucsc")]
+    Ucsc,
+    // Ensembl style: 1, X, MT
+    #[value(name = "ensembl")]
+    Ensembl,
+}
+
+// Layout of the --pairwise_matrix output
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatrixFormat {
+    // Header row of sample IDs, then one row per sample
+    #[value(name = "csv")]
+    Csv,
+    // PHYLIP distance matrix: the sample count, then one row per sample, as read by PHYLIP neighbor or fastme
+    #[value(name = "phylip")]
+    Phylip,
+}
+
+// Format of the main statistics output
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[value(name = "csv")]
+    Csv,
+    #[value(name = "json")]
+    Json,
+}
+
+// Verbosity of the diagnostics written to stderr
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    #[value(name = "error")]
+    Error,
+    #[value(name = "warn")]
+    Warn,
+    #[value(name = "info")]
+    Info,
+    #[value(name = "debug")]
+    Debug,
+    #[value(name = "trace")]
+    Trace,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+// Data structures
+#[derive(Debug, Clone)]
+pub struct ConfigEntry {
+    pub seqname: String,
+    pub start: i64,
+    pub end: i64,
+    // Optional human-readable name, written as the first output column
+    pub label: Option<String>,
+    pub samples_unfiltered: HashMap<String, (u8, u8)>,
+    pub samples_filtered: HashMap<String, (u8, u8)>,
+}
+
+// Top level of a --yaml_config file
+#[derive(Debug, Deserialize)]
+pub struct YamlConfig {
+    pub regions: Vec<YamlRegion>,
+}
+
+// One region of a YAML config; samples map a sample name to its genotype string, e.g. "0|1"
+#[derive(Debug, Deserialize)]
+pub struct YamlRegion {
+    pub seqname: String,
+    pub start: i64,
+    pub end: i64,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub samples: HashMap<String, String>,
+}
+
+// One region's results, as written to JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionStats {
+    // Config mode: the region's label, if the config gives one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub chr: String,
+    pub region_start: i64,
+    pub region_end: i64,
+    pub sequence_length: i64,
+    pub sequence_length_adjusted: i64,
+    // Bases of the region in the --accessible_mask, used in place of sequence_length when given
+    pub sequence_length_accessible: Option<i64>,
+    // Fraction of G and C in the reference over the region; None when the reference lacks it
+    pub gc_content: Option<f64>,
+    // Window and BED modes: all haplotypes together, at the top level
+    #[serde(flatten)]
+    pub all: Option<HaplotypeSetStats>,
+    // Config mode: one nested object per haplotype group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_0: Option<HaplotypeSetStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_1: Option<HaplotypeSetStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inversion_freq_no_filter: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inversion_freq_filter: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fst: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fst_filtered: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dxy: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dxy_filtered: Option<f64>,
+    // Window and BED modes: inbreeding coefficient F of the samples
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inbreeding_f: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inbreeding_f_filtered: Option<f64>,
+    // Config mode: permutation p-value of fst (NaN unless --fst_permutations is set)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fst_p_value: Option<f64>,
+    // Config mode: sites fixed for different alleles in groups 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_diffs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_diffs_filtered: Option<usize>,
+    // Config mode: Weir & Cockerham FST for every pair of haplotype groups
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pairwise_fst: Vec<PairwiseFst>,
+    // Statistics come from a --max_variants subsample and are approximate
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub subsampled: bool,
+    // Config mode with --flag_outliers: some statistic of the region is an outlier across regions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier: Option<bool>,
+}
+
+// One region's results in --group_file mode, with every group and every pair of groups
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupRegionStats {
+    pub chr: String,
+    pub region_start: i64,
+    pub region_end: i64,
+    pub sequence_length: i64,
+    pub sequence_length_adjusted: i64,
+    // Bases of the region in the --accessible_mask, used in place of sequence_length when given
+    pub sequence_length_accessible: Option<i64>,
+    pub groups: Vec<LabeledGroupStats>,
+    pub pairs: Vec<GroupPairStats>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub subsampled: bool,
+}
+
+// Within-group statistics of one --group_file group
+#[derive(Debug, Clone, Serialize)]
+pub struct LabeledGroupStats {
+    pub label: String,
+    pub unfiltered: GroupStats,
+    pub filtered: GroupStats,
+}
+
+// Between-group divergence of two --group_file groups
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupPairStats {
+    pub group_a: String,
+    pub group_b: String,
+    pub dxy: f64,
+    pub dxy_filtered: f64,
+    pub fst: f64,
+    pub fst_filtered: f64,
+}
+
+// Weir & Cockerham FST between two haplotype groups of a config region
+#[derive(Debug, Clone, Serialize)]
+pub struct PairwiseFst {
+    pub group_a: u8,
+    pub group_b: u8,
+    pub fst: f64,
+    pub fst_filtered: f64,
+}
+
+// Statistics of one set of haplotypes before and after filtering, with bootstrap intervals
+#[derive(Debug, Clone, Serialize)]
+pub struct HaplotypeSetStats {
+    pub unfiltered: GroupStats,
+    pub filtered: GroupStats,
+    pub pi_lower: f64,
+    pub pi_upper: f64,
+    pub theta_lower: f64,
+    pub theta_upper: f64,
+    // Config mode with --mutation_rate: Ne = theta / (4 mu) from the unfiltered theta_W and pi
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ne_theta_w: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ne_pi: Option<f64>,
+}
+
+// Per-haplotype-group results from process_variants
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GroupStats {
+    pub segregating_sites: usize,
+    pub w_theta: f64,
+    pub pi: f64,
+    pub num_haplotypes: usize,
+    pub fu_li_d_star: f64,
+    pub fu_li_f_star: f64,
+    pub fay_wu_h: f64,
+    pub theta_h: f64,
+    pub theta_l: f64,
+    pub zeng_e: f64,
+    pub tajima_d: f64,
+    pub fu_fs: f64,
+    // pi / w_theta; None when w_theta is zero
+    pub neutrality_index: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct FilteringStats {
+    pub total_variants: usize,
+    pub _filtered_variants: usize,
+    pub filtered_due_to_mask: usize,
+    pub repeat_masked_count: usize,
+    pub filtered_due_to_allow: usize,
+    pub filtered_positions: HashSet<i64>,
+    pub missing_data_variants: usize,
+    pub low_gq_variants: usize,
+    pub multi_allelic_variants: usize,
+    pub low_maf_variants: usize,
+    pub hwe_filtered_count: usize,
+    pub unphased_variants: usize,
+    pub info_filtered_count: usize,
+    // Variants below --qual_threshold, and those among the checked ones whose QUAL is missing
+    pub qual_filtered_count: usize,
+    pub missing_qual_count: usize,
+    pub structural_variant_skipped: usize,
+    pub indels_skipped: usize,
+    pub cpg_excluded: usize,
+    pub variant_type_skipped: usize,
+    // Variants of each type in the region, before the --variant_type filter
+    pub variant_type_counts: BTreeMap<VariantType, usize>,
+    pub filtered_examples: Vec<String>,
+}
+
+impl FilteringStats {
+    // Adds an example if there are fewer than 5
+    pub fn add_example(&mut self, example: String) {
+        if self.filtered_examples.len() < 5 {
+            // println!("Adding example - {}", example); // Debug
+            self.filtered_examples.push(example);
+        }
+    }
+}
+
+// e.g. "snp 120, deletion 4", or "none"
+pub fn format_variant_type_counts(counts: &BTreeMap<VariantType, usize>) -> String {
+    if counts.is_empty() {
+        return "none".to_string();
+    }
+    counts
+        .iter()
+        .map(|(variant_type, count)| format!("{} {}", variant_type.as_str(), count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Variant {
+    pub position: i64,
+    pub genotypes: Vec<Option<Vec<u8>>>,
+    pub ancestral_allele: Option<u8>, // Allele index matching the INFO AA tag, if any
+    pub ref_allele: u8,               // REF base, or b'N' unless it is a single A/C/G/T
+    pub alt_allele: u8,               // ALT base, or b'N' unless it is a single A/C/G/T
+    pub variant_type: VariantType,    // Type of the first ALT allele
+}
+
+// Genotype columns above which Variant::allele_count counts in parallel
+const PARALLEL_ALLELE_COUNT_MIN: usize = 1000;
+
+impl Variant {
+    // (copies of allele, called alleles) over all genotypes
+    pub fn allele_count(&self, allele: u8) -> (usize, usize) {
+        let count = |genotype: &Option<Vec<u8>>| match genotype {
+            Some(alleles) => (alleles.iter().filter(|&&a| a == allele).count(), alleles.len()),
+            None => (0, 0),
+        };
+        let add = |a: (usize, usize), b: (usize, usize)| (a.0 + b.0, a.1 + b.1);
+        if self.genotypes.len() > PARALLEL_ALLELE_COUNT_MIN {
+            self.genotypes.par_iter().map(count).reduce(|| (0, 0), add)
+        } else {
+            self.genotypes.iter().map(count).fold((0, 0), add)
+        }
+    }
+}
+
+// IN PROGRESS
+#[derive(Debug, Clone)]
+pub struct SeqInfo {
+    pub sample_index: usize,         // The index of the sample this allele belongs to
+    pub haplotype_group: u8,         // 0 or 1 for haplotype group
+    pub vcf_allele: Option<u8>,      // The VCF allele value (0 or 1) (can be None)
+    pub nucleotide: Option<u8>,      // The allele nucleotide (A, T, C, G) in u8 form (can be None)
+    pub chromosome: String,          // Chromosome identifier
+    pub position: i64,               // Chromosome position
+    pub filtered: bool,              // Was this allele filtered or not
+}
+
+// Comparison operator of an --info_filter expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoOp {
+    Ge,
+    Le,
+    Gt,
+    Lt,
+    Eq,
+    Ne,
+}
+
+// One --info_filter expression such as DP>=10
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoFilter {
+    pub key: String,
+    pub op: InfoOp,
+    pub value: String,
+}
+
+// Contents of a VCF file as counted by the `check` subcommand
+#[derive(Debug, Default, Clone)]
+pub struct VcfAuditReport {
+    pub total_lines: usize,
+    pub header_lines: usize,
+    pub variant_lines: usize,
+    pub variants_per_chromosome: BTreeMap<String, usize>,
+    pub multiallelic_sites: usize,
+    pub sample_names: Vec<String>,
+    pub missing_per_sample: Vec<usize>,
+}
+
+impl VcfAuditReport {
+    // Fraction of variant lines where the sample's genotype is missing or partly missing
+    pub fn missing_rate(&self, sample: usize) -> f64 {
+        if self.variant_lines == 0 {
+            return 0.0;
+        }
+        self.missing_per_sample[sample] as f64 / self.variant_lines as f64
+    }
+}
+
+// One statistic of one region that changed between two output files
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffRow {
+    pub region: String,
+    pub column: String,
+    pub old_value: f64,
+    pub new_value: f64,
+    pub relative_change: f64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MissingDataInfo {
+    pub total_data_points: usize,
+    pub missing_data_points: usize,
+    // Sorted so that --verbose_positions writes them in a fixed order
+    pub positions_with_missing: BTreeSet<i64>,
+    pub filtered_by_qual: usize,
+    pub low_gq_count: usize,
+    pub low_dp_count: usize,
+    pub high_dp_count: usize,
+    // Per-sample counts of parsed sites and missing genotypes, in VCF sample order
+    pub sample_sites: Vec<usize>,
+    pub sample_missing: Vec<usize>,
+}
+
+// Custom error types
+#[derive(Debug)]
+pub enum VcfError {
+    Io(io::Error),
+    Parse(String),
+    InvalidRegion(String),
+    NoVcfFiles,
+    InvalidVcfFormat(String),
+    // A required field is absent or empty; line 0 means the value did not come from a file
+    MissingField { line: usize, field: &'static str },
+    // A field is present but cannot be converted to the expected type
+    BadValue { line: usize, field: &'static str, value: String },
+    ChannelSend,
+    ChannelRecv,
+}
+
+impl<T> From<crossbeam_channel::SendError<T>> for VcfError {
+    fn from(_: crossbeam_channel::SendError<T>) -> Self {
+        VcfError::ChannelSend
+    }
+}
+
+impl From<crossbeam_channel::RecvError> for VcfError {
+    fn from(_: crossbeam_channel::RecvError) -> Self {
+        VcfError::ChannelRecv
+    }
+}
+
+impl std::fmt::Display for VcfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VcfError::Io(err) => write!(f, "IO error: {}", err),
+            VcfError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            VcfError::InvalidRegion(msg) => write!(f, "Invalid region: {}", msg),
+            VcfError::NoVcfFiles => write!(f, "No VCF files found"),
+            VcfError::InvalidVcfFormat(msg) => write!(f, "Invalid VCF format: {}", msg),
+            VcfError::MissingField { line: 0, field } => write!(f, "missing {} field", field),
+            VcfError::MissingField { line, field } => write!(f, "line {}: missing {} field", line, field),
+            VcfError::BadValue { line: 0, field, value } => write!(f, "invalid {} value '{}'", field, value),
+            VcfError::BadValue { line, field, value } => {
+                write!(f, "line {}: invalid {} value '{}'", line, field, value)
+            }
+            VcfError::ChannelSend => write!(f, "Error sending data through channel"),
+            VcfError::ChannelRecv => write!(f, "Error receiving data from channel"),
+        }
+    }
+}
+
+// Lets VcfError be boxed as dyn Error or wrapped in anyhow::Error
+impl std::error::Error for VcfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VcfError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VcfError {
+    fn from(err: io::Error) -> VcfError {
+        VcfError::Io(err)
+    }
+}
+
+// Run the analysis selected by the command-line arguments
+pub fn run(mut args: Args) -> Result<(), VcfError> {
+    if let Some(mu) = args.mutation_rate.filter(|&mu| mu.is_nan() || mu <= 0.0) {
+        return Err(VcfError::Parse(format!("--mutation_rate must be positive (got {})", mu)));
+    }
+    if args.write_params {
+        // Record the thread count actually used rather than 0
+        args.threads = rayon::current_num_threads();
+        let params_path = params_output_path(Path::new(args.output_file.as_deref().unwrap_or("output.csv")));
+        write_run_params(&RunParams::new(&args), &params_path)?;
+        info!("Run parameters written to {}", params_path.display());
+    }
+    // Names are only rewritten for the outputs; matching across files ignores the convention
+    let chr_prefix_mode = args.chr_prefix_mode;
+    args.chr = args.chr.map(|chr| normalize_chr(&chr, chr_prefix_mode));
+    let rename_regions = |regions: Vec<(String, i64, i64)>| -> Vec<(String, i64, i64)> {
+        regions
+            .into_iter()
+            .map(|(chr, start, end)| (normalize_chr(&chr, chr_prefix_mode), start, end))
+            .collect()
+    };
+
+    // Parse the mask file (exclude regions)
+    let mut mask_regions = if let Some(mask_file) = args.mask_file.as_ref() {
+        info!("Mask file provided: {}", mask_file);
+        Some(parse_regions_file(Path::new(mask_file))?)
+    } else {
+        None
+    };
+
+    // Excluded intervals such as centromeres are masked the same way
+    if let Some(exclude_bed) = args.exclude_bed.as_ref() {
+        let excluded = parse_bed_file(Path::new(exclude_bed))?;
+        info!("Excluding {} intervals from {}", excluded.len(), exclude_bed);
+        merge_bed_regions(mask_regions.get_or_insert_with(HashMap::new), &excluded);
+    }
+
+    // Repeats are excluded along with the mask, and kept apart to count the variants they remove
+    let repeat_regions = if let Some(repeat_mask) = args.repeat_mask.as_ref() {
+        let repeats = parse_bed_file(Path::new(repeat_mask))?;
+        info!("Excluding {} repeat intervals from {}", repeats.len(), repeat_mask);
+        merge_bed_regions(mask_regions.get_or_insert_with(HashMap::new), &repeats);
+        let mut repeat_regions = HashMap::new();
+        merge_bed_regions(&mut repeat_regions, &repeats);
+        Some(Arc::new(repeat_regions))
+    } else {
+        None
+    };
+    let mask_regions = mask_regions.map(Arc::new);
+
+    // Callable sites, parsed once and shared by every region and chromosome
+    let accessible_regions = if let Some(accessible_mask) = args.accessible_mask.as_ref() {
+        info!("Accessible mask provided: {}", accessible_mask);
+        Some(Arc::new(parse_accessible_mask(Path::new(accessible_mask))?))
+    } else {
+        None
+    };
+
+    // Parse the allow file (include regions)
+    let allow_regions = if let Some(allow_file) = args.allow_file.as_ref() {
+        info!("Allow file provided: {}", allow_file);
+        let parsed_allow = parse_regions_file(Path::new(allow_file))?;
+        debug!("Parsed Allow Regions: {:?}", parsed_allow);
+        Some(Arc::new(parsed_allow))
+    } else {
+        None
+    };
+    let masks = RegionMasks {
+        mask: mask_regions,
+        repeat: repeat_regions,
+        allow: allow_regions,
+        accessible: accessible_regions,
+    };
+
+    info!("Starting VCF diversity analysis...");
+
+    let mut config_entries = if let Some(config_file) = args.config_file.as_ref() {
+        info!("Config file provided: {}", config_file);
+        Some(parse_config_file(Path::new(config_file))?)
+    } else if let Some(yaml_config) = args.yaml_config.as_ref() {
+        info!("YAML config file provided: {}", yaml_config);
+        Some(parse_yaml_config(Path::new(yaml_config))?)
+    } else {
+        None
+    };
+
+    if let Some(ped_file) = args.ped_file.as_ref() {
+        info!("PED/FAM file provided: {}", ped_file);
+        let sample_filter: HashMap<String, (u8, u8)> = parse_fam_file(Path::new(ped_file))?
+            .into_iter()
+            .map(|(sample, group)| (sample, (group, group)))
+            .collect();
+        info!("{} samples assigned to a haplotype group", sample_filter.len());
+        // The config keeps its regions and labels; otherwise each --bed_file or --region interval is a region
+        let mut entries = match config_entries.take() {
+            Some(entries) => entries,
+            None => {
+                let regions = if let Some(bed_file) = args.bed_file.as_ref() {
+                    rename_regions(parse_bed_file(Path::new(bed_file))?)
+                } else if let (Some(chr), Some(region)) = (args.chr.as_ref(), args.region.as_ref()) {
+                    parse_region(region)?.into_iter().map(|(start, end)| (chr.clone(), start, end)).collect()
+                } else {
+                    return Err(VcfError::Parse(
+                        "--ped_file needs regions from --config_file, --yaml_config, --bed_file or --chr with --region"
+                            .to_string(),
+                    ));
+                };
+                regions
+                    .into_iter()
+                    .map(|(seqname, start, end)| ConfigEntry {
+                        seqname,
+                        start,
+                        end,
+                        label: None,
+                        samples_unfiltered: HashMap::new(),
+                        samples_filtered: HashMap::new(),
+                    })
+                    .collect()
+            }
+        };
+        for entry in &mut entries {
+            entry.samples_unfiltered = sample_filter.clone();
+            entry.samples_filtered = sample_filter.clone();
+        }
+        config_entries = Some(entries);
+    }
+
+    if let Some(mut config_entries) = config_entries {
+        for entry in &mut config_entries {
+            entry.seqname = normalize_chr(&entry.seqname, chr_prefix_mode);
+        }
+        check_overlapping_entries(&config_entries, args.strict)?;
+        for entry in &config_entries {
+            debug!("Config entry chromosome: {}", entry.seqname);
+        }
+        let output_file = args
+            .output_file
+            .as_ref()
+            .map(Path::new)
+            .unwrap_or_else(|| Path::new("output.csv"));
+        info!("Output file: {}", output_file.display());
+        if args.per_site {
+            warn!("--per_site is only supported together with --chr; ignoring it.");
+        }
+        if args.ld_window_bp.is_some() {
+            warn!("--ld_window_bp is only supported together with --chr; ignoring it.");
+        }
+        if args.clr_window_size.is_some() {
+            warn!("--clr_window_size is only supported together with --chr; ignoring it.");
+        }
+        if args.fasta_output.is_some() {
+            warn!("--fasta_output is only supported together with --chr; ignoring it.");
+        }
+        if args.pairwise_matrix.is_some() {
+            warn!("--pairwise_matrix is only supported together with --chr; ignoring it.");
+        }
+        if args.diff_positions_output.is_some() {
+            warn!("--diff_positions_output is only supported together with --chr; ignoring it.");
+        }
+        if args.verbose_positions {
+            warn!("--verbose_positions is only supported together with --chr; ignoring it.");
+        }
+        process_config_entries(
+            &config_entries,
+            &args.vcf_folder,
+            output_file,
+            &masks,
+            &args,
+        )?;
+    } else if args.annotation_gff.is_some() {
+        return Err(VcfError::Parse(
+            "--annotation_gff needs the two haplotype groups of --config_file or --yaml_config".to_string(),
+        ));
+    } else if args.flag_outliers.is_some() {
+        return Err(VcfError::Parse(
+            "--flag_outliers compares the regions of --config_file or --yaml_config".to_string(),
+        ));
+    } else if args.checkpoint_file.is_some() {
+        return Err(VcfError::Parse(
+            "--checkpoint_file records the regions of --config_file or --yaml_config".to_string(),
+        ));
+    } else if let Some(group_file) = args.group_file.as_ref() {
+        info!("Group file provided: {}", group_file);
+        let groups = parse_group_file(Path::new(group_file))?;
+        let regions = if let Some(bed_file) = args.bed_file.as_ref() {
+            info!("BED file provided: {}", bed_file);
+            rename_regions(parse_bed_file(Path::new(bed_file))?)
+        } else if let Some(chr) = args.chr.as_ref() {
+            // Each --region interval is its own row
+            let intervals = match args.region.as_ref() {
+                Some(region) => parse_region(region)?,
+                None => vec![(1, i64::MAX)],
+            };
+            intervals.into_iter().map(|(start, end)| (chr.clone(), start, end)).collect()
+        } else {
+            return Err(VcfError::Parse(
+                "--group_file needs regions from --bed_file or --chr".to_string(),
+            ));
+        };
+        if args.window_size.is_some() {
+            warn!("--window_size is not supported together with --group_file; ignoring it.");
+        }
+        run_group_analysis(
+            &args,
+            &regions,
+            &groups,
+            &masks,
+        )?;
+    } else if let Some(bed_file) = args.bed_file.as_ref() {
+        info!("BED file provided: {}", bed_file);
+        let regions = rename_regions(parse_bed_file(Path::new(bed_file))?);
+        info!("Read {} regions", regions.len());
+        run_bed_region_analysis(
+            &args,
+            &regions,
+            &masks,
+        )?;
+    } else if let (Some(chr), Some(window_size)) = (args.chr.as_ref(), args.window_size) {
+        info!("Chromosome provided: {}", chr);
+        let (start, end) = if let Some(region) = args.region.as_ref() {
+            info!("Region provided: {}", region);
+            match parse_region(region)?[..] {
+                [interval] => interval,
+                _ => {
+                    return Err(VcfError::InvalidRegion(
+                        "Sliding-window mode takes a single --region interval".to_string(),
+                    ))
+                }
+            }
+        } else {
+            info!("No region provided, scanning the whole chromosome.");
+            (1, i64::MAX)
+        };
+        info!(
+            "Sliding windows of {} bp with a step of {} bp",
+            window_size,
+            args.step_size.unwrap_or(window_size)
+        );
+        run_sliding_window_analysis(
+            &args,
+            chr,
+            start,
+            end,
+            &masks,
+        )?;
+    } else if let Some(chr) = args.chr.as_ref() {
+        info!("Chromosome provided: {}", chr);
+        let intervals = if let Some(region) = args.region.as_ref() {
+            info!("Region provided: {}", region);
+            parse_region(region)?
+        } else {
+            info!("No region provided, using default region covering most of the chromosome.");
+            vec![(1, i64::MAX)]
+        };
+        // Several intervals are read over their span; process_vcf skips the records in the gaps
+        let start = intervals[0].0;
+        let end = intervals[intervals.len() - 1].1;
+        let vcf_file = resolve_vcf_file(&args, chr)?;
+
+        info!("Processing VCF file: {}", vcf_file.display());
+        
+        let ref_sequence = read_reference_sequence(
+            &Path::new(&args.reference_path),
+            chr,
+            start,
+            end
+        )?;
+        
+        let cds_regions = parse_gff_file(
+            &Path::new(&args.gff_path),
+            chr,
+            start,
+            end
+        )?;
+        
+
+        // Initialize shared SeqInfo storage
+        let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
+
+        let position_allele_map = Arc::new(Mutex::new(HashMap::<i64, (char, char)>::new()));
+
+
+        // Theta, pi and the per-sample summaries are summed as the VCF is read; the unfiltered
+        // variants themselves are only kept for the outputs that go through them
+        let keep_variants = args.per_site
+            || args.allele_freq_output.is_some()
+            || args.ld_window_bp.is_some()
+            || args.fasta_output.is_some()
+            || args.pairwise_matrix.is_some()
+            || args.diff_positions_output.is_some()
+            || args.clr_window_size.is_some()
+            || args.sfs
+            || args.max_variants > 0
+            || args.concordance_check;
+
+        // Process the VCF file
+        let (
+            mut unfiltered_variants,
+            mut _filtered_variants,
+            sample_names,
+            chr_length,
+            missing_data_info,
+            _filtering_stats,
+            streamed_stats,
+        ) = process_vcf(
+            &vcf_file,
+            &chr,
+            &intervals,
+            &masks,
+            &VcfOptions {
+                stream_unfiltered: (!keep_variants).then_some(args.ancestral_source),
+                ..VcfOptions::from_args(&args)?
+            },
+            Arc::clone(&seqinfo_storage), // Pass the storage
+            Arc::clone(&position_allele_map),
+        )?;
+        let subsampled = subsample_variants(
+            &mut unfiltered_variants,
+            &mut _filtered_variants,
+            args.max_variants,
+            &mut bootstrap_rng(args.seed),
+        );
+        
+        {
+            let seqinfo = seqinfo_storage.lock();
+            if !seqinfo.is_empty() {
+                display_seqinfo_entries(&seqinfo, 12);
+            } else {
+                debug!("No SeqInfo entries were stored.");
+            }
+        }
+        info!("Calculating diversity statistics...");
+
+        // Subsampling keeps the variants, so the sums cover the subsample
+        let chromosome_stats = streamed_stats.unwrap_or_else(|| {
+            let mut chromosome_stats = StreamingChromosomeStats::new(sample_names.len(), args.ancestral_source);
+            for variant in &unfiltered_variants {
+                chromosome_stats.add(variant);
+            }
+            chromosome_stats
+        });
+
+        let seq_length = if end == i64::MAX {
+            let last_position = chromosome_stats.last_position().unwrap_or(0);
+            let (length, known) = whole_chromosome_length(start, chr_length, last_position);
+            if !known {
+                warn!("The sequence length may be underestimated. Consider using the --region parameter for more accurate results.");
+            }
+            length
+        } else {
+            // The union of the intervals, not their span
+            intervals.iter().map(|&(s, e)| e - (s - 1)).sum()
+        };
+
+        let n = sample_names.len();
+        if n == 0 {
+            return Err(VcfError::Parse(
+                "No samples found after processing VCF.".to_string(),
+            ));
+        }
+
+        // Theta and pi only need running sums, not the per-pair difference positions
+        let accumulator = chromosome_stats.diversity();
+        let num_segsites = accumulator.segregating_sites();
+        let raw_variant_count = accumulator.variant_count();
+
+        // With --accessible_mask only callable sites count toward theta and pi
+        let accessible_sequence_length = match masks.accessible.as_ref() {
+            Some(regions) => {
+                let site_intervals = if end == i64::MAX { vec![(start, start - 1 + seq_length)] } else { intervals.clone() };
+                let mut accessible = 0;
+                for &(interval_start, interval_end) in &site_intervals {
+                    accessible += count_accessible_bases(regions, chr, interval_start, interval_end);
+                }
+                Some(accessible)
+            }
+            None => None,
+        };
+        let diversity_length = accessible_sequence_length.unwrap_or(seq_length);
+
+        let w_theta = accumulator.watterson_theta(diversity_length);
+        let pi = accumulator.pi(diversity_length);
+        let theta_h = chromosome_stats.theta_h(2 * n, diversity_length);
+        let theta_l = chromosome_stats.theta_l(2 * n, diversity_length);
+
+        // Ts/Tv well below ~2 (whole genome) suggests calling artifacts
+        let (transitions, transversions) = chromosome_stats.ts_tv();
+        let ts_tv = transitions as f64 / transversions as f64;
+        if transversions > 0 && ts_tv < 1.5 {
+            warn!("Ts/Tv ratio is {:.3}, below 1.5; the call set may contain sequencing artifacts.", ts_tv);
+        }
+
+        if let Some(path) = &args.per_sample_missing {
+            let mut totals = SampleTotals::default();
+            add_sample_missing(&mut totals, &sample_names, &missing_data_info);
+            write_per_sample_missing(Path::new(path), &totals.rows, args.output_precision)?;
+        }
+
+        let missing = missing_fraction(&missing_data_info);
+        let too_much_missing = missing > args.max_missing;
+        if too_much_missing {
+            warn!(
+                "Region has {:.2}% missing genotypes (--max_missing {}); reporting NA statistics",
+                missing * 100.0, args.max_missing
+            );
+        }
+
+        println!("\n{}", "Results:".green().bold());
+        if subsampled {
+            let note = format!("Subsampled to {} variants (--max_variants); statistics are approximate", args.max_variants);
+            println!("{}", note.yellow());
+        }
+        println!("\nSequence Length:{}", seq_length);
+        if let Some(accessible) = accessible_sequence_length {
+            println!("Accessible Sequence Length:{}", accessible);
+        }
+        if too_much_missing {
+            println!("Number of Segregating Sites:NA");
+            println!("Raw Variant Count:{}", raw_variant_count);
+            println!("Watterson Theta:NA");
+            println!("pi:NA");
+            println!("Theta H:NA");
+            println!("Theta L:NA");
+        } else {
+            println!("Number of Segregating Sites:{}", num_segsites);
+            println!("Raw Variant Count:{}", raw_variant_count);
+            println!("Watterson Theta:{:.6}", w_theta);
+            println!("pi:{:.6}", pi);
+            println!("Theta H:{:.6}", theta_h);
+            println!("Theta L:{:.6}", theta_l);
+            if let Some(mu) = args.mutation_rate {
+                println!("Ne from Watterson Theta:{:.1}", effective_population_size(w_theta, mu));
+                println!("Ne from pi:{:.1}", effective_population_size(pi, mu));
+                println!(
+                    "{}",
+                    "Ne = theta / (4 mu) assumes a neutral, constant-size population and the infinite-sites mutation model".yellow()
+                );
+            }
+        }
+        println!("Ts/Tv:{:.3} ({} transitions, {} transversions)", ts_tv, transitions, transversions);
+
+        let heterozygosity = chromosome_stats.per_sample_heterozygosity();
+        let mut samples = Table::new();
+        samples.add_row(row!["Sample", "Heterozygosity"]);
+        for (name, het) in sample_names.iter().zip(&heterozygosity) {
+            samples.add_row(row![name, format_f64(*het, args.output_precision)]);
+        }
+        println!("\n{}", "Heterozygosity per Sample:".green().bold());
+        samples.printstd();
+
+        if args.per_site && !too_much_missing {
+            let output_file = args
+                .output_file
+                .as_ref()
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("output.csv"));
+            let site_intervals = if end == i64::MAX {
+                vec![(start, start - 1 + seq_length)]
+            } else {
+                intervals.clone()
+            };
+            write_per_site_pi(&output_path_with_suffix(output_file, "_per_site"), chr, &site_intervals, &unfiltered_variants, 2 * n, args.output_precision)?;
+        }
+        if let Some(allele_freq_output) = args.allele_freq_output.as_ref() {
+            write_allele_frequencies(Path::new(allele_freq_output), chr, &unfiltered_variants, args.output_precision)?;
+        }
+        if args.verbose_positions {
+            let output_file = args
+                .output_file
+                .as_ref()
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("output.csv"));
+            write_missing_positions(
+                &output_path_with_suffix(output_file, "_missing_positions").with_extension("txt"),
+                &missing_data_info.positions_with_missing,
+            )?;
+        }
+        if args.ld_window_bp.is_some() && !too_much_missing {
+            let output_file = args
+                .output_file
+                .as_ref()
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("output.csv"));
+            write_ld_pairs(&output_path_with_suffix(output_file, "_ld"), &unfiltered_variants, 2 * n, &args)?;
+        }
+        if let (Some(fasta_output), false) = (args.fasta_output.as_ref(), too_much_missing) {
+            let haplotype_indices: Vec<(usize, usize)> = (0..n).flat_map(|i| [(i, 0), (i, 1)]).collect();
+            write_fasta_haplotypes(
+                &unfiltered_variants,
+                &haplotype_indices,
+                &sample_names,
+                &ref_sequence,
+                start,
+                Path::new(fasta_output),
+            )?;
+        }
+        if (args.pairwise_matrix.is_some() || args.diff_positions_output.is_some()) && !too_much_missing {
+            let diffs = calculate_pairwise_differences(&unfiltered_variants, n);
+            if let Some(pairwise_matrix) = args.pairwise_matrix.as_ref() {
+                write_pairwise_matrix(&diffs, &sample_names, Path::new(pairwise_matrix), args.pairwise_matrix_format)?;
+            }
+            if let Some(diff_positions_output) = args.diff_positions_output.as_ref() {
+                write_diff_positions(&diffs, &sample_names, args.min_diff_count, Path::new(diff_positions_output))?;
+            }
+        }
+        if args.clr_window_size.is_some() && !too_much_missing {
+            let output_file = args
+                .output_file
+                .as_ref()
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("output.csv"));
+            write_clr_scores(
+                &output_path_with_suffix(output_file, "_clr"),
+                chr,
+                &unfiltered_variants,
+                2 * n,
+                (start, start - 1 + seq_length),
+                &args,
+            )?;
+        }
+        if args.sfs && !too_much_missing {
+            let output_file = args
+                .output_file
+                .as_ref()
+                .map(Path::new)
+                .unwrap_or_else(|| Path::new("output.csv"));
+            write_sfs(
+                &sfs_output_path(output_file, ""),
+                &unfiltered_variants,
+                2 * n,
+                args.sfs_folded,
+                args.sfs_normalized,
+                args.sfs_exclude_missing,
+                args.output_precision,
+            )?;
+        }
+
+        if raw_variant_count == 0 {
+            warn!("No variants found in the specified region.");
+        }
+
+        if num_segsites == 0 {
+            warn!("All sites are monomorphic.");
+        }
+
+        if num_segsites != raw_variant_count {
+            info!(
+                "Number of segregating sites ({}) differs from raw variant count ({}).",
+                num_segsites, raw_variant_count
+            );
+        }
+
+        println!("\n{}", "Filtering Statistics:".green().bold());
+        println!(
+            "Total variants processed: {}",
+            _filtering_stats.total_variants
+        );
+        println!(
+            "Filtered variants: {} ({:.2}%)",
+            _filtering_stats._filtered_variants,
+            (_filtering_stats._filtered_variants as f64 / _filtering_stats.total_variants as f64)
+                * 100.0
+        );
+        println!("Filtered due to mask: {}", _filtering_stats.filtered_due_to_mask);
+        println!("Filtered due to repeat mask: {}", _filtering_stats.repeat_masked_count);
+        println!("Multi-allelic variants: {}", _filtering_stats.multi_allelic_variants);
+        println!("Low GQ variants: {}", _filtering_stats.low_gq_variants);
+        println!("Low MAF variants: {}", _filtering_stats.low_maf_variants);
+        println!("Variants failing --hwe_filter: {}", _filtering_stats.hwe_filtered_count);
+        println!(
+            "Missing data variants: {}",
+            _filtering_stats.missing_data_variants
+        );
+        println!("Unphased variants: {}", _filtering_stats.unphased_variants);
+        println!("Variants excluded by INFO filters: {}", _filtering_stats.info_filtered_count);
+        println!("Variants below --qual_threshold: {}", _filtering_stats.qual_filtered_count);
+        println!("Symbolic variants skipped: {}", _filtering_stats.structural_variant_skipped);
+        println!("Indels skipped: {}", _filtering_stats.indels_skipped);
+        println!("CpG variants excluded: {}", _filtering_stats.cpg_excluded);
+        println!("Variants excluded by --variant_type: {}", _filtering_stats.variant_type_skipped);
+        println!("Variants by type: {}", format_variant_type_counts(&_filtering_stats.variant_type_counts));
+
+        let missing_data_percentage = missing * 100.0;
+        println!("\n{}", "Missing Data Information:".yellow().bold());
+        println!(
+            "Number of missing data points: {}",
+            missing_data_info.missing_data_points
+        );
+        println!("Percentage of missing data: {:.2}%", missing_data_percentage);
+        println!(
+            "Number of positions with missing data: {}",
+            missing_data_info.positions_with_missing.len()
+        );
+        println!(
+            "Variants excluded by FILTER: {}",
+            missing_data_info.filtered_by_qual
+        );
+        println!("Genotypes below --min_gq: {}", missing_data_info.low_gq_count);
+        println!("Genotypes below --min_dp: {}", missing_data_info.low_dp_count);
+        println!("Genotypes above --max_dp: {}", missing_data_info.high_dp_count);
+    } else {
+        return Err(VcfError::Parse(
+            "Either config file, BED file or chromosome must be specified".to_string(),
+        ));
+    }
+
+    info!("Analysis complete.");
+    Ok(())
+}
+
+// The `check` subcommand: audit a VCF and print the summary tables
+pub fn run_check(path: &Path) -> Result<(), VcfError> {
+    info!("Checking VCF file: {}", path.display());
+    let report = vcf_audit(path)?;
+
+    let mut overview = Table::new();
+    overview.add_row(row!["Total lines", report.total_lines]);
+    overview.add_row(row!["Header lines", report.header_lines]);
+    overview.add_row(row!["Variant lines", report.variant_lines]);
+    overview.add_row(row!["Multi-allelic sites", report.multiallelic_sites]);
+    overview.add_row(row!["Samples", report.sample_names.len()]);
+    println!("\n{}", "VCF Summary:".green().bold());
+    overview.printstd();
+
+    let mut chromosomes = Table::new();
+    chromosomes.add_row(row!["Chromosome", "Variant lines"]);
+    for (chr, count) in &report.variants_per_chromosome {
+        chromosomes.add_row(row![chr, count]);
+    }
+    println!("\n{}", "Variants per Chromosome:".green().bold());
+    chromosomes.printstd();
+
+    let mut samples = Table::new();
+    samples.add_row(row!["Sample", "Missing genotypes", "Missing rate"]);
+    for (i, name) in report.sample_names.iter().enumerate() {
+        samples.add_row(row![
+            name,
+            report.missing_per_sample[i],
+            format!("{:.2}%", report.missing_rate(i) * 100.0)
+        ]);
+    }
+    println!("\n{}", "Missing Data per Sample:".green().bold());
+    samples.printstd();
+    Ok(())
+}
+
+// Columns that identify a row rather than hold a statistic
+const REGION_KEY_COLUMNS: [&str; 5] = ["chr", "region_start", "region_end", "window_start", "window_end"];
+
+// Rows of an output CSV keyed by region (chr:start-end)
+type OutputRows = BTreeMap<String, Vec<String>>;
+
+// Read an output CSV as its column names and rows
+fn read_output_csv(path: &Path) -> Result<(Vec<String>, OutputRows), VcfError> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| VcfError::Io(e.into()))?;
+    let headers: Vec<String> = reader
+        .headers()
+        .map_err(|e| VcfError::Io(e.into()))?
+        .iter()
+        .map(String::from)
+        .collect();
+    let key_indices: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| REGION_KEY_COLUMNS.contains(&name.as_str()))
+        .map(|(i, _)| i)
+        .collect();
+    if key_indices.is_empty() {
+        return Err(VcfError::Parse(format!(
+            "{} has no chr/start/end columns to match regions on",
+            path.display()
+        )));
+    }
+
+    let mut rows = BTreeMap::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| VcfError::Io(e.into()))?;
+        let key: Vec<&str> = key_indices.iter().map(|&i| record.get(i).unwrap_or("")).collect();
+        let region = match key.as_slice() {
+            [chr, start, end] => format!("{}:{}-{}", chr, start, end),
+            _ => key.join(":"),
+        };
+        rows.insert(region, record.iter().map(String::from).collect());
+    }
+    Ok((headers, rows))
+}
+
+// Relative change from old to new: NaN on both sides is no change, NaN on one side gives NaN,
+// and any change away from zero is infinite
+pub fn relative_change(old_value: f64, new_value: f64) -> f64 {
+    if old_value.is_nan() && new_value.is_nan() {
+        return 0.0;
+    }
+    if old_value == new_value {
+        return 0.0;
+    }
+    (new_value - old_value) / old_value.abs()
+}
+
+// Statistics of regions present in both files that changed by more than `tol` (relative)
+// Non-numeric cells, such as NA rows, are compared as text
+pub fn compare_csv_files(f1: &Path, f2: &Path, tol: f64) -> Result<Vec<DiffRow>, VcfError> {
+    let (headers1, rows1) = read_output_csv(f1)?;
+    let (headers2, rows2) = read_output_csv(f2)?;
+
+    let only_in_one = rows1.keys().filter(|region| !rows2.contains_key(*region)).count()
+        + rows2.keys().filter(|region| !rows1.contains_key(*region)).count();
+    if only_in_one > 0 {
+        warn!("{} regions appear in only one of the files and are not compared", only_in_one);
+    }
+
+    // Statistic columns of both files, in the order of the first
+    let columns: Vec<(usize, usize, &String)> = headers1
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| !REGION_KEY_COLUMNS.contains(&name.as_str()))
+        .filter_map(|(i, name)| headers2.iter().position(|other| other == name).map(|j| (i, j, name)))
+        .collect();
+
+    let mut diffs = Vec::new();
+    for (region, values1) in &rows1 {
+        let values2 = match rows2.get(region) {
+            Some(values) => values,
+            None => continue,
+        };
+        for &(i, j, column) in &columns {
+            let (old_text, new_text) = (values1[i].as_str(), values2[j].as_str());
+            let (old_value, new_value, change) = match (old_text.parse::<f64>(), new_text.parse::<f64>()) {
+                (Ok(old_value), Ok(new_value)) => (old_value, new_value, relative_change(old_value, new_value)),
+                _ if old_text == new_text => continue,
+                (old_value, new_value) => (old_value.unwrap_or(f64::NAN), new_value.unwrap_or(f64::NAN), f64::NAN),
+            };
+            if change.is_nan() || change.abs() > tol {
+                diffs.push(DiffRow {
+                    region: region.clone(),
+                    column: column.clone(),
+                    old_value,
+                    new_value,
+                    relative_change: change,
+                });
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+pub fn run_compare(file1: &Path, file2: &Path, tolerance: f64) -> Result<(), VcfError> {
+    info!("Comparing {} with {}", file1.display(), file2.display());
+    let diffs = compare_csv_files(file1, file2, tolerance)?;
+    if diffs.is_empty() {
+        println!(
+            "{}",
+            format!("No statistic differs by more than {}% between the files.", tolerance * 100.0).green().bold()
+        );
+        return Ok(());
+    }
+
+    let regions: BTreeSet<&str> = diffs.iter().map(|diff| diff.region.as_str()).collect();
+    println!(
+        "\n{}",
+        format!("{} regions differ by more than {}%:", regions.len(), tolerance * 100.0).red().bold()
+    );
+    for region in regions {
+        println!("{}", region.yellow().bold());
+        for diff in diffs.iter().filter(|diff| diff.region == region) {
+            println!(
+                "  {}: {} -> {} ({})",
+                diff.column,
+                diff.old_value,
+                diff.new_value,
+                format!("{:+.2}%", diff.relative_change * 100.0).red()
+            );
+        }
+    }
+    Ok(())
+}
+
+// Expand glob patterns into the files they match, in sorted order within each pattern
+pub fn expand_input_patterns(patterns: &[String]) -> Result<Vec<PathBuf>, VcfError> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        let matches = glob::glob(pattern)
+            .map_err(|e| VcfError::Parse(format!("Invalid input pattern '{}': {}", pattern, e)))?;
+        let before = inputs.len();
+        for entry in matches {
+            inputs.push(entry.map_err(|e| VcfError::Io(e.into()))?);
+        }
+        if inputs.len() == before {
+            return Err(VcfError::Parse(format!("No files match '{}'", pattern)));
+        }
+    }
+    Ok(inputs)
+}
+
+// Write the header of the first input once, then the data rows of every input in order.
+// All inputs must have the same header. Returns the number of data rows written.
+pub fn merge_csv_files(inputs: &[PathBuf], output: &Path) -> Result<usize, VcfError> {
+    let first = inputs
+        .first()
+        .ok_or_else(|| VcfError::Parse("No input files to merge".to_string()))?;
+    let mut writer = csv::Writer::from_path(output).map_err(|e| VcfError::Io(e.into()))?;
+    let mut header: Option<csv::StringRecord> = None;
+    let mut rows = 0;
+    for input in inputs {
+        let mut reader = csv::Reader::from_path(input).map_err(|e| VcfError::Io(e.into()))?;
+        let input_header = reader.headers().map_err(|e| VcfError::Io(e.into()))?.clone();
+        match &header {
+            Some(expected) if *expected != input_header => {
+                return Err(VcfError::Parse(format!(
+                    "{} has a different header than {}",
+                    input.display(),
+                    first.display()
+                )));
+            }
+            Some(_) => {}
+            None => {
+                writer.write_record(&input_header).map_err(|e| VcfError::Io(e.into()))?;
+                header = Some(input_header);
+            }
+        }
+        for record in reader.records() {
+            let record = record.map_err(|e| VcfError::Io(e.into()))?;
+            writer.write_record(&record).map_err(|e| VcfError::Io(e.into()))?;
+            rows += 1;
+        }
+    }
+    writer.flush().map_err(VcfError::Io)?;
+    Ok(rows)
+}
+
+pub fn run_merge(patterns: &[String], output: &Path) -> Result<(), VcfError> {
+    let inputs = expand_input_patterns(patterns)?;
+    info!("Merging {} files into {}", inputs.len(), output.display());
+    let rows = merge_csv_files(&inputs, output)?;
+    println!(
+        "{}",
+        format!("Wrote {} rows from {} files to {}", rows, inputs.len(), output.display()).green()
+    );
+    Ok(())
+}
+
+fn display_seqinfo_entries(seqinfo: &[SeqInfo], limit: usize) {
+    // Create a buffer for the table output
+    let mut output = Vec::new();
+    let mut table = Table::new();
+    
+    // Set headers
+    table.add_row(row![
+        "Index", "Sample Index", "Haplotype Group", "VCF Allele", "Nucleotide", "Chromosome", "Position", "Filtered"
+    ]);
+    
+    // Add rows
+    for (i, info) in seqinfo.iter().take(limit).enumerate() {
+        table.add_row(row![
+            i + 1,
+            info.sample_index,
+            info.haplotype_group,
+            info.vcf_allele.map(|a| a.to_string()).unwrap_or("-".to_string()),
+            info.nucleotide.map(|n| n as char).unwrap_or('N'),
+            info.chromosome,
+            info.position,
+            info.filtered
+        ]);
+    }
+    
+    // Render the table to our buffer
+    table.print(&mut output).expect("Failed to print table to buffer");
+    
+    // Now print everything atomically as a single block
+    let table_string = String::from_utf8(output).expect("Failed to convert table to string");
+    
+    // Combine all output into a single print statement
+    print!("\n{}\n{}", 
+           "Sample SeqInfo Entries:".green().bold(),
+           table_string);
+    
+    // Add the count of remaining entries if any
+    if seqinfo.len() > limit {
+        println!("... and {} more entries.", seqinfo.len() - limit);
+    }
+    
+    // Everything is flushed
+    std::io::stdout().flush().expect("Failed to flush stdout");
+}
+
+// Map each (sample, haplotype) assigned to the given group in the config to (VCF sample index, allele index)
+pub fn collect_haplotype_indices(
+    sample_names: &[String],
+    haplotype_group: u8,
+    sample_filter: &HashMap<String, (u8, u8)>,
+) -> Vec<(usize, usize)> {
+    // Map sample names to indices
+    let mut vcf_sample_id_to_index: HashMap<&str, usize> = HashMap::new();
+    for (i, name) in sample_names.iter().enumerate() {
+        let sample_id = extract_sample_id(name);
+        vcf_sample_id_to_index.insert(sample_id, i);
+    }
+
+    let mut haplotype_indices = Vec::new();
+    for (sample_name, &(left_tsv, right_tsv)) in sample_filter.iter() {
+        if let Some(&i) = vcf_sample_id_to_index.get(sample_name.as_str()) {
+            if left_tsv == haplotype_group {
+                haplotype_indices.push((i, 0)); // Include left haplotype
+            }
+            if right_tsv == haplotype_group {
+                haplotype_indices.push((i, 1)); // Include right haplotype
+            }
+        }
+    }
+    haplotype_indices
+}
+
+// One biallelic variant per ALT allele: that ALT becomes 1, REF and the other ALTs become 0
+pub fn split_multiallelic(variant: &Variant, num_alts: usize) -> Vec<Variant> {
+    (1..=num_alts)
+        .map(|alt| {
+            let alt = alt as u8;
+            Variant {
+                position: variant.position,
+                genotypes: variant
+                    .genotypes
+                    .iter()
+                    .map(|gt| {
+                        gt.as_ref()
+                            .map(|alleles| alleles.iter().map(|&a| u8::from(a == alt)).collect())
+                    })
+                    .collect(),
+                ancestral_allele: variant.ancestral_allele.and_then(|aa| match aa {
+                    0 => Some(0),
+                    aa if aa == alt => Some(1),
+                    // The ancestral allele is another ALT, which has no index in this record
+                    _ => None,
+                }),
+                ref_allele: variant.ref_allele,
+                alt_allele: variant.alt_allele,
+                variant_type: variant.variant_type,
+            }
+        })
+        .collect()
+}
+
+// Apply the --multiallelic mode to a parsed variant with num_alts ALT alleles
+pub fn expand_multiallelic(variant: Variant, num_alts: usize, mode: MultiallelicMode) -> Vec<Variant> {
+    if num_alts <= 1 {
+        return vec![variant];
+    }
+    match mode {
+        MultiallelicMode::Skip => vec![variant],
+        MultiallelicMode::Split => split_multiallelic(&variant, num_alts),
+        MultiallelicMode::Keep => vec![Variant {
+            position: variant.position,
+            genotypes: variant
+                .genotypes
+                .iter()
+                .map(|gt| gt.as_ref().map(|alleles| alleles.iter().map(|&a| a.min(1)).collect()))
+                .collect(),
+            ancestral_allele: variant.ancestral_allele.map(|aa| aa.min(1)),
+            ref_allele: variant.ref_allele,
+            alt_allele: variant.alt_allele,
+            variant_type: variant.variant_type,
+        }],
+    }
+}
+
+// Rewrite a variant so each genotype is a single haplotype from the given group
+pub fn project_haplotypes(variant: &Variant, haplotype_indices: &[(usize, usize)]) -> Variant {
+    Variant {
+        position: variant.position,
+        genotypes: haplotype_indices
+            .iter()
+            .map(|&(sample_idx, allele_idx)| {
+                variant.genotypes.get(sample_idx)
+                    .and_then(|gt| gt.as_ref())
+                    .and_then(|alleles| alleles.get(allele_idx))
+                    .map(|&allele| vec![allele])
+            })
+            .collect(),
+        ancestral_allele: variant.ancestral_allele,
+        ref_allele: variant.ref_allele,
+        alt_allele: variant.alt_allele,
+        variant_type: variant.variant_type,
+    }
+}
+
+// Number of distinct samples in the group with a called genotype at this site
+pub fn count_genotyped_samples(variant: &Variant, haplotype_indices: &[(usize, usize)]) -> usize {
+    haplotype_indices
+        .iter()
+        .map(|&(sample_idx, _)| sample_idx)
+        .filter(|&sample_idx| matches!(variant.genotypes.get(sample_idx), Some(Some(_))))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+// Fraction of genotype calls that were missing, 0.0 when nothing was genotyped
+pub fn missing_fraction(info: &MissingDataInfo) -> f64 {
+    if info.total_data_points == 0 {
+        return 0.0;
+    }
+    info.missing_data_points as f64 / info.total_data_points as f64
+}
+
+// Count one site towards each sample's total, and towards its missing count if the genotype is absent
+pub fn record_sample_missing(info: &mut MissingDataInfo, genotypes: &[Option<Vec<u8>>]) {
+    if info.sample_sites.len() < genotypes.len() {
+        info.sample_sites.resize(genotypes.len(), 0);
+        info.sample_missing.resize(genotypes.len(), 0);
+    }
+    for (i, genotype) in genotypes.iter().enumerate() {
+        info.sample_sites[i] += 1;
+        if genotype.is_none() {
+            info.sample_missing[i] += 1;
+        }
+    }
+}
+
+// Running per-sample (sample, count, count) totals in order of first appearance, indexed by sample name
+#[derive(Debug, Default)]
+pub struct SampleTotals {
+    pub rows: Vec<(String, usize, usize)>,
+    index: HashMap<String, usize>,
+}
+
+impl SampleTotals {
+    pub fn add(&mut self, name: &str, first: usize, second: usize) {
+        let index = match self.index.get(name) {
+            Some(&index) => index,
+            None => {
+                self.rows.push((name.to_string(), 0, 0));
+                self.index.insert(name.to_string(), self.rows.len() - 1);
+                self.rows.len() - 1
+            }
+        };
+        self.rows[index].1 += first;
+        self.rows[index].2 += second;
+    }
+}
+
+// Add the per-sample counts of one VCF to running (sample, total_sites, missing_sites) totals, matching by name
+pub fn add_sample_missing(totals: &mut SampleTotals, sample_names: &[String], info: &MissingDataInfo) {
+    for (i, name) in sample_names.iter().enumerate().take(info.sample_sites.len()) {
+        totals.add(name, info.sample_sites[i], info.sample_missing[i]);
+    }
+}
+
+// Add one chromosome's (heterozygous, called) site counts to the running per-sample totals
+pub fn add_sample_heterozygosity(totals: &mut SampleTotals, sample_names: &[String], counts: &[(usize, usize)]) {
+    for (name, &(heterozygous, called)) in sample_names.iter().zip(counts) {
+        totals.add(name, heterozygous, called);
+    }
+}
+
+// Write per-sample statistics as sample_name,heterozygous_sites,called_sites,heterozygosity
+pub fn write_sample_stats(path: &Path, totals: &[(String, usize, usize)], precision: usize) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["sample_name", "heterozygous_sites", "called_sites", "heterozygosity"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for (name, heterozygous, called) in totals {
+        let heterozygosity = if *called == 0 { f64::NAN } else { *heterozygous as f64 / *called as f64 };
+        writer
+            .write_record([
+                name.clone(),
+                heterozygous.to_string(),
+                called.to_string(),
+                format_f64(heterozygosity, precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("Per-sample statistics for {} samples written to {}", totals.len(), path.display());
+    Ok(())
+}
+
+// Write per-sample missing data as sample_name,total_sites,missing_sites,missing_fraction
+pub fn write_per_sample_missing(path: &Path, totals: &[(String, usize, usize)], precision: usize) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["sample_name", "total_sites", "missing_sites", "missing_fraction"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for (name, total_sites, missing_sites) in totals {
+        let fraction = if *total_sites == 0 {
+            0.0
+        } else {
+            *missing_sites as f64 / *total_sites as f64
+        };
+        writer
+            .write_record([
+                name.clone(),
+                total_sites.to_string(),
+                missing_sites.to_string(),
+                format_f64(fraction, precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("Per-sample missing data for {} samples written to {}", totals.len(), path.display());
+    Ok(())
+}
+
+// Missing-genotype counts over a set of parsed variants
+pub fn missing_data_in_variants(variants: &[Variant]) -> MissingDataInfo {
+    let mut info = MissingDataInfo::default();
+    for variant in variants {
+        record_sample_missing(&mut info, &variant.genotypes);
+        for genotype in &variant.genotypes {
+            info.total_data_points += 1;
+            if genotype.is_none() {
+                info.missing_data_points += 1;
+                info.positions_with_missing.insert(variant.position);
+            }
+        }
+    }
+    info
+}
+
+// True when a VCF line's FILTER column is PASS or unset (.)
+pub fn filter_column_passes(line: &str) -> bool {
+    matches!(line.split('\t').nth(6).map(str::trim), Some("PASS") | Some("."))
+}
+
+// Order collected (line number, FILTER passes, variant) records by position; at a shared position
+// FILTER=PASS records come first, then file order. The sort is stable, so split multi-allelic
+// records from one line keep their ALT order.
+pub fn sort_collected_variants(mut records: Vec<(usize, bool, Variant)>) -> Vec<Variant> {
+    records.sort_by_key(|(line_num, filter_pass, variant)| (variant.position, !filter_pass, *line_num));
+    records.into_iter().map(|(_, _, variant)| variant).collect()
+}
+
+// Keep only the first record at each position of a sorted list, returning how many were dropped
+pub fn dedup_by_position(variants: &mut Vec<Variant>) -> usize {
+    let before = variants.len();
+    variants.dedup_by_key(|v| v.position);
+    before - variants.len()
+}
+
+// The --chr statistics summed in the collector, in place of keeping the unfiltered variants.
+// Lines wait here until every line before them is in, so records are counted in file order;
+// in a sorted VCF the records at a position are then adjacent, and the one kept is the one
+// sort_collected_variants and dedup_by_position would keep.
+struct UnfilteredStream {
+    stats: StreamingChromosomeStats,
+    dedup: bool,
+    next_line: usize,
+    waiting: BTreeMap<usize, (bool, Vec<Variant>)>,
+    // The record kept so far at the latest position, and whether its FILTER passes
+    current: Option<(bool, Variant)>,
+    duplicates: usize,
+    unsorted: bool,
+}
+
+impl UnfilteredStream {
+    fn new(stats: StreamingChromosomeStats, dedup: bool, first_line: usize) -> Self {
+        UnfilteredStream {
+            stats,
+            dedup,
+            next_line: first_line,
+            waiting: BTreeMap::new(),
+            current: None,
+            duplicates: 0,
+            unsorted: false,
+        }
+    }
+
+    // Every line has to be received, with or without records, for the lines after it to be counted
+    fn receive(&mut self, line_num: usize, filter_pass: bool, variants: Vec<Variant>) {
+        self.waiting.insert(line_num, (filter_pass, variants));
+        while let Some((filter_pass, variants)) = self.waiting.remove(&self.next_line) {
+            self.next_line += 1;
+            for variant in variants {
+                self.keep(filter_pass, variant);
+            }
+        }
+    }
+
+    fn keep(&mut self, filter_pass: bool, variant: Variant) {
+        match self.current.take() {
+            Some((kept_pass, kept)) if self.dedup && kept.position == variant.position => {
+                self.duplicates += 1;
+                // A passing FILTER wins over an earlier failing one
+                self.current = Some(if filter_pass && !kept_pass { (filter_pass, variant) } else { (kept_pass, kept) });
+            }
+            Some((_, kept)) => {
+                self.unsorted |= variant.position < kept.position;
+                self.stats.push(kept);
+                self.current = Some((filter_pass, variant));
+            }
+            None => self.current = Some((filter_pass, variant)),
+        }
+    }
+
+    // Count whatever is still held: the last record, and lines after one that never arrived
+    fn finish(mut self) -> (StreamingChromosomeStats, usize, bool) {
+        for (_, (filter_pass, variants)) in std::mem::take(&mut self.waiting) {
+            for variant in variants {
+                self.keep(filter_pass, variant);
+            }
+        }
+        if let Some((_, variant)) = self.current.take() {
+            self.stats.push(variant);
+        }
+        (self.stats, self.duplicates, self.unsorted)
+    }
+}
+
+// Haplotype-projected variants of one group within a region, for between-group statistics
+pub fn extract_group_variants(
+    variants: &[Variant],
+    sample_names: &[String],
+    haplotype_group: u8,
+    sample_filter: &HashMap<String, (u8, u8)>,
+    region_start: i64,
+    region_end: i64,
+) -> (Vec<Variant>, usize) {
+    let haplotype_indices = collect_haplotype_indices(sample_names, haplotype_group, sample_filter);
+    let group_variants = variants
+        .iter()
+        .filter(|v| v.position >= region_start && v.position <= region_end)
+        .map(|v| project_haplotypes(v, &haplotype_indices))
+        .collect();
+    (group_variants, haplotype_indices.len())
+}
+
+// A config region with its reference and CDS data, shared by the statistics of each haplotype group
+#[derive(Clone, Copy)]
+pub struct ConfigRegion<'a> {
+    pub chromosome: &'a str,
+    pub start: i64,
+    pub end: i64,
+    pub sample_names: &'a [String],
+    pub reference_sequence: &'a [u8],
+    pub cds_regions: &'a [CdsRegion],
+    pub seqinfo_storage: &'a Mutex<Vec<SeqInfo>>,
+    pub position_allele_map: &'a Mutex<HashMap<i64, (char, char)>>,
+    pub ancestral_source: AncestralSource,
+    pub min_samples: usize,
+    pub phylip_dir: &'a Path,
+}
+
+pub fn process_variants(
+    variants: &[Variant],
+    haplotype_group: u8,
+    sample_filter: &HashMap<String, (u8, u8)>,
+    adjusted_sequence_length: Option<i64>,
+    is_filtered_set: bool,
+    region: &ConfigRegion,
+) -> Result<Option<GroupStats>, VcfError> {
+    let ConfigRegion {
+        chromosome,
+        start: region_start,
+        end: region_end,
+        sample_names,
+        reference_sequence,
+        cds_regions,
+        seqinfo_storage,
+        position_allele_map,
+        ancestral_source,
+        min_samples,
+        phylip_dir,
+    } = *region;
+    // Collect haplotype indices for the specified group
+    let haplotype_indices = collect_haplotype_indices(sample_names, haplotype_group, sample_filter);
+
+    if haplotype_indices.is_empty() {
+        debug!(
+            "No haplotypes found for the specified group {}.",
+            haplotype_group
+        );
+        return Ok(None);
+    }
+
+    let mut num_segsites = 0;
+    let mut tot_pair_diff = 0;
+    let n = haplotype_indices.len();
+
+    // Early return if no variants
+    if variants.is_empty() {
+        // Return zero values but valid result
+        return Ok(Some(GroupStats {
+            num_haplotypes: n,
+            fu_li_d_star: f64::NAN,
+            fu_li_f_star: f64::NAN,
+            fay_wu_h: f64::NAN,
+            theta_h: f64::NAN,
+            theta_l: f64::NAN,
+            zeng_e: f64::NAN,
+            tajima_d: f64::NAN,
+            fu_fs: f64::NAN,
+            ..Default::default()
+        }));
+    }
+
+    // One single-allele genotype per haplotype in this group, for the SFS-based statistics
+    let mut group_variants = Vec::new();
+    let mut low_sample_variants = 0;
+
+    // Collect alleles and compute statistics
+    for variant in variants {
+        if variant.position < region_start || variant.position > region_end {
+            continue;
+        }
+
+        // Skip sites genotyped in too few samples of this group
+        if count_genotyped_samples(variant, &haplotype_indices) < min_samples {
+            low_sample_variants += 1;
+            continue;
+        }
+
+        group_variants.push(project_haplotypes(variant, &haplotype_indices));
+
+        let mut variant_alleles = Vec::new();
+
+        for &(sample_idx, allele_idx) in &haplotype_indices {
+            let allele = variant.genotypes.get(sample_idx)
+                .and_then(|gt| gt.as_ref())
+                .and_then(|alleles| alleles.get(allele_idx))
+                .copied();
+
+
+                // Convert VCF allele to actual nucleotide
+                let nucleotide = if let Some(allele_val) = allele {
+                    let map = position_allele_map.lock();
+                    if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
+                        match allele_val {
+                            0 => Some(ref_allele as u8),
+                            1 => Some(alt_allele as u8),
+                            _ => Some(b'N')
+                        }
+                    } else {
+                        warn!("No allele mapping found for position {}", variant.position);
+                        Some(b'N')
+                    }
+                } else {
+                    None
+                };
+                
+
+                // Create and store SeqInfo
+                let seq_info = SeqInfo {
+                    sample_index: sample_idx,
+                    haplotype_group,
+                    vcf_allele: allele,
+                    nucleotide,
+                    chromosome: chromosome.to_string(),
+                    position: variant.position,
+                    filtered: is_filtered_set, // MUST USE ACTUAL FILTERING INFO.
+                    // Perhaps since different aspects are updated in different places we can update sections of SeqInfo at a time. However, need way to ID same allele each update
+                };
+        
+                // Store SeqInfo if we have a valid nucleotide
+                if let Some(allele_val) = allele {
+                    let mut storage = seqinfo_storage.lock();
+                    storage.push(seq_info);
+                    variant_alleles.push(allele_val);
+                }
+        }
+
+        // Determine if the variant is a segregating site
+        let unique_alleles: HashSet<u8> = variant_alleles.iter().cloned().collect();
+        if unique_alleles.len() > 1 {
+            num_segsites += 1;
+        }
+
+        // Compute pairwise differences
+        if !variant_alleles.is_empty() {
+            for i in 0..n {
+                if let Some(&allele_i) = variant_alleles.get(i) {
+                    for j in (i + 1)..n {
+                        if let Some(&allele_j) = variant_alleles.get(j) {
+                            if allele_i != allele_j {
+                                tot_pair_diff += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if low_sample_variants > 0 {
+        debug!(
+            "Group {}: dropped {} variants genotyped in fewer than {} samples",
+            haplotype_group, low_sample_variants, min_samples
+        );
+    }
+
+    let seq_length = adjusted_sequence_length.unwrap_or(region_end - region_start + 1);
+    let w_theta = calculate_watterson_theta(num_segsites, n, seq_length);
+    let pi = calculate_pi(tot_pair_diff, n, seq_length);
+
+    let num_singletons = count_singletons(&group_variants);
+    let mean_pair_diff = if n > 1 {
+        tot_pair_diff as f64 / (n * (n - 1) / 2) as f64
+    } else {
+        0.0
+    };
+    let fu_li_d_star = calculate_fu_li_d_star(num_segsites, num_singletons, n);
+    let fu_li_f_star = calculate_fu_li_f_star(num_segsites, num_singletons, mean_pair_diff, n);
+    let fay_wu_h = calculate_fay_wu_h(&group_variants, n, seq_length, ancestral_source);
+    let theta_h = calculate_theta_h(&group_variants, n, seq_length, ancestral_source);
+    let theta_l = calculate_theta_l(&group_variants, n, seq_length, ancestral_source);
+    let zeng_e = calculate_zeng_e(&group_variants, n, seq_length, ancestral_source);
+    let tajima_d = calculate_tajima_d(num_segsites, mean_pair_diff, n);
+    let fu_fs = calculate_fu_fs(count_distinct_haplotypes(&group_variants, n), n, mean_pair_diff);
+
+    // Process CDS regions and generate final coding sequences per transcript
+    for cds in cds_regions {
+        let transcript_id = &cds.transcript_id;
+        let cds_min = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
+        let cds_max = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
+    
+        // Map each haplotype to a combined coding sequence
+        let mut combined_sequences: HashMap<String, Vec<u8>> = HashMap::new();
+        for (sample_idx, hap_idx) in &haplotype_indices {
+            let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
+            combined_sequences.insert(sample_name, Vec::new());
+        }
+    
+        // Determine overlapping segments and append them to each haplotype’s sequence
+        let mut segment_map = Vec::new();
+        {
+            let mut current_length = 0;
+            for &(seg_start, seg_end) in &cds.segments {
+                if seg_end < region_start || seg_start > region_end {
+                    continue;
+                }
+                let overlap_start = std::cmp::max(seg_start, region_start);
+                let overlap_end = std::cmp::min(seg_end, region_end);
+                let start_offset = (overlap_start - region_start) as usize;
+                let end_offset = (overlap_end - region_start + 1) as usize;
+    
+                if end_offset > reference_sequence.len() {
+                    continue;
+                }
+    
+                let segment_ref_seq = &reference_sequence[start_offset..end_offset];
+                for (sample_idx, hap_idx) in &haplotype_indices {
+                    let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
+                    combined_sequences.get_mut(&sample_name).unwrap().extend_from_slice(segment_ref_seq);
+                }
+    
+                let segment_len = end_offset - start_offset;
+                segment_map.push((overlap_start, overlap_end, current_length));
+                current_length += segment_len;
+            }
+        }
+    
+        // Apply variants to the combined coding sequences
+        for variant in variants {
+            if variant.position < cds_min || variant.position > cds_max {
+                continue;
+            }
+            let mut pos_in_cds = None;
+            for &(seg_s, seg_e, offset) in &segment_map {
+                if variant.position >= seg_s && variant.position <= seg_e {
+                    let rel = variant.position - seg_s;
+                    let idx = offset + rel as usize;
+                    pos_in_cds = Some(idx);
+                    break;
+                }
+            }
+    
+            if let Some(pos_in_seq) = pos_in_cds {
+                for (sample_idx, hap_idx) in &haplotype_indices {
+                    if let Some(Some(alleles)) = variant.genotypes.get(*sample_idx) {
+                        if let Some(allele) = alleles.get(*hap_idx) {
+                            let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
+                            if let Some(seq) = combined_sequences.get_mut(&sample_name) {
+                                if pos_in_seq < seq.len() {
+                                    let map = position_allele_map.lock();
+                                    if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
+                                        seq[pos_in_seq] = if *allele == 0 {
+                                            ref_allele as u8
+                                        } else {
+                                            alt_allele as u8
+                                        };
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    
+        // Make sure all sequences are the same length across haplotypes
+        let full_seq_lengths: Vec<usize> = combined_sequences.values().map(|v| v.len()).collect();
+        if full_seq_lengths.is_empty() {
+            continue;
+        }
+        let final_length = full_seq_lengths[0];
+        if !full_seq_lengths.iter().all(|&l| l == final_length) {
+            continue;
+        }
+    
+    let remainder = final_length % 3;
+    if remainder != 0 {
+        warn!("Skipping transcript {} for haplotype group {} on chr {} because final coding sequence length ({}) is not divisible by 3.", transcript_id, haplotype_group, chromosome, final_length);
+        continue;
+    }
+
+    // Check for stop codons in the final coding sequences
+    let stop_codons = ["TAA", "TAG", "TGA"];
+    let mut skip_due_to_stop = false;
+    for (sample_name, seq) in &combined_sequences {
+        let seq_str = String::from_utf8_lossy(seq);
+        let upper_seq = seq_str.to_ascii_uppercase();
+        for i in (0..upper_seq.len()).step_by(3) {
+            if i + 2 < upper_seq.len() {
+                let codon = &upper_seq[i..i+3];
+                if stop_codons.contains(&codon) {
+                    warn!("Skipping transcript {} for haplotype group {} on chr {} due to internal stop codon '{}' at codon position {} in sample {}.", transcript_id, haplotype_group, chromosome, codon, i/3, sample_name);
+                    skip_due_to_stop = true;
+                    break;
+                }
+            }
+        }
+        if skip_due_to_stop {
+            break;
+        }
+    }
+
+    if skip_due_to_stop {
+        continue;
+    }
+
+    // Convert to char sequences and write out a single final PHYLIP file per transcript
+    let filename = phylip_dir.join(format!("group_{}_{}_chr_{}_combined.phy", haplotype_group, transcript_id, chromosome));
+    let char_sequences: HashMap<String, Vec<char>> = combined_sequences
+        .into_iter()
+        .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
+        .collect();
+    write_phylip_file(&filename, &char_sequences)?;
+    }
+
+    // Print the current contents before clearing
+    {
+        let seqinfo = seqinfo_storage.lock();
+        if !seqinfo.is_empty() {
+            display_seqinfo_entries(&seqinfo, 12);
+        } else {
+            debug!("No SeqInfo entries were stored.");
+        }
+    }
+
+    // Clear storage for next group
+    seqinfo_storage.lock().clear();
+
+    if is_filtered_set {
+        make_sequences(variants, haplotype_group, sample_filter, region)?;
+    }
+    Ok(Some(GroupStats {
+        segregating_sites: num_segsites,
+        w_theta,
+        pi,
+        num_haplotypes: n,
+        fu_li_d_star,
+        fu_li_f_star,
+        fay_wu_h,
+        theta_h,
+        theta_l,
+        zeng_e,
+        tajima_d,
+        fu_fs,
+        neutrality_index: calculate_neutrality_index(pi, w_theta),
+    }))
+}
+
+fn make_sequences(
+    variants: &[Variant],
+    haplotype_group: u8,
+    sample_filter: &HashMap<String, (u8, u8)>,
+    region: &ConfigRegion,
+) -> Result<(), VcfError> {
+    let ConfigRegion {
+        chromosome,
+        start: region_start,
+        end: region_end,
+        sample_names,
+        reference_sequence,
+        cds_regions,
+        position_allele_map,
+        phylip_dir,
+        ..
+    } = *region;
+    // Map sample names to indices
+    let mut vcf_sample_id_to_index: HashMap<&str, usize> = HashMap::new();
+    for (i, name) in sample_names.iter().enumerate() {
+        let sample_id = extract_sample_id(name);
+        vcf_sample_id_to_index.insert(sample_id, i);
+    }
+
+    // Collect haplotype indices for the specified group
+    let mut haplotype_indices = Vec::new();
+    for (sample_name, &(left_tsv, right_tsv)) in sample_filter.iter() {
+        if let Some(&i) = vcf_sample_id_to_index.get(sample_name.as_str()) {
+            if left_tsv == haplotype_group as u8 {
+                haplotype_indices.push((i, 0)); // Include left haplotype
+            }
+            if right_tsv == haplotype_group as u8 {
+                haplotype_indices.push((i, 1)); // Include right haplotype
+            }
+        } else {
+            // Sample not found in VCF
+        }
+    }
+
+    if haplotype_indices.is_empty() {
+        debug!(
+            "No haplotypes found for the specified group {}.",
+            haplotype_group
+        );
+        return Ok(());
+    }
+
+
+    // Initialize sequences for each sample haplotype with the reference sequence
+    let mut hap_sequences: HashMap<String, Vec<u8>> = HashMap::new();
+    for (sample_idx, hap_idx) in &haplotype_indices {
+        let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
+        hap_sequences.insert(sample_name, reference_sequence.to_vec());
+    }
+
+    // Apply variants to sequences
+    for variant in variants {
+        if variant.position >= region_start && variant.position <= region_end {
+            let pos_in_seq = (variant.position - region_start) as usize;
+            for (sample_idx, hap_idx) in &haplotype_indices {
+                if let Some(Some(alleles)) = variant.genotypes.get(*sample_idx) {
+                    if let Some(allele) = alleles.get(*hap_idx) {
+                        let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
+                        if let Some(seq) = hap_sequences.get_mut(&sample_name) {
+                            if pos_in_seq >= seq.len() {
+                                warn!(
+                                    "Position {} is out of bounds for sequence of length {}. Skipping variant.",
+                                    pos_in_seq, seq.len()
+                                );
+                                continue;
+                            }
+                            let map = position_allele_map.lock();
+                            if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
+                                seq[pos_in_seq] = if *allele == 0 {
+                                    ref_allele as u8
+                                } else {
+                                    alt_allele as u8
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Print batch statistics before CDS processing
+    if hap_sequences.is_empty() {
+        warn!("No haplotype sequences generated. Cannot compute batch statistics.");
+    } else {
+        let total_sequences = hap_sequences.len();
+        let mut stop_codon_or_too_short = 0;
+        let mut skipped_sequences = 0;
+        let mut not_divisible_by_three = 0;
+        let mut mid_sequence_stop = 0;
+        let mut length_modified = 0;
+
+        let stop_codons = ["TAA", "TAG", "TGA"];
+
+        // Validate all sequences once before CDS processing
+        for (_sample_name, sequence) in &hap_sequences {
+            let sequence_str = String::from_utf8_lossy(sequence);
+
+            if sequence.len() < 3 || !sequence_str.starts_with("ATG") {
+                stop_codon_or_too_short += 1;
+                skipped_sequences += 1;
+                continue;
+            }
+
+            if sequence.len() % 3 != 0 {
+                not_divisible_by_three += 1;
+                length_modified += 1;
+            }
+
+            // Check for mid-sequence stop codons
+            for i in (0..sequence.len() - 2).step_by(3) {
+                let codon = &sequence_str[i..i + 3];
+                if stop_codons.contains(&codon) {
+                    mid_sequence_stop += 1;
+                    break;
+                }
+            }
+        }
+
+        println!("\nBatch Statistics:");
+        println!(
+            "Percentage of sequences with stop codon or too short: {:.2}%",
+            (stop_codon_or_too_short as f64 / total_sequences as f64) * 100.0
+        );
+        println!(
+            "Percentage of sequences skipped: {:.2}%",
+            (skipped_sequences as f64 / total_sequences as f64) * 100.0
+        );
+        println!(
+            "Percentage of sequences not divisible by three: {:.2}%",
+            (not_divisible_by_three as f64 / total_sequences as f64) * 100.0
+        );
+        println!(
+            "Percentage of sequences with a mid-sequence stop codon: {:.2}%",
+            (mid_sequence_stop as f64 / total_sequences as f64) * 100.0
+        );
+        println!(
+            "Percentage of sequences with modified length: {:.2}%",
+            (length_modified as f64 / total_sequences as f64) * 100.0
+        );
+    }
+
+    // For each CDS, extract sequences and write to PHYLIP file
+    for cds in cds_regions {
+        let cds_start = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
+        let cds_end = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
+
+        // Check overlap with region
+        let mut combined_cds_sequences: HashMap<String, Vec<u8>> = HashMap::new();
+        for (name, original_seq) in &hap_sequences {
+            combined_cds_sequences.insert(name.clone(), Vec::new());
+        }
+        
+        for &(seg_start, seg_end) in &cds.segments {
+            if seg_end < region_start || seg_start > region_end {
+                continue;
+            }
+        
+            let cds_start = std::cmp::max(seg_start - 1, region_start - 1); // 0-based
+            let cds_end = std::cmp::min(seg_end, region_end);
+        
+            // Extract per-segment and append to combined_cds_sequences[name]
+        }
+        
+        // After processing all segments, write one final .phy file
+        let filename = phylip_dir.join(format!(
+            "group_{}_{}_chr_{}_combined.phy",
+            haplotype_group,
+            cds.transcript_id,
+            chromosome
+        ));
+        
+        // Convert combined_cds_sequences to char sequences
+        let char_sequences: HashMap<String, Vec<char>> = combined_cds_sequences
+            .into_iter()
+            .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
+            .collect();
+        
+        write_phylip_file(&filename, &char_sequences)?;
+
+        let mut combined_sequences: HashMap<String, Vec<u8>> = HashMap::new();
+
+        // After processing all segments into combined_sequences, compute final length:
+        let full_seq_lengths: Vec<usize> = combined_sequences.values().map(|v| v.len()).collect();
+        if full_seq_lengths.is_empty() { continue; }
+        let final_length = full_seq_lengths[0];
+        // Check all are equal length
+        if !full_seq_lengths.iter().all(|&l| l == final_length) {
+            error!("Not all sequences are the same length after concatenation. Skipping.");
+            continue;
+        }
+
+        // Check if length is multiple of 3
+        if final_length % 3 != 0 {
+            warn!("Skipping because final length ({}) is not divisible by 3.", final_length);
+            continue;
+        }
+
+        // For each haplotype sequence, extract CDS sequence
+        let mut cds_sequences: HashMap<String, Vec<u8>> = HashMap::new();
+        for (sample_name, seq) in &hap_sequences {
+            let start_offset = (cds_start - region_start) as usize;
+            let end_offset = (cds_end - region_start) as usize; // No +1 needed for half-open intervals
+
+            if end_offset > seq.len() {
+                warn!(
+                    "CDS end offset {} exceeds sequence length {} for sample {}. Skipping CDS.",
+                    end_offset, seq.len(), sample_name
+                );
+                continue;
+            }
+
+            let cds_seq = seq[start_offset..end_offset].to_vec();
+            cds_sequences.insert(sample_name.clone(), cds_seq);
+        }
+
+        let cds_start = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
+        let cds_end = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
+
+        if cds_sequences.is_empty() {
+            warn!(
+                "No CDS sequences generated for CDS region {}-{}. Skipping PHYLIP file writing.",
+                cds_start, cds_end
+            );
+            continue;
+        }
+
+        // Write sequences to PHYLIP file
+        let filename = format!(
+            "group_{}_{}_chr_{}_start_{}_end_{}.phy",
+            haplotype_group,
+            cds.transcript_id,
+            chromosome,
+            cds_start,
+            cds_end
+        );
+
+        
+        let filename = phylip_dir.join(format!(
+            "group_{}_{}_chr_{}_combined.phy",
+            haplotype_group,
+            cds.transcript_id,
+            chromosome
+        ));
+        
+        // Convert combined_sequences to char sequences
+        let char_sequences: HashMap<String, Vec<char>> = combined_sequences
+            .into_iter()
+            .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
+            .collect();
+        
+        write_phylip_file(&filename, &char_sequences)?;
+    }
+
+    Ok(())
+}
+
+// A statistic with a fixed number of decimal places, or NA when it is NaN or infinite
+pub fn format_f64(v: f64, precision: usize) -> String {
+    if v.is_finite() {
+        format!("{:.*}", precision, v)
+    } else {
+        "NA".to_string()
+    }
+}
+
+// CSV row of a config region, in the column order of the config-mode header
+pub fn config_stats_record(stats: &RegionStats, precision: usize) -> Vec<String> {
+    let empty = HaplotypeSetStats {
+        unfiltered: GroupStats::default(),
+        filtered: GroupStats::default(),
+        pi_lower: f64::NAN,
+        pi_upper: f64::NAN,
+        theta_lower: f64::NAN,
+        theta_upper: f64::NAN,
+        ne_theta_w: None,
+        ne_pi: None,
+    };
+    let g0 = stats.group_0.as_ref().unwrap_or(&empty);
+    let g1 = stats.group_1.as_ref().unwrap_or(&empty);
+    vec![
+        stats.chr.clone(),
+        stats.region_start.to_string(),
+        stats.region_end.to_string(),
+        stats.sequence_length.to_string(),                 // 0_sequence_length
+        stats.sequence_length.to_string(),                 // 1_sequence_length
+        stats.sequence_length_adjusted.to_string(),        // 0_sequence_length_adjusted
+        stats.sequence_length_adjusted.to_string(),        // 1_sequence_length_adjusted
+        g0.unfiltered.segregating_sites.to_string(),       // 0_segregating_sites
+        g1.unfiltered.segregating_sites.to_string(),       // 1_segregating_sites
+        format_f64(g0.unfiltered.w_theta, precision),      // 0_w_theta
+        format_f64(g1.unfiltered.w_theta, precision),      // 1_w_theta
+        format_f64(g0.unfiltered.pi, precision),           // 0_pi
+        format_f64(g1.unfiltered.pi, precision),           // 1_pi
+        format_f64(g0.unfiltered.fu_li_d_star, precision), // 0_fu_li_d_star
+        format_f64(g1.unfiltered.fu_li_d_star, precision), // 1_fu_li_d_star
+        format_f64(g0.unfiltered.fu_li_f_star, precision), // 0_fu_li_f_star
+        format_f64(g1.unfiltered.fu_li_f_star, precision), // 1_fu_li_f_star
+        format_f64(g0.unfiltered.fay_wu_h, precision),     // 0_fay_wu_h
+        format_f64(g1.unfiltered.fay_wu_h, precision),     // 1_fay_wu_h
+        format_f64(g0.unfiltered.theta_h, precision),      // 0_theta_h
+        format_f64(g1.unfiltered.theta_h, precision),      // 1_theta_h
+        format_f64(g0.unfiltered.zeng_e, precision),       // 0_zeng_e
+        format_f64(g1.unfiltered.zeng_e, precision),       // 1_zeng_e
+        format_f64(g0.unfiltered.fu_fs, precision),        // 0_fu_fs
+        format_f64(g1.unfiltered.fu_fs, precision),        // 1_fu_fs
+        format_f64(g0.unfiltered.neutrality_index.unwrap_or(f64::NAN), precision), // 0_neutrality_index
+        format_f64(g1.unfiltered.neutrality_index.unwrap_or(f64::NAN), precision), // 1_neutrality_index
+        g0.filtered.segregating_sites.to_string(),         // 0_segregating_sites_filtered
+        g1.filtered.segregating_sites.to_string(),         // 1_segregating_sites_filtered
+        format_f64(g0.filtered.w_theta, precision),        // 0_w_theta_filtered
+        format_f64(g1.filtered.w_theta, precision),        // 1_w_theta_filtered
+        format_f64(g0.filtered.pi, precision),             // 0_pi_filtered
+        format_f64(g1.filtered.pi, precision),             // 1_pi_filtered
+        format_f64(g0.filtered.fu_li_d_star, precision),   // 0_fu_li_d_star_filtered
+        format_f64(g1.filtered.fu_li_d_star, precision),   // 1_fu_li_d_star_filtered
+        format_f64(g0.filtered.fu_li_f_star, precision),   // 0_fu_li_f_star_filtered
+        format_f64(g1.filtered.fu_li_f_star, precision),   // 1_fu_li_f_star_filtered
+        format_f64(g0.filtered.fay_wu_h, precision),       // 0_fay_wu_h_filtered
+        format_f64(g1.filtered.fay_wu_h, precision),       // 1_fay_wu_h_filtered
+        format_f64(g0.filtered.theta_h, precision),        // 0_theta_h_filtered
+        format_f64(g1.filtered.theta_h, precision),        // 1_theta_h_filtered
+        format_f64(g0.filtered.zeng_e, precision),         // 0_zeng_e_filtered
+        format_f64(g1.filtered.zeng_e, precision),         // 1_zeng_e_filtered
+        format_f64(g0.filtered.fu_fs, precision),          // 0_fu_fs_filtered
+        format_f64(g1.filtered.fu_fs, precision),          // 1_fu_fs_filtered
+        format_f64(g0.filtered.neutrality_index.unwrap_or(f64::NAN), precision), // 0_neutrality_index_filtered
+        format_f64(g1.filtered.neutrality_index.unwrap_or(f64::NAN), precision), // 1_neutrality_index_filtered
+        g0.unfiltered.num_haplotypes.to_string(),          // 0_num_hap_no_filter
+        g1.unfiltered.num_haplotypes.to_string(),          // 1_num_hap_no_filter
+        g0.filtered.num_haplotypes.to_string(),            // 0_num_hap_filter
+        g1.filtered.num_haplotypes.to_string(),            // 1_num_hap_filter
+        // -1.0 should never occur
+        format_f64(stats.inversion_freq_no_filter.unwrap_or(-1.0), precision), // inversion_freq_no_filter
+        format_f64(stats.inversion_freq_filter.unwrap_or(-1.0), precision),    // inversion_freq_filter
+        format_f64(stats.fst.unwrap_or(f64::NAN), precision),                  // fst
+        format_f64(stats.fst_filtered.unwrap_or(f64::NAN), precision),         // fst_filtered
+        format_f64(stats.dxy.unwrap_or(f64::NAN), precision),                  // dxy
+        format_f64(stats.dxy_filtered.unwrap_or(f64::NAN), precision),         // dxy_filtered
+        format_f64(g0.pi_lower, precision),                // 0_pi_lower
+        format_f64(g0.pi_upper, precision),                // 0_pi_upper
+        format_f64(g1.pi_lower, precision),                // 1_pi_lower
+        format_f64(g1.pi_upper, precision),                // 1_pi_upper
+        format_f64(g0.theta_lower, precision),             // 0_theta_lower
+        format_f64(g0.theta_upper, precision),             // 0_theta_upper
+        format_f64(g1.theta_lower, precision),             // 1_theta_lower
+        format_f64(g1.theta_upper, precision),             // 1_theta_upper
+        format_f64(stats.fst_p_value.unwrap_or(f64::NAN), precision),          // fst_p_value
+        stats.fixed_diffs.map_or("NA".to_string(), |count| count.to_string()), // fixed_diffs
+        stats.fixed_diffs_filtered.map_or("NA".to_string(), |count| count.to_string()), // fixed_diffs_filtered
+        format_f64(stats.gc_content.unwrap_or(f64::NAN), precision),           // gc_content
+        stats.sequence_length_accessible.map_or("NA".to_string(), |length| length.to_string()), // sequence_length_accessible
+        format_f64(g0.unfiltered.theta_l, precision),      // 0_theta_l
+        format_f64(g1.unfiltered.theta_l, precision),      // 1_theta_l
+        format_f64(g0.filtered.theta_l, precision),        // 0_theta_l_filtered
+        format_f64(g1.filtered.theta_l, precision),        // 1_theta_l_filtered
+        format_f64(g0.ne_theta_w.unwrap_or(f64::NAN), precision), // 0_ne_theta_w
+        format_f64(g1.ne_theta_w.unwrap_or(f64::NAN), precision), // 1_ne_theta_w
+        format_f64(g0.ne_pi.unwrap_or(f64::NAN), precision),      // 0_ne_pi
+        format_f64(g1.ne_pi.unwrap_or(f64::NAN), precision),      // 1_ne_pi
+        format_f64(stats.inbreeding_f.unwrap_or(f64::NAN), precision),          // inbreeding_f
+        format_f64(stats.inbreeding_f_filtered.unwrap_or(f64::NAN), precision), // inbreeding_f_filtered
+    ]
+    .into_iter()
+    .chain(stats.pairwise_fst.iter().flat_map(|pair| {
+        [format_f64(pair.fst, precision), format_f64(pair.fst_filtered, precision)] // fst_wc_<a>_<b>(_filtered)
+    }))
+    .collect()
+}
+
+// Print the regions marked by --flag_outliers
+fn print_outlier_summary(results: &[RegionStats], z_threshold: f64) {
+    let outliers: Vec<&RegionStats> = results.iter().filter(|stats| stats.outlier == Some(true)).collect();
+    if outliers.is_empty() {
+        println!("{}", format!("No region has a statistic with |Z| > {}.", z_threshold).green().bold());
+        return;
+    }
+    println!(
+        "\n{}",
+        format!("{} of {} regions have a statistic with |Z| > {}:", outliers.len(), results.len(), z_threshold)
+            .red()
+            .bold()
+    );
+    for stats in outliers {
+        println!("{}", format!("{}:{}-{}", stats.chr, stats.region_start, stats.region_end).yellow());
+    }
+}
+
+// Genomic order of regions: numbered chromosomes numerically, then the others by name
+type RegionSortKey = (bool, u64, String, i64, i64);
+
+pub fn region_sort_key(chr: &str, start: i64, end: i64) -> RegionSortKey {
+    let number = canonical_chr(chr).parse::<u64>().ok();
+    (number.is_none(), number.unwrap_or(0), chr.to_string(), start, end)
+}
+
+// Write a config row now with --output_unsorted, otherwise keep it to be written in sorted order
+fn push_config_row(
+    writer: &mut csv::Writer<OutputWriter>,
+    pending_rows: &mut Vec<(RegionSortKey, Vec<String>)>,
+    unsorted: bool,
+    checkpoint: Option<&Path>,
+    stats: &RegionStats,
+    record: Vec<String>,
+) -> Result<(), VcfError> {
+    if unsorted {
+        writer.write_record(record).map_err(|e| VcfError::Io(e.into()))?;
+        writer.flush().map_err(VcfError::Io)?;
+        if let Some(path) = checkpoint {
+            save_checkpoint(path, &checkpoint_key(&stats.chr, stats.region_start, stats.region_end))?;
+        }
+    } else {
+        pending_rows.push((region_sort_key(&stats.chr, stats.region_start, stats.region_end), record));
+    }
+    Ok(())
+}
+
+// The label cell of a config row, if the output has a label column
+fn label_column(stats: &RegionStats, with_label: bool) -> impl Iterator<Item = String> {
+    with_label.then(|| stats.label.clone().unwrap_or_default()).into_iter()
+}
+
+// CSV row of a config region whose statistics are withheld: coordinates and lengths, then NA
+pub fn config_na_record(stats: &RegionStats, num_columns: usize) -> Vec<String> {
+    // Only the coordinates and lengths are kept, so the precision does not matter
+    let mut record = config_stats_record(stats, 0);
+    record.truncate(7);
+    record.resize(num_columns, "NA".to_string());
+    record
+}
+
+// Every haplotype group label used across the config regions, in ascending order
+pub fn config_group_labels(entries: &[ConfigEntry]) -> Vec<u8> {
+    let labels: BTreeSet<u8> = entries
+        .iter()
+        .flat_map(|entry| entry.samples_unfiltered.values())
+        .flat_map(|&(left, right)| [left, right])
+        .collect();
+    labels.into_iter().collect()
+}
+
+// Weir & Cockerham FST of one region for every pair of group labels
+pub fn pairwise_weir_cockerham_fst(
+    variants: &[Variant],
+    sample_names: &[String],
+    labels: &[u8],
+    sample_filter: &HashMap<String, (u8, u8)>,
+    region_start: i64,
+    region_end: i64,
+) -> Vec<(u8, u8, f64)> {
+    let group_indices: Vec<Vec<(usize, usize)>> = labels
+        .iter()
+        .map(|&label| collect_haplotype_indices(sample_names, label, sample_filter))
+        .collect();
+
+    let mut pairs = Vec::new();
+    for a in 0..labels.len() {
+        for b in (a + 1)..labels.len() {
+            // Project both groups into one haplotype list: group a first, then group b
+            let combined: Vec<(usize, usize)> =
+                group_indices[a].iter().chain(&group_indices[b]).copied().collect();
+            let populations = vec![
+                (0..group_indices[a].len()).collect::<Vec<usize>>(),
+                (group_indices[a].len()..combined.len()).collect::<Vec<usize>>(),
+            ];
+            let projected: Vec<Variant> = variants
+                .iter()
+                .filter(|v| v.position >= region_start && v.position <= region_end)
+                .map(|v| project_haplotypes(v, &combined))
+                .collect();
+            pairs.push((labels[a], labels[b], calculate_weir_cockerham_fst(&populations, &projected)));
+        }
+    }
+    pairs
+}
+
+// Outside --fail_on_region_error a failed region is logged and kept for the _errors.csv summary instead of ending the run
+pub fn recover_region<T>(
+    result: Result<T, VcfError>,
+    region: &str,
+    fail_on_region_error: bool,
+    errors: &mut Vec<(String, VcfError)>,
+) -> Result<Option<T>, VcfError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if fail_on_region_error => Err(e),
+        Err(e) => {
+            error!("Skipping {}: {}", region, e);
+            errors.push((region.to_string(), e));
+            Ok(None)
+        }
+    }
+}
+
+// Key of a config region in a --checkpoint_file
+pub fn checkpoint_key(chr: &str, start: i64, end: i64) -> String {
+    format!("{}:{}:{}", chr, start, end)
+}
+
+// Keys of the regions finished by earlier runs, one per line
+pub fn load_checkpoint(path: &Path) -> Result<HashSet<String>, VcfError> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// Append one finished region; the line is written whole, so an interrupted run leaves complete keys
+pub fn save_checkpoint(path: &Path, key: &str) -> Result<(), VcfError> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", key).as_bytes())?;
+    Ok(())
+}
+
+// One row per failed region (or chromosome) with its error message
+pub fn write_region_errors(path: &Path, errors: &[(String, VcfError)]) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer.write_record(["region", "error"]).map_err(|e| VcfError::Io(e.into()))?;
+    for (region, error) in errors {
+        writer
+            .write_record([region.as_str(), error.to_string().as_str()])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn process_config_entries(
+    config_entries: &[ConfigEntry],
+    vcf_folder: &str,
+    output_file: &Path,
+    masks: &RegionMasks,
+    args: &Args,
+) -> Result<(), VcfError> {
+    // Initialize shared SeqInfo storage
+    let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
+    let mut sample_missing_totals = SampleTotals::default();
+    let mut region_errors: Vec<(String, VcfError)> = Vec::new();
+    let mut sample_het_totals = SampleTotals::default();
+    // Samples of the first chromosome's VCF, which every later VCF must match
+    let mut first_vcf_samples: Option<(String, Vec<String>)> = None;
+    
+    // Write headers
+    let header = [
+        "chr",
+        "region_start",
+        "region_end",
+        "0_sequence_length",
+        "1_sequence_length",
+        "0_sequence_length_adjusted",
+        "1_sequence_length_adjusted",
+        "0_segregating_sites",
+        "1_segregating_sites",
+        "0_w_theta",
+        "1_w_theta",
+        "0_pi",
+        "1_pi",
+        "0_fu_li_d_star",
+        "1_fu_li_d_star",
+        "0_fu_li_f_star",
+        "1_fu_li_f_star",
+        "0_fay_wu_h",
+        "1_fay_wu_h",
+        "0_theta_h",
+        "1_theta_h",
+        "0_zeng_e",
+        "1_zeng_e",
+        "0_fu_fs",
+        "1_fu_fs",
+        "0_neutrality_index",
+        "1_neutrality_index",
+        "0_segregating_sites_filtered",
+        "1_segregating_sites_filtered",
+        "0_w_theta_filtered",
+        "1_w_theta_filtered",
+        "0_pi_filtered",
+        "1_pi_filtered",
+        "0_fu_li_d_star_filtered",
+        "1_fu_li_d_star_filtered",
+        "0_fu_li_f_star_filtered",
+        "1_fu_li_f_star_filtered",
+        "0_fay_wu_h_filtered",
+        "1_fay_wu_h_filtered",
+        "0_theta_h_filtered",
+        "1_theta_h_filtered",
+        "0_zeng_e_filtered",
+        "1_zeng_e_filtered",
+        "0_fu_fs_filtered",
+        "1_fu_fs_filtered",
+        "0_neutrality_index_filtered",
+        "1_neutrality_index_filtered",
+        "0_num_hap_no_filter",
+        "1_num_hap_no_filter",
+        "0_num_hap_filter",
+        "1_num_hap_filter",
+        "inversion_freq_no_filter",
+        "inversion_freq_filter",
+        "fst",
+        "fst_filtered",
+        "dxy",
+        "dxy_filtered",
+        "0_pi_lower",
+        "0_pi_upper",
+        "1_pi_lower",
+        "1_pi_upper",
+        "0_theta_lower",
+        "0_theta_upper",
+        "1_theta_lower",
+        "1_theta_upper",
+        "fst_p_value",
+        "fixed_diffs",
+        "fixed_diffs_filtered",
+        "gc_content",
+        "sequence_length_accessible",
+        "0_theta_l",
+        "1_theta_l",
+        "0_theta_l_filtered",
+        "1_theta_l_filtered",
+        "0_ne_theta_w",
+        "1_ne_theta_w",
+        "0_ne_pi",
+        "1_ne_pi",
+        "inbreeding_f",
+        "inbreeding_f_filtered",
+    ];
+    // One pair of Weir & Cockerham FST columns per pair of group labels in the config
+    let labels = config_group_labels(config_entries);
+    let mut pair_columns = Vec::new();
+    for (i, a) in labels.iter().enumerate() {
+        for b in &labels[i + 1..] {
+            pair_columns.push(format!("fst_wc_{}_{}", a, b));
+            pair_columns.push(format!("fst_wc_{}_{}_filtered", a, b));
+        }
+    }
+    let num_columns = header.len() + pair_columns.len();
+    // Labelled configs get a leading label column; it is left empty for unlabelled regions
+    let with_label = config_entries.iter().any(|entry| entry.label.is_some());
+
+    // Regions finished by an earlier run with the same --checkpoint_file
+    let checkpoint = args.checkpoint_file.as_ref().map(Path::new);
+    let finished = match checkpoint {
+        Some(path) if args.resume && path.exists() => load_checkpoint(path)?,
+        _ => HashSet::new(),
+    };
+    if let Some(path) = checkpoint {
+        if args.output_format != OutputFormat::Csv {
+            return Err(VcfError::Parse("--checkpoint_file needs CSV output".to_string()));
+        }
+        if !finished.is_empty() && !output_file.exists() {
+            return Err(VcfError::Parse(format!(
+                "{} lists finished regions, but the output {} does not exist",
+                path.display(),
+                output_file.display()
+            )));
+        }
+        if !args.resume {
+            File::create(path)?;
+        }
+    }
+    let mut writer = if !finished.is_empty() {
+        info!("Resuming: {} regions are already in {}", finished.len(), output_file.display());
+        Some(open_csv_append(output_file)?)
+    } else {
+        open_csv_output(
+            args,
+            output_file,
+            with_label
+                .then_some("label")
+                .into_iter()
+                .chain(header)
+                .chain(pair_columns.iter().map(String::as_str))
+                .chain(args.flag_outliers.map(|_| "outlier")),
+        )?
+    };
+    let mut results = Vec::new();
+    // CSV rows held back until every region is done, unless --output_unsorted; outliers need every region first.
+    // A checkpoint only lists regions already in the output, so with one, rows are written as they finish.
+    let mut pending_rows: Vec<(RegionSortKey, Vec<String>)> = Vec::new();
+    let write_early = (args.output_unsorted || checkpoint.is_some()) && args.flag_outliers.is_none();
+    // (chr, region start, region end, gene, table) for --annotation_gff
+    let mut mk_rows: Vec<(String, i64, i64, String, MkTable)> = Vec::new();
+
+    let position_allele_map = Arc::new(Mutex::new(HashMap::<i64, (char, char)>::new()));
+    let mut rng = bootstrap_rng(args.seed);
+    let mut options = VcfOptions::from_args(args)?;
+
+    let remaining: Vec<&ConfigEntry> = config_entries
+        .iter()
+        .filter(|entry| !finished.contains(&checkpoint_key(&entry.seqname, entry.start, entry.end)))
+        .collect();
+    let progress_bar = ProgressBar::new(remaining.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} regions (ETA {eta}) {msg}")
+            .expect("Failed to create progress bar template")
+            .progress_chars("=>-"),
+    );
+
+    // Organize regions by chromosome, in a fixed order so that a --seed draws the same numbers every run
+    let mut regions_per_chr: BTreeMap<String, Vec<&ConfigEntry>> = BTreeMap::new();
+    for entry in remaining {
+        regions_per_chr
+            .entry(entry.seqname.clone())
+            .or_insert_with(Vec::new)
+            .push(entry);
+    }
+
+    for (chr, entries) in regions_per_chr {
+        info!("Processing chromosome: {}", chr);
+
+        // Read reference sequence and CDS regions once per chromosome
+        let Some(ref_sequence) = recover_region(
+            read_reference_sequence(
+                &Path::new(&args.reference_path),
+                &chr,
+                entries.iter().map(|e| e.start).min().unwrap_or(0),
+                entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX)
+            ),
+            &chr,
+            args.fail_on_region_error,
+            &mut region_errors,
+        )? else {
+            progress_bar.inc(entries.len() as u64);
+            continue;
+        };
+        
+        let Some(cds_regions) = recover_region(
+            parse_gff_file(
+                &Path::new(&args.gff_path),
+                &chr,
+                entries.iter().map(|e| e.start).min().unwrap_or(0),
+                entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX)
+            ),
+            &chr,
+            args.fail_on_region_error,
+            &mut region_errors,
+        )? else {
+            progress_bar.inc(entries.len() as u64);
+            continue;
+        };
+        
+        // Determine the range to process
+        let min_start = entries.iter().map(|e| e.start).min().unwrap_or(0);
+        let max_end = entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX);
+    
+        // Locate the appropriate VCF file
+        let Some(vcf_file) = recover_region(find_vcf_file(vcf_folder, &chr), &chr, args.fail_on_region_error, &mut region_errors)? else {
+            progress_bar.inc(entries.len() as u64);
+            continue;
+        };
+    
+        info!(
+            "Processing VCF file for chromosome {} from {} to {}",
+            chr, min_start, max_end
+        );
+    
+        options.report_samples = first_vcf_samples.is_none();
+        let variants_data = recover_region(process_vcf(
+            &vcf_file,
+            &chr,
+            &[(min_start, max_end)],
+            masks,
+            &options,
+            Arc::clone(&seqinfo_storage),
+            Arc::clone(&position_allele_map),
+        ), &chr, args.fail_on_region_error, &mut region_errors)?;
+        let Some(variants_data) = variants_data else {
+            progress_bar.inc(entries.len() as u64);
+            continue;
+        };
+    
+        let (
+            mut unfiltered_variants,
+            mut _filtered_variants,
+            sample_names,
+            _chr_length,
+            _missing_data_info,
+            _filtering_stats,
+            _,
+        ) = variants_data;
+        let subsampled =
+            subsample_variants(&mut unfiltered_variants, &mut _filtered_variants, args.max_variants, &mut rng);
+
+        match &first_vcf_samples {
+            Some((first_chr, first_samples)) => {
+                let consistent = check_sample_consistency(first_samples, &sample_names);
+                if consistent.is_err() {
+                    warn!("The VCF of chromosome {} has other samples than that of chromosome {}", chr, first_chr);
+                }
+                if recover_region(consistent, &chr, args.fail_on_region_error, &mut region_errors)?.is_none() {
+                    progress_bar.inc(entries.len() as u64);
+                    continue;
+                }
+            }
+            None => first_vcf_samples = Some((chr.clone(), sample_names.clone())),
+        }
+
+        println!("\n{}", "Filtering Statistics:".green().bold());
+        println!("Total variants processed: {}", _filtering_stats.total_variants);
+        println!(
+            "Filtered variants: {} ({:.2}%)",
+            _filtering_stats._filtered_variants,
+            (_filtering_stats._filtered_variants as f64 / _filtering_stats.total_variants as f64)
+                * 100.0
+        );
+        println!("Filtered due to allow: {}", _filtering_stats.filtered_due_to_allow);
+        println!("Filtered due to mask: {}", _filtering_stats.filtered_due_to_mask);
+        println!("Filtered due to repeat mask: {}", _filtering_stats.repeat_masked_count);
+        println!("Multi-allelic variants: {}", _filtering_stats.multi_allelic_variants);
+        println!("Low GQ variants: {}", _filtering_stats.low_gq_variants);
+        println!("Low MAF variants: {}", _filtering_stats.low_maf_variants);
+        println!("Variants failing --hwe_filter: {}", _filtering_stats.hwe_filtered_count);
+        println!("Missing data variants: {}", _filtering_stats.missing_data_variants);
+        println!("Unphased variants: {}", _filtering_stats.unphased_variants);
+        println!("Variants excluded by INFO filters: {}", _filtering_stats.info_filtered_count);
+        println!("Variants below --qual_threshold: {}", _filtering_stats.qual_filtered_count);
+        println!("Symbolic variants skipped: {}", _filtering_stats.structural_variant_skipped);
+        println!("Indels skipped: {}", _filtering_stats.indels_skipped);
+        println!("CpG variants excluded: {}", _filtering_stats.cpg_excluded);
+        println!("Variants excluded by --variant_type: {}", _filtering_stats.variant_type_skipped);
+        println!("Variants by type: {}", format_variant_type_counts(&_filtering_stats.variant_type_counts));
+        println!("Variants excluded by FILTER: {}", _missing_data_info.filtered_by_qual);
+        println!("Genotypes below --min_gq: {}", _missing_data_info.low_gq_count);
+        println!("Genotypes below --min_dp: {}", _missing_data_info.low_dp_count);
+        println!("Genotypes above --max_dp: {}", _missing_data_info.high_dp_count);
+    
+        println!("\n{}", "Example Filtered Variants:".green().bold());
+        for (i, example) in _filtering_stats.filtered_examples.iter().enumerate().take(5) {
+            println!("Example {}: {}", i + 1, example);
+        }
+        if _filtering_stats.filtered_examples.len() > 5 {
+            println!(
+                "... and {} more.",
+                _filtering_stats.filtered_examples.len() - 5
+            );
+        }
+
+        // Collect all config samples for this chromosome
+        let all_config_samples: HashSet<String> = entries
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .samples_unfiltered
+                    .keys()
+                    .cloned()
+                    .chain(entry.samples_filtered.keys().cloned())
+            })
+            .collect();
+
+        // Collect VCF sample names
+        let vcf_sample_set: HashSet<String> = sample_names
+            .iter()
+            .map(|s| extract_sample_id(s).to_string())
+            .collect();
+
+        // Find missing samples
+        let missing_samples: Vec<String> = all_config_samples
+            .difference(&vcf_sample_set)
+            .cloned()
+            .collect();
+
+        // Print warning if there are missing samples
+        if !missing_samples.is_empty() {
+            warn!(
+                "The following samples from config file are missing in VCF for chromosome {}: {:?}",
+                chr, missing_samples
+            );
+        }
+
+        // Heterozygosity over the sites inside this chromosome's config regions
+        if args.sample_stats_output.is_some() {
+            let region_variants: Vec<Variant> = unfiltered_variants
+                .iter()
+                .filter(|v| entries.iter().any(|e| v.position >= e.start && v.position <= e.end))
+                .cloned()
+                .collect();
+            let counts = count_per_sample_heterozygous(&region_variants, sample_names.len());
+            add_sample_heterozygosity(&mut sample_het_totals, &sample_names, &counts);
+        }
+
+        for entry in entries {
+            debug!(
+                "Processing entry: {}:{}-{}",
+                entry.seqname, entry.start, entry.end
+            );
+            // Counted as soon as it starts, since the skip paths below leave the loop body early
+            let region = format!("{}:{}-{}", entry.seqname, entry.start, entry.end);
+            progress_bar.set_message(region.clone());
+            progress_bar.inc(1);
+
+            // Define regions
+            let sequence_length = entry.end - entry.start + 1;
+
+            // Calculate adjusted sequence length considering allow and mask regions
+            let adjusted_sequence_length = calculate_adjusted_sequence_length(
+                entry.start,
+                entry.end,
+                masks.allow.as_ref().and_then(|a| a.get(&canonical_chr(&chr))),
+                masks.mask.as_ref().and_then(|m| m.get(&canonical_chr(&chr))),
+            );
+            // With --accessible_mask, theta, pi and divergence of the unfiltered variants use the callable length
+            let accessible_sequence_length = masks.accessible.as_ref().map(|accessible| {
+                accessible.get(&canonical_chr(&chr))
+                    .map_or(0, |intervals| accessible_length(intervals, entry.start, entry.end))
+            });
+            let diversity_length = accessible_sequence_length.unwrap_or(sequence_length);
+
+            // Process haplotype_group=0 (unfiltered)
+            debug!("Processing region {}-{} with {} variants", 
+                    entry.start, entry.end, unfiltered_variants.len());
+            
+            let variants_in_region: Vec<_> = unfiltered_variants.iter()
+                .filter(|v| v.position >= entry.start && v.position <= entry.end)
+                .cloned()
+                .collect();
+            debug!("Found {} variants in region", variants_in_region.len());
+
+            let region_missing = missing_data_in_variants(&variants_in_region);
+            add_sample_missing(&mut sample_missing_totals, &sample_names, &region_missing);
+            let missing = missing_fraction(&region_missing);
+            let too_short = sequence_length < args.min_seq_length;
+            if too_short || missing > args.max_missing {
+                if too_short {
+                    warn!(
+                        "Region {} is {} bp long (--min_seq_length {}); writing NA statistics",
+                        region, sequence_length, args.min_seq_length
+                    );
+                } else {
+                    warn!(
+                        "Region {}:{}-{} has {:.2}% missing genotypes (--max_missing {}); writing NA statistics",
+                        entry.seqname, entry.start, entry.end, missing * 100.0, args.max_missing
+                    );
+                }
+                let stats = RegionStats {
+                    label: entry.label.clone(),
+                    chr: entry.seqname.clone(),
+                    region_start: entry.start,
+                    region_end: entry.end,
+                    sequence_length,
+                    sequence_length_adjusted: adjusted_sequence_length,
+                    sequence_length_accessible: accessible_sequence_length,
+                    gc_content: None,
+                    all: None,
+                    group_0: None,
+                    group_1: None,
+                    inversion_freq_no_filter: None,
+                    inversion_freq_filter: None,
+                    fst: None,
+                    fst_filtered: None,
+                    dxy: None,
+                    dxy_filtered: None,
+                    inbreeding_f: None,
+                    inbreeding_f_filtered: None,
+                    fst_p_value: None,
+                    fixed_diffs: None,
+                    fixed_diffs_filtered: None,
+                    pairwise_fst: Vec::new(),
+                    subsampled,
+                    outlier: None,
+                };
+                if let Some(writer) = writer.as_mut() {
+                    let record = label_column(&stats, with_label).chain(config_na_record(&stats, num_columns));
+                    push_config_row(writer, &mut pending_rows, write_early, checkpoint, &stats, record.collect())?;
+                }
+                if writer.is_none() || args.flag_outliers.is_some() {
+                    results.push(stats);
+                }
+                continue;
+            }
+
+            // Add these lines before calling process_variants
+            let Some(ref_sequence) = recover_region(
+                read_reference_sequence(&Path::new(&args.reference_path), &chr, entry.start, entry.end),
+                &region,
+                args.fail_on_region_error,
+                &mut region_errors,
+            )? else {
+                continue;
+            };
+            
+            let Some(cds_regions) = recover_region(
+                parse_gff_file(&Path::new(&args.gff_path), &chr, entry.start, entry.end),
+                &region,
+                args.fail_on_region_error,
+                &mut region_errors,
+            )? else {
+                continue;
+            };
+            
+            let config_region = ConfigRegion {
+                chromosome: &entry.seqname,
+                start: entry.start,
+                end: entry.end,
+                sample_names: &sample_names,
+                reference_sequence: &ref_sequence,
+                cds_regions: &cds_regions,
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: args.ancestral_source,
+                min_samples: args.min_samples,
+                phylip_dir: Path::new("."),
+            };
+
+            let stats_0 =
+                match recover_region(process_variants(
+                    &variants_in_region,
+                    0,
+                    &entry.samples_unfiltered,
+                    accessible_sequence_length,
+                    false,  // unfiltered variants
+                    &config_region,
+                ), &region, args.fail_on_region_error, &mut region_errors)?.flatten() {
+                    Some(values) => values,
+                    None => continue, // Skip writing this record
+                };
+
+            // Process haplotype_group=1 (unfiltered)
+            let stats_1 =
+                match recover_region(process_variants(
+                    &unfiltered_variants,
+                    1,
+                    &entry.samples_unfiltered,
+                    accessible_sequence_length,
+                    false,  // unfiltered variants
+                    &config_region,
+                ), &region, args.fail_on_region_error, &mut region_errors)?.flatten() {
+                    Some(values) => values,
+                    None => continue, // Skip writing this record
+                };
+
+            // Calculate allele frequency of inversions (no filter)
+            let inversion_freq_no_filter =
+                calculate_inversion_allele_frequency(&entry.samples_unfiltered);
+
+            // Process haplotype_group=0 (filtered)
+            let stats_0_filt =
+                match recover_region(process_variants(
+                    &_filtered_variants,
+                    0,
+                    &entry.samples_filtered,
+                    Some(adjusted_sequence_length),
+                    true,  // filtered variants
+                    &config_region,
+                ), &region, args.fail_on_region_error, &mut region_errors)?.flatten() {
+                    Some(values) => values,
+                    None => continue, // Skip writing this record
+                };
+
+            // Process haplotype_group=1 (filtered)
+            let stats_1_filt =
+                match recover_region(process_variants(
+                    &_filtered_variants,
+                    1,
+                    &entry.samples_filtered,
+                    Some(adjusted_sequence_length),
+                    true,  // filtered variants
+                    &config_region,
+                ), &region, args.fail_on_region_error, &mut region_errors)?.flatten() {
+                    Some(values) => values,
+                    None => continue, // Skip writing this record
+                };
+
+            // Calculate allele frequency of inversions
+            let inversion_freq_filt =
+                calculate_inversion_allele_frequency(&entry.samples_filtered);
+
+            let (group_0, n0) = extract_group_variants(
+                &variants_in_region, &sample_names, 0, &entry.samples_unfiltered, entry.start, entry.end,
+            );
+            let (group_1, n1) = extract_group_variants(
+                &variants_in_region, &sample_names, 1, &entry.samples_unfiltered, entry.start, entry.end,
+            );
+
+            if args.sfs {
+                for (group, variants, n) in [(0, &group_0, n0), (1, &group_1, n1)] {
+                    let suffix = format!("_{}_{}_{}_group{}", entry.seqname, entry.start, entry.end, group);
+                    let written = write_sfs(
+                        &sfs_output_path(output_file, &suffix),
+                        variants,
+                        n,
+                        args.sfs_folded,
+                        args.sfs_normalized,
+                        args.sfs_exclude_missing,
+                        args.output_precision,
+                    );
+                    recover_region(written, &region, args.fail_on_region_error, &mut region_errors)?;
+                }
+            }
+
+            // FST between the two haplotype groups, once both groups' variants are extracted
+            let fst = calculate_fst_hudson(&group_0, n0, &group_1, n1, diversity_length);
+            let dxy = calculate_dxy(&group_0, n0, &group_1, n1, diversity_length);
+            let fixed_diffs = count_fixed_differences(&group_0, n0, &group_1, n1, args.fixed_allow_missing);
+            if let Some(annotation_gff) = args.annotation_gff.as_ref() {
+                let annotation = recover_region(
+                    parse_annotation_gff(Path::new(annotation_gff), Path::new(&args.reference_path), &chr, entry.start, entry.end),
+                    &region,
+                    args.fail_on_region_error,
+                    &mut region_errors,
+                )?;
+                if let Some(annotation) = annotation {
+                    for (gene, table) in tally_mk_tables(&group_0, n0, &group_1, n1, &annotation, args.fixed_allow_missing) {
+                        mk_rows.push((entry.seqname.clone(), entry.start, entry.end, gene, table));
+                    }
+                }
+            }
+            let fst_p_value = fst_permutation_p_value(
+                &group_0, n0, &group_1, n1, diversity_length, args.fst_permutations, &mut rng,
+            );
+
+            // Bootstrap intervals (NaN unless --bootstrap is set)
+            let (pi_lower_0, pi_upper_0) = bootstrap_pi(&group_0, n0, diversity_length, args.bootstrap, &mut rng);
+            let (pi_lower_1, pi_upper_1) = bootstrap_pi(&group_1, n1, diversity_length, args.bootstrap, &mut rng);
+            let (theta_lower_0, theta_upper_0) = bootstrap_theta(&group_0, n0, diversity_length, args.bootstrap, &mut rng);
+            let (theta_lower_1, theta_upper_1) = bootstrap_theta(&group_1, n1, diversity_length, args.bootstrap, &mut rng);
+            let (fst_filt, dxy_filt, fixed_diffs_filt) = {
+                let (group_0, n0) = extract_group_variants(
+                    &_filtered_variants, &sample_names, 0, &entry.samples_filtered, entry.start, entry.end,
+                );
+                let (group_1, n1) = extract_group_variants(
+                    &_filtered_variants, &sample_names, 1, &entry.samples_filtered, entry.start, entry.end,
+                );
+                (
+                    calculate_fst_hudson(&group_0, n0, &group_1, n1, adjusted_sequence_length),
+                    calculate_dxy(&group_0, n0, &group_1, n1, adjusted_sequence_length),
+                    count_fixed_differences(&group_0, n0, &group_1, n1, args.fixed_allow_missing),
+                )
+            };
+            info!(
+                "FST: {:.6} (filtered {:.6}), Dxy: {:.6} (filtered {:.6})",
+                fst, fst_filt, dxy, dxy_filt
+            );
+
+            let pairwise_fst = pairwise_weir_cockerham_fst(
+                &variants_in_region, &sample_names, &labels, &entry.samples_unfiltered, entry.start, entry.end,
+            )
+            .into_iter()
+            .zip(pairwise_weir_cockerham_fst(
+                &_filtered_variants, &sample_names, &labels, &entry.samples_filtered, entry.start, entry.end,
+            ))
+            .map(|((group_a, group_b, fst), (_, _, fst_filtered))| PairwiseFst {
+                group_a,
+                group_b,
+                fst,
+                fst_filtered,
+            })
+            .collect();
+
+            let stats = RegionStats {
+                label: entry.label.clone(),
+                chr: entry.seqname.clone(),
+                region_start: entry.start,
+                region_end: entry.end,
+                sequence_length,
+                sequence_length_adjusted: adjusted_sequence_length,
+                sequence_length_accessible: accessible_sequence_length,
+                gc_content: Some(gc_fraction(&ref_sequence)),
+                all: None,
+                group_0: Some(HaplotypeSetStats {
+                    ne_theta_w: args.mutation_rate.map(|mu| effective_population_size(stats_0.w_theta, mu)),
+                    ne_pi: args.mutation_rate.map(|mu| effective_population_size(stats_0.pi, mu)),
+                    unfiltered: stats_0,
+                    filtered: stats_0_filt,
+                    pi_lower: pi_lower_0,
+                    pi_upper: pi_upper_0,
+                    theta_lower: theta_lower_0,
+                    theta_upper: theta_upper_0,
+                }),
+                group_1: Some(HaplotypeSetStats {
+                    ne_theta_w: args.mutation_rate.map(|mu| effective_population_size(stats_1.w_theta, mu)),
+                    ne_pi: args.mutation_rate.map(|mu| effective_population_size(stats_1.pi, mu)),
+                    unfiltered: stats_1,
+                    filtered: stats_1_filt,
+                    pi_lower: pi_lower_1,
+                    pi_upper: pi_upper_1,
+                    theta_lower: theta_lower_1,
+                    theta_upper: theta_upper_1,
+                }),
+                inversion_freq_no_filter,
+                inversion_freq_filter: inversion_freq_filt,
+                fst: Some(fst),
+                fst_filtered: Some(fst_filt),
+                dxy: Some(dxy),
+                dxy_filtered: Some(dxy_filt),
+                // Over every sample of the VCF, from the genotypes before they are split into groups
+                inbreeding_f: Some(calculate_genotype_inbreeding_f(&variants_in_region)),
+                inbreeding_f_filtered: Some(calculate_genotype_inbreeding_f(
+                    &_filtered_variants
+                        .iter()
+                        .filter(|v| v.position >= entry.start && v.position <= entry.end)
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                )),
+                fst_p_value: Some(fst_p_value),
+                fixed_diffs: Some(fixed_diffs),
+                fixed_diffs_filtered: Some(fixed_diffs_filt),
+                pairwise_fst,
+                subsampled,
+                outlier: None,
+            };
+
+            // Write the aggregated results to CSV, or keep them for the JSON array and --flag_outliers
+            if let Some(writer) = writer.as_mut() {
+                let record =
+                    label_column(&stats, with_label).chain(config_stats_record(&stats, args.output_precision));
+                push_config_row(writer, &mut pending_rows, write_early, checkpoint, &stats, record.collect())?;
+            }
+            if writer.is_none() || args.flag_outliers.is_some() {
+                results.push(stats);
+            }
+
+            debug!(
+                "Successfully wrote record for {}:{}-{}",
+                entry.seqname, entry.start, entry.end
+            );
+        }
+    }
+    progress_bar.finish_with_message("All config entries processed");
+
+    if let Some(z_threshold) = args.flag_outliers {
+        let flags = flag_outliers(&results, z_threshold);
+        for (stats, &flag) in results.iter_mut().zip(&flags) {
+            stats.outlier = Some(flag);
+        }
+        // With a CSV, rows and results were pushed together and are still in the same order
+        for ((_, record), &flag) in pending_rows.iter_mut().zip(&flags) {
+            record.push(flag.to_string());
+        }
+        print_outlier_summary(&results, z_threshold);
+    }
+
+    match writer {
+        Some(mut writer) => {
+            if !args.output_unsorted {
+                pending_rows.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            for (_, record) in pending_rows {
+                writer.write_record(record).map_err(|e| VcfError::Io(e.into()))?;
+            }
+            finish_csv_output(writer)?
+        }
+        None => {
+            if !args.output_unsorted {
+                results.sort_by_key(|stats| region_sort_key(&stats.chr, stats.region_start, stats.region_end));
+            }
+            write_json_output(&results, output_file)?
+        }
+    }
+    if let Some(path) = &args.per_sample_missing {
+        write_per_sample_missing(Path::new(path), &sample_missing_totals.rows, args.output_precision)?;
+    }
+    if let Some(path) = &args.sample_stats_output {
+        write_sample_stats(Path::new(path), &sample_het_totals.rows, args.output_precision)?;
+    }
+    if args.annotation_gff.is_some() {
+        if !args.output_unsorted {
+            mk_rows.sort_by_key(|(chr, start, end, _, _)| region_sort_key(chr, *start, *end));
+        }
+        write_mk_tables(&output_path_with_suffix(output_file, "_mk_table"), &mk_rows, args.output_precision)?;
+    }
+    if !region_errors.is_empty() {
+        let errors_path = output_path_with_suffix(output_file, "_errors");
+        warn!("{} regions failed; see {}", region_errors.len(), errors_path.display());
+        write_region_errors(&errors_path, &region_errors)?;
+    }
+    info!("Processing complete. Check the output file: {:?}", output_file);
+    Ok(())
+}
+
+// Regions from a BED-style file, keyed by chromosome
+pub type RegionMap = Arc<HashMap<String, Vec<(i64, i64)>>>;
+
+// The region files of a run, parsed once and shared by every region and chromosome
+#[derive(Debug, Clone, Default)]
+pub struct RegionMasks {
+    // --mask_file, with the --repeat_mask intervals merged in
+    pub mask: Option<RegionMap>,
+    // --repeat_mask alone, to count the variants it removes
+    pub repeat: Option<RegionMap>,
+    pub allow: Option<RegionMap>,
+    pub accessible: Option<RegionMap>,
+}
+
+// All haplotypes of one chromosome span, sorted by position so regions can be found by binary search
+pub struct HaplotypeSet {
+    pub sample_names: Vec<String>,
+    pub unfiltered: Vec<Variant>,
+    pub filtered: Vec<Variant>,
+    pub num_haplotypes: usize,
+    pub chr_length: i64,
+    pub subsampled: bool,
+    // The chromosome's --accessible_mask intervals, when given
+    pub accessible: Option<Vec<(i64, i64)>>,
+}
+
+// Columns shared by the sliding-window and BED outputs, after chr and the region bounds
+const REGION_STATS_COLUMNS: [&str; 23] = [
+    "sequence_length",
+    "sequence_length_adjusted",
+    "sequence_length_accessible",
+    "gc_content",
+    "num_haplotypes",
+    "segregating_sites",
+    "w_theta",
+    "pi",
+    "tajima_d",
+    "fu_fs",
+    "neutrality_index",
+    "inbreeding_f",
+    "segregating_sites_filtered",
+    "w_theta_filtered",
+    "pi_filtered",
+    "tajima_d_filtered",
+    "fu_fs_filtered",
+    "neutrality_index_filtered",
+    "inbreeding_f_filtered",
+    "pi_lower",
+    "pi_upper",
+    "theta_lower",
+    "theta_upper",
+];
+
+// Length from start to the end of the chromosome, taken from the ##contig header or reference index.
+// Without a known length it ends at the last variant; the flag tells whether the length was known.
+pub fn whole_chromosome_length(start: i64, chr_length: i64, last_position: i64) -> (i64, bool) {
+    if chr_length > 0 {
+        (chr_length - (start - 1), true)
+    } else {
+        (last_position - (start - 1), false)
+    }
+}
+
+// The VCF to read for chr: standard input with --stdin, otherwise the chromosome's file in --vcf_folder
+fn resolve_vcf_file(args: &Args, chr: &str) -> Result<PathBuf, VcfError> {
+    if args.stdin {
+        return Ok(PathBuf::from(STDIN_PATH));
+    }
+    find_vcf_file(&args.vcf_folder, chr)
+}
+
+// Read chr:start-end and project every sample onto its two haplotypes
+pub fn load_haplotypes(
+    args: &Args,
+    chr: &str,
+    start: i64,
+    end: i64,
+    masks: &RegionMasks,
+    report_samples: bool,
+) -> Result<HaplotypeSet, VcfError> {
+    let vcf_file = resolve_vcf_file(args, chr)?;
+    info!("Processing VCF file: {}", vcf_file.display());
+
+    let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
+    let position_allele_map = Arc::new(Mutex::new(HashMap::<i64, (char, char)>::new()));
+    let (
+        mut unfiltered_variants,
+        mut filtered_variants,
+        sample_names,
+        chr_length,
+        missing_data_info,
+        _filtering_stats,
+        _,
+    ) = process_vcf(
+        &vcf_file,
+        chr,
+        &[(start, end)],
+        masks,
+        &VcfOptions { report_samples, ..VcfOptions::from_args(args)? },
+        seqinfo_storage,
+        position_allele_map,
+    )?;
+
+    if sample_names.is_empty() {
+        return Err(VcfError::Parse(
+            "No samples found after processing VCF.".to_string(),
+        ));
+    }
+
+    if let Some(path) = &args.per_sample_missing {
+        let mut totals = SampleTotals::default();
+        add_sample_missing(&mut totals, &sample_names, &missing_data_info);
+        write_per_sample_missing(Path::new(path), &totals.rows, args.output_precision)?;
+    }
+
+    // Every haplotype of every sample
+    let haplotype_indices: Vec<(usize, usize)> = (0..sample_names.len())
+        .flat_map(|i| [(i, 0), (i, 1)])
+        .collect();
+
+    let subsampled = subsample_variants(
+        &mut unfiltered_variants,
+        &mut filtered_variants,
+        args.max_variants,
+        &mut bootstrap_rng(args.seed),
+    );
+    unfiltered_variants.sort_by_key(|v| v.position);
+    filtered_variants.sort_by_key(|v| v.position);
+    let accessible = masks.accessible.as_ref().map(|regions| regions.get(&canonical_chr(chr)).cloned().unwrap_or_default());
+    Ok(HaplotypeSet {
+        sample_names,
+        unfiltered: unfiltered_variants
+            .iter()
+            .map(|v| project_haplotypes(v, &haplotype_indices))
+            .collect(),
+        filtered: filtered_variants
+            .iter()
+            .map(|v| project_haplotypes(v, &haplotype_indices))
+            .collect(),
+        num_haplotypes: haplotype_indices.len(),
+        chr_length,
+        subsampled,
+        accessible,
+    })
+}
+
+// Randomly keep at most max_variants unfiltered variants (0 keeps all), in their original order,
+// and the filtered variants at the kept positions. Returns whether any variant was dropped.
+pub fn subsample_variants(
+    unfiltered: &mut Vec<Variant>,
+    filtered: &mut Vec<Variant>,
+    max_variants: usize,
+    rng: &mut StdRng,
+) -> bool {
+    if max_variants == 0 || unfiltered.len() <= max_variants {
+        return false;
+    }
+    let total = unfiltered.len();
+    let indices: Vec<usize> = (0..total).collect();
+    let mut keep: Vec<usize> = indices.choose_multiple(rng, max_variants).copied().collect();
+    keep.sort_unstable();
+    let mut variants = std::mem::take(unfiltered).into_iter().map(Some).collect::<Vec<_>>();
+    *unfiltered = keep.iter().filter_map(|&i| variants[i].take()).collect();
+    let positions: HashSet<i64> = unfiltered.iter().map(|v| v.position).collect();
+    filtered.retain(|v| positions.contains(&v.position));
+    warn!(
+        "Subsampled {} of {} variants (--max_variants); statistics are approximate",
+        max_variants, total
+    );
+    true
+}
+
+// Variants of a position-sorted slice that fall in [start, end]
+fn variants_in_range(variants: &[Variant], start: i64, end: i64) -> &[Variant] {
+    let first = variants.partition_point(|v| v.position < start);
+    let last = variants.partition_point(|v| v.position <= end);
+    &variants[first..last]
+}
+
+// The --reference sequence of chr:start-end for GC content, or None (NA for every region) if the reference cannot supply it
+fn read_gc_reference(args: &Args, chr: &str, start: i64, end: i64) -> Option<ReferenceSpan> {
+    match ReferenceSpan::read(Path::new(&args.reference_path), chr, start, end) {
+        Ok(span) => Some(span),
+        Err(e) => {
+            debug!("No GC content for {}:{}-{}: {}", chr, start, end, e);
+            None
+        }
+    }
+}
+
+// Statistics of chr:start-end over all haplotypes
+pub fn calculate_region_stats(
+    haplotypes: &HaplotypeSet,
+    chr: &str,
+    start: i64,
+    end: i64,
+    adjusted_sequence_length: i64,
+    bootstrap: usize,
+    rng: &mut StdRng,
+) -> RegionStats {
+    let n = haplotypes.num_haplotypes;
+    let sequence_length = end - start + 1;
+    let accessible_sequence_length = haplotypes
+        .accessible
+        .as_ref()
+        .map(|intervals| accessible_length(intervals, start, end));
+    let diversity_length = accessible_sequence_length.unwrap_or(sequence_length);
+    let region_haplotypes = variants_in_range(&haplotypes.unfiltered, start, end);
+    let (pi_lower, pi_upper) = bootstrap_pi(region_haplotypes, n, diversity_length, bootstrap, rng);
+    let (theta_lower, theta_upper) = bootstrap_theta(region_haplotypes, n, diversity_length, bootstrap, rng);
+
+    RegionStats {
+        label: None,
+        chr: chr.to_string(),
+        region_start: start,
+        region_end: end,
+        sequence_length,
+        sequence_length_adjusted: adjusted_sequence_length,
+        sequence_length_accessible: accessible_sequence_length,
+        gc_content: None,
+        all: Some(HaplotypeSetStats {
+            unfiltered: calculate_window_stats(region_haplotypes, n, diversity_length),
+            filtered: calculate_window_stats(
+                variants_in_range(&haplotypes.filtered, start, end),
+                n,
+                adjusted_sequence_length,
+            ),
+            pi_lower,
+            pi_upper,
+            theta_lower,
+            theta_upper,
+            ne_theta_w: None,
+            ne_pi: None,
+        }),
+        group_0: None,
+        group_1: None,
+        inversion_freq_no_filter: None,
+        inversion_freq_filter: None,
+        fst: None,
+        fst_filtered: None,
+        dxy: None,
+        dxy_filtered: None,
+        inbreeding_f: Some(calculate_inbreeding_f(region_haplotypes, n)),
+        inbreeding_f_filtered: Some(calculate_inbreeding_f(
+            variants_in_range(&haplotypes.filtered, start, end),
+            n,
+        )),
+        fst_p_value: None,
+        fixed_diffs: None,
+        fixed_diffs_filtered: None,
+        pairwise_fst: Vec::new(),
+        subsampled: haplotypes.subsampled,
+        outlier: None,
+    }
+}
+
+// CSV row of an all-haplotype region: chr, start, end, then REGION_STATS_COLUMNS
+pub fn region_stats_record(stats: &RegionStats, precision: usize) -> Vec<String> {
+    let all = stats
+        .all
+        .as_ref()
+        .expect("region_stats_record needs all-haplotype statistics");
+    vec![
+        stats.chr.clone(),
+        stats.region_start.to_string(),
+        stats.region_end.to_string(),
+        stats.sequence_length.to_string(),
+        stats.sequence_length_adjusted.to_string(),
+        stats.sequence_length_accessible.map_or("NA".to_string(), |length| length.to_string()),
+        format_f64(stats.gc_content.unwrap_or(f64::NAN), precision),
+        all.unfiltered.num_haplotypes.to_string(),
+        all.unfiltered.segregating_sites.to_string(),
+        format_f64(all.unfiltered.w_theta, precision),
+        format_f64(all.unfiltered.pi, precision),
+        format_f64(all.unfiltered.tajima_d, precision),
+        format_f64(all.unfiltered.fu_fs, precision),
+        format_f64(all.unfiltered.neutrality_index.unwrap_or(f64::NAN), precision),
+        format_f64(stats.inbreeding_f.unwrap_or(f64::NAN), precision),
+        all.filtered.segregating_sites.to_string(),
+        format_f64(all.filtered.w_theta, precision),
+        format_f64(all.filtered.pi, precision),
+        format_f64(all.filtered.tajima_d, precision),
+        format_f64(all.filtered.fu_fs, precision),
+        format_f64(all.filtered.neutrality_index.unwrap_or(f64::NAN), precision),
+        format_f64(stats.inbreeding_f_filtered.unwrap_or(f64::NAN), precision),
+        format_f64(all.pi_lower, precision),
+        format_f64(all.pi_upper, precision),
+        format_f64(all.theta_lower, precision),
+        format_f64(all.theta_upper, precision),
+    ]
+}
+
+// Pretty-printed JSON array with one object per region
+pub fn write_json_output<T: Serialize>(results: &[T], path: &Path) -> Result<(), VcfError> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, results).map_err(|e| VcfError::Io(e.into()))?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Everything needed to tell how an output file was produced
+#[derive(Debug, Serialize)]
+pub struct RunParams<'a> {
+    pub ferromic_version: &'static str,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub hostname: Option<String>,
+    pub args: &'a Args,
+}
+
+impl<'a> RunParams<'a> {
+    pub fn new(args: &'a Args) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        RunParams {
+            ferromic_version: env!("CARGO_PKG_VERSION"),
+            timestamp,
+            hostname: System::new().host_name(),
+            args,
+        }
+    }
+}
+
+// output.csv -> output.params.json
+pub fn params_output_path(output_file: &Path) -> PathBuf {
+    output_file.with_extension("params.json")
+}
+
+pub fn write_run_params(params: &RunParams, path: &Path) -> Result<(), VcfError> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, params).map_err(|e| VcfError::Io(e.into()))?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// output.csv -> output.csv.gz when compressing; paths already ending in .gz are kept
+pub fn compressed_output_path(path: &Path, compress: bool) -> PathBuf {
+    if !compress || path.extension().is_some_and(|ext| ext == "gz") {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+// Plain or gzip-compressed output file. finish() writes the gzip trailer and reports any error,
+// which dropping the encoder would swallow.
+pub enum OutputWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl OutputWriter {
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut file) => file.flush(),
+            OutputWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(file) => file.write(buf),
+            OutputWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(file) => file.flush(),
+            OutputWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Plain or gzip-compressed (level 6) output file; compressed paths gain a .gz suffix
+pub fn open_output_writer(path: &Path, compress: bool) -> Result<OutputWriter, VcfError> {
+    let path = compressed_output_path(path, compress);
+    let file = File::create(&path)?;
+    if compress {
+        info!("Writing gzip-compressed output to {}", path.display());
+        Ok(OutputWriter::Gzip(GzEncoder::new(file, Compression::new(6))))
+    } else {
+        Ok(OutputWriter::Plain(file))
+    }
+}
+
+// The filters and reader settings of process_vcf; parse_variant applies the per-record ones
+#[derive(Debug, Clone)]
+pub struct VcfOptions {
+    pub reference_path: PathBuf,
+    pub min_gq: u8,
+    pub min_dp: u8,
+    pub max_dp: u32,
+    pub qual_threshold: f64,
+    pub min_maf: f64,
+    pub hwe_filter: f64,
+    pub multiallelic: MultiallelicMode,
+    pub filter_pass_only: bool,
+    pub exclude_filters: Vec<String>,
+    pub info_filters: Vec<InfoFilter>,
+    pub require_phased: bool,
+    pub include_symbolic: bool,
+    pub snps_only: bool,
+    pub variant_type: VariantTypeFilter,
+    pub exclude_cpg: bool,
+    pub concordance_check: bool,
+    // Samples named by --sample_list, or None to keep every VCF sample
+    pub sample_list: Option<Vec<String>>,
+    pub exclude_samples: Vec<String>,
+    // Warn about the samples only for the first VCF of a run
+    pub report_samples: bool,
+    // Sum the --chr statistics instead of keeping the unfiltered variants
+    pub stream_unfiltered: Option<AncestralSource>,
+    pub channel_buffer: usize,
+    pub result_buffer: usize,
+}
+
+impl VcfOptions {
+    // Reads the --sample_list and --exclude_samples files and parses the --info_filter expressions
+    pub fn from_args(args: &Args) -> Result<Self, VcfError> {
+        let sample_list = args
+            .sample_list
+            .as_ref()
+            .map(|path| parse_sample_list(Path::new(path)))
+            .transpose()?;
+        let exclude_samples = match args.exclude_samples.as_ref() {
+            Some(path) => parse_sample_list(Path::new(path))?,
+            None => Vec::new(),
+        };
+        let info_filters = args
+            .info_filter
+            .iter()
+            .map(|expr| parse_info_filter(expr))
+            .collect::<Result<_, _>>()?;
+        Ok(VcfOptions {
+            reference_path: PathBuf::from(&args.reference_path),
+            min_gq: args.min_gq,
+            min_dp: args.min_dp,
+            max_dp: args.max_dp,
+            qual_threshold: args.qual_threshold,
+            min_maf: args.min_maf,
+            hwe_filter: args.hwe_filter,
+            multiallelic: args.multiallelic,
+            filter_pass_only: args.filter_pass_only,
+            exclude_filters: args.exclude_filter.clone(),
+            info_filters,
+            require_phased: args.require_phased,
+            include_symbolic: args.include_symbolic,
+            snps_only: args.snps_only,
+            variant_type: args.variant_type,
+            exclude_cpg: args.exclude_cpg,
+            concordance_check: args.concordance_check,
+            sample_list,
+            exclude_samples,
+            report_samples: true,
+            stream_unfiltered: None,
+            channel_buffer: args.channel_buffer,
+            result_buffer: args.result_buffer,
+        })
+    }
+}
+
+// The command-line defaults: no filter beyond skipping multi-allelic sites
+impl Default for VcfOptions {
+    fn default() -> Self {
+        VcfOptions {
+            reference_path: PathBuf::new(),
+            min_gq: 0,
+            min_dp: 0,
+            max_dp: u32::MAX,
+            qual_threshold: 0.0,
+            min_maf: 0.0,
+            hwe_filter: 0.0,
+            multiallelic: MultiallelicMode::Skip,
+            filter_pass_only: false,
+            exclude_filters: Vec::new(),
+            info_filters: Vec::new(),
+            require_phased: false,
+            include_symbolic: false,
+            snps_only: false,
+            variant_type: VariantTypeFilter::All,
+            exclude_cpg: false,
+            concordance_check: false,
+            sample_list: None,
+            exclude_samples: Vec::new(),
+            report_samples: true,
+            stream_unfiltered: None,
+            channel_buffer: 1000,
+            result_buffer: 1000,
+        }
+    }
+}
+
+// CSV writer with its header already written, or None when the output is JSON
+fn open_csv_output<'a>(
+    args: &Args,
+    output_file: &Path,
+    header: impl IntoIterator<Item = &'a str>,
+) -> Result<Option<csv::Writer<OutputWriter>>, VcfError> {
+    if args.output_format != OutputFormat::Csv {
+        return Ok(None);
+    }
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(open_output_writer(output_file, args.compress_output)?);
+    writer.write_record(header).map_err(|e| VcfError::Io(e.into()))?;
+    Ok(Some(writer))
+}
+
+// The CSV output of an earlier run, reopened to add rows after its header (--resume)
+fn open_csv_append(output_file: &Path) -> Result<csv::Writer<OutputWriter>, VcfError> {
+    let file = fs::OpenOptions::new().append(true).open(output_file)?;
+    Ok(WriterBuilder::new().has_headers(false).from_writer(OutputWriter::Plain(file)))
+}
+
+// Flush the last CSV rows and finish the file; a full disk surfaces here instead of leaving a truncated .gz
+fn finish_csv_output(writer: csv::Writer<OutputWriter>) -> Result<(), VcfError> {
+    writer.into_inner().map_err(|e| VcfError::Io(e.into_error()))?.finish()?;
+    Ok(())
+}
+
+// Sliding-window mode: one CSV row of statistics per window across chr:start-end
+pub fn run_sliding_window_analysis(
+    args: &Args,
+    chr: &str,
+    start: i64,
+    end: i64,
+    masks: &RegionMasks,
+) -> Result<(), VcfError> {
+    let window_size = args
+        .window_size
+        .ok_or_else(|| VcfError::Parse("Sliding-window mode requires --window_size".to_string()))?;
+    let step_size = args.step_size.unwrap_or(window_size);
+    if window_size <= 0 || step_size <= 0 {
+        return Err(VcfError::Parse(format!(
+            "Window size and step size must be positive (got {} and {})",
+            window_size, step_size
+        )));
+    }
+
+    let haplotypes = load_haplotypes(args, chr, start, end, masks, true)?;
+    let n = haplotypes.num_haplotypes;
+
+    // Without an explicit region, scan up to the end of the chromosome
+    let end = end.min(haplotypes.chr_length);
+
+    let output_file = args
+        .output_file
+        .as_ref()
+        .map(Path::new)
+        .unwrap_or_else(|| Path::new("output.csv"));
+    info!("Output file: {}", output_file.display());
+
+    let header = ["chr", "window_start", "window_end"].into_iter().chain(REGION_STATS_COLUMNS);
+    let mut writer = open_csv_output(args, output_file, header)?;
+
+    // Windows are independent, so they run in parallel; the collected results keep window order
+    let windows: Vec<(i64, i64)> = sliding_windows(start, end, window_size, step_size).collect();
+    let allow_chr = masks.allow.as_ref().and_then(|a| a.get(&canonical_chr(chr)));
+    let mask_chr = masks.mask.as_ref().and_then(|m| m.get(&canonical_chr(chr)));
+    let reference = read_gc_reference(args, chr, start, end);
+    let results: Vec<RegionStats> = windows
+        .par_iter()
+        .enumerate()
+        .map(|(index, &(window_start, window_end))| {
+            let adjusted_sequence_length =
+                calculate_adjusted_sequence_length(window_start, window_end, allow_chr, mask_chr);
+            // Each window gets its own generator so the bootstrap does not depend on thread scheduling
+            let mut rng = bootstrap_rng(args.seed.map(|seed| seed.wrapping_add(index as u64)));
+            let mut stats = calculate_region_stats(
+                &haplotypes,
+                chr,
+                window_start,
+                window_end,
+                adjusted_sequence_length,
+                args.bootstrap,
+                &mut rng,
+            );
+            stats.gc_content = reference.as_ref().and_then(|span| span.gc_content(window_start, window_end));
+            stats
+        })
+        .collect();
+
+    match writer {
+        Some(mut writer) => {
+            for stats in &results {
+                writer
+                    .write_record(region_stats_record(stats, args.output_precision))
+                    .map_err(|e| VcfError::Io(e.into()))?;
+            }
+            finish_csv_output(writer)?;
+        }
+        None => write_json_output(&results, output_file)?,
+    }
+    info!("Wrote {} windows for {}:{}-{}", results.len(), chr, start, end);
+
+    if args.per_site {
+        write_per_site_pi(&output_path_with_suffix(output_file, "_per_site"), chr, &[(start, end)], &haplotypes.unfiltered, n, args.output_precision)?;
+    }
+    if let Some(allele_freq_output) = args.allele_freq_output.as_ref() {
+        write_allele_frequencies(Path::new(allele_freq_output), chr, &haplotypes.unfiltered, args.output_precision)?;
+    }
+    if args.ld_window_bp.is_some() {
+        write_ld_pairs(&output_path_with_suffix(output_file, "_ld"), &haplotypes.unfiltered, n, args)?;
+    }
+    if args.sfs {
+        write_sfs(
+            &sfs_output_path(output_file, ""),
+            &haplotypes.unfiltered,
+            n,
+            args.sfs_folded,
+            args.sfs_normalized,
+            args.sfs_exclude_missing,
+            args.output_precision,
+        )?;
+    }
+    Ok(())
+}
+
+// BED mode: one CSV row of statistics per BED region, over all haplotypes
+pub fn run_bed_region_analysis(
+    args: &Args,
+    regions: &[(String, i64, i64)],
+    masks: &RegionMasks,
+) -> Result<(), VcfError> {
+    let output_file = args
+        .output_file
+        .as_ref()
+        .map(Path::new)
+        .unwrap_or_else(|| Path::new("output.csv"));
+    info!("Output file: {}", output_file.display());
+
+    let header = ["chr", "region_start", "region_end"].into_iter().chain(REGION_STATS_COLUMNS);
+    let mut writer = open_csv_output(args, output_file, header)?;
+    let mut results = Vec::new();
+    let mut written = 0;
+
+    // Read each chromosome once, over the span covering all of its regions
+    let mut samples_reported = false;
+    let mut chromosomes: Vec<&str> = Vec::new();
+    for (chr, _, _) in regions {
+        if !chromosomes.contains(&chr.as_str()) {
+            chromosomes.push(chr);
+        }
+    }
+
+    let mut rng = bootstrap_rng(args.seed);
+    for chr in chromosomes {
+        let chr_regions: Vec<(i64, i64)> = regions
+            .iter()
+            .filter(|(c, _, _)| c == chr)
+            .map(|&(_, start, end)| (start, end))
+            .collect();
+        let span_start = chr_regions.iter().map(|&(start, _)| start).min().unwrap_or(1);
+        let span_end = chr_regions.iter().map(|&(_, end)| end).max().unwrap_or(span_start);
+
+        let haplotypes = match load_haplotypes(
+            args,
+            chr,
+            span_start,
+            span_end,
+            masks,
+            !samples_reported,
+        ) {
+            Ok(haplotypes) => haplotypes,
+            Err(e) => {
+                error!("Error processing VCF file for {}: {}", chr, e);
+                continue;
+            }
+        };
+        samples_reported = true;
+        let reference = read_gc_reference(args, chr, span_start, span_end);
+
+        for (start, end) in chr_regions {
+            let adjusted_sequence_length = calculate_adjusted_sequence_length(
+                start,
+                end,
+                masks.allow.as_ref().and_then(|a| a.get(&canonical_chr(chr))),
+                masks.mask.as_ref().and_then(|m| m.get(&canonical_chr(chr))),
+            );
+            let mut stats = calculate_region_stats(
+                &haplotypes,
+                chr,
+                start,
+                end,
+                adjusted_sequence_length,
+                args.bootstrap,
+                &mut rng,
+            );
+            stats.gc_content = reference.as_ref().and_then(|span| span.gc_content(start, end));
+            if args.sfs {
+                write_sfs(
+                    &sfs_output_path(output_file, &format!("_{}_{}_{}", chr, start, end)),
+                    variants_in_range(&haplotypes.unfiltered, start, end),
+                    haplotypes.num_haplotypes,
+                    args.sfs_folded,
+                    args.sfs_normalized,
+                    args.sfs_exclude_missing,
+                    args.output_precision,
+                )?;
+            }
+            match writer.as_mut() {
+                Some(writer) => writer
+                    .write_record(region_stats_record(&stats, args.output_precision))
+                    .map_err(|e| VcfError::Io(e.into()))?,
+                None => results.push(stats),
+            }
+            written += 1;
+        }
+        if let Some(writer) = writer.as_mut() {
+            writer.flush()?;
+        }
+    }
+    match writer {
+        Some(writer) => finish_csv_output(writer)?,
+        None => write_json_output(&results, output_file)?,
+    }
+
+    info!("Wrote {} of {} regions to {}", written, regions.len(), output_file.display());
+    Ok(())
+}
+
+// Haplotypes of each labelled group, as indices into a HaplotypeSet (sample i owns haplotypes 2i and 2i + 1)
+// Samples are matched by full name or by extract_sample_id; samples without a group are left out
+pub fn group_haplotype_indices(
+    sample_names: &[String],
+    groups: &HashMap<String, String>,
+    labels: &[String],
+) -> Vec<Vec<usize>> {
+    let mut indices = vec![Vec::new(); labels.len()];
+    for (i, name) in sample_names.iter().enumerate() {
+        let label = groups.get(name).or_else(|| groups.get(extract_sample_id(name)));
+        if let Some(slot) = label.and_then(|label| labels.iter().position(|l| l == label)) {
+            indices[slot].extend([2 * i, 2 * i + 1]);
+        }
+    }
+    indices
+}
+
+// Columns of the --group_file output after chr and the region bounds
+pub fn group_region_columns(labels: &[String]) -> Vec<String> {
+    let mut columns = vec![
+        "sequence_length".to_string(),
+        "sequence_length_adjusted".to_string(),
+        "sequence_length_accessible".to_string(),
+    ];
+    for label in labels {
+        for stat in ["num_haplotypes", "segregating_sites", "w_theta", "pi"] {
+            columns.push(format!("{}_{}", label, stat));
+        }
+        for stat in ["segregating_sites", "w_theta", "pi"] {
+            columns.push(format!("{}_{}_filtered", label, stat));
+        }
+    }
+    for (i, a) in labels.iter().enumerate() {
+        for b in &labels[i + 1..] {
+            for stat in ["dxy", "fst"] {
+                columns.push(format!("{}_{}_{}", stat, a, b));
+                columns.push(format!("{}_{}_{}_filtered", stat, a, b));
+            }
+        }
+    }
+    columns
+}
+
+// Statistics of chr:start-end within each group and between every pair of groups
+pub fn calculate_group_region_stats(
+    haplotypes: &HaplotypeSet,
+    labels: &[String],
+    group_indices: &[Vec<usize>],
+    chr: &str,
+    start: i64,
+    end: i64,
+    adjusted_sequence_length: i64,
+) -> GroupRegionStats {
+    let sequence_length = end - start + 1;
+    let accessible_sequence_length = haplotypes
+        .accessible
+        .as_ref()
+        .map(|intervals| accessible_length(intervals, start, end));
+    let diversity_length = accessible_sequence_length.unwrap_or(sequence_length);
+    let project = |variants: &[Variant], indices: &[usize]| -> Vec<Variant> {
+        let haplotype_indices: Vec<(usize, usize)> = indices.iter().map(|&h| (h, 0)).collect();
+        variants_in_range(variants, start, end)
+            .iter()
+            .map(|v| project_haplotypes(v, &haplotype_indices))
+            .collect()
+    };
+    let unfiltered: Vec<Vec<Variant>> = group_indices.iter().map(|indices| project(&haplotypes.unfiltered, indices)).collect();
+    let filtered: Vec<Vec<Variant>> = group_indices.iter().map(|indices| project(&haplotypes.filtered, indices)).collect();
+
+    let groups = labels
+        .iter()
+        .enumerate()
+        .map(|(g, label)| {
+            let n = group_indices[g].len();
+            LabeledGroupStats {
+                label: label.clone(),
+                unfiltered: calculate_window_stats(&unfiltered[g], n, diversity_length),
+                filtered: calculate_window_stats(&filtered[g], n, adjusted_sequence_length),
+            }
+        })
+        .collect();
+
+    let mut pairs = Vec::new();
+    for a in 0..labels.len() {
+        for b in (a + 1)..labels.len() {
+            let (na, nb) = (group_indices[a].len(), group_indices[b].len());
+            pairs.push(GroupPairStats {
+                group_a: labels[a].clone(),
+                group_b: labels[b].clone(),
+                dxy: calculate_dxy(&unfiltered[a], na, &unfiltered[b], nb, diversity_length),
+                dxy_filtered: calculate_dxy(&filtered[a], na, &filtered[b], nb, adjusted_sequence_length),
+                fst: calculate_fst_hudson(&unfiltered[a], na, &unfiltered[b], nb, diversity_length),
+                fst_filtered: calculate_fst_hudson(&filtered[a], na, &filtered[b], nb, adjusted_sequence_length),
+            });
+        }
+    }
+
+    GroupRegionStats {
+        chr: chr.to_string(),
+        region_start: start,
+        region_end: end,
+        sequence_length,
+        sequence_length_adjusted: adjusted_sequence_length,
+        sequence_length_accessible: accessible_sequence_length,
+        groups,
+        pairs,
+        subsampled: haplotypes.subsampled,
+    }
+}
+
+// CSV row of a --group_file region: chr, start, end, then group_region_columns
+pub fn group_region_record(stats: &GroupRegionStats, precision: usize) -> Vec<String> {
+    let mut record = vec![
+        stats.chr.clone(),
+        stats.region_start.to_string(),
+        stats.region_end.to_string(),
+        stats.sequence_length.to_string(),
+        stats.sequence_length_adjusted.to_string(),
+        stats.sequence_length_accessible.map_or("NA".to_string(), |length| length.to_string()),
+    ];
+    for group in &stats.groups {
+        record.push(group.unfiltered.num_haplotypes.to_string());
+        record.push(group.unfiltered.segregating_sites.to_string());
+        record.push(format_f64(group.unfiltered.w_theta, precision));
+        record.push(format_f64(group.unfiltered.pi, precision));
+        record.push(group.filtered.segregating_sites.to_string());
+        record.push(format_f64(group.filtered.w_theta, precision));
+        record.push(format_f64(group.filtered.pi, precision));
+    }
+    for pair in &stats.pairs {
+        record.push(format_f64(pair.dxy, precision));
+        record.push(format_f64(pair.dxy_filtered, precision));
+        record.push(format_f64(pair.fst, precision));
+        record.push(format_f64(pair.fst_filtered, precision));
+    }
+    record
+}
+
+// Group-file mode: one CSV row per region with within-group and between-group statistics
+pub fn run_group_analysis(
+    args: &Args,
+    regions: &[(String, i64, i64)],
+    groups: &HashMap<String, String>,
+    masks: &RegionMasks,
+) -> Result<(), VcfError> {
+    let output_file = args
+        .output_file
+        .as_ref()
+        .map(Path::new)
+        .unwrap_or_else(|| Path::new("output.csv"));
+    info!("Output file: {}", output_file.display());
+
+    let labels: Vec<String> = groups.values().cloned().collect::<BTreeSet<_>>().into_iter().collect();
+    info!("{} samples in {} groups: {:?}", groups.len(), labels.len(), labels);
+    let columns = group_region_columns(&labels);
+    let header = ["chr", "region_start", "region_end"]
+        .into_iter()
+        .chain(columns.iter().map(String::as_str));
+    let mut writer = open_csv_output(args, output_file, header)?;
+    let mut results = Vec::new();
+    let mut written = 0;
+
+    // Read each chromosome once, over the span covering all of its regions
+    let mut samples_reported = false;
+    let mut chromosomes: Vec<&str> = Vec::new();
+    for (chr, _, _) in regions {
+        if !chromosomes.contains(&chr.as_str()) {
+            chromosomes.push(chr);
+        }
+    }
+
+    for chr in chromosomes {
+        let chr_regions: Vec<(i64, i64)> = regions
+            .iter()
+            .filter(|(c, _, _)| c == chr)
+            .map(|&(_, start, end)| (start, end))
+            .collect();
+        let span_start = chr_regions.iter().map(|&(start, _)| start).min().unwrap_or(1);
+        let span_end = chr_regions.iter().map(|&(_, end)| end).max().unwrap_or(span_start);
+
+        let haplotypes = match load_haplotypes(
+            args,
+            chr,
+            span_start,
+            span_end,
+            masks,
+            !samples_reported,
+        ) {
+            Ok(haplotypes) => haplotypes,
+            Err(e) => {
+                error!("Error processing VCF file for {}: {}", chr, e);
+                continue;
+            }
+        };
+        samples_reported = true;
+
+        let group_indices = group_haplotype_indices(&haplotypes.sample_names, groups, &labels);
+        for (label, indices) in labels.iter().zip(&group_indices) {
+            if indices.is_empty() {
+                warn!("No VCF samples of group {} found for {}", label, chr);
+            }
+        }
+
+        for (start, end) in chr_regions {
+            // Without an explicit region, scan up to the end of the chromosome
+            let end = if end == i64::MAX {
+                haplotypes
+                    .unfiltered
+                    .last()
+                    .map(|v| v.position)
+                    .unwrap_or(0)
+                    .max(haplotypes.chr_length)
+            } else {
+                end
+            };
+            let adjusted_sequence_length = calculate_adjusted_sequence_length(
+                start,
+                end,
+                masks.allow.as_ref().and_then(|a| a.get(&canonical_chr(chr))),
+                masks.mask.as_ref().and_then(|m| m.get(&canonical_chr(chr))),
+            );
+            let stats = calculate_group_region_stats(
+                &haplotypes,
+                &labels,
+                &group_indices,
+                chr,
+                start,
+                end,
+                adjusted_sequence_length,
+            );
+            match writer.as_mut() {
+                Some(writer) => writer
+                    .write_record(group_region_record(&stats, args.output_precision))
+                    .map_err(|e| VcfError::Io(e.into()))?,
+                None => results.push(stats),
+            }
+            written += 1;
+        }
+        if let Some(writer) = writer.as_mut() {
+            writer.flush()?;
+        }
+    }
+    match writer {
+        Some(writer) => finish_csv_output(writer)?,
+        None => write_json_output(&results, output_file)?,
+    }
+
+    info!("Wrote {} of {} regions to {}", written, regions.len(), output_file.display());
+    Ok(())
+}
+
+// output.csv -> output<suffix>.csv, next to the main output
+pub fn output_path_with_suffix(output_file: &Path, suffix: &str) -> PathBuf {
+    let stem = output_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_string());
+    let file_name = match output_file.extension() {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}{}", stem, suffix),
+    };
+    output_file.with_file_name(file_name)
+}
+
+// output.csv -> output<suffix>.sfs
+pub fn sfs_output_path(output_file: &Path, suffix: &str) -> PathBuf {
+    output_path_with_suffix(output_file, suffix).with_extension("sfs")
+}
+
+// One line of space-separated counts, frequency class 1 first
+// Normalized or projected spectra are written as decimals
+pub fn write_sfs(
+    path: &Path,
+    variants: &[Variant],
+    n: usize,
+    folded: bool,
+    normalized: bool,
+    exclude_missing: bool,
+    precision: usize,
+) -> Result<(), VcfError> {
+    if !folded && !variants.is_empty() && variants.iter().all(|v| v.ancestral_allele.is_none()) {
+        warn!("No AA INFO tags found; the unfolded SFS in {} is empty.", path.display());
+    }
+    let format_values = |values: &[f64]| values.iter().map(|&value| format_f64(value, precision)).collect::<Vec<_>>().join(" ");
+    let line = if exclude_missing {
+        let (sfs, n_proj) = project_sfs(variants, n, folded);
+        if n_proj < n {
+            info!("SFS in {} projected from {} to {} haplotypes", path.display(), n, n_proj);
+        }
+        let total: f64 = sfs.iter().sum();
+        if normalized && total > 0.0 {
+            format_values(&sfs.iter().map(|value| value / total).collect::<Vec<_>>())
+        } else {
+            format_values(&sfs)
+        }
+    } else {
+        let sfs = compute_sfs(variants, n, folded);
+        if normalized {
+            format_values(&normalize_sfs(&sfs))
+        } else {
+            sfs.iter().map(|count| count.to_string()).collect::<Vec<_>>().join(" ")
+        }
+    };
+    fs::write(path, format!("{}\n", line))?;
+    info!("Wrote {} SFS to {}", if folded { "folded" } else { "unfolded" }, path.display());
+    Ok(())
+}
+
+// One row per position of each [start, end] interval; positions without a variant have pi_site = 0
+pub fn write_per_site_pi(
+    path: &Path,
+    chr: &str,
+    intervals: &[(i64, i64)],
+    variants: &[Variant],
+    n: usize,
+    precision: usize,
+) -> Result<(), VcfError> {
+    // A split multi-allelic site yields several records at one position
+    let mut pi_by_position: HashMap<i64, f64> = HashMap::new();
+    for (position, pi_site) in calculate_per_site_pi(variants, n) {
+        *pi_by_position.entry(position).or_insert(0.0) += pi_site;
+    }
+
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["chr", "pos", "pi_site"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for pos in intervals.iter().flat_map(|&(start, end)| start..=end) {
+        let pi_site = pi_by_position.get(&pos).copied().unwrap_or(0.0);
+        writer
+            .write_record([chr.to_string(), pos.to_string(), format_f64(pi_site, precision)])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("Per-site pi written to {}", path.display());
+    Ok(())
+}
+
+// Confidence level of the per-variant allele frequency intervals
+const ALLELE_FREQ_CI_ALPHA: f64 = 0.05;
+
+// One McDonald-Kreitman table per gene and config region, with its Fisher's exact test p-value
+pub fn write_mk_tables(
+    path: &Path,
+    rows: &[(String, i64, i64, String, MkTable)],
+    precision: usize,
+) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["chr", "region_start", "region_end", "gene", "dn", "ds", "pn", "ps", "fisher_p"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for (chr, start, end, gene, table) in rows {
+        writer
+            .write_record([
+                chr.clone(),
+                start.to_string(),
+                end.to_string(),
+                gene.clone(),
+                table.dn.to_string(),
+                table.ds.to_string(),
+                table.pn.to_string(),
+                table.ps.to_string(),
+                format_f64(table.fisher_p_value(), precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("McDonald-Kreitman tables of {} genes written to {}", rows.len(), path.display());
+    Ok(())
+}
+
+// Positions with at least one missing genotype, one per line in ascending order
+pub fn write_missing_positions(path: &Path, positions: &BTreeSet<i64>) -> Result<(), VcfError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for position in positions {
+        writeln!(writer, "{}", position)?;
+    }
+    writer.flush()?;
+    info!("{} positions with missing data written to {}", positions.len(), path.display());
+    Ok(())
+}
+
+// Per-variant allele counts and frequencies, one row per record, in a pass of its own
+pub fn write_allele_frequencies(path: &Path, chr: &str, variants: &[Variant], precision: usize) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["chr", "pos", "ac", "an", "af", "ci_lower", "ci_upper"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for variant in variants {
+        let (ac, an) = called_allele_counts(variant);
+        let af = if an == 0 { f64::NAN } else { ac as f64 / an as f64 };
+        let (ci_lower, ci_upper) = clopper_pearson_ci(ac, an, ALLELE_FREQ_CI_ALPHA);
+        writer
+            .write_record([
+                chr.to_string(),
+                variant.position.to_string(),
+                ac.to_string(),
+                an.to_string(),
+                format_f64(af, precision),
+                format_f64(ci_lower, precision),
+                format_f64(ci_upper, precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("Allele frequencies written to {}", path.display());
+    Ok(())
+}
+
+// FASTA bases per line
+const FASTA_LINE_WIDTH: usize = 60;
+
+// One FASTA record per (sample, haplotype), named >sample_id_hap1 or _hap2: the reference from
+// ref_start on, with the first ALT base where the haplotype carries it. Missing calls, and ALT
+// alleles without a single-base sequence, are written as N.
+pub fn write_fasta_haplotypes(
+    variants: &[Variant],
+    haplotype_indices: &[(usize, usize)],
+    names: &[String],
+    ref_seq: &[u8],
+    ref_start: i64,
+    out: &Path,
+) -> Result<(), VcfError> {
+    let mut writer = BufWriter::new(File::create(out)?);
+    for &(sample, haplotype) in haplotype_indices {
+        let mut sequence = ref_seq.to_ascii_uppercase();
+        for variant in variants {
+            let offset = variant.position - ref_start;
+            if offset < 0 || offset as usize >= sequence.len() {
+                continue;
+            }
+            let allele = variant
+                .genotypes
+                .get(sample)
+                .and_then(|genotype| genotype.as_ref())
+                .and_then(|alleles| alleles.get(haplotype));
+            sequence[offset as usize] = match allele {
+                Some(0) => continue,
+                Some(1) => variant.alt_allele,
+                _ => b'N',
+            };
+        }
+        writeln!(writer, ">{}_hap{}", extract_sample_id(&names[sample]), haplotype + 1)?;
+        for line in sequence.chunks(FASTA_LINE_WIDTH) {
+            writer.write_all(line)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    writer.flush()?;
+    info!("{} haplotype sequences written to {}", haplotype_indices.len(), out.display());
+    Ok(())
+}
+
+// Symmetric matrix of differing sites between every pair of samples, from calculate_pairwise_differences,
+// with zeros on the diagonal
+pub fn write_pairwise_matrix(
+    diffs: &[((usize, usize), usize, Vec<i64>)],
+    sample_names: &[String],
+    path: &Path,
+    format: MatrixFormat,
+) -> Result<(), VcfError> {
+    let n = sample_names.len();
+    let mut matrix = vec![vec![0; n]; n];
+    for &((i, j), count, _) in diffs {
+        matrix[i][j] = count;
+        matrix[j][i] = count;
+    }
+    let names: Vec<&str> = sample_names.iter().map(|name| extract_sample_id(name)).collect();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        MatrixFormat::Csv => {
+            let mut csv_writer = WriterBuilder::new().from_writer(writer);
+            csv_writer
+                .write_record(std::iter::once("sample").chain(names.iter().copied()))
+                .map_err(|e| VcfError::Io(e.into()))?;
+            for (name, row) in names.iter().zip(&matrix) {
+                csv_writer
+                    .write_record(std::iter::once(name.to_string()).chain(row.iter().map(usize::to_string)))
+                    .map_err(|e| VcfError::Io(e.into()))?;
+            }
+            csv_writer.flush()?;
+        }
+        MatrixFormat::Phylip => {
+            writeln!(writer, "{}", n)?;
+            for (name, row) in names.iter().zip(&matrix) {
+                let values: Vec<String> = row.iter().map(usize::to_string).collect();
+                writeln!(writer, "{:<10} {}", name, values.join(" "))?;
+            }
+            writer.flush()?;
+        }
+    }
+    info!("Pairwise differences of {} samples written to {}", n, path.display());
+    Ok(())
+}
+
+// One row per pair of samples with more than min_diff differing sites, listing the sites separated by
+// semicolons, in the pair order of calculate_pairwise_differences
+pub fn write_diff_positions(
+    diffs: &[((usize, usize), usize, Vec<i64>)],
+    names: &[String],
+    min_diff: usize,
+    path: &Path,
+) -> Result<(), VcfError> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["sample_i", "sample_j", "diff_count", "positions"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    let mut rows = 0;
+    for ((i, j), count, positions) in diffs.iter().filter(|(_, count, _)| *count > min_diff) {
+        let positions: Vec<String> = positions.iter().map(i64::to_string).collect();
+        writer
+            .write_record([
+                extract_sample_id(&names[*i]).to_string(),
+                extract_sample_id(&names[*j]).to_string(),
+                count.to_string(),
+                positions.join(";"),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+        rows += 1;
+    }
+    writer.flush()?;
+    info!("Differing positions of {} sample pairs written to {}", rows, path.display());
+    Ok(())
+}
+
+// CLR score of each non-overlapping --clr_window_size window of [start, end], with the window's SFS
+// over n haplotypes tested against the neutral SFS. The unfolded spectrum needs AA INFO tags, as for --sfs.
+pub fn write_clr_scores(
+    path: &Path,
+    chr: &str,
+    variants: &[Variant],
+    n: usize,
+    (start, end): (i64, i64),
+    args: &Args,
+) -> Result<(), VcfError> {
+    let window_size = args.clr_window_size.unwrap_or(0);
+    let (folded, precision) = (args.sfs_folded, args.output_precision);
+    if window_size <= 0 {
+        return Err(VcfError::Parse(format!("--clr_window_size must be positive (got {})", window_size)));
+    }
+    if !folded && !variants.is_empty() && variants.iter().all(|v| v.ancestral_allele.is_none()) {
+        warn!("No AA INFO tags found; the unfolded SFS behind the CLR scores in {} is empty and every score is 0.", path.display());
+    }
+    let expected = neutral_sfs(n, folded);
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["chr", "window_start", "window_end", "sfs_sites", "clr"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    let mut windows = 0;
+    for (window_start, window_end) in sliding_windows(start, end, window_size, window_size) {
+        let sfs = compute_sfs(variants_in_range(variants, window_start, window_end), n, folded);
+        writer
+            .write_record([
+                chr.to_string(),
+                window_start.to_string(),
+                window_end.to_string(),
+                sfs.iter().sum::<usize>().to_string(),
+                format_f64(clr_score(&sfs, &expected), precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+        windows += 1;
+    }
+    writer.flush()?;
+    info!("CLR scores of {} windows written to {}", windows, path.display());
+    Ok(())
+}
+
+// Pairwise LD between variants within window_bp of each other, over n haplotypes
+pub fn write_ld_pairs(path: &Path, variants: &[Variant], n: usize, args: &Args) -> Result<(), VcfError> {
+    let precision = args.output_precision;
+    let window_bp = match args.ld_window_bp {
+        Some(window_bp) => window_bp,
+        None => return Ok(()),
+    };
+    if !(0.0..=1.0).contains(&args.ld_min_complete) {
+        return Err(VcfError::Parse(format!(
+            "--ld_min_complete must be between 0 and 1 (got {})",
+            args.ld_min_complete
+        )));
+    }
+    let min_called = (args.ld_min_complete * n as f64).ceil() as usize;
+    let pairs = calculate_ld_pairs(variants, window_bp, min_called);
+
+    let mut writer = WriterBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .map_err(|e| VcfError::Io(e.into()))?;
+    writer
+        .write_record(["pos1", "pos2", "r_squared", "d_prime"])
+        .map_err(|e| VcfError::Io(e.into()))?;
+    for (pos1, pos2, r_squared, d_prime) in &pairs {
+        writer
+            .write_record([
+                pos1.to_string(),
+                pos2.to_string(),
+                format_f64(*r_squared, precision),
+                format_f64(*d_prime, precision),
+            ])
+            .map_err(|e| VcfError::Io(e.into()))?;
+    }
+    writer.flush()?;
+    info!("{} LD pairs written to {}", pairs.len(), path.display());
+
+    if let Some(num_bins) = args.ld_decay_bins {
+        let bins = log_spaced_bins(num_bins, window_bp);
+        let ld_values: Vec<((i64, i64), f64)> = pairs
+            .iter()
+            .map(|&(pos1, pos2, r_squared, _)| ((pos1, pos2), r_squared))
+            .collect();
+        let decay_path = output_path_with_suffix(path, "_decay");
+        let mut writer = WriterBuilder::new()
+            .has_headers(true)
+            .from_path(&decay_path)
+            .map_err(|e| VcfError::Io(e.into()))?;
+        writer
+            .write_record(["bin_start", "bin_end", "bin_center", "mean_r_squared", "pair_count"])
+            .map_err(|e| VcfError::Io(e.into()))?;
+        for (&(start, end), (center, mean_r_squared, count)) in bins.iter().zip(ld_decay_bins(&ld_values, &bins)) {
+            writer
+                .write_record([
+                    start.to_string(),
+                    (end - 1).to_string(),
+                    center.to_string(),
+                    format_f64(mean_r_squared, precision),
+                    count.to_string(),
+                ])
+                .map_err(|e| VcfError::Io(e.into()))?;
+        }
+        writer.flush()?;
+        info!("LD decay over {} distance bins written to {}", bins.len(), decay_path.display());
+    }
+    Ok(())
+}
+
+// What process_vcf reads from a VCF
+pub type ProcessedVcf = (
+    Vec<Variant>,        // Unfiltered variants, empty when streamed
+    Vec<Variant>,        // Filtered variants
+    Vec<String>,         // Sample names
+    i64,                 // Chromosome length
+    MissingDataInfo,
+    FilteringStats,
+    Option<StreamingChromosomeStats>,  // The streamed unfiltered variants
+);
+
+// Function to process a VCF file
+pub fn process_vcf(
+    file: &Path,
+    chr: &str,
+    intervals: &[(i64, i64)],  // Sorted, 1-based inclusive; records in the gaps between them are skipped
+    masks: &RegionMasks,
+    options: &VcfOptions,
+    seqinfo_storage: Arc<Mutex<Vec<SeqInfo>>>,
+    position_allele_map: Arc<Mutex<HashMap<i64, (char, char)>>>,
+) -> Result<ProcessedVcf, VcfError> {
+    let VcfOptions {
+        multiallelic,
+        min_maf,
+        hwe_filter,
+        qual_threshold,
+        require_phased,
+        exclude_cpg,
+        concordance_check,
+        report_samples,
+        stream_unfiltered,
+        channel_buffer,
+        result_buffer,
+        ..
+    } = *options;
+    let reference_path = options.reference_path.as_path();
+    let start = intervals[0].0;
+    let end = intervals[intervals.len() - 1].1;
+    // 0-based half-open for position_in_regions; a single interval is already enforced by start and end
+    let gaps = (intervals.len() > 1).then(|| Arc::new(intervals.iter().map(|&(s, e)| (s - 1, e)).collect::<Vec<_>>()));
+    let mut reader = open_vcf_reader(file)?;
+    let mut sample_names = Vec::new();
+
+    // Existing unfiltered and filtered variants storage
+    let unfiltered_variants = Arc::new(Mutex::new(Vec::new()));
+    let filtered_variants = Arc::new(Mutex::new(Vec::new()));
+
+    // Existing missing data and filtering stats
+    let missing_data_info = Arc::new(Mutex::new(MissingDataInfo::default()));
+    let _filtering_stats = Arc::new(Mutex::new(FilteringStats::default()));
+
+    // BCF is BGZF-compressed too, and standard input has no size, so neither measures progress
+    let is_stdin = file == Path::new(STDIN_PATH);
+    let is_gzipped =
+        is_stdin || has_gzip_extension(file) || file.extension().and_then(|s| s.to_str()) == Some("bcf");
+    let progress_bar = if is_gzipped {
+        ProgressBar::new_spinner()
+    } else {
+        let file_size = fs::metadata(file)?.len();
+        ProgressBar::new(file_size)
+    };
+
+    let style = if is_gzipped {
+        ProgressStyle::default_spinner()
+            .template("{spinner:.bold.green} 🧬 {msg} 🧬 [{elapsed_precise}]")
+            .expect("Failed to create spinner template")
+            .tick_strings(&[
+                "░▒▓██▓▒░", "▒▓██▓▒░", "▓██▓▒░", "██▓▒░", "█▓▒░", "▓▒░", "▒░", "░", "▒░", "▓▒░", "█▓▒░", "██▓▒░", "▓██▓▒░", "▒▓██▓▒░"
+            ])
+    } else {
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {msg}")
+            .expect("Failed to create progress bar template")
+            .progress_chars("=>-")
+    };
+
+    progress_bar.set_style(style);
+
+    let processing_complete = Arc::new(AtomicBool::new(false));
+    let processing_complete_clone = processing_complete.clone();
+
+    // Spawn a thread to update the progress bar
+    let progress_thread = thread::spawn(move || {
+        while !processing_complete_clone.load(Ordering::Relaxed) {
+            progress_bar.tick();
+            thread::sleep(Duration::from_millis(100));
+        }
+        progress_bar.finish_with_message("Variant processing complete");
+    });
+
+    // Process header
+    let mut buffer = String::new();
+    let mut contig_lengths: HashMap<String, i64> = HashMap::new();
+    let mut header_lines = 0;
+    while reader.read_line(&mut buffer)? > 0 {
+        header_lines += 1;
+        if buffer.starts_with("##") {
+            if let Some((id, length)) = parse_contig_length(&buffer) {
+                contig_lengths.insert(id, length);
+            }
+        } else if buffer.starts_with("#CHROM") {
+            validate_vcf_header(&buffer)?;
+            sample_names = buffer.split_whitespace().skip(9).map(String::from).collect();
+            break;
+        }
+        buffer.clear();
+    }
+    buffer.clear();
+
+    // Keep only the listed samples; their VCF columns are passed on to parse_variant
+    let mut sample_columns = match &options.sample_list {
+        Some(list) => Some(select_sample_columns(&sample_names, list)?),
+        None => None,
+    };
+    // Exclusions win over the sample list
+    if !options.exclude_samples.is_empty() {
+        let missing = missing_excluded_samples(&sample_names, &options.exclude_samples);
+        if report_samples && !missing.is_empty() {
+            warn!("The following excluded samples are not in the VCF: {:?}", missing);
+        }
+        let columns = sample_columns.unwrap_or_else(|| (0..sample_names.len()).collect());
+        sample_columns = Some(exclude_sample_columns(&sample_names, columns, &options.exclude_samples)?);
+    }
+    if let Some(columns) = &sample_columns {
+        sample_names = columns.iter().map(|&i| sample_names[i].clone()).collect();
+        info!("Restricting analysis to {} samples from --sample_list and --exclude_samples", sample_names.len());
+    }
+
+    // Prefer the length declared in the VCF header, then the reference index
+    let contig_length = contig_lengths
+        .iter()
+        .find(|(name, _)| canonical_chr(name) == canonical_chr(chr))
+        .map(|(_, &length)| length);
+    let chr_length = match contig_length {
+        Some(length) => length,
+        None => {
+            let mut fasta_reader = bio::io::fasta::IndexedReader::from_file(&reference_path)
+                .map_err(|e| VcfError::Io(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+            // Create an owned copy of the sequences
+            let sequences = fasta_reader.index.sequences().to_vec();
+            let seq_info = sequences.iter()
+                .find(|seq| canonical_chr(&seq.name) == canonical_chr(chr))
+                .ok_or_else(|| VcfError::Parse(format!("Chromosome {} not found in reference", chr)))?;
+            seq_info.len as i64
+        }
+    };
+
+    let cpg_sites = if exclude_cpg {
+        let cpg_sites = build_cpg_set(reference_path, chr, start.max(1), end.min(chr_length))?;
+        info!("{} CpG positions in {}:{}-{} will be excluded", cpg_sites.len(), chr, start.max(1), end.min(chr_length));
+        Some(Arc::new(cpg_sites))
+    } else {
+        None
+    };
+
+    // Set up channels for communication between threads
+    // bounded(0) would make every send wait for a receiver, so keep at least one slot
+    let (line_sender, line_receiver) = bounded(channel_buffer.max(1));
+    let (result_sender, result_receiver) = bounded(result_buffer.max(1));
+
+    // Seek straight to the region if the VCF is indexed, otherwise scan the whole file
+    let region_lines = if is_stdin { None } else { try_open_tabix_reader(file, chr, start, end)? };
+
+    let indexed = region_lines.is_some();
+    // Numbered as the producer below numbers them
+    let first_line = if indexed { 1 } else { header_lines + 1 };
+    let unfiltered_stream = stream_unfiltered.map(|ancestral| {
+        UnfilteredStream::new(
+            StreamingChromosomeStats::new(sample_names.len(), ancestral),
+            multiallelic != MultiallelicMode::Split,
+            first_line,
+        )
+    });
+
+    // Spawn producer thread
+    // Each line goes out with its number: the file line when scanning, the record number
+    // within the region when reading through the index, where errors name CHROM:POS instead
+    let producer_thread = thread::spawn(move || -> Result<(), VcfError> {
+        if let Some(lines) = region_lines {
+            for (record_num, line) in lines.enumerate() {
+                let mut line = line?;
+                line.push('\n');
+                line_sender.send((record_num + 1, line)).map_err(|_| VcfError::ChannelSend)?;
+            }
+        } else {
+            let mut line_num = header_lines;
+            while reader.read_line(&mut buffer)? > 0 {
+                line_num += 1;
+                line_sender.send((line_num, buffer.clone())).map_err(|_| VcfError::ChannelSend)?;
+                buffer.clear();
+            }
+        }
+        drop(line_sender);
+        Ok(())
+    });
+
+    // Spawn consumer threads
+    // Sized to the global pool, which honours --threads
+    let num_threads = rayon::current_num_threads();
+    let sample_names = Arc::new(sample_names);
+    let sample_columns = Arc::new(sample_columns);
+    let masks = Arc::new(masks.clone());
+    let options = Arc::new(options.clone());
+    let consumer_threads: Vec<_> = (0..num_threads)
+        .map(|_| {
+            let line_receiver = line_receiver.clone();
+            let result_sender = result_sender.clone();
+            let chr = chr.to_string();
+            let sample_names = Arc::clone(&sample_names);
+            let masks = Arc::clone(&masks);
+            let options = Arc::clone(&options);
+            let position_allele_map = Arc::clone(&position_allele_map);
+            let sample_columns = Arc::clone(&sample_columns);
+            let cpg_sites = cpg_sites.clone();
+            let gaps = gaps.clone();
+            
+            thread::spawn(
+                move || -> Result<(), VcfError> {
+                    let context = RecordContext {
+                        chr: &chr,
+                        start,
+                        end,
+                        sample_names: &sample_names,
+                        sample_columns: sample_columns.as_deref(),
+                        masks: &masks,
+                        cpg_sites: cpg_sites.as_deref(),
+                        options: &options,
+                    };
+                    while let Ok((line_num, line)) = line_receiver.recv() {
+                        let mut local_missing_data_info = MissingDataInfo::default();
+                        let mut local_filtering_stats = FilteringStats::default();
+
+                        // A record between two --region intervals is dropped before it is counted anywhere
+                        let in_gap = gaps.as_ref().is_some_and(|intervals| {
+                            line.split('\t')
+                                .nth(1)
+                                .and_then(|pos| pos.trim().parse::<i64>().ok())
+                                .is_some_and(|pos| !position_in_regions(pos - 1, intervals))
+                        });
+                        let parsed = if in_gap {
+                            Ok(None)
+                        } else {
+                            parse_variant(
+                                &line,
+                                line_num,
+                                &context,
+                                &position_allele_map,
+                                &mut local_missing_data_info,
+                                &mut local_filtering_stats,
+                            )
+                        };
+                        match parsed {
+                            Ok(variant_option) => {
+                                // A split multi-allelic site becomes several records
+                                let mut variants: Vec<(Variant, bool)> = match variant_option {
+                                    Some((variant, passes_filters)) => {
+                                        let alt_alleles: Vec<&str> = line.split('\t').nth(4).map_or(Vec::new(), |alt| alt.split(',').collect());
+                                        // Symbolic sites only get here with --include_symbolic, already collapsed to one ALT
+                                        let num_alts = if alt_alleles.iter().any(|alt| is_symbolic_allele(alt)) {
+                                            1
+                                        } else {
+                                            alt_alleles.len().max(1)
+                                        };
+                                        let mut expanded = expand_multiallelic(variant, num_alts, multiallelic);
+                                        // Each split record is biallelic for one of the ALTs
+                                        if expanded.len() > 1 {
+                                            for (record, alt) in expanded.iter_mut().zip(&alt_alleles) {
+                                                record.alt_allele = snp_base(alt);
+                                            }
+                                        }
+                                        expanded
+                                            .into_iter()
+                                            .map(|v| (v, passes_filters))
+                                            .collect()
+                                    }
+                                    None => Vec::new(),
+                                };
+                                // Checked after splitting so each ALT of a multi-allelic site stands on its own frequency
+                                if min_maf > 0.0 {
+                                    variants.retain(|(variant, _)| {
+                                        let maf = minor_allele_frequency(variant);
+                                        if maf < min_maf {
+                                            local_filtering_stats.low_maf_variants += 1;
+                                            local_filtering_stats.add_example(format!(
+                                                "{}: Filtered due to low MAF ({:.4})",
+                                                line.trim(),
+                                                maf
+                                            ));
+                                            false
+                                        } else {
+                                            true
+                                        }
+                                    });
+                                }
+                                if hwe_filter > 0.0 {
+                                    variants.retain(|(variant, _)| {
+                                        let (hom_ref, het, hom_alt) = count_genotype_classes(variant);
+                                        let p_value = hwe_exact_test(hom_ref, het, hom_alt);
+                                        if p_value < hwe_filter {
+                                            local_filtering_stats.hwe_filtered_count += 1;
+                                            local_filtering_stats.add_example(format!(
+                                                "{}: Filtered due to HWE departure (p = {:.3e})",
+                                                line.trim(),
+                                                p_value
+                                            ));
+                                            false
+                                        } else {
+                                            true
+                                        }
+                                    });
+                                }
+                                result_sender
+                                    .send(Ok((
+                                        line_num,
+                                        filter_column_passes(&line),
+                                        variants,
+                                        local_missing_data_info,
+                                        local_filtering_stats,
+                                    )))
+                                    .map_err(|_| VcfError::ChannelSend)?;
+                            }
+                            Err(e) => {
+                                let e = if indexed { locate_indexed_record_error(e, &line) } else { e };
+                                result_sender
+                                    .send(Err((line_num, e)))
+                                    .map_err(|_| VcfError::ChannelSend)?;
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+            )
+        })
+        .collect();
+
+    // Collector thread
+    let collector_thread = thread::spawn({
+        let unfiltered_variants = unfiltered_variants.clone();
+        let filtered_variants = filtered_variants.clone();
+        let missing_data_info = missing_data_info.clone();
+        let _filtering_stats = _filtering_stats.clone();
+        let mut unfiltered_stream = unfiltered_stream;
+        move || -> Result<Option<UnfilteredStream>, VcfError> {
+            while let Ok(result) = result_receiver.recv() {
+                match result {
+                    Ok((line_num, filter_pass, variants, local_missing_data_info, local_filtering_stats)) => {
+                        let mut streamed = Vec::new();
+                        for (variant, passes_filters) in variants {
+                            if passes_filters {
+                                filtered_variants.lock().push((line_num, filter_pass, variant.clone()));
+                            }
+                            match unfiltered_stream.as_mut() {
+                                Some(_) => streamed.push(variant),
+                                None => unfiltered_variants.lock().push((line_num, filter_pass, variant)),
+                            }
+                        }
+                        if let Some(stream) = unfiltered_stream.as_mut() {
+                            stream.receive(line_num, filter_pass, streamed);
+                        }
+                        let mut global_missing_data_info = missing_data_info.lock();
+                        global_missing_data_info.total_data_points += local_missing_data_info.total_data_points;
+                        global_missing_data_info.missing_data_points += local_missing_data_info.missing_data_points;
+                        global_missing_data_info.positions_with_missing.extend(local_missing_data_info.positions_with_missing);
+                        global_missing_data_info.filtered_by_qual += local_missing_data_info.filtered_by_qual;
+                        global_missing_data_info.low_gq_count += local_missing_data_info.low_gq_count;
+                        global_missing_data_info.low_dp_count += local_missing_data_info.low_dp_count;
+                        global_missing_data_info.high_dp_count += local_missing_data_info.high_dp_count;
+                        let num_samples = local_missing_data_info.sample_sites.len();
+                        if global_missing_data_info.sample_sites.len() < num_samples {
+                            global_missing_data_info.sample_sites.resize(num_samples, 0);
+                            global_missing_data_info.sample_missing.resize(num_samples, 0);
+                        }
+                        for i in 0..num_samples {
+                            global_missing_data_info.sample_sites[i] += local_missing_data_info.sample_sites[i];
+                            global_missing_data_info.sample_missing[i] += local_missing_data_info.sample_missing[i];
+                        }
+                        
+                        let mut global_filtering_stats = _filtering_stats.lock();
+                        global_filtering_stats.total_variants += local_filtering_stats.total_variants;
+                        global_filtering_stats._filtered_variants += local_filtering_stats._filtered_variants;
+                        global_filtering_stats.filtered_positions.extend(local_filtering_stats.filtered_positions);
+                        global_filtering_stats.filtered_due_to_mask += local_filtering_stats.filtered_due_to_mask;
+                        global_filtering_stats.repeat_masked_count += local_filtering_stats.repeat_masked_count;
+                        global_filtering_stats.filtered_due_to_allow += local_filtering_stats.filtered_due_to_allow;
+                        global_filtering_stats.missing_data_variants += local_filtering_stats.missing_data_variants;
+                        global_filtering_stats.low_gq_variants += local_filtering_stats.low_gq_variants;
+                        global_filtering_stats.multi_allelic_variants += local_filtering_stats.multi_allelic_variants;
+                        global_filtering_stats.low_maf_variants += local_filtering_stats.low_maf_variants;
+                        global_filtering_stats.hwe_filtered_count += local_filtering_stats.hwe_filtered_count;
+                        global_filtering_stats.unphased_variants += local_filtering_stats.unphased_variants;
+                        global_filtering_stats.info_filtered_count += local_filtering_stats.info_filtered_count;
+                        global_filtering_stats.qual_filtered_count += local_filtering_stats.qual_filtered_count;
+                        global_filtering_stats.missing_qual_count += local_filtering_stats.missing_qual_count;
+                        global_filtering_stats.structural_variant_skipped += local_filtering_stats.structural_variant_skipped;
+                        global_filtering_stats.indels_skipped += local_filtering_stats.indels_skipped;
+                        global_filtering_stats.cpg_excluded += local_filtering_stats.cpg_excluded;
+                        global_filtering_stats.variant_type_skipped += local_filtering_stats.variant_type_skipped;
+                        for (variant_type, count) in local_filtering_stats.variant_type_counts {
+                            *global_filtering_stats.variant_type_counts.entry(variant_type).or_insert(0) += count;
+                        }
+
+                        for example in local_filtering_stats.filtered_examples.iter() {
+                            global_filtering_stats.add_example(example.clone());
+                        }
+                    },
+                    Err((line_num, e)) => {
+                        // Record the error but continue consuming messages
+                        error!("Error processing variant: {}", e);
+                        if let Some(stream) = unfiltered_stream.as_mut() {
+                            stream.receive(line_num, false, Vec::new());
+                        }
+                    },
+                }
+            }
+            Ok(unfiltered_stream)
+        }
+    });
+
+    // Wait for all threads to complete
+    producer_thread.join().expect("Producer thread panicked")?;
+    for thread in consumer_threads {
+        thread.join().expect("Consumer thread panicked")?;
+    }
+    // Signal completion before joining collector
+    processing_complete.store(true, Ordering::Relaxed);
+    
+    // All consumers must have finished and dropped their Arc references
+    drop(result_sender);
+    
+    // Now join collector thread
+    let unfiltered_stream = collector_thread.join().expect("Collector thread panicked")?;
+
+    // Wait for the progress thread to finish
+    progress_thread.join().expect("Couldn't join progress thread");
+
+    {
+        let seqinfo = seqinfo_storage.lock();
+        if !seqinfo.is_empty() {
+            display_seqinfo_entries(&seqinfo, 12);
+        } else {
+            debug!("No SeqInfo entries were stored.");
+        }
+    }
+    
+    let final_unfiltered_variants = Arc::try_unwrap(unfiltered_variants)
+        .map_err(|_| VcfError::Parse("Unfiltered variants still have multiple owners".to_string()))?
+        .into_inner();
+    let final_filtered_variants = Arc::try_unwrap(filtered_variants)
+        .map_err(|_| VcfError::Parse("Filtered variants still have multiple owners".to_string()))?
+        .into_inner();
+
+    // Consumer threads parse lines concurrently, so the collector sees them in arrival order;
+    // sorting on the line number as well makes the order, and the record kept below, independent of timing
+    let mut final_unfiltered_variants = sort_collected_variants(final_unfiltered_variants);
+    let mut final_filtered_variants = sort_collected_variants(final_filtered_variants);
+
+    let (streamed_stats, streamed_duplicates) = match unfiltered_stream {
+        Some(stream) => {
+            let (stats, duplicates, unsorted) = stream.finish();
+            if unsorted {
+                warn!(
+                    "{} is not sorted by position; repeated positions that are not adjacent were each counted",
+                    file.display()
+                );
+            }
+            (Some(stats), duplicates)
+        }
+        None => (None, 0),
+    };
+
+    // Merged cohorts can repeat a position; split multi-allelic records share theirs on purpose
+    if multiallelic != MultiallelicMode::Split {
+        let duplicates = dedup_by_position(&mut final_unfiltered_variants) + streamed_duplicates;
+        dedup_by_position(&mut final_filtered_variants);
+        if duplicates > 0 {
+            warn!(
+                "Removed {} duplicate positions from {}; kept the first FILTER=PASS record at each, or the first record",
+                duplicates,
+                file.display()
+            );
+        }
+    }
+            
+    let final_missing_data_info = Arc::try_unwrap(missing_data_info)
+        .map_err(|_| VcfError::Parse("Missing data info still have multiple owners".to_string()))?
+        .into_inner();
+    let final_filtering_stats = Arc::try_unwrap(_filtering_stats)
+        .map_err(|_| VcfError::Parse("Filtering stats still have multiple owners".to_string()))?
+        .into_inner();
+
+    // A missing QUAL counts as 0, so a call set without QUAL values loses most of its variants
+    if qual_threshold > 0.0 && final_filtering_stats.missing_qual_count * 10 > final_filtering_stats.total_variants {
+        warn!(
+            "QUAL is missing (.) for {} of {} variants; --qual_threshold treats these as QUAL 0",
+            final_filtering_stats.missing_qual_count, final_filtering_stats.total_variants
+        );
+    }
+
+    // Haplotype groups pick allele 0 or 1 of each sample, which needs phased calls
+    let unphased = final_filtering_stats.unphased_variants;
+    if unphased as f64 > 0.05 * final_filtering_stats.total_variants as f64 {
+        if require_phased {
+            warn!(
+                "{} of {} variants have unphased genotypes, which were treated as missing",
+                unphased, final_filtering_stats.total_variants
+            );
+        } else {
+            warn!(
+                "{} of {} variants have unphased genotypes; haplotype assignments at these sites are arbitrary (see --require_phased)",
+                unphased, final_filtering_stats.total_variants
+            );
+        }
+    }
+
+    let sample_names = Arc::try_unwrap(sample_names)
+        .map_err(|_| VcfError::Parse("Sample names have multiple owners".to_string()))?;
+
+    // Concordance depends on the chromosome's genotypes, so it is reported for every VCF
+    if report_samples || concordance_check {
+        report_duplicate_samples(&sample_names, &final_unfiltered_variants, concordance_check);
+    }
+
+    Ok((
+        final_unfiltered_variants,
+        final_filtered_variants,
+        sample_names,
+        chr_length,
+        final_missing_data_info,
+        final_filtering_stats,
+        streamed_stats,
+    ))
+}
+
+// Records read through a tabix index have no file line number, so their field errors name CHROM:POS instead
+pub fn locate_indexed_record_error(error: VcfError, line: &str) -> VcfError {
+    let mut fields = line.split('\t');
+    let location = match (fields.next(), fields.next()) {
+        (Some(chrom), Some(pos)) if !chrom.is_empty() && !pos.trim().is_empty() => format!("{}:{}", chrom, pos.trim()),
+        _ => "indexed record".to_string(),
+    };
+    match error {
+        VcfError::MissingField { field, .. } => VcfError::Parse(format!("{}: missing {} field", location, field)),
+        VcfError::BadValue { field, value, .. } => {
+            VcfError::Parse(format!("{}: invalid {} value '{}'", location, field, value))
+        }
+        other => other,
+    }
+}
+
+// Warn about VCF columns that share a config sample ID; with --concordance_check also compare their genotypes
+pub fn report_duplicate_samples(sample_names: &[String], variants: &[Variant], concordance_check: bool) {
+    for (sample_id, first, second) in find_duplicate_sample_ids(sample_names) {
+        if !concordance_check {
+            warn!(
+                "Samples {} and {} share the ID {}; config entries match the later one (see --concordance_check)",
+                sample_names[first], sample_names[second], sample_id
+            );
+            continue;
+        }
+        let column = |i: usize| -> Vec<Option<Vec<u8>>> {
+            variants.iter().map(|v| v.genotypes.get(i).cloned().flatten()).collect()
+        };
+        let concordance = genotype_concordance(&column(first), &column(second));
+        info!(
+            "Genotype concordance of duplicate samples {} and {} (ID {}): {}",
+            sample_names[first],
+            sample_names[second],
+            sample_id,
+            format_f64(concordance, 4)
+        );
+    }
+}
+
+// Struct to hold CDS region information
+pub struct CdsRegion {
+    pub transcript_id: String,
+    pub segments: Vec<(i64, i64)>,
+}
+
+// IN PROGRESS
+// Write sequences to PHYLIP file
+fn write_phylip_file(
+    output_file: &Path,
+    hap_sequences: &HashMap<String, Vec<char>>,
+) -> Result<(), VcfError> {
+    let file = File::create(output_file).map_err(|e| {
+        VcfError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to create PHYLIP file '{}': {:?}", output_file.display(), e),
+        ))
+    })?;
+    let mut writer = BufWriter::new(file);
+
+    // Process and write each sequence
+    for (sample_name, seq_chars) in hap_sequences {
+        let padded_name = format!("{:<10}", sample_name);
+        let sequence: String = seq_chars.iter().collect();
+        
+        // Write sequence to PHYLIP file
+        writeln!(writer, "{}{}", padded_name, sequence).map_err(|e| {
+            VcfError::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to write to PHYLIP file '{}': {:?}", output_file.display(), e),
+            ))
+        })?;
+    }
+
+    writer.flush().map_err(|e| {
+        VcfError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to flush PHYLIP file '{}': {:?}", output_file.display(), e),
+        ))
+    })?;
+
+    debug!("PHYLIP file '{}' written successfully.", output_file.display());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    include!("tests/stats_tests.rs");
+    include!("tests/filter_tests.rs");
+}
@@ -1,549 +1,14 @@
-use clap::Parser;
-use colored::*;
-use flate2::read::MultiGzDecoder;
-use indicatif::{ProgressBar, ProgressStyle};
-use parking_lot::Mutex;
+use crate::parse::{classify_site, GffIndex, SiteClass};
+use crate::process::*;
 use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
-use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader};
-use std::io::{BufWriter, Write};
-use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::collections::{HashMap, HashSet};
-use csv::{WriterBuilder};
-use crossbeam_channel::bounded;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use std::thread;
-use prettytable::{Table, row};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use statrs::distribution::{Beta, ContinuousCDF};
 
-// Define command-line arguments using clap
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    // Folder containing VCF files
-    #[arg(short, long = "vcf_folder")]
-    vcf_folder: String,
-
-    // Chromosome to process
-    #[arg(short, long = "chr")]
-    chr: Option<String>,
-
-    // Region to process (start-end)
-    #[arg(short, long = "region")]
-    region: Option<String>,
-
-    // Configuration file
-    #[arg(long = "config_file")]
-    config_file: Option<String>,
-
-    // Output file
-    #[arg(short, long = "output_file")]
-    output_file: Option<String>,
-
-    // Minimum genotype quality
-    #[arg(long = "min_gq", default_value = "30")]
-    min_gq: u16,
-
-    // Mask file (regions to exclude)
-    #[arg(long = "mask_file")]
-    mask_file: Option<String>,
-
-    // Allow file (regions to include)
-    #[arg(long = "allow_file")]
-    allow_file: Option<String>,
-
-    #[arg(long = "reference")]
-    reference_path: String,
-
-    #[arg(long = "gff")]
-    gff_path: String,
-}
-
-// Data structures
-#[derive(Debug, Clone)]
-struct ConfigEntry {
-    seqname: String,
-    start: i64,
-    end: i64,
-    samples_unfiltered: HashMap<String, (u8, u8)>,
-    samples_filtered: HashMap<String, (u8, u8)>,
-}
-
-#[derive(Debug)]
-struct RegionStats {
-    chr: String,
-    region_start: i64,
-    region_end: i64,
-    sequence_length: i64,
-    segregating_sites: usize,
-    w_theta: f64,
-    pi: f64,
-}
-
-#[derive(Debug, Default)]
-struct FilteringStats {
-    total_variants: usize,
-    _filtered_variants: usize,
-    filtered_due_to_mask: usize,
-    filtered_due_to_allow: usize,
-    filtered_positions: HashSet<i64>,
-    missing_data_variants: usize,
-    low_gq_variants: usize,
-    multi_allelic_variants: usize,
-    filtered_examples: Vec<String>,
-}
-
-impl FilteringStats {
-    // Adds an example if there are fewer than 5
-    fn add_example(&mut self, example: String) {
-        if self.filtered_examples.len() < 5 {
-            // println!("Adding example - {}", example); // Debug
-            self.filtered_examples.push(example);
-        }
-    }
-}
-
-#[derive(PartialEq, Debug, Clone)]
-struct Variant {
-    position: i64,
-    genotypes: Vec<Option<Vec<u8>>>,
-}
-
-// IN PROGRESS
-#[derive(Debug, Clone)]
-struct SeqInfo {
-    sample_index: usize,         // The index of the sample this allele belongs to
-    haplotype_group: u8,         // 0 or 1 for haplotype group
-    vcf_allele: Option<u8>,      // The VCF allele value (0 or 1) (can be None)
-    nucleotide: Option<u8>,      // The allele nucleotide (A, T, C, G) in u8 form (can be None)
-    chromosome: String,          // Chromosome identifier
-    position: i64,               // Chromosome position
-    filtered: bool,              // Was this allele filtered or not
-}
-
-#[derive(Debug, Default, Clone)]
-struct MissingDataInfo {
-    total_data_points: usize,
-    missing_data_points: usize,
-    positions_with_missing: HashSet<i64>,
-}
-
-// Custom error types
-#[derive(Debug)]
-enum VcfError {
-    Io(io::Error),
-    Parse(String),
-    InvalidRegion(String),
-    NoVcfFiles,
-    InvalidVcfFormat(String),
-    ChannelSend,
-    ChannelRecv,
-}
-
-impl<T> From<crossbeam_channel::SendError<T>> for VcfError {
-    fn from(_: crossbeam_channel::SendError<T>) -> Self {
-        VcfError::ChannelSend
-    }
-}
-
-impl From<crossbeam_channel::RecvError> for VcfError {
-    fn from(_: crossbeam_channel::RecvError) -> Self {
-        VcfError::ChannelRecv
-    }
-}
-
-impl std::fmt::Display for VcfError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            VcfError::Io(err) => write!(f, "IO error: {}", err),
-            VcfError::Parse(msg) => write!(f, "Parse error: {}", msg),
-            VcfError::InvalidRegion(msg) => write!(f, "Invalid region: {}", msg),
-            VcfError::NoVcfFiles => write!(f, "No VCF files found"),
-            VcfError::InvalidVcfFormat(msg) => write!(f, "Invalid VCF format: {}", msg),
-            VcfError::ChannelSend => write!(f, "Error sending data through channel"),
-            VcfError::ChannelRecv => write!(f, "Error receiving data from channel"),
-        }
-    }
-}
-
-impl From<io::Error> for VcfError {
-    fn from(err: io::Error) -> VcfError {
-        VcfError::Io(err)
-    }
-}
-
-// Main function
-fn main() -> Result<(), VcfError> {
-    let args = Args::parse();
-
-    // Set Rayon to use all logical CPUs
-    let num_logical_cpus = num_cpus::get();
-    ThreadPoolBuilder::new()
-        .num_threads(num_logical_cpus)
-        .build_global()
-        .unwrap();
-
-    // Parse the mask file (exclude regions)
-    let mask_regions = if let Some(mask_file) = args.mask_file.as_ref() {
-        println!("Mask file provided: {}", mask_file);
-        Some(Arc::new(parse_regions_file(Path::new(mask_file))?))
-    } else {
-        None
-    };
-
-    // Parse the allow file (include regions)
-    let allow_regions = if let Some(allow_file) = args.allow_file.as_ref() {
-        println!("Mask file provided: {}", allow_file);
-        let parsed_allow = parse_regions_file(Path::new(allow_file))?;
-        println!("Parsed Allow Regions: {:?}", parsed_allow);
-        Some(Arc::new(parsed_allow))
-    } else {
-        None
-    };
-
-    println!("{}", "Starting VCF diversity analysis...".green());
-
-    if let Some(config_file) = args.config_file.as_ref() {
-        println!("Config file provided: {}", config_file);
-        let config_entries = parse_config_file(Path::new(config_file))?;
-        for entry in &config_entries {
-            println!("Config entry chromosome: {}", entry.seqname);
-        }
-        let output_file = args
-            .output_file
-            .as_ref()
-            .map(Path::new)
-            .unwrap_or_else(|| Path::new("output.csv"));
-        println!("Output file: {}", output_file.display());
-        process_config_entries(
-            &config_entries,
-            &args.vcf_folder,
-            output_file,
-            args.min_gq,
-            mask_regions.clone(),
-            allow_regions.clone(),
-            &args,
-        )?;
-    } else if let Some(chr) = args.chr.as_ref() {
-        println!("Chromosome provided: {}", chr);
-        let (start, end) = if let Some(region) = args.region.as_ref() {
-            println!("Region provided: {}", region);
-            parse_region(region)?
-        } else {
-            println!("No region provided, using default region covering most of the chromosome.");
-            (1, i64::MAX)
-        };
-        let vcf_file = find_vcf_file(&args.vcf_folder, chr)?;
-
-        println!(
-            "{}",
-            format!("Processing VCF file: {}", vcf_file.display()).cyan()
-        );
-        
-        let ref_sequence = read_reference_sequence(
-            &Path::new(&args.reference_path),
-            chr,
-            start,
-            end
-        )?;
-        
-        let cds_regions = parse_gff_file(
-            &Path::new(&args.gff_path),
-            chr,
-            start,
-            end
-        )?;
-        
-
-        println!(
-            "{}",
-            format!("Processing VCF file: {}", vcf_file.display()).cyan()
-        );
-
-        // Initialize shared SeqInfo storage
-        let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
-
-        let position_allele_map = Arc::new(Mutex::new(HashMap::<i64, (char, char)>::new()));
-
-        // Process the VCF file
-        let (
-            unfiltered_variants,
-            _filtered_variants,
-            sample_names,
-            chr_length,
-            missing_data_info,
-            _filtering_stats,
-        ) = process_vcf(
-            &vcf_file,
-            &Path::new(&args.reference_path),
-            &chr,
-            start,
-            end,
-            args.min_gq,
-            mask_regions.clone(),
-            allow_regions.clone(),
-            Arc::clone(&seqinfo_storage), // Pass the storage
-            Arc::clone(&position_allele_map),
-        )?;
-        
-        {
-            let seqinfo = seqinfo_storage.lock();
-            if !seqinfo.is_empty() {
-                display_seqinfo_entries(&seqinfo, 12);
-            } else {
-                println!("No SeqInfo entries were stored.");
-            }
-        }
-        println!("{}", "Calculating diversity statistics...".blue());
-
-        let seq_length = if end == i64::MAX {
-            unfiltered_variants
-                .last()
-                .map(|v| v.position)
-                .unwrap_or(0)
-                .max(chr_length)
-                - (start - 1)
-        } else {
-            end - (start - 1)
-        };
-
-        if end == i64::MAX
-            && unfiltered_variants
-                .last()
-                .map(|v| v.position)
-                .unwrap_or(0)
-                < chr_length
-        {
-            println!("{}", "Warning: The sequence length may be underestimated. Consider using the --region parameter for more accurate results.".yellow());
-        }
-
-        let num_segsites = count_segregating_sites(&unfiltered_variants); // Also need filtered here? Output required in csv: 0_segregating_sites_filtered, 1_segregating_sites_filtered
-        let raw_variant_count = unfiltered_variants.len();
-
-        let n = sample_names.len();
-        if n == 0 {
-            return Err(VcfError::Parse(
-                "No samples found after processing VCF.".to_string(),
-            ));
-        }
-
-        let pairwise_diffs = calculate_pairwise_differences(&unfiltered_variants, n); // Also need filtered here?
-        let tot_pair_diff: usize = pairwise_diffs.iter().map(|&(_, count, _)| count).sum();
-
-        let w_theta = calculate_watterson_theta(num_segsites, n, seq_length);
-        let pi = calculate_pi(tot_pair_diff, n, seq_length);
-
-        println!("\n{}", "Results:".green().bold());
-        println!("\nSequence Length:{}", seq_length);
-        println!("Number of Segregating Sites:{}", num_segsites);
-        println!("Raw Variant Count:{}", raw_variant_count);
-        println!("Watterson Theta:{:.6}", w_theta);
-        println!("pi:{:.6}", pi);
-
-        if unfiltered_variants.is_empty() {
-            println!(
-                "{}",
-                "Warning: No variants found in the specified region.".yellow()
-            );
-        }
-
-        if num_segsites == 0 {
-            println!("{}", "Warning: All sites are monomorphic.".yellow());
-        }
-
-        if num_segsites != raw_variant_count {
-            println!(
-                "{}",
-                format!(
-                    "Note: Number of segregating sites ({}) differs from raw variant count ({}).",
-                    num_segsites, raw_variant_count
-                )
-                .yellow()
-            );
-        }
-
-        println!("\n{}", "Filtering Statistics:".green().bold());
-        println!(
-            "Total variants processed: {}",
-            _filtering_stats.total_variants
-        );
-        println!(
-            "Filtered variants: {} ({:.2}%)",
-            _filtering_stats._filtered_variants,
-            (_filtering_stats._filtered_variants as f64 / _filtering_stats.total_variants as f64)
-                * 100.0
-        );
-        println!("Multi-allelic variants: {}", _filtering_stats.multi_allelic_variants);
-        println!("Low GQ variants: {}", _filtering_stats.low_gq_variants);
-        println!(
-            "Missing data variants: {}",
-            _filtering_stats.missing_data_variants
-        );
-
-        let missing_data_percentage =
-            (missing_data_info.missing_data_points as f64 / missing_data_info.total_data_points as f64) * 100.0;
-        println!("\n{}", "Missing Data Information:".yellow().bold());
-        println!(
-            "Number of missing data points: {}",
-            missing_data_info.missing_data_points
-        );
-        println!("Percentage of missing data: {:.2}%", missing_data_percentage);
-        println!(
-            "Number of positions with missing data: {}",
-            missing_data_info.positions_with_missing.len()
-        );
-    } else {
-        return Err(VcfError::Parse(
-            "Either config file or chromosome must be specified".to_string(),
-        ));
-    }
-
-    println!("{}", "Analysis complete.".green());
-    Ok(())
-}
-
-fn display_seqinfo_entries(seqinfo: &[SeqInfo], limit: usize) {
-    // Create a buffer for the table output
-    let mut output = Vec::new();
-    let mut table = Table::new();
-    
-    // Set headers
-    table.add_row(row![
-        "Index", "Sample Index", "Haplotype Group", "VCF Allele", "Nucleotide", "Chromosome", "Position", "Filtered"
-    ]);
-    
-    // Add rows
-    for (i, info) in seqinfo.iter().take(limit).enumerate() {
-        table.add_row(row![
-            i + 1,
-            info.sample_index,
-            info.haplotype_group,
-            info.vcf_allele.map(|a| a.to_string()).unwrap_or("-".to_string()),
-            info.nucleotide.map(|n| n as char).unwrap_or('N'),
-            info.chromosome,
-            info.position,
-            info.filtered
-        ]);
-    }
-    
-    // Render the table to our buffer
-    table.print(&mut output).expect("Failed to print table to buffer");
-    
-    // Now print everything atomically as a single block
-    let table_string = String::from_utf8(output).expect("Failed to convert table to string");
-    
-    // Combine all output into a single print statement
-    print!("\n{}\n{}", 
-           "Sample SeqInfo Entries:".green().bold(),
-           table_string);
-    
-    // Add the count of remaining entries if any
-    if seqinfo.len() > limit {
-        println!("... and {} more entries.", seqinfo.len() - limit);
-    }
-    
-    // Everything is flushed
-    std::io::stdout().flush().expect("Failed to flush stdout");
-}
-
-// Function to parse regions file (mask or allow)
-fn parse_regions_file(
-    path: &Path,
-) -> Result<HashMap<String, Vec<(i64, i64)>>, VcfError> {
-    let is_bed_file = path.extension().and_then(|s| s.to_str()) == Some("bed");
-
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut regions: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
-
-    for (line_num, line_result) in reader.lines().enumerate() {
-        let line = line_result?;
-        let fields: Vec<&str> = line.split_whitespace().collect();
-        if fields.len() < 3 {
-            eprintln!(
-                "{}",
-                format!("Skipping invalid line {}: '{}'", line_num + 1, line).red()
-            );
-            continue; // Skip invalid lines
-        }
-
-        let chr = fields[0].trim_start_matches("chr").to_string(); // Normalize chromosome name
-        let start: i64 = match fields[1].trim().parse() {
-            Ok(val) => val,
-            Err(_) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "Invalid start position on line {}: '{}'",
-                        line_num + 1,
-                        fields[1]
-                    )
-                    .red()
-                );
-                continue;
-            }
-        };
-        let end: i64 = match fields[2].trim().parse() {
-            Ok(val) => val,
-            Err(_) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "Invalid end position on line {}: '{}'",
-                        line_num + 1,
-                        fields[2]
-                    )
-                    .red()
-                );
-                continue;
-            }
-        };
-
-        // Adjust positions based on file type
-        let (start, end) = if is_bed_file {
-            // BED files are zero-based, half-open intervals [start, end)
-            (start, end)
-        } else {
-            // Other files are one-based, inclusive intervals [start-1, end)
-            (start - 1, end)
-        };
-
-        regions.entry(chr.clone()).or_default().push((start, end));
-    }
-
-    // Sort the intervals for each chromosome
-    for intervals in regions.values_mut() {
-        intervals.sort_by_key(|&(start, _)| start);
-    }
-
-    Ok(regions)
-}
-
-// Function to check if a position is within any of the regions
-fn position_in_regions(pos: i64, regions: &[(i64, i64)]) -> bool {
-    // pos is zero-based
-    // regions are sorted by start position
-    let mut left = 0;
-    let mut right = regions.len();
-
-    while left < right {
-        let mid = (left + right) / 2;
-        let (start, end) = regions[mid];
-        if pos < start {
-            right = mid;
-        } else if pos >= end {
-            left = mid + 1;
-        } else {
-            // pos in [start, end)
-            return true;
-        }
-    }
-    false
-}
-
-fn calculate_masked_length(region_start: i64, region_end: i64, mask: &[(i64, i64)]) -> i64 {
+pub fn calculate_masked_length(region_start: i64, region_end: i64, mask: &[(i64, i64)]) -> i64 {
     let mut total = 0;
     for &(start, end) in mask {
         let overlap_start = std::cmp::max(region_start, start);
@@ -557,929 +22,213 @@ fn calculate_masked_length(region_start: i64, region_end: i64, mask: &[(i64, i64
     total
 }
 
-fn process_variants(
-    variants: &[Variant],
-    sample_names: &[String],
-    haplotype_group: u8,
-    sample_filter: &HashMap<String, (u8, u8)>,
-    region_start: i64,
-    region_end: i64,
-    adjusted_sequence_length: Option<i64>,
-    seqinfo_storage: Arc<Mutex<Vec<SeqInfo>>>,
-    position_allele_map: Arc<Mutex<HashMap<i64, (char, char)>>>,
-    chromosome: String,
-    is_filtered_set: bool,
-    reference_sequence: &[u8],
-    cds_regions: &[CdsRegion],
-) -> Result<Option<(usize, f64, f64, usize)>, VcfError> {
-    // Map sample names to indices
-    let mut vcf_sample_id_to_index: HashMap<&str, usize> = HashMap::new();
-    for (i, name) in sample_names.iter().enumerate() {
-        let sample_id = extract_sample_id(name);
-        vcf_sample_id_to_index.insert(sample_id, i);
-    }
-
-    // Collect haplotype indices for the specified group
-    let mut haplotype_indices = Vec::new();
-    for (sample_name, &(left_tsv, right_tsv)) in sample_filter.iter() {
-        if let Some(&i) = vcf_sample_id_to_index.get(sample_name.as_str()) {
-            if left_tsv == haplotype_group as u8 {
-                haplotype_indices.push((i, 0)); // Include left haplotype
-            }
-            if right_tsv == haplotype_group as u8 {
-                haplotype_indices.push((i, 1)); // Include right haplotype
-            }
-        }
-    }
-
-    if haplotype_indices.is_empty() {
-        println!(
-            "No haplotypes found for the specified group {}.",
-            haplotype_group
-        );
-        return Ok(None);
-    }
-
-    let mut num_segsites = 0;
-    let mut tot_pair_diff = 0;
-    let n = haplotype_indices.len();
-
-    // Early return if no variants
-    if variants.is_empty() {
-        return Ok(Some((0, 0.0, 0.0, n))); // Return zero values but valid result
-    }
-
-    // Collect alleles and compute statistics
-    for variant in variants {
-        if variant.position < region_start || variant.position > region_end {
-            continue;
-        }
-
-        let mut variant_alleles = Vec::new();
-
-        for &(sample_idx, allele_idx) in &haplotype_indices {
-            let allele = variant.genotypes.get(sample_idx)
-                .and_then(|gt| gt.as_ref())
-                .and_then(|alleles| alleles.get(allele_idx))
-                .copied();
-
-
-                // Convert VCF allele to actual nucleotide
-                let nucleotide = if let Some(allele_val) = allele {
-                    let map = position_allele_map.lock();
-                    if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
-                        match allele_val {
-                            0 => Some(ref_allele as u8),
-                            1 => Some(alt_allele as u8),
-                            _ => Some(b'N')
-                        }
-                    } else {
-                        eprintln!("Warning: No allele mapping found for position {}", variant.position);
-                        Some(b'N')
-                    }
-                } else {
-                    None
-                };
-                
-
-                // Create and store SeqInfo
-                let seq_info = SeqInfo {
-                    sample_index: sample_idx,
-                    haplotype_group,
-                    vcf_allele: allele,
-                    nucleotide,
-                    chromosome: chromosome.clone(),
-                    position: variant.position,
-                    filtered: is_filtered_set, // MUST USE ACTUAL FILTERING INFO.
-                    // Perhaps since different aspects are updated in different places we can update sections of SeqInfo at a time. However, need way to ID same allele each update
-                };
-        
-                // Store SeqInfo if we have a valid nucleotide
-                if let Some(allele_val) = allele {
-                    let mut storage = seqinfo_storage.lock();
-                    storage.push(seq_info);
-                    variant_alleles.push(allele_val);
-                }
-        }
+// Windows of window_size bp starting every step_size bp, as 1-based inclusive (start, end)
+// The last window is truncated at the end of the region
+pub fn sliding_windows(start: i64, end: i64, window_size: i64, step_size: i64) -> impl Iterator<Item = (i64, i64)> {
+    let mut window_start = start;
+    let mut done = window_size <= 0 || step_size <= 0 || start > end;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let window_end = window_start.saturating_add(window_size - 1).min(end);
+        let window = (window_start, window_end);
+        // Once a window reaches the end of the region, later windows would only be subsets of it
+        done = window_end >= end;
+        window_start = window_start.saturating_add(step_size);
+        Some(window)
+    })
+}
 
-        // Determine if the variant is a segregating site
-        let unique_alleles: HashSet<u8> = variant_alleles.iter().cloned().collect();
-        if unique_alleles.len() > 1 {
-            num_segsites += 1;
-        }
+// Running sums for Watterson's theta and pi over a stream of variants, so the variants need not be kept.
+// Differing pairs at a site are the called pairs minus the pairs with identical genotypes,
+// which gives the same totals as calculate_pairwise_differences without enumerating pairs.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingDiversityAccumulator {
+    num_samples: usize,
+    variant_count: usize,
+    segregating_sites: usize,
+    total_pairwise_differences: usize,
+}
 
-        // Compute pairwise differences
-        if !variant_alleles.is_empty() {
-            for i in 0..n {
-                if let Some(&allele_i) = variant_alleles.get(i) {
-                    for j in (i + 1)..n {
-                        if let Some(&allele_j) = variant_alleles.get(j) {
-                            if allele_i != allele_j {
-                                tot_pair_diff += 1;
-                            }
-                        }
-                    }
-                }
-            }
+impl StreamingDiversityAccumulator {
+    pub fn new(num_samples: usize) -> Self {
+        StreamingDiversityAccumulator {
+            num_samples,
+            ..Default::default()
         }
     }
 
-    let seq_length = adjusted_sequence_length.unwrap_or(region_end - region_start + 1);
-    let w_theta = calculate_watterson_theta(num_segsites, n, seq_length);
-    let pi = calculate_pi(tot_pair_diff, n, seq_length);
-
-    // Process CDS regions and generate final coding sequences per transcript
-    for cds in cds_regions {
-        let transcript_id = &cds.transcript_id;
-        let cds_min = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
-        let cds_max = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
-    
-        // Map each haplotype to a combined coding sequence
-        let mut combined_sequences: HashMap<String, Vec<u8>> = HashMap::new();
-        for (sample_idx, hap_idx) in &haplotype_indices {
-            let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
-            combined_sequences.insert(sample_name, Vec::new());
-        }
-    
-        // Determine overlapping segments and append them to each haplotype’s sequence
-        let mut segment_map = Vec::new();
-        {
-            let mut current_length = 0;
-            for &(seg_start, seg_end) in &cds.segments {
-                if seg_end < region_start || seg_start > region_end {
-                    continue;
-                }
-                let overlap_start = std::cmp::max(seg_start, region_start);
-                let overlap_end = std::cmp::min(seg_end, region_end);
-                let start_offset = (overlap_start - region_start) as usize;
-                let end_offset = (overlap_end - region_start + 1) as usize;
-    
-                if end_offset > reference_sequence.len() {
-                    continue;
-                }
-    
-                let segment_ref_seq = &reference_sequence[start_offset..end_offset];
-                for (sample_idx, hap_idx) in &haplotype_indices {
-                    let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
-                    combined_sequences.get_mut(&sample_name).unwrap().extend_from_slice(segment_ref_seq);
-                }
-    
-                let segment_len = end_offset - start_offset;
-                segment_map.push((overlap_start, overlap_end, current_length));
-                current_length += segment_len;
-            }
-        }
-    
-        // Apply variants to the combined coding sequences
-        for variant in variants {
-            if variant.position < cds_min || variant.position > cds_max {
-                continue;
-            }
-            let mut pos_in_cds = None;
-            for &(seg_s, seg_e, offset) in &segment_map {
-                if variant.position >= seg_s && variant.position <= seg_e {
-                    let rel = variant.position - seg_s;
-                    let idx = offset + rel as usize;
-                    pos_in_cds = Some(idx);
-                    break;
-                }
-            }
-    
-            if let Some(pos_in_seq) = pos_in_cds {
-                for (sample_idx, hap_idx) in &haplotype_indices {
-                    if let Some(Some(alleles)) = variant.genotypes.get(*sample_idx) {
-                        if let Some(allele) = alleles.get(*hap_idx) {
-                            let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
-                            if let Some(seq) = combined_sequences.get_mut(&sample_name) {
-                                if pos_in_seq < seq.len() {
-                                    let map = position_allele_map.lock();
-                                    if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
-                                        seq[pos_in_seq] = if *allele == 0 {
-                                            ref_allele as u8
-                                        } else {
-                                            alt_allele as u8
-                                        };
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    
-        // Make sure all sequences are the same length across haplotypes
-        let full_seq_lengths: Vec<usize> = combined_sequences.values().map(|v| v.len()).collect();
-        if full_seq_lengths.is_empty() {
-            continue;
-        }
-        let final_length = full_seq_lengths[0];
-        if !full_seq_lengths.iter().all(|&l| l == final_length) {
-            continue;
-        }
-    
-    let remainder = final_length % 3;
-    if remainder != 0 {
-        eprintln!("Warning: Skipping transcript {} for haplotype group {} on chr {} because final coding sequence length ({}) is not divisible by 3.", transcript_id, haplotype_group, chromosome, final_length);
-        continue;
-    }
-
-    // Check for stop codons in the final coding sequences
-    let stop_codons = ["TAA", "TAG", "TGA"];
-    let mut skip_due_to_stop = false;
-    for (sample_name, seq) in &combined_sequences {
-        let seq_str = String::from_utf8_lossy(seq);
-        let upper_seq = seq_str.to_ascii_uppercase();
-        for i in (0..upper_seq.len()).step_by(3) {
-            if i + 2 < upper_seq.len() {
-                let codon = &upper_seq[i..i+3];
-                if stop_codons.contains(&codon) {
-                    eprintln!("Warning: Skipping transcript {} for haplotype group {} on chr {} due to internal stop codon '{}' at codon position {} in sample {}.", transcript_id, haplotype_group, chromosome, codon, i/3, sample_name);
-                    skip_due_to_stop = true;
-                    break;
-                }
-            }
-        }
-        if skip_due_to_stop {
-            break;
-        }
+    // Takes the variant, so a caller reading a VCF can count it without keeping it
+    pub fn push(&mut self, variant: Variant) {
+        self.add(&variant);
     }
 
-    if skip_due_to_stop {
-        continue;
-    }
+    // For callers that keep their variants, such as the window statistics
+    pub fn add(&mut self, variant: &Variant) {
+        self.variant_count += 1;
 
-    // Convert to char sequences and write out a single final PHYLIP file per transcript
-    let filename = format!("group_{}_{}_chr_{}_combined.phy", haplotype_group, transcript_id, chromosome);
-    let char_sequences: HashMap<String, Vec<char>> = combined_sequences
-        .into_iter()
-        .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
-        .collect();
-    write_phylip_file(&filename, &char_sequences)?;
-    }
-
-    // Print the current contents before clearing
-    {
-        let seqinfo = seqinfo_storage.lock();
-        if !seqinfo.is_empty() {
-            display_seqinfo_entries(&seqinfo, 12);
-        } else {
-            println!("No SeqInfo entries were stored.");
+        let mut genotype_counts: HashMap<&[u8], usize> = HashMap::new();
+        let mut alleles = HashSet::new();
+        for genotype in variant.genotypes.iter().take(self.num_samples).flatten() {
+            *genotype_counts.entry(genotype.as_slice()).or_insert(0) += 1;
+            alleles.extend(genotype.iter().copied());
         }
-    }
-
-    // Clear storage for next group
-    seqinfo_storage.lock().clear();
-
-    if is_filtered_set {
-        make_sequences(
-            variants,
-            sample_names,
-            haplotype_group,
-            sample_filter,
-            region_start,
-            region_end,
-            reference_sequence,
-            cds_regions,
-            position_allele_map.clone(),
-            &chromosome,
-        )?;
-    }
-    Ok(Some((num_segsites, w_theta, pi, n)))
-}
-
-fn make_sequences(
-    variants: &[Variant],
-    sample_names: &[String],
-    haplotype_group: u8,
-    sample_filter: &HashMap<String, (u8, u8)>,
-    region_start: i64,
-    region_end: i64,
-    reference_sequence: &[u8],
-    cds_regions: &[CdsRegion],
-    position_allele_map: Arc<Mutex<HashMap<i64, (char, char)>>>,
-    chromosome: &str,
-) -> Result<(), VcfError> {
-    // Map sample names to indices
-    let mut vcf_sample_id_to_index: HashMap<&str, usize> = HashMap::new();
-    for (i, name) in sample_names.iter().enumerate() {
-        let sample_id = extract_sample_id(name);
-        vcf_sample_id_to_index.insert(sample_id, i);
-    }
-
-    // Collect haplotype indices for the specified group
-    let mut haplotype_indices = Vec::new();
-    for (sample_name, &(left_tsv, right_tsv)) in sample_filter.iter() {
-        if let Some(&i) = vcf_sample_id_to_index.get(sample_name.as_str()) {
-            if left_tsv == haplotype_group as u8 {
-                haplotype_indices.push((i, 0)); // Include left haplotype
-            }
-            if right_tsv == haplotype_group as u8 {
-                haplotype_indices.push((i, 1)); // Include right haplotype
-            }
-        } else {
-            // Sample not found in VCF
+        if alleles.len() > 1 {
+            self.segregating_sites += 1;
         }
-    }
 
-    if haplotype_indices.is_empty() {
-        println!(
-            "No haplotypes found for the specified group {}.",
-            haplotype_group
-        );
-        return Ok(());
+        let pairs = |count: usize| count * count.saturating_sub(1) / 2;
+        let called: usize = genotype_counts.values().sum();
+        let identical: usize = genotype_counts.values().map(|&count| pairs(count)).sum();
+        self.total_pairwise_differences += pairs(called) - identical;
     }
 
-
-    // Initialize sequences for each sample haplotype with the reference sequence
-    let mut hap_sequences: HashMap<String, Vec<u8>> = HashMap::new();
-    for (sample_idx, hap_idx) in &haplotype_indices {
-        let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
-        hap_sequences.insert(sample_name, reference_sequence.to_vec());
+    pub fn variant_count(&self) -> usize {
+        self.variant_count
     }
 
-    // Apply variants to sequences
-    for variant in variants {
-        if variant.position >= region_start && variant.position <= region_end {
-            let pos_in_seq = (variant.position - region_start) as usize;
-            for (sample_idx, hap_idx) in &haplotype_indices {
-                if let Some(Some(alleles)) = variant.genotypes.get(*sample_idx) {
-                    if let Some(allele) = alleles.get(*hap_idx) {
-                        let sample_name = format!("{}_{}", sample_names[*sample_idx], hap_idx);
-                        if let Some(seq) = hap_sequences.get_mut(&sample_name) {
-                            if pos_in_seq >= seq.len() {
-                                eprintln!(
-                                    "Warning: Position {} is out of bounds for sequence of length {}. Skipping variant.",
-                                    pos_in_seq, seq.len()
-                                );
-                                continue;
-                            }
-                            let map = position_allele_map.lock();
-                            if let Some(&(ref_allele, alt_allele)) = map.get(&variant.position) {
-                                seq[pos_in_seq] = if *allele == 0 {
-                                    ref_allele as u8
-                                } else {
-                                    alt_allele as u8
-                                };
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    pub fn segregating_sites(&self) -> usize {
+        self.segregating_sites
     }
 
-    // Print batch statistics before CDS processing
-    if hap_sequences.is_empty() {
-        eprintln!("No haplotype sequences generated. Cannot compute batch statistics.");
-    } else {
-        let total_sequences = hap_sequences.len();
-        let mut stop_codon_or_too_short = 0;
-        let mut skipped_sequences = 0;
-        let mut not_divisible_by_three = 0;
-        let mut mid_sequence_stop = 0;
-        let mut length_modified = 0;
-
-        let stop_codons = ["TAA", "TAG", "TGA"];
-
-        // Validate all sequences once before CDS processing
-        for (_sample_name, sequence) in &hap_sequences {
-            let sequence_str = String::from_utf8_lossy(sequence);
-
-            if sequence.len() < 3 || !sequence_str.starts_with("ATG") {
-                stop_codon_or_too_short += 1;
-                skipped_sequences += 1;
-                continue;
-            }
-
-            if sequence.len() % 3 != 0 {
-                not_divisible_by_three += 1;
-                length_modified += 1;
-            }
-
-            // Check for mid-sequence stop codons
-            for i in (0..sequence.len() - 2).step_by(3) {
-                let codon = &sequence_str[i..i + 3];
-                if stop_codons.contains(&codon) {
-                    mid_sequence_stop += 1;
-                    break;
-                }
-            }
-        }
-
-        println!("\nBatch Statistics:");
-        println!(
-            "Percentage of sequences with stop codon or too short: {:.2}%",
-            (stop_codon_or_too_short as f64 / total_sequences as f64) * 100.0
-        );
-        println!(
-            "Percentage of sequences skipped: {:.2}%",
-            (skipped_sequences as f64 / total_sequences as f64) * 100.0
-        );
-        println!(
-            "Percentage of sequences not divisible by three: {:.2}%",
-            (not_divisible_by_three as f64 / total_sequences as f64) * 100.0
-        );
-        println!(
-            "Percentage of sequences with a mid-sequence stop codon: {:.2}%",
-            (mid_sequence_stop as f64 / total_sequences as f64) * 100.0
-        );
-        println!(
-            "Percentage of sequences with modified length: {:.2}%",
-            (length_modified as f64 / total_sequences as f64) * 100.0
-        );
+    pub fn total_pairwise_differences(&self) -> usize {
+        self.total_pairwise_differences
     }
 
-    // For each CDS, extract sequences and write to PHYLIP file
-    for cds in cds_regions {
-        let cds_start = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
-        let cds_end = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
-
-        // Check overlap with region
-        let mut combined_cds_sequences: HashMap<String, Vec<u8>> = HashMap::new();
-        for (name, original_seq) in &hap_sequences {
-            combined_cds_sequences.insert(name.clone(), Vec::new());
-        }
-        
-        for &(seg_start, seg_end) in &cds.segments {
-            if seg_end < region_start || seg_start > region_end {
-                continue;
-            }
-        
-            let cds_start = std::cmp::max(seg_start - 1, region_start - 1); // 0-based
-            let cds_end = std::cmp::min(seg_end, region_end);
-        
-            // Extract per-segment and append to combined_cds_sequences[name]
-        }
-        
-        // After processing all segments, write one final .phy file
-        let filename = format!(
-            "group_{}_{}_chr_{}_combined.phy",
-            haplotype_group,
-            cds.transcript_id,
-            chromosome
-        );
-        
-        // Convert combined_cds_sequences to char sequences
-        let char_sequences: HashMap<String, Vec<char>> = combined_cds_sequences
-            .into_iter()
-            .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
-            .collect();
-        
-        write_phylip_file(&filename, &char_sequences)?;
-
-        let mut combined_sequences: HashMap<String, Vec<u8>> = HashMap::new();
-
-        // After processing all segments into combined_sequences, compute final length:
-        let full_seq_lengths: Vec<usize> = combined_sequences.values().map(|v| v.len()).collect();
-        if full_seq_lengths.is_empty() { continue; }
-        let final_length = full_seq_lengths[0];
-        // Check all are equal length
-        if !full_seq_lengths.iter().all(|&l| l == final_length) {
-            eprintln!("Error: Not all sequences are the same length after concatenation. Skipping.");
-            continue;
-        }
-
-        // Check if length is multiple of 3
-        if final_length % 3 != 0 {
-            eprintln!("Warning: Skipping because final length ({}) is not divisible by 3.", final_length);
-            continue;
-        }
-
-        // For each haplotype sequence, extract CDS sequence
-        let mut cds_sequences: HashMap<String, Vec<u8>> = HashMap::new();
-        for (sample_name, seq) in &hap_sequences {
-            let start_offset = (cds_start - region_start) as usize;
-            let end_offset = (cds_end - region_start) as usize; // No +1 needed for half-open intervals
-
-            if end_offset > seq.len() {
-                eprintln!(
-                    "Warning: CDS end offset {} exceeds sequence length {} for sample {}. Skipping CDS.",
-                    end_offset, seq.len(), sample_name
-                );
-                continue;
-            }
-
-            let cds_seq = seq[start_offset..end_offset].to_vec();
-            cds_sequences.insert(sample_name.clone(), cds_seq);
-        }
-
-        let cds_start = cds.segments.iter().map(|(s,_)| *s).min().unwrap();
-        let cds_end = cds.segments.iter().map(|(_,e)| *e).max().unwrap();
-
-        if cds_sequences.is_empty() {
-            eprintln!(
-                "No CDS sequences generated for CDS region {}-{}. Skipping PHYLIP file writing.",
-                cds_start, cds_end
-            );
-            continue;
-        }
-
-        // Write sequences to PHYLIP file
-        let filename = format!(
-            "group_{}_{}_chr_{}_start_{}_end_{}.phy",
-            haplotype_group,
-            cds.transcript_id,
-            chromosome,
-            cds_start,
-            cds_end
-        );
+    pub fn watterson_theta(&self, seq_length: i64) -> f64 {
+        calculate_watterson_theta(self.segregating_sites, self.num_samples, seq_length)
+    }
 
-        
-        let filename = format!(
-            "group_{}_{}_chr_{}_combined.phy",
-            haplotype_group,
-            cds.transcript_id,
-            chromosome
-        );
-        
-        // Convert combined_sequences to char sequences
-        let char_sequences: HashMap<String, Vec<char>> = combined_sequences
-            .into_iter()
-            .map(|(name, seq)| (name, seq.into_iter().map(|b| b as char).collect()))
-            .collect();
-        
-        write_phylip_file(&filename, &char_sequences)?;
+    pub fn pi(&self, seq_length: i64) -> f64 {
+        calculate_pi(self.total_pairwise_differences, self.num_samples, seq_length)
     }
+}
 
-    Ok(())
+// Everything --chr mode reports from its unfiltered variants, summed one site at a time so that
+// process_vcf can drop each variant once counted. Each sum reuses the per-site part of the batch
+// function it stands in for, so both give the same numbers.
+#[derive(Debug, Clone)]
+pub struct StreamingChromosomeStats {
+    diversity: StreamingDiversityAccumulator,
+    ancestral: AncestralSource,
+    theta_h_sum: f64,
+    theta_l_sum: f64,
+    transitions: usize,
+    transversions: usize,
+    // (heterozygous, called) sites of each sample
+    heterozygous: Vec<(usize, usize)>,
+    last_position: Option<i64>,
 }
 
-fn process_config_entries(
-    config_entries: &[ConfigEntry],
-    vcf_folder: &str,
-    output_file: &Path,
-    min_gq: u16,
-    mask: Option<Arc<HashMap<String, Vec<(i64, i64)>>>>,
-    allow: Option<Arc<HashMap<String, Vec<(i64, i64)>>>>,
-    args: &Args,
-) -> Result<(), VcfError> {
-    // Initialize shared SeqInfo storage
-    let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
-    
-    let mut writer = WriterBuilder::new()
-        .has_headers(true)
-        .from_path(output_file)
-        .map_err(|e| VcfError::Io(e.into()))?;
-
-    // Write headers
-    writer
-        .write_record(&[
-            "chr",
-            "region_start",
-            "region_end",
-            "0_sequence_length",
-            "1_sequence_length",
-            "0_sequence_length_adjusted",
-            "1_sequence_length_adjusted",
-            "0_segregating_sites",
-            "1_segregating_sites",
-            "0_w_theta",
-            "1_w_theta",
-            "0_pi",
-            "1_pi",
-            "0_segregating_sites_filtered",
-            "1_segregating_sites_filtered",
-            "0_w_theta_filtered",
-            "1_w_theta_filtered",
-            "0_pi_filtered",
-            "1_pi_filtered",
-            "0_num_hap_no_filter",
-            "1_num_hap_no_filter",
-            "0_num_hap_filter",
-            "1_num_hap_filter",
-            "inversion_freq_no_filter",
-            "inversion_freq_filter",
-        ])
-        .map_err(|e| VcfError::Io(e.into()))?;
-
-    let position_allele_map = Arc::new(Mutex::new(HashMap::<i64, (char, char)>::new()));
-
-    // Organize regions by chromosome
-    let mut regions_per_chr: HashMap<String, Vec<&ConfigEntry>> = HashMap::new();
-    for entry in config_entries {
-        regions_per_chr
-            .entry(entry.seqname.clone())
-            .or_insert_with(Vec::new)
-            .push(entry);
-    }
-
-    for (chr, entries) in regions_per_chr {
-        println!("Processing chromosome: {}", chr);
-
-        // Read reference sequence and CDS regions once per chromosome
-        let ref_sequence = read_reference_sequence(
-            &Path::new(&args.reference_path),
-            &chr,
-            entries.iter().map(|e| e.start).min().unwrap_or(0),
-            entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX)
-        )?;
-        
-        let cds_regions = parse_gff_file(
-            &Path::new(&args.gff_path),
-            &chr,
-            entries.iter().map(|e| e.start).min().unwrap_or(0),
-            entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX)
-        )?;
-        
-        // Determine the range to process
-        let min_start = entries.iter().map(|e| e.start).min().unwrap_or(0);
-        let max_end = entries.iter().map(|e| e.end).max().unwrap_or(i64::MAX);
-    
-        // Locate the appropriate VCF file
-        let vcf_file = match find_vcf_file(vcf_folder, &chr) {
-            Ok(file) => file,
-            Err(e) => {
-                eprintln!("Error finding VCF file for {}: {:?}", chr, e);
-                continue;
-            }
-        };
-    
-        println!(
-            "Processing VCF file for chromosome {} from {} to {}",
-            chr, min_start, max_end
-        );
-    
-        // Pass the mask and allow regions (clone the Arc)
-        let variants_data = match process_vcf(
-            &vcf_file,
-            &Path::new(&args.reference_path),
-            &chr,
-            min_start,
-            max_end,
-            min_gq,
-            mask.clone(),
-            allow.clone(),
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-        ) {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("Error processing VCF file for {}: {}", chr, e);
-                continue;
-            }
-        };
-    
-        let (
-            unfiltered_variants,
-            _filtered_variants,
-            sample_names,
-            _chr_length,
-            _missing_data_info,
-            _filtering_stats,
-        ) = variants_data;
-
-        println!("\n{}", "Filtering Statistics:".green().bold());
-        println!("Total variants processed: {}", _filtering_stats.total_variants);
-        println!(
-            "Filtered variants: {} ({:.2}%)",
-            _filtering_stats._filtered_variants,
-            (_filtering_stats._filtered_variants as f64 / _filtering_stats.total_variants as f64)
-                * 100.0
-        );
-        println!("Filtered due to allow: {}", _filtering_stats.filtered_due_to_allow);
-        println!("Filtered due to mask: {}", _filtering_stats.filtered_due_to_mask);
-        println!("Multi-allelic variants: {}", _filtering_stats.multi_allelic_variants);
-        println!("Low GQ variants: {}", _filtering_stats.low_gq_variants);
-        println!("Missing data variants: {}", _filtering_stats.missing_data_variants);
-    
-        println!("\n{}", "Example Filtered Variants:".green().bold());
-        for (i, example) in _filtering_stats.filtered_examples.iter().enumerate().take(5) {
-            println!("Example {}: {}", i + 1, example);
-        }
-        if _filtering_stats.filtered_examples.len() > 5 {
-            println!(
-                "... and {} more.",
-                _filtering_stats.filtered_examples.len() - 5
-            );
+impl StreamingChromosomeStats {
+    pub fn new(num_samples: usize, ancestral: AncestralSource) -> Self {
+        StreamingChromosomeStats {
+            diversity: StreamingDiversityAccumulator::new(num_samples),
+            ancestral,
+            theta_h_sum: 0.0,
+            theta_l_sum: 0.0,
+            transitions: 0,
+            transversions: 0,
+            heterozygous: vec![(0, 0); num_samples],
+            last_position: None,
         }
+    }
 
-        // Collect all config samples for this chromosome
-        let all_config_samples: HashSet<String> = entries
-            .iter()
-            .flat_map(|entry| {
-                entry
-                    .samples_unfiltered
-                    .keys()
-                    .cloned()
-                    .chain(entry.samples_filtered.keys().cloned())
-            })
-            .collect();
+    pub fn push(&mut self, variant: Variant) {
+        self.add(&variant);
+    }
 
-        // Collect VCF sample names
-        let vcf_sample_set: HashSet<String> = sample_names
-            .iter()
-            .map(|s| extract_sample_id(s).to_string())
-            .collect();
+    pub fn add(&mut self, variant: &Variant) {
+        let site = std::slice::from_ref(variant);
+        self.theta_h_sum += fay_wu_thetas(site, self.ancestral).1;
+        self.theta_l_sum += theta_l_sum(site, self.ancestral).1;
+        let (transitions, transversions) = count_ts_tv(site);
+        self.transitions += transitions;
+        self.transversions += transversions;
+        let num_samples = self.heterozygous.len();
+        for (total, (heterozygous, called)) in self.heterozygous.iter_mut().zip(count_per_sample_heterozygous(site, num_samples)) {
+            total.0 += heterozygous;
+            total.1 += called;
+        }
+        self.last_position = Some(self.last_position.map_or(variant.position, |last| last.max(variant.position)));
+        self.diversity.add(variant);
+    }
 
-        // Find missing samples
-        let missing_samples: Vec<String> = all_config_samples
-            .difference(&vcf_sample_set)
-            .cloned()
-            .collect();
+    pub fn diversity(&self) -> &StreamingDiversityAccumulator {
+        &self.diversity
+    }
 
-        // Print warning if there are missing samples
-        if !missing_samples.is_empty() {
-            eprintln!(
-                "Warning: The following samples from config file are missing in VCF for chromosome {}: {:?}",
-                chr, missing_samples
-            );
+    // Same as calculate_theta_h over the pushed variants
+    pub fn theta_h(&self, n: usize, seq_length: i64) -> f64 {
+        if n <= 1 || seq_length <= 0 {
+            return f64::NAN;
         }
+        self.theta_h_sum / seq_length as f64
+    }
 
-        for entry in entries {
-            println!(
-                "Processing entry: {}:{}-{}",
-                entry.seqname, entry.start, entry.end
-            );
-
-            // Define regions
-            let sequence_length = entry.end - entry.start + 1;
-
-            // Calculate adjusted sequence length considering allow and mask regions
-            let adjusted_sequence_length = calculate_adjusted_sequence_length(
-                entry.start,
-                entry.end,
-                allow.as_ref().and_then(|a| a.get(&chr)),
-                mask.as_ref().and_then(|m| m.get(&chr)),
-            );
-
-            // Process haplotype_group=0 (unfiltered)
-            println!("Processing region {}-{} with {} variants", 
-                    entry.start, entry.end, unfiltered_variants.len());
-            
-            let variants_in_region: Vec<_> = unfiltered_variants.iter()
-                .filter(|v| v.position >= entry.start && v.position <= entry.end)
-                .cloned()
-                .collect();
-            println!("Found {} variants in region", variants_in_region.len());
-
-            // Add these lines before calling process_variants
-            let ref_sequence = read_reference_sequence(
-                &Path::new(&args.reference_path),
-                &chr,
-                entry.start,
-                entry.end
-            )?;
-            
-            let cds_regions = parse_gff_file(
-                &Path::new(&args.gff_path),
-                &chr,
-                entry.start,
-                entry.end
-            )?;
-            
-            let (num_segsites_0, w_theta_0, pi_0, n_hap_0_no_filter) =
-                match process_variants(
-                    &variants_in_region,
-                    &sample_names,
-                    0,
-                    &entry.samples_unfiltered,
-                    entry.start,
-                    entry.end,
-                    None,
-                    Arc::clone(&seqinfo_storage),
-                    Arc::clone(&position_allele_map),
-                    entry.seqname.clone(),
-                    false,  // unfiltered variants
-                    &ref_sequence,
-                    &cds_regions,
-                )? {
-                    Some(values) => values,
-                    None => continue, // Skip writing this record
-                };
-
-            let ref_sequence = read_reference_sequence(
-                &Path::new(&args.reference_path),
-                &chr,
-                entry.start,
-                entry.end
-            )?;
-            
-            let cds_regions = parse_gff_file(
-                &Path::new(&args.gff_path),
-                &chr,
-                entry.start,
-                entry.end
-            )?;
-
-            // Process haplotype_group=1 (unfiltered)
-            let (num_segsites_1, w_theta_1, pi_1, n_hap_1_no_filter) =
-                match process_variants(
-                    &unfiltered_variants,
-                    &sample_names,
-                    1,
-                    &entry.samples_unfiltered,
-                    entry.start,
-                    entry.end,
-                    None,
-                    Arc::clone(&seqinfo_storage),
-                    Arc::clone(&position_allele_map),
-                    entry.seqname.clone(),
-                    false,  // unfiltered variants
-                    &ref_sequence,
-                    &cds_regions,
-                )? {
-                    Some(values) => values,
-                    None => continue, // Skip writing this record
-                };
-
-            // Calculate allele frequency of inversions (no filter)
-            let inversion_freq_no_filter =
-                calculate_inversion_allele_frequency(&entry.samples_unfiltered);
-
-            // Process haplotype_group=0 (filtered)
-            let (num_segsites_0_filt, w_theta_0_filt, pi_0_filt, n_hap_0_filt) =
-                match process_variants(
-                    &_filtered_variants,
-                    &sample_names,
-                    0,
-                    &entry.samples_filtered,
-                    entry.start,
-                    entry.end,
-                    Some(adjusted_sequence_length),
-                    Arc::clone(&seqinfo_storage),
-                    Arc::clone(&position_allele_map),
-                    entry.seqname.clone(),
-                    true,  // filtered variants
-                    &ref_sequence,
-                    &cds_regions,
-                )? {
-                    Some(values) => values,
-                    None => continue, // Skip writing this record
-                };
-
-            // Process haplotype_group=1 (filtered)
-            let (num_segsites_1_filt, w_theta_1_filt, pi_1_filt, n_hap_1_filt) =
-                match process_variants(
-                    &_filtered_variants,
-                    &sample_names,
-                    1,
-                    &entry.samples_filtered,
-                    entry.start,
-                    entry.end,
-                    Some(adjusted_sequence_length),
-                    Arc::clone(&seqinfo_storage),
-                    Arc::clone(&position_allele_map),
-                    entry.seqname.clone(),
-                    true,  // filtered variants
-                    &ref_sequence,
-                    &cds_regions,
-                )? {
-                    Some(values) => values,
-                    None => continue, // Skip writing this record
-                };
-
-            // Calculate allele frequency of inversions
-            let inversion_freq_filt =
-                calculate_inversion_allele_frequency(&entry.samples_filtered);
-
-            // Write the aggregated results to CSV
-            writer
-                .write_record(&[
-                    &entry.seqname,
-                    &entry.start.to_string(),
-                    &entry.end.to_string(),
-                    &sequence_length.to_string(),          // 0_sequence_length
-                    &sequence_length.to_string(),          // 1_sequence_length
-                    &adjusted_sequence_length.to_string(), // 0_sequence_length_adjusted
-                    &adjusted_sequence_length.to_string(), // 1_sequence_length_adjusted
-                    &num_segsites_0.to_string(),           // 0_segregating_sites
-                    &num_segsites_1.to_string(),           // 1_segregating_sites
-                    &format!("{:.6}", w_theta_0),          // 0_w_theta
-                    &format!("{:.6}", w_theta_1),          // 1_w_theta
-                    &format!("{:.6}", pi_0),               // 0_pi
-                    &format!("{:.6}", pi_1),               // 1_pi
-                    &num_segsites_0_filt.to_string(),      // 0_segregating_sites_filtered
-                    &num_segsites_1_filt.to_string(),      // 1_segregating_sites_filtered
-                    &format!("{:.6}", w_theta_0_filt),     // 0_w_theta_filtered
-                    &format!("{:.6}", w_theta_1_filt),     // 1_w_theta_filtered
-                    &format!("{:.6}", pi_0_filt),          // 0_pi_filtered
-                    &format!("{:.6}", pi_1_filt),          // 1_pi_filtered
-                    &n_hap_0_no_filter.to_string(),        // 0_num_hap_no_filter
-                    &n_hap_1_no_filter.to_string(),        // 1_num_hap_no_filter
-                    &n_hap_0_filt.to_string(),             // 0_num_hap_filter
-                    &n_hap_1_filt.to_string(),             // 1_num_hap_filter
-                    // -1.0 should never occur
-                    &format!("{:.6}", inversion_freq_no_filter.unwrap_or(-1.0)), // inversion_freq_no_filter
-                    &format!("{:.6}", inversion_freq_filt.unwrap_or(-1.0)),      // inversion_freq_filter
-                ])
-                .map_err(|e| VcfError::Io(e.into()))?;
-
-            println!(
-                "Successfully wrote record for {}:{}-{}",
-                entry.seqname, entry.start, entry.end
-            );
-            writer.flush().map_err(|e| VcfError::Io(e.into()))?;
+    // Same as calculate_theta_l over the pushed variants
+    pub fn theta_l(&self, n: usize, seq_length: i64) -> f64 {
+        if n <= 1 || seq_length <= 0 {
+            return f64::NAN;
         }
+        self.theta_l_sum / seq_length as f64
+    }
+
+    // (transitions, transversions), as count_ts_tv
+    pub fn ts_tv(&self) -> (usize, usize) {
+        (self.transitions, self.transversions)
+    }
+
+    // Same as calculate_per_sample_heterozygosity over the pushed variants
+    pub fn per_sample_heterozygosity(&self) -> Vec<f64> {
+        self.heterozygous
+            .iter()
+            .map(|&(heterozygous, called)| if called == 0 { f64::NAN } else { heterozygous as f64 / called as f64 })
+            .collect()
     }
 
-    writer.flush().map_err(|e| VcfError::Io(e.into()))?;
-    println!("Processing complete. Check the output file: {:?}", output_file);
-    Ok(())
+    pub fn last_position(&self) -> Option<i64> {
+        self.last_position
+    }
 }
 
+// Diversity statistics over all sample haplotypes of a set of variants, for one window
+pub fn calculate_window_stats(variants: &[Variant], n: usize, seq_length: i64) -> GroupStats {
+    let mut accumulator = StreamingDiversityAccumulator::new(n);
+    for variant in variants {
+        accumulator.add(variant);
+    }
+    let segregating_sites = accumulator.segregating_sites();
+    let tot_pair_diff = accumulator.total_pairwise_differences();
+    let mean_pair_diff = if n > 1 {
+        tot_pair_diff as f64 / (n * (n - 1) / 2) as f64
+    } else {
+        0.0
+    };
+
+    let w_theta = calculate_watterson_theta(segregating_sites, n, seq_length);
+    let pi = calculate_pi(tot_pair_diff, n, seq_length);
+
+    GroupStats {
+        segregating_sites,
+        w_theta,
+        pi,
+        num_haplotypes: n,
+        tajima_d: calculate_tajima_d(segregating_sites, mean_pair_diff, n),
+        fu_fs: calculate_fu_fs(count_distinct_haplotypes(variants, n), n, mean_pair_diff),
+        neutrality_index: calculate_neutrality_index(pi, w_theta),
+        // Not computed per window
+        fu_li_d_star: f64::NAN,
+        fu_li_f_star: f64::NAN,
+        fay_wu_h: f64::NAN,
+        zeng_e: f64::NAN,
+        theta_h: f64::NAN,
+        theta_l: f64::NAN,
+    }
+}
 
-fn calculate_adjusted_sequence_length(
+pub fn calculate_adjusted_sequence_length(
     region_start: i64,
     region_end: i64,
     allow_regions_chr: Option<&Vec<(i64, i64)>>,
@@ -1513,7 +262,7 @@ fn calculate_adjusted_sequence_length(
     adjusted_length
 }
 
-fn subtract_regions(
+pub fn subtract_regions(
     intervals: &Vec<(i64, i64)>,
     masks: Option<&Vec<(i64, i64)>>
 ) -> Vec<(i64, i64)> {
@@ -1558,105 +307,7 @@ fn subtract_regions(
     result
 }
 
-fn parse_config_file(path: &Path) -> Result<Vec<ConfigEntry>, VcfError> {
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .from_path(path)
-        .map_err(|e| VcfError::Io(e.into()))?;
-
-    let headers = reader.headers().map_err(|e| VcfError::Io(e.into()))?.clone();
-    let sample_names: Vec<String> = headers.iter().skip(7).map(String::from).collect();
-
-    // Check if the number of sample names is consistent
-    if sample_names.is_empty() {
-        eprintln!("{}", "Error: No sample names found in the configuration file header after skipping the first 7 columns. Tabs must separate all columns, including sample names.".red());
-        return Err(VcfError::Parse("No sample names found in config file header.".to_string()));
-    }
-
-    let mut entries = Vec::new();
-    let mut invalid_genotypes = 0;
-    let mut total_genotypes = 0;
-
-    for (line_num, result) in reader.records().enumerate() {
-        let record = result.map_err(|e| VcfError::Io(e.into()))?;
-
-        // Check if the record has the expected number of fields
-        if record.len() != headers.len() {
-            eprintln!("{}", format!("Error: Record on line {} does not have the same number of fields as the header. Expected {}, found {}. Please check for missing tabs in the config file.", line_num + 2, headers.len(), record.len()).red());
-            return Err(VcfError::Parse(format!("Mismatched number of fields in record on line {}", line_num + 2)));
-        }
-
-        // Normalize chromosome name by removing "chr" prefix
-        let seqname = record.get(0)
-            .ok_or(VcfError::Parse("Missing seqname".to_string()))?
-            .trim()
-            .trim_start_matches("chr")
-            .to_string();
-        let start: i64 = record.get(1).ok_or(VcfError::Parse("Missing start".to_string()))?.parse().map_err(|_| VcfError::Parse("Invalid start".to_string()))?;
-        let end: i64 = record.get(2).ok_or(VcfError::Parse("Missing end".to_string()))?.parse().map_err(|_| VcfError::Parse("Invalid end".to_string()))?;
-
-        let mut samples_unfiltered = HashMap::new();
-        let mut samples_filtered = HashMap::new();
-
-        for (i, field) in record.iter().enumerate().skip(7) {
-            total_genotypes += 1;
-            if i < sample_names.len() + 7 {
-                let sample_name = &sample_names[i - 7];
-                
-                // For samples_unfiltered (split on '_')
-                let genotype_str_unfiltered = field.split('_').next().unwrap_or("");
-                
-                if genotype_str_unfiltered.len() >= 3 && genotype_str_unfiltered.chars().nth(1) == Some('|') {
-                    let left_char = genotype_str_unfiltered.chars().nth(0).unwrap();
-                    let right_char = genotype_str_unfiltered.chars().nth(2).unwrap();
-                    if let (Some(left), Some(right)) = (left_char.to_digit(10), right_char.to_digit(10)) {
-                        let left = left as u8;
-                        let right = right as u8;
-                        if left <= 1 && right <= 1 {
-                            samples_unfiltered.insert(sample_name.clone(), (left, right));
-                        } else {
-                            invalid_genotypes += 1;
-                        }
-                    } else {
-                        invalid_genotypes += 1;
-                    }
-                } else {
-                    invalid_genotypes += 1;
-                }
-                
-                // For samples_filtered (exact matches)
-                if field == "0|0" || field == "0|1" || field == "1|0" || field == "1|1" {
-                    let left = field.chars().nth(0).unwrap().to_digit(10).unwrap() as u8;
-                    let right = field.chars().nth(2).unwrap().to_digit(10).unwrap() as u8;
-                    samples_filtered.insert(sample_name.clone(), (left, right));
-                }
-            } else {
-                eprintln!("Warning: More genotype fields than sample names at line {}.", line_num + 2);
-            }
-        }
-
-        if samples_unfiltered.is_empty() {
-            println!("Warning: No valid genotypes found for region {}:{}-{}", seqname, start, end);
-            continue;
-        }
-
-        entries.push(ConfigEntry {
-            seqname,
-            start,
-            end,
-            samples_unfiltered,
-            samples_filtered,
-        });
-    }
-
-    let invalid_percentage = (invalid_genotypes as f64 / total_genotypes as f64) * 100.0;
-    println!("Number of invalid genotypes: {} ({:.2}%)", invalid_genotypes, invalid_percentage);
-
-    Ok(entries)
-}
-
-
-fn calculate_inversion_allele_frequency(
+pub fn calculate_inversion_allele_frequency(
     sample_filter: &HashMap<String, (u8, u8)>,
 ) -> Option<f64> {
     let mut num_ones = 0;
@@ -1686,1203 +337,1382 @@ fn calculate_inversion_allele_frequency(
     }
 }
 
-fn parse_region(region: &str) -> Result<(i64, i64), VcfError> {
-    let parts: Vec<&str> = region.split('-').collect();
-    if parts.len() != 2 {
-        return Err(VcfError::InvalidRegion(
-            "Invalid region format. Use start-end".to_string(),
-        ));
-    }
-    let start: i64 = parts[0]
-        .parse()
-        .map_err(|_| VcfError::InvalidRegion("Invalid start position".to_string()))?;
-    let end: i64 = parts[1]
-        .parse()
-        .map_err(|_| VcfError::InvalidRegion("Invalid end position".to_string()))?;
-    if start >= end {
-        return Err(VcfError::InvalidRegion(
-            "Start position must be less than end position".to_string(),
-        ));
-    }
-    Ok((start, end))
-}
-
-fn find_vcf_file(folder: &str, chr: &str) -> Result<PathBuf, VcfError> {
-    let path = Path::new(folder);
-    let chr_specific_files: Vec<_> = fs::read_dir(path)?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            let path = entry.path();
-            let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-            let chr_pattern = format!("chr{}", chr);
-            (file_name.starts_with(&chr_pattern) || file_name.starts_with(chr)) &&
-                (file_name.ends_with(".vcf") || file_name.ends_with(".vcf.gz")) &&
-                file_name.chars().nth(chr_pattern.len()).map_or(false, |c| !c.is_ascii_digit())
+// A site segregates when its called alleles take more than one value, so a multi-allelic
+// site counts once however many ALT alleles it has
+pub fn count_segregating_sites(variants: &[Variant]) -> usize {
+    variants
+        .par_iter()
+        .filter(|v| {
+            let alleles: HashSet<_> = v.genotypes
+                .iter()
+                .flatten()
+                .flatten()
+                .collect();
+            alleles.len() > 1
         })
-        .map(|entry| entry.path())
-        .collect();
+        .count()
+}
 
-    match chr_specific_files.len() {
-        0 => Err(VcfError::NoVcfFiles),
-        1 => Ok(chr_specific_files[0].clone()),
-        _ => {
-            let exact_match = chr_specific_files.iter().find(|&file| {
-                let file_name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
-                let chr_pattern = format!("chr{}", chr);
-                (file_name.starts_with(&chr_pattern) || file_name.starts_with(chr)) &&
-                    file_name.chars().nth(chr_pattern.len()).map_or(false, |c| !c.is_ascii_digit())
-            });
-
-            if let Some(exact_file) = exact_match {
-                Ok(exact_file.clone())
-            } else {
-                println!("{}", "Multiple VCF files found:".yellow());
-                for (i, file) in chr_specific_files.iter().enumerate() {
-                    println!("{}. {}", i + 1, file.display());
-                }
-                
-                println!("Please enter the number of the file you want to use:");
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                let choice: usize = input.trim().parse().map_err(|_| VcfError::Parse("Invalid input".to_string()))?;
-                
-                chr_specific_files.get(choice - 1)
-                    .cloned()
-                    .ok_or_else(|| VcfError::Parse("Invalid file number".to_string()))
+// (transitions, transversions) among segregating biallelic SNPs; other sites are not counted
+pub fn count_ts_tv(variants: &[Variant]) -> (usize, usize) {
+    let mut transitions = 0;
+    let mut transversions = 0;
+    for variant in variants {
+        let (ref_base, alt_base) = (variant.ref_allele, variant.alt_allele);
+        if ref_base == b'N' || alt_base == b'N' || ref_base == alt_base {
+            continue;
+        }
+        let alleles: HashSet<u8> = variant.genotypes.iter().flatten().flatten().copied().collect();
+        if alleles.len() < 2 {
+            continue;
+        }
+        let purines = [b'A', b'G'];
+        if purines.contains(&ref_base) == purines.contains(&alt_base) {
+            transitions += 1;
+        } else {
+            transversions += 1;
+        }
+    }
+    (transitions, transversions)
+}
+
+// A site is a singleton if one of its alleles is carried by exactly one chromosome
+pub fn count_singletons(variants: &[Variant]) -> usize {
+    variants
+        .par_iter()
+        .filter(|v| {
+            let mut allele_counts: HashMap<u8, usize> = HashMap::new();
+            for &allele in v.genotypes.iter().flatten().flatten() {
+                *allele_counts.entry(allele).or_insert(0) += 1;
+            }
+            allele_counts.len() > 1 && allele_counts.values().any(|&count| count == 1)
+        })
+        .count()
+}
+
+// Diploid genotype classes of a site: (hom_ref, het, hom_alt), with any non-REF allele as ALT.
+// Missing and non-diploid calls are not counted.
+pub fn count_genotype_classes(variant: &Variant) -> (usize, usize, usize) {
+    let mut counts = (0, 0, 0);
+    for genotype in variant.genotypes.iter().flatten() {
+        if let [a, b] = genotype.as_slice() {
+            match usize::from(*a != 0) + usize::from(*b != 0) {
+                0 => counts.0 += 1,
+                1 => counts.1 += 1,
+                _ => counts.2 += 1,
             }
         }
     }
+    counts
 }
 
-fn open_vcf_reader(path: &Path) -> Result<Box<dyn BufRead + Send>, VcfError> {
-    let file = File::open(path)?;
-    
-    if path.extension().and_then(|s| s.to_str()) == Some("gz") {
-        let decoder = MultiGzDecoder::new(file);
-        Ok(Box::new(BufReader::new(decoder)))
+// Hardy-Weinberg exact test (Wigginton, Cutler & Abecasis 2005): the probability of a
+// heterozygote count at most as likely as the observed one, given the allele counts
+pub fn hwe_exact_test(n_hom_ref: usize, n_het: usize, n_hom_alt: usize) -> f64 {
+    let hom_rare = n_hom_ref.min(n_hom_alt);
+    let hom_common = n_hom_ref.max(n_hom_alt);
+    let genotypes = n_het + hom_rare + hom_common;
+    if genotypes == 0 {
+        return 1.0;
+    }
+    let rare_copies = 2 * hom_rare + n_het;
+
+    // Start from the most likely heterozygote count, which has the parity of rare_copies
+    let mut mid = rare_copies * (2 * genotypes - rare_copies) / (2 * genotypes);
+    if mid % 2 != rare_copies % 2 {
+        mid += 1;
+    }
+    let mid_homr = (rare_copies - mid) / 2;
+    let mid_homc = genotypes - mid - mid_homr;
+    let mut het_probs = vec![0.0; rare_copies + 1];
+    het_probs[mid] = 1.0;
+    let mut sum = 1.0;
+
+    let (mut hets, mut homr, mut homc) = (mid, mid_homr, mid_homc);
+    while hets > 1 {
+        het_probs[hets - 2] =
+            het_probs[hets] * (hets * (hets - 1)) as f64 / (4 * (homr + 1) * (homc + 1)) as f64;
+        sum += het_probs[hets - 2];
+        hets -= 2;
+        homr += 1;
+        homc += 1;
+    }
+
+    let (mut hets, mut homr, mut homc) = (mid, mid_homr, mid_homc);
+    while hets + 2 <= rare_copies {
+        het_probs[hets + 2] = het_probs[hets] * (4 * homr * homc) as f64 / ((hets + 2) * (hets + 1)) as f64;
+        sum += het_probs[hets + 2];
+        hets += 2;
+        homr -= 1;
+        homc -= 1;
+    }
+
+    let observed = het_probs[n_het];
+    let p_value: f64 = het_probs.iter().filter(|&&prob| prob <= observed).sum::<f64>() / sum;
+    p_value.min(1.0)
+}
+
+// Frequency of the alleles other than the most common one among called haplotypes, 0.0 if nothing was called
+pub fn minor_allele_frequency(variant: &Variant) -> f64 {
+    let (alt_count, total) = called_allele_counts(variant);
+    if total == 0 {
+        return 0.0;
+    }
+    // Only multi-allelic sites need the count of each ALT allele
+    let max_allele = variant.genotypes.iter().flatten().flatten().copied().max().unwrap_or(0);
+    let major = if max_allele <= 1 {
+        alt_count.max(total - alt_count)
     } else {
-        Ok(Box::new(BufReader::new(file)))
+        (0..=max_allele).map(|allele| variant.allele_count(allele).0).max().unwrap_or(0)
+    };
+    (total - major) as f64 / total as f64
+}
+
+// Differing sites of every pair of samples. Alleles are compared by index, so distinct ALT
+// alleles (1 and 2) differ from each other as well as from REF; missing calls are skipped.
+pub fn calculate_pairwise_differences(
+    variants: &[Variant],
+    n: usize,
+) -> Vec<((usize, usize), usize, Vec<i64>)> {
+    let variants = Arc::new(variants);
+
+    // Iterate over all sample indices from 0 to n - 1
+    (0..n).into_par_iter().flat_map(|i| {
+        let variants = Arc::clone(&variants);
+
+        // For each i, iterate over j from i + 1 to n - 1
+        (i+1..n).into_par_iter().map(move |j| {
+            let mut diff_count = 0;
+            let mut diff_positions = Vec::new();
+
+            // For each variant, compare genotypes of samples i and j
+            for v in variants.iter() {
+                if let (Some(gi), Some(gj)) = (&v.genotypes[i], &v.genotypes[j]) {
+                    if gi != gj {
+                        diff_count += 1;
+                        diff_positions.push(v.position);
+                    }
+                } else {
+                    // Skip if either genotype is missing
+                    continue;
+                }
+            }
+
+            // Return the pair of sample indices, difference count, and positions
+            ((i, j), diff_count, diff_positions)
+        }).collect::<Vec<_>>()
+    }).collect()
+}
+
+
+
+const EULER_GAMMA: f64 = 0.577_215_664_901_532_9;
+
+// Largest n whose harmonic number is summed exactly
+const HARMONIC_EXACT_MAX: usize = 30;
+
+pub fn harmonic(n: usize) -> f64 {
+    if n <= HARMONIC_EXACT_MAX {
+        return harmonic_exact(n);
+    }
+    // Asymptotic expansion of digamma(n + 1) + gamma; the first omitted term, 1 / (240 n^8), is below
+    // machine epsilon relative to H_n for n > 30
+    let x = n as f64;
+    let x2 = x * x;
+    x.ln() + EULER_GAMMA + 1.0 / (2.0 * x) - 1.0 / (12.0 * x2) + 1.0 / (120.0 * x2 * x2)
+        - 1.0 / (252.0 * x2 * x2 * x2)
+}
+
+// 1/1 + 1/2 + ... + 1/n
+pub fn harmonic_exact(n: usize) -> f64 {
+    (1..=n).map(|i| 1.0 / i as f64).sum()
+}
+
+// Effective population size of a diploid population from per-site theta = 4 Ne mu, with mu per site
+// per generation; only meaningful under neutrality at equilibrium
+pub fn effective_population_size(theta: f64, mutation_rate: f64) -> f64 {
+    theta / (4.0 * mutation_rate)
+}
+
+pub fn calculate_watterson_theta(seg_sites: usize, n: usize, seq_length: i64) -> f64 {
+    // Handle edge cases
+    if n <= 1 || seq_length == 0 {
+        return f64::INFINITY; // Return infinity if only 1 or fewer haplotypes or if sequence length is zero
+    }
+    
+    let harmonic_value = harmonic(n - 1);
+    if harmonic_value == 0.0 {
+        return f64::INFINITY; // Return infinity to avoid division by zero
+    }
+
+    seg_sites as f64 / harmonic_value / seq_length as f64
+}
+
+pub fn calculate_pi(tot_pair_diff: usize, n: usize, seq_length: i64) -> f64 {
+    // Handle edge cases
+    if n <= 1 || seq_length == 0 {
+        return f64::INFINITY; // Return infinity if only 1 or fewer haplotypes or if sequence length is zero
+    }
+
+    let num_comparisons = n * (n - 1) / 2;
+    if num_comparisons == 0 {
+        return f64::NAN; // Return NaN if there's somehow no valid pairwise comparison
+    }
+
+    tot_pair_diff as f64 / num_comparisons as f64 / seq_length as f64
+}
+
+// Number of differing haplotype pairs at one site; missing alleles are skipped
+pub fn site_pairwise_differences(variant: &Variant) -> usize {
+    let mut allele_counts: HashMap<u8, usize> = HashMap::new();
+    for &allele in variant.genotypes.iter().flatten().flatten() {
+        *allele_counts.entry(allele).or_insert(0) += 1;
     }
+    let m: usize = allele_counts.values().sum();
+    let same_pairs: usize = allele_counts.values().map(|&c| c * c.saturating_sub(1) / 2).sum();
+    m * m.saturating_sub(1) / 2 - same_pairs
 }
 
-// Function to collect all unique chromosome names from VCF files in the folder
-fn collect_vcf_chromosomes(vcf_folder: &str) -> Result<Vec<String>, VcfError> {
-    let path = Path::new(vcf_folder);
-    let mut chromosomes = HashSet::new();
+// Total number of differing haplotype pairs, summed over sites
+pub fn total_pairwise_differences(variants: &[Variant]) -> usize {
+    variants.iter().map(site_pairwise_differences).sum()
+}
 
-    // Iterate over all files in the VCF folder
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let file_path = entry.path();
+// Site-frequency spectrum over sites called in all n haplotypes
+// Unfolded: counts of derived alleles 1..n-1, polarised by the AA INFO tag (sites without it are skipped)
+// Folded: counts of minor alleles 1..n/2, polarised by REF
+pub fn compute_sfs(variants: &[Variant], n: usize, folded: bool) -> Vec<usize> {
+    if n < 2 {
+        return Vec::new();
+    }
+    let mut sfs = vec![0; if folded { n / 2 } else { n - 1 }];
 
-        // Process only .vcf and .vcf.gz files
-        if let Some(ext) = file_path.extension().and_then(|s| s.to_str()) {
-            if ext != "vcf" && ext != "gz" {
+    for variant in variants {
+        let count = if folded {
+            let (ref_count, called) = variant.allele_count(0);
+            if called != n {
                 continue;
             }
+            let non_ref = called - ref_count;
+            non_ref.min(n - non_ref)
         } else {
-            continue;
+            let Some(ancestral) = variant.ancestral_allele else {
+                continue;
+            };
+            let (ancestral_count, called) = variant.allele_count(ancestral);
+            if called != n {
+                continue;
+            }
+            called - ancestral_count
+        };
+        if count > 0 && count <= sfs.len() {
+            sfs[count - 1] += 1;
         }
+    }
+    sfs
+}
 
-        // Open the VCF file (handle gzipped files)
-        let file = File::open(&file_path)?;
-        let mut reader: Box<dyn BufRead> = if file_path.extension().and_then(|s| s.to_str()) == Some("gz") {
-            Box::new(BufReader::new(MultiGzDecoder::new(file)))
-        } else {
-            Box::new(BufReader::new(file))
-        };
+// Expected SFS proportions of n haplotypes under the neutral constant-size coalescent:
+// class i is proportional to 1/i, or to 1/i + 1/(n - i) when folded
+pub fn neutral_sfs(n: usize, folded: bool) -> Vec<f64> {
+    if n < 2 {
+        return Vec::new();
+    }
+    let weights: Vec<f64> = if folded {
+        (1..=n / 2)
+            .map(|i| if 2 * i == n { 1.0 / i as f64 } else { 1.0 / i as f64 + 1.0 / (n - i) as f64 })
+            .collect()
+    } else {
+        (1..n).map(|i| 1.0 / i as f64).collect()
+    };
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|weight| weight / total).collect()
+}
 
-        // Read lines until header is found
-        let mut buffer = String::new();
-        while reader.read_line(&mut buffer)? > 0 {
-            if buffer.starts_with("#CHROM") {
-                break;
+// Composite likelihood ratio of an observed SFS against expected proportions:
+// 2 * sum of k_i * ln(p_i / q_i), where p_i = k_i / S is the spectrum's own maximum-likelihood fit.
+// 0 without sites; infinite if a class the expectation rules out is observed.
+pub fn clr_score(observed_sfs: &[usize], expected_sfs: &[f64]) -> f64 {
+    let total: usize = observed_sfs.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    2.0 * observed_sfs
+        .iter()
+        .zip(expected_sfs)
+        .filter(|(&count, _)| count > 0)
+        .map(|(&count, &expected)| {
+            let observed = count as f64 / total as f64;
+            count as f64 * (observed / expected).ln()
+        })
+        .sum::<f64>()
+}
+
+// SFS counts as proportions of all counted sites; an empty spectrum stays all zero
+pub fn normalize_sfs(sfs: &[usize]) -> Vec<f64> {
+    let total: usize = sfs.iter().sum();
+    if total == 0 {
+        return vec![0.0; sfs.len()];
+    }
+    sfs.iter().map(|&count| count as f64 / total as f64).collect()
+}
+
+// SFS over sites with missing calls, projected down to a common sample size (Nielsen et al. 2005):
+// a site with k of m called alleles adds the hypergeometric probability of j in a subsample of size
+// n_proj to class j. Sites called in fewer than half of the n haplotypes are skipped, and n_proj is the
+// smallest number of called haplotypes among the rest. Returns the spectrum and n_proj.
+pub fn project_sfs(variants: &[Variant], n: usize, folded: bool) -> (Vec<f64>, usize) {
+    let sites: Vec<(usize, usize)> = variants
+        .iter()
+        .filter_map(|variant| {
+            let alleles: Vec<u8> = variant.genotypes.iter().flatten().flatten().copied().collect();
+            let m = alleles.len();
+            if m < 2 || 2 * m < n {
+                return None;
             }
-            buffer.clear();
+            let count = if folded {
+                alleles.iter().filter(|&&a| a != 0).count()
+            } else {
+                let ancestral = variant.ancestral_allele?;
+                alleles.iter().filter(|&&a| a != ancestral).count()
+            };
+            Some((m, count))
+        })
+        .collect();
+    let n_proj = match sites.iter().map(|&(m, _)| m).min() {
+        Some(n_proj) => n_proj,
+        None => return (Vec::new(), 0),
+    };
+    let max_m = sites.iter().map(|&(m, _)| m).max().unwrap_or(0);
+
+    // ln(i!) for i in 0..=max_m
+    let mut ln_factorial = vec![0.0; max_m + 1];
+    for i in 1..=max_m {
+        ln_factorial[i] = ln_factorial[i - 1] + (i as f64).ln();
+    }
+    let ln_choose = |a: usize, b: usize| ln_factorial[a] - ln_factorial[b] - ln_factorial[a - b];
+
+    let mut sfs = vec![0.0; if folded { n_proj / 2 } else { n_proj - 1 }];
+    for &(m, k) in &sites {
+        let lowest = (n_proj + k).saturating_sub(m).max(1);
+        for j in lowest..=k.min(n_proj - 1) {
+            let probability = (ln_choose(k, j) + ln_choose(m - k, n_proj - j) - ln_choose(m, n_proj)).exp();
+            let class = if folded { j.min(n_proj - j) } else { j };
+            sfs[class - 1] += probability;
         }
+    }
+    (sfs, n_proj)
+}
 
-        // Read variant records to collect chromosome names
-        for line_result in reader.lines() {
-            let line = line_result?;
-            if line.starts_with("#") {
-                continue; // Skip header lines
-            }
-            let fields: Vec<&str> = line.split('\t').collect();
-            if fields.len() < 1 {
-                continue; // Skip invalid lines
-            }
-            let vcf_chr = fields[0].trim_start_matches("chr").to_string();
-            if !vcf_chr.is_empty() {
-                chromosomes.insert(vcf_chr);
+// Pi at each variant position over n haplotypes; invariant positions are left to the caller
+pub fn calculate_per_site_pi(variants: &[Variant], n: usize) -> Vec<(i64, f64)> {
+    if n <= 1 {
+        return variants.iter().map(|v| (v.position, f64::NAN)).collect();
+    }
+    let num_comparisons = (n * (n - 1)) as f64;
+    variants
+        .par_iter()
+        .map(|v| (v.position, 2.0 * site_pairwise_differences(v) as f64 / num_comparisons))
+        .collect()
+}
+
+// Haplotype counts over chromosomes called at both sites: (called, derived at v1, derived at v2, derived at both).
+// Genotypes are paired per sample so a missing sample cannot shift the haplotypes of the next one.
+fn ld_haplotype_counts(v1: &Variant, v2: &Variant) -> (usize, usize, usize, usize) {
+    let mut counts = (0, 0, 0, 0);
+    for (g1, g2) in v1.genotypes.iter().zip(v2.genotypes.iter()) {
+        if let (Some(g1), Some(g2)) = (g1, g2) {
+            for (&a, &b) in g1.iter().zip(g2.iter()) {
+                counts.0 += 1;
+                counts.1 += usize::from(a != 0);
+                counts.2 += usize::from(b != 0);
+                counts.3 += usize::from(a != 0 && b != 0);
             }
         }
     }
+    counts
+}
 
-    let chromosomes: Vec<String> = chromosomes.into_iter().collect();
-    Ok(chromosomes)
-}
-
-
-// Function to process a VCF file
-fn process_vcf(
-    file: &Path,
-    reference_path: &Path,
-    chr: &str,
-    start: i64,
-    end: i64,
-    min_gq: u16,
-    mask_regions: Option<Arc<HashMap<String, Vec<(i64, i64)>>>>,
-    allow_regions: Option<Arc<HashMap<String, Vec<(i64, i64)>>>>,
-    seqinfo_storage: Arc<Mutex<Vec<SeqInfo>>>,
-    position_allele_map: Arc<Mutex<HashMap<i64, (char, char)>>>,
-) -> Result<(
-    Vec<Variant>,        // Unfiltered variants
-    Vec<Variant>,        // Filtered variants
-    Vec<String>,         // Sample names
-    i64,                 // Chromosome length
-    MissingDataInfo,
-    FilteringStats,
-), VcfError> {
-    let mut reader = open_vcf_reader(file)?;
-    let mut sample_names = Vec::new();
-    let chr_length = {
-        let mut fasta_reader = bio::io::fasta::IndexedReader::from_file(&reference_path)
-            .map_err(|e| VcfError::Io(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
-        // Create an owned copy of the sequences
-        let sequences = fasta_reader.index.sequences().to_vec();
-        let seq_info = sequences.iter()
-            .find(|seq| seq.name == chr || seq.name == format!("chr{}", chr))
-            .ok_or_else(|| VcfError::Parse(format!("Chromosome {} not found in reference", chr)))?;
-        seq_info.len as i64
-    };
-
-    // Existing unfiltered and filtered variants storage
-    let unfiltered_variants = Arc::new(Mutex::new(Vec::new()));
-    let filtered_variants = Arc::new(Mutex::new(Vec::new()));
+// D and the allele frequencies at both sites, None if fewer than n haplotypes are called at both
+fn linkage_disequilibrium(v1: &Variant, v2: &Variant, n: usize) -> Option<(f64, f64, f64)> {
+    let (called, derived_1, derived_2, derived_both) = ld_haplotype_counts(v1, v2);
+    if called == 0 || called < n {
+        return None;
+    }
+    let p_a = derived_1 as f64 / called as f64;
+    let p_b = derived_2 as f64 / called as f64;
+    let p_ab = derived_both as f64 / called as f64;
+    Some((p_ab - p_a * p_b, p_a, p_b))
+}
 
-    // Existing missing data and filtering stats
-    let missing_data_info = Arc::new(Mutex::new(MissingDataInfo::default()));
-    let _filtering_stats = Arc::new(Mutex::new(FilteringStats::default()));
+// r^2 between two sites, treating any non-reference allele as derived.
+// n is the number of haplotypes that must be called at both sites; None if monomorphic or too incomplete.
+pub fn calculate_r_squared(v1: &Variant, v2: &Variant, n: usize) -> Option<f64> {
+    let (d, p_a, p_b) = linkage_disequilibrium(v1, v2, n)?;
+    let denominator = p_a * (1.0 - p_a) * p_b * (1.0 - p_b);
+    if denominator == 0.0 {
+        return None;
+    }
+    Some(d * d / denominator)
+}
 
-    let is_gzipped = file.extension().and_then(|s| s.to_str()) == Some("gz");
-    let progress_bar = if is_gzipped {
-        ProgressBar::new_spinner()
+// Lewontin's D' (|D| / Dmax), same conditions as calculate_r_squared
+pub fn calculate_d_prime(v1: &Variant, v2: &Variant, n: usize) -> Option<f64> {
+    let (d, p_a, p_b) = linkage_disequilibrium(v1, v2, n)?;
+    let d_max = if d < 0.0 {
+        (p_a * p_b).min((1.0 - p_a) * (1.0 - p_b))
     } else {
-        let file_size = fs::metadata(file)?.len();
-        ProgressBar::new(file_size)
+        (p_a * (1.0 - p_b)).min((1.0 - p_a) * p_b)
     };
+    if d_max == 0.0 {
+        return None;
+    }
+    Some(d.abs() / d_max)
+}
 
-    let style = if is_gzipped {
-        ProgressStyle::default_spinner()
-            .template("{spinner:.bold.green} 🧬 {msg} 🧬 [{elapsed_precise}]")
-            .expect("Failed to create spinner template")
-            .tick_strings(&[
-                "░▒▓██▓▒░", "▒▓██▓▒░", "▓██▓▒░", "██▓▒░", "█▓▒░", "▓▒░", "▒░", "░", "▒░", "▓▒░", "█▓▒░", "██▓▒░", "▓██▓▒░", "▒▓██▓▒░"
-            ])
-    } else {
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {msg}")
-            .expect("Failed to create progress bar template")
-            .progress_chars("=>-")
-    };
+// (pos1, pos2, r^2, D') for every pair of variants at most window_bp apart
+pub fn calculate_ld_pairs(variants: &[Variant], window_bp: i64, n: usize) -> Vec<(i64, i64, f64, f64)> {
+    let mut sorted: Vec<&Variant> = variants.iter().collect();
+    sorted.sort_by_key(|v| v.position);
+    (0..sorted.len())
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let v1 = sorted[i];
+            sorted[i + 1..]
+                .iter()
+                .take_while(move |v2| v2.position - v1.position <= window_bp)
+                .filter_map(move |v2| {
+                    let r_squared = calculate_r_squared(v1, v2, n)?;
+                    let d_prime = calculate_d_prime(v1, v2, n)?;
+                    Some((v1.position, v2.position, r_squared, d_prime))
+                })
+        })
+        .collect()
+}
 
-    progress_bar.set_style(style);
+// n distance bins [start, end) spaced logarithmically from 1 bp up to and including max_bp.
+// Bins that would be empty after rounding to whole base pairs are merged away.
+pub fn log_spaced_bins(n: usize, max_bp: i64) -> Vec<(i64, i64)> {
+    if n == 0 || max_bp < 1 {
+        return Vec::new();
+    }
+    let top = (max_bp + 1) as f64;
+    let mut edges: Vec<i64> = (0..=n)
+        .map(|i| top.powf(i as f64 / n as f64).round() as i64)
+        .collect();
+    edges.dedup();
+    edges.windows(2).map(|w| (w[0], w[1])).collect()
+}
 
-    let processing_complete = Arc::new(AtomicBool::new(false));
-    let processing_complete_clone = processing_complete.clone();
+// (bin_center_bp, mean r^2, pair count) for LD pairs binned by distance; the mean is NaN for empty bins
+pub fn ld_decay_bins(ld_values: &[((i64, i64), f64)], bins: &[(i64, i64)]) -> Vec<(i64, f64, usize)> {
+    bins.iter()
+        .map(|&(start, end)| {
+            let (sum, count) = ld_values
+                .iter()
+                .filter(|((pos1, pos2), _)| {
+                    let distance = (pos2 - pos1).abs();
+                    distance >= start && distance < end
+                })
+                .fold((0.0, 0usize), |(sum, count), (_, r_squared)| (sum + r_squared, count + 1));
+            let mean = if count > 0 { sum / count as f64 } else { f64::NAN };
+            ((start + end) / 2, mean, count)
+        })
+        .collect()
+}
 
-    // Spawn a thread to update the progress bar
-    let progress_thread = thread::spawn(move || {
-        while !processing_complete_clone.load(Ordering::Relaxed) {
-            progress_bar.tick();
-            thread::sleep(Duration::from_millis(100));
-        }
-        progress_bar.finish_with_message("Variant processing complete");
-    });
-
-    // Process header
-    let mut buffer = String::new();
-    while reader.read_line(&mut buffer)? > 0 {
-        if buffer.starts_with("##") {
-            // Skip meta-information lines
-        } else if buffer.starts_with("#CHROM") {
-            validate_vcf_header(&buffer)?;
-            sample_names = buffer.split_whitespace().skip(9).map(String::from).collect();
-            break;
-        }
-        buffer.clear();
+pub fn bootstrap_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
     }
-    buffer.clear();
+}
 
-    // Set up channels for communication between threads
-    let (line_sender, line_receiver) = bounded(1000);
-    let (result_sender, result_receiver) = bounded(1000);
+// Percentile (0-100) of sorted values, interpolating linearly between ranks
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
 
-    // Spawn producer thread
-    let producer_thread = thread::spawn(move || -> Result<(), VcfError> {
-        let mut _line_count = 0;
-        while reader.read_line(&mut buffer)? > 0 {
-            line_sender.send(buffer.clone()).map_err(|_| VcfError::ChannelSend)?;
-            buffer.clear();
-            _line_count += 1;
-        }
-        drop(line_sender);
-        Ok(())
-    });
-
-    // Spawn consumer threads
-    let num_threads = num_cpus::get();
-    let sample_names = Arc::new(sample_names);
-    let consumer_threads: Vec<_> = (0..num_threads)
-        .map(|_| {
-            let line_receiver = line_receiver.clone();
-            let result_sender = result_sender.clone();
-            let chr = chr.to_string();
-            let sample_names = Arc::clone(&sample_names);
-            let mask_regions = mask_regions.clone();
-            let position_allele_map = Arc::clone(&position_allele_map);
-            
-            thread::spawn({
-                let allow_regions = allow_regions.clone();
-                move || -> Result<(), VcfError> {
-                    while let Ok(line) = line_receiver.recv() {
-                        let mut local_missing_data_info = MissingDataInfo::default();
-                        let mut local_filtering_stats = FilteringStats::default();
-                        
-                        match parse_variant(
-                            &line,
-                            &chr,
-                            start,
-                            end,
-                            &mut local_missing_data_info,
-                            &sample_names,
-                            min_gq,
-                            &mut local_filtering_stats,
-                            allow_regions.as_ref().map(|arc| arc.as_ref()),
-                            mask_regions.as_ref().map(|arc| arc.as_ref()),
-                            &position_allele_map,
-                        ) {
-                            Ok(variant_option) => {
-                                result_sender
-                                    .send(Ok((
-                                        variant_option,
-                                        local_missing_data_info,
-                                        local_filtering_stats,
-                                    )))
-                                    .map_err(|_| VcfError::ChannelSend)?;
-                            }
-                            Err(e) => {
-                                result_sender
-                                    .send(Err(e))
-                                    .map_err(|_| VcfError::ChannelSend)?;
-                            }
-                        }
-                    }
-                    Ok(())
-                }
-            })
+// 95% bootstrap interval of a statistic: sites are resampled with replacement `reps` times
+// Each replicate gets its own seed drawn from `rng`, so results are reproducible under rayon
+pub fn bootstrap_statistic<F>(
+    variants: &[Variant],
+    n: usize,
+    seq_length: i64,
+    stat_fn: F,
+    reps: usize,
+    rng: &mut impl Rng,
+) -> (f64, f64)
+where
+    F: Fn(&[Variant], usize, i64) -> f64 + Sync,
+{
+    if reps == 0 || variants.is_empty() {
+        return (f64::NAN, f64::NAN);
+    }
+
+    let seeds: Vec<u64> = (0..reps).map(|_| rng.gen()).collect();
+    let mut replicates: Vec<f64> = seeds
+        .into_par_iter()
+        .map(|seed| {
+            let mut replicate_rng = StdRng::seed_from_u64(seed);
+            let resampled: Vec<Variant> = (0..variants.len())
+                .map(|_| variants[replicate_rng.gen_range(0..variants.len())].clone())
+                .collect();
+            stat_fn(&resampled, n, seq_length)
         })
+        .filter(|value| value.is_finite())
         .collect();
+    replicates.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
-    // Collector thread
-    let collector_thread = thread::spawn({
-        let unfiltered_variants = unfiltered_variants.clone();
-        let filtered_variants = Arc::new(Mutex::new(Vec::new())); // Or let filtered_variants = filtered_variants.clone();?
-        let missing_data_info = missing_data_info.clone();
-        let _filtering_stats = _filtering_stats.clone();
-        move || -> Result<(), VcfError> {
-            while let Ok(result) = result_receiver.recv() {
-                match result {
-                    Ok((Some((variant, passes_filters)), local_missing_data_info, local_filtering_stats)) => {
-                        unfiltered_variants.lock().push(variant.clone());
-                        if passes_filters {
-                            filtered_variants.lock().push(variant);
-                        }
-                        let mut global_missing_data_info = missing_data_info.lock();
-                        global_missing_data_info.total_data_points += local_missing_data_info.total_data_points;
-                        global_missing_data_info.missing_data_points += local_missing_data_info.missing_data_points;
-                        global_missing_data_info.positions_with_missing.extend(local_missing_data_info.positions_with_missing);
-                        
-                        let mut global_filtering_stats = _filtering_stats.lock();
-                        global_filtering_stats.total_variants += local_filtering_stats.total_variants;
-                        global_filtering_stats._filtered_variants += local_filtering_stats._filtered_variants;
-                        global_filtering_stats.filtered_positions.extend(local_filtering_stats.filtered_positions);
-                        global_filtering_stats.filtered_due_to_mask += local_filtering_stats.filtered_due_to_mask;
-                        global_filtering_stats.filtered_due_to_allow += local_filtering_stats.filtered_due_to_allow;
-                        global_filtering_stats.missing_data_variants += local_filtering_stats.missing_data_variants;
-                        global_filtering_stats.low_gq_variants += local_filtering_stats.low_gq_variants;
-                        global_filtering_stats.multi_allelic_variants += local_filtering_stats.multi_allelic_variants;
-
-                        for example in local_filtering_stats.filtered_examples.iter() {
-                            global_filtering_stats.add_example(example.clone());
-                        }
-                    },
-                    Ok((None, local_missing_data_info, local_filtering_stats)) => {
-                        let mut global_missing_data_info = missing_data_info.lock();
-                        global_missing_data_info.total_data_points += local_missing_data_info.total_data_points;
-                        global_missing_data_info.missing_data_points += local_missing_data_info.missing_data_points;
-                        global_missing_data_info.positions_with_missing.extend(local_missing_data_info.positions_with_missing);
-                        
-                        let mut global_filtering_stats = _filtering_stats.lock();
-                        global_filtering_stats.total_variants += local_filtering_stats.total_variants;
-                        global_filtering_stats._filtered_variants += local_filtering_stats._filtered_variants;
-                        global_filtering_stats.filtered_positions.extend(local_filtering_stats.filtered_positions);
-                        global_filtering_stats.filtered_due_to_mask += local_filtering_stats.filtered_due_to_mask;
-                        global_filtering_stats.filtered_due_to_allow += local_filtering_stats.filtered_due_to_allow;
-                        global_filtering_stats.missing_data_variants += local_filtering_stats.missing_data_variants;
-                        global_filtering_stats.low_gq_variants += local_filtering_stats.low_gq_variants;
-                        global_filtering_stats.multi_allelic_variants += local_filtering_stats.multi_allelic_variants;
-                        for example in local_filtering_stats.filtered_examples.iter() {
-                            global_filtering_stats.add_example(example.clone());
-                        }
-                    },
-                    Err(e) => {
-                        // Record the error but continue consuming messages
-                        eprintln!("Error processing variant: {}", e);
-                    },
-                }
-            }
-            Ok(())
-        }
-    });
+    (percentile(&replicates, 2.5), percentile(&replicates, 97.5))
+}
 
-    // Wait for all threads to complete
-    producer_thread.join().expect("Producer thread panicked")?;
-    for thread in consumer_threads {
-        thread.join().expect("Consumer thread panicked")?;
+pub fn bootstrap_pi(variants: &[Variant], n: usize, seq_length: i64, reps: usize, rng: &mut impl Rng) -> (f64, f64) {
+    bootstrap_statistic(
+        variants,
+        n,
+        seq_length,
+        |v, n, len| calculate_pi(total_pairwise_differences(v), n, len),
+        reps,
+        rng,
+    )
+}
+
+pub fn bootstrap_theta(variants: &[Variant], n: usize, seq_length: i64, reps: usize, rng: &mut impl Rng) -> (f64, f64) {
+    bootstrap_statistic(
+        variants,
+        n,
+        seq_length,
+        |v, n, len| calculate_watterson_theta(count_segregating_sites(v), n, len),
+        reps,
+        rng,
+    )
+}
+
+// Tajima's D from the number of segregating sites and the mean number of pairwise differences
+pub fn calculate_tajima_d(seg_sites: usize, mean_pair_diff: f64, n: usize) -> f64 {
+    // Undefined for fewer than 3 haplotypes or no segregating sites
+    if n < 3 || seg_sites == 0 {
+        return f64::NAN;
+    }
+
+    let nf = n as f64;
+    let s = seg_sites as f64;
+    let a1 = harmonic(n - 1);
+    let a2: f64 = (1..n).map(|i| 1.0 / (i * i) as f64).sum();
+    let b1 = (nf + 1.0) / (3.0 * (nf - 1.0));
+    let b2 = 2.0 * (nf * nf + nf + 3.0) / (9.0 * nf * (nf - 1.0));
+    let c1 = b1 - 1.0 / a1;
+    let c2 = b2 - (nf + 2.0) / (a1 * nf) + a2 / (a1 * a1);
+    let e1 = c1 / a1;
+    let e2 = c2 / (a1 * a1 + a2);
+
+    (mean_pair_diff - s / a1) / (e1 * s + e2 * s * (s - 1.0)).sqrt()
+}
+
+// Neutrality index NI = pi / theta_W, a per-region ratio without the synonymous/non-synonymous split
+// NI > 1 suggests balancing selection, NI < 1 purifying or positive selection
+// None when theta_W is zero (no segregating sites)
+pub fn calculate_neutrality_index(pi: f64, theta_w: f64) -> Option<f64> {
+    if theta_w == 0.0 {
+        return None;
     }
-    // Signal completion before joining collector
-    processing_complete.store(true, Ordering::Relaxed);
-    
-    // All consumers must have finished and dropped their Arc references
-    drop(result_sender);
-    
-    // Now join collector thread
-    collector_thread.join().expect("Collector thread panicked")?;
+    Some(pi / theta_w)
+}
 
-    // Wait for the progress thread to finish
-    progress_thread.join().expect("Couldn't join progress thread");
+// Inbreeding coefficient F = 1 - H_obs / H_exp over n haplotypes, with H_exp = sum of 2p(1-p) per site.
+// Haplotypes 2k and 2k+1 are the two haplotypes of sample k, as load_haplotypes projects them;
+// only samples called on both count at a site. NaN for n < 2 or when no site varies.
+pub fn calculate_inbreeding_f(variants: &[Variant], n: usize) -> f64 {
+    if n < 2 {
+        return f64::NAN;
+    }
+    inbreeding_f(variants.iter().map(|variant| {
+        variant.genotypes[..n.min(variant.genotypes.len())].chunks_exact(2).filter_map(|pair| {
+            let allele = |genotype: &Option<Vec<u8>>| genotype.as_ref().and_then(|alleles| alleles.first().copied());
+            Some((allele(&pair[0])?, allele(&pair[1])?))
+        })
+    }))
+}
 
-    {
-        let seqinfo = seqinfo_storage.lock();
-        if !seqinfo.is_empty() {
-            display_seqinfo_entries(&seqinfo, 12);
-        } else {
-            println!("No SeqInfo entries were stored.");
+// The same F from unprojected genotypes, as config mode reads them: the first two alleles of each sample
+pub fn calculate_genotype_inbreeding_f(variants: &[Variant]) -> f64 {
+    inbreeding_f(variants.iter().map(|variant| {
+        variant.genotypes.iter().filter_map(|genotype| match genotype.as_deref() {
+            Some([a, b, ..]) => Some((*a, *b)),
+            _ => None,
+        })
+    }))
+}
+
+// F from the (first, second) alleles of the samples called at each site
+fn inbreeding_f<S, P>(sites: S) -> f64
+where
+    S: Iterator<Item = P>,
+    P: Iterator<Item = (u8, u8)>,
+{
+    let mut observed = 0.0;
+    let mut expected = 0.0;
+    for pairs in sites {
+        let mut samples = 0usize;
+        let mut derived = 0usize;
+        let mut heterozygous = 0usize;
+        for (a, b) in pairs {
+            samples += 1;
+            derived += usize::from(a != 0) + usize::from(b != 0);
+            heterozygous += usize::from((a != 0) != (b != 0));
+        }
+        if samples == 0 {
+            continue;
         }
+        let p = derived as f64 / (2 * samples) as f64;
+        observed += heterozygous as f64;
+        expected += 2.0 * p * (1.0 - p) * samples as f64;
     }
-    
-    let final_unfiltered_variants = Arc::try_unwrap(unfiltered_variants)
-        .map_err(|_| VcfError::Parse("Unfiltered variants still have multiple owners".to_string()))?
-        .into_inner();
-    let final_filtered_variants = Arc::try_unwrap(filtered_variants)
-        .map_err(|_| VcfError::Parse("Filtered variants still have multiple owners".to_string()))?
-        .into_inner();
-            
-    let final_missing_data_info = Arc::try_unwrap(missing_data_info)
-        .map_err(|_| VcfError::Parse("Missing data info still have multiple owners".to_string()))?
-        .into_inner();
-    let final_filtering_stats = Arc::try_unwrap(_filtering_stats)
-        .map_err(|_| VcfError::Parse("Filtering stats still have multiple owners".to_string()))?
-        .into_inner();
-
-    let sample_names = Arc::try_unwrap(sample_names)
-        .map_err(|_| VcfError::Parse("Sample names have multiple owners".to_string()))?;
-
-    Ok((
-        final_unfiltered_variants,
-        final_filtered_variants,
-        sample_names,
-        chr_length,
-        final_missing_data_info,
-        final_filtering_stats,
-    ))
-}
-
-
-// Function to validate VCF header
-fn validate_vcf_header(header: &str) -> Result<(), VcfError> {
-    let fields: Vec<&str> = header.split('\t').collect();
-    let required_fields = vec!["#CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO", "FORMAT"];
-
-    if fields.len() < required_fields.len() || fields[..required_fields.len()] != required_fields[..] {
-        return Err(VcfError::InvalidVcfFormat("Invalid VCF header format".to_string()));
-    }
-    Ok(())
-}
-
-
-fn read_reference_sequence(
-    fasta_path: &Path,
-    chr: &str, 
-    start: i64,
-    end: i64
-) -> Result<Vec<u8>, VcfError> {
-    // Input validation
-    if start < 0 || end < 0 {
-        return Err(VcfError::Parse(format!(
-            "Invalid coordinates: start ({}) and end ({}) must be non-negative",
-            start, end
-        )));
+    if expected == 0.0 {
+        return f64::NAN;
     }
-    
-    if start > end {
-        return Err(VcfError::Parse(format!(
-            "Invalid coordinates: start ({}) must be less than or equal to end ({})",
-            start, end
-        )));
-    }
-
-    // Create reader for the FASTA file and its index
-    let mut reader = bio::io::fasta::IndexedReader::from_file(&fasta_path)
-        .map_err(|e| VcfError::Io(io::Error::new(
-            io::ErrorKind::Other, 
-            format!("Failed to open FASTA file: {}", e)
-        )))?;
-
-    // Try both with and without "chr" prefix
-    let chr_with_prefix = if !chr.starts_with("chr") {
-        format!("chr{}", chr)
-    } else {
-        chr.to_string()
-    };
+    1.0 - observed / expected
+}
 
-    // Get sequences and find our chromosome
-    let sequences = reader.index.sequences();
-    let seq_info = sequences
-        .iter()
-        .find(|seq| seq.name == chr_with_prefix || seq.name == chr)
-        .ok_or_else(|| VcfError::Parse(format!(
-            "Chromosome {} (or {}) not found in reference", chr, chr_with_prefix
-        )))?;
-
-    let seq_length = seq_info.len;
-    let actual_chr_name = seq_info.name.as_str(); // Get a reference to the name
-
-    // Validate start position
-    if start as u64 >= seq_length {
-        return Err(VcfError::Parse(format!(
-            "Start position {} exceeds sequence length {} for chromosome {}",
-            start, seq_length, actual_chr_name
-        )));
-    }
-
-    // Clamp end position to sequence length
-    let adjusted_end = std::cmp::min(end as u64, seq_length - 1);
-    if adjusted_end as i64 != end {
-        println!("Warning: End position {} exceeds sequence length {}. Clamping to {}", 
-                 end, seq_length, adjusted_end);
-    }
-
-    // Calculate region length and allocate buffer
-    let region_length = (adjusted_end - (start as u64) + 1) as usize;
-    let mut sequence = Vec::with_capacity(region_length);
-
-    // Fetch and read the sequence with proper error handling
-    reader.fetch(actual_chr_name, (start - 1) as u64, end as u64)
-        .map_err(|e| VcfError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to fetch region {}:{}-{}: {}", actual_chr_name, start, adjusted_end, e)
-        )))?;
-
-    reader.read(&mut sequence)
-        .map_err(|e| VcfError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to read sequence for region {}:{}-{}: {}", 
-                    actual_chr_name, start, adjusted_end, e)
-        )))?;
-
-    // Verify sequence length
-    if sequence.len() != region_length {
-        return Err(VcfError::Parse(format!(
-            "Expected sequence length {} but got {} for region {}:{}-{}",
-            region_length, sequence.len(), actual_chr_name, start, adjusted_end
-        )));
-    }
-
-    // Verify sequence content
-    let invalid_chars: Vec<(usize, u8)> = sequence.iter()
-        .enumerate()
-        .filter(|(_, &b)| !matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
-        .take(10)  // Show first 10 invalid chars
-        .map(|(i, &b)| (i, b))  // Dereference here
-        .collect();
-    
-    if !invalid_chars.is_empty() {
-        println!("Found invalid characters:");
-        for (pos, ch) in invalid_chars {
-            println!("Position {}: '{}' (ASCII: {})", 
-                    pos, 
-                    String::from_utf8_lossy(&[ch]), 
-                    ch);
+// Heterozygous and called sites of each sample: (heterozygous, called)
+pub fn count_per_sample_heterozygous(variants: &[Variant], n_samples: usize) -> Vec<(usize, usize)> {
+    let mut counts = vec![(0, 0); n_samples];
+    for variant in variants {
+        for (count, genotype) in counts.iter_mut().zip(&variant.genotypes) {
+            if let Some(alleles) = genotype {
+                count.1 += 1;
+                count.0 += usize::from(alleles.iter().any(|&allele| allele != alleles[0]));
+            }
         }
-        return Err(VcfError::Parse(format!(
-            "Invalid nucleotides found in sequence for region {}:{}-{}",
-            actual_chr_name, start, adjusted_end
-        )));
     }
-
-    Ok(sequence)
+    counts
 }
 
+// Fraction of each sample's called sites where its two alleles differ (0|1, 1|0); NaN for samples never called
+pub fn calculate_per_sample_heterozygosity(variants: &[Variant], n_samples: usize) -> Vec<f64> {
+    count_per_sample_heterozygous(variants, n_samples)
+        .into_iter()
+        .map(|(heterozygous, called)| {
+            if called == 0 {
+                f64::NAN
+            } else {
+                heterozygous as f64 / called as f64
+            }
+        })
+        .collect()
+}
 
+// Fraction of sites called in both samples where the two genotypes agree, ignoring allele order
+// NaN when no site is called in both
+pub fn genotype_concordance(v1: &[Option<Vec<u8>>], v2: &[Option<Vec<u8>>]) -> f64 {
+    let mut compared = 0;
+    let mut concordant = 0;
+    for (g1, g2) in v1.iter().zip(v2.iter()) {
+        if let (Some(g1), Some(g2)) = (g1, g2) {
+            let mut g1 = g1.clone();
+            let mut g2 = g2.clone();
+            g1.sort_unstable();
+            g2.sort_unstable();
+            compared += 1;
+            concordant += usize::from(g1 == g2);
+        }
+    }
+    if compared == 0 {
+        return f64::NAN;
+    }
+    concordant as f64 / compared as f64
+}
 
-// IN PROGRESS
-// Helper function to parse GFF file and extract CDS regions
-// GTF and GFF use 1-based coordinate system
-fn parse_gff_file(
-    gff_path: &Path, 
-    chr: &str,
-    region_start: i64,
-    region_end: i64,
-) -> Result<Vec<CdsRegion>, VcfError> {
-    println!("\n{}", "Parsing GFF file...".green().bold());
-    println!("Chromosome: {}", chr);
-    println!("Region: {}-{}", region_start, region_end);
-
-    let file = File::open(gff_path).map_err(|e| {
-        VcfError::Io(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("GFF file not found: {:?}", e),
-        ))
-    })?;
-    let reader = BufReader::new(file);
-    
-    // Change to use transcript ID as key since CDS belongs to transcripts
-    let mut transcript_cdss: HashMap<String, Vec<(i64, i64, i64)>> = HashMap::new();
-    let mut skipped_lines = 0;
-    let mut processed_lines = 0;
-    let mut transcripts_found = HashSet::new();
-    let mut malformed_attributes = 0;
-
-    println!("Reading GFF entries...");
-
-    for (line_num, line_result) in reader.lines().enumerate() {
-        let line = line_result?;
-        if line.starts_with('#') {
-            continue;
-        }
+// Non-reference allele count and number of called alleles at a site: (AC, AN)
+pub fn called_allele_counts(variant: &Variant) -> (usize, usize) {
+    let (ref_count, an) = variant.allele_count(0);
+    (an - ref_count, an)
+}
 
-        let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() < 9 {
-            skipped_lines += 1;
-            continue;
-        }
+// Clopper-Pearson exact (1 - alpha) interval for a binomial proportion of k successes in n trials
+// The bounds are Beta quantiles; k = 0 and k = n pin the lower or upper bound to 0 or 1
+pub fn clopper_pearson_ci(k: usize, n: usize, alpha: f64) -> (f64, f64) {
+    if n == 0 || k > n {
+        return (0.0, 1.0);
+    }
+    let (k, n) = (k as f64, n as f64);
+    let lower = if k == 0.0 {
+        0.0
+    } else {
+        Beta::new(k, n - k + 1.0).map_or(0.0, |beta| beta.inverse_cdf(alpha / 2.0))
+    };
+    let upper = if k == n {
+        1.0
+    } else {
+        Beta::new(k + 1.0, n - k).map_or(1.0, |beta| beta.inverse_cdf(1.0 - alpha / 2.0))
+    };
+    (lower, upper)
+}
 
-        let seqname = fields[0].trim().trim_start_matches("chr");
-        if seqname != chr.trim_start_matches("chr") {
-            continue;
-        }
+// Harmonic-number coefficients shared by Fu and Li's D* and F*
+// Returns (an, bn, an+1)
+pub fn fu_li_coefficients(n: usize) -> (f64, f64, f64) {
+    let an = harmonic(n - 1);
+    let bn: f64 = (1..n).map(|i| 1.0 / (i * i) as f64).sum();
+    let an1 = an + 1.0 / n as f64;
+    (an, bn, an1)
+}
 
-        if fields[2] != "CDS" {
-            continue;
-        }
+// Fu and Li's D* (no outgroup), with the Simonsen et al. (1995) variance terms
+pub fn calculate_fu_li_d_star(seg_sites: usize, singletons: usize, n: usize) -> f64 {
+    // Undefined for fewer than 3 haplotypes or no segregating sites
+    if n < 3 || seg_sites == 0 {
+        return f64::NAN;
+    }
 
-        processed_lines += 1;
-        if processed_lines % 10000 == 0 {
-            println!("Processed {} CDS entries...", processed_lines);
-        }
+    let nf = n as f64;
+    let s = seg_sites as f64;
+    let eta_s = singletons as f64;
+    let (an, bn, an1) = fu_li_coefficients(n);
 
-        let start: i64 = match fields[3].parse() {
-            Ok(s) => s,
-            Err(_) => {
-                eprintln!("Warning: Invalid start position at line {}, skipping", line_num + 1);
-                skipped_lines += 1;
-                continue;
-            }
-        };
+    let cn = 2.0 * (nf * an - 2.0 * (nf - 1.0)) / ((nf - 1.0) * (nf - 2.0));
+    let dn = cn + (nf - 2.0) / ((nf - 1.0) * (nf - 1.0))
+        + 2.0 / (nf - 1.0) * (1.5 - (2.0 * an1 - 3.0) / (nf - 2.0) - 1.0 / nf);
 
-        let end: i64 = match fields[4].parse() {
-            Ok(e) => e,
-            Err(_) => {
-                eprintln!("Warning: Invalid end position at line {}, skipping", line_num + 1);
-                skipped_lines += 1;
-                continue;
-            }
-        };
+    let ratio = nf / (nf - 1.0);
+    let v = (ratio * ratio * bn + an * an * dn - 2.0 * nf * an * (an + 1.0) / ((nf - 1.0) * (nf - 1.0)))
+        / (an * an + bn);
+    let u = ratio * (an - ratio) - v;
 
-        if end < region_start || start > region_end {
-            continue;
-        }
+    (ratio * s - an * eta_s) / (u * s + v * s * s).sqrt()
+}
 
-        let frame: i64 = fields[7].parse().unwrap_or_else(|_| {
-            eprintln!("Warning: Invalid frame at line {}, using 0", line_num + 1);
-            0
-        });
-
-        // Parse attributes to get transcript_id and gene_name
-        let attributes = fields[8];
-        let mut transcript_id = None;
-        let mut gene_name = None;
-        
-        for attr in attributes.split(';') {
-            let attr = attr.trim();
-            let parts: Vec<&str> = if attr.contains('=') {
-                attr.splitn(2, '=').collect()
-            } else {
-                attr.splitn(2, ' ').collect()
-            };
+// Fu and Li's F* (no outgroup), with the Simonsen et al. (1995) variance terms
+// mean_pair_diff is the average number of pairwise differences (not per site)
+pub fn calculate_fu_li_f_star(seg_sites: usize, singletons: usize, mean_pair_diff: f64, n: usize) -> f64 {
+    // Undefined for fewer than 3 haplotypes or no segregating sites
+    if n < 3 || seg_sites == 0 {
+        return f64::NAN;
+    }
 
-            if parts.len() != 2 {
-                continue;
-            }
+    let nf = n as f64;
+    let s = seg_sites as f64;
+    let eta_s = singletons as f64;
+    let (an, bn, an1) = fu_li_coefficients(n);
 
-            let key = parts[0].trim();
-            let value = parts[1].trim().trim_matches('"').trim_matches('\'');
+    let v = ((2.0 * nf.powi(3) + 110.0 * nf * nf - 255.0 * nf + 153.0) / (9.0 * nf * nf * (nf - 1.0))
+        + 2.0 * (nf - 1.0) * an / (nf * nf)
+        - 8.0 * bn / nf)
+        / (an * an + bn);
+    let u = ((4.0 * nf * nf + 19.0 * nf + 3.0 - 12.0 * (nf + 1.0) * an1) / (3.0 * nf * (nf - 1.0))) / an - v;
 
-            match key {
-                "transcript_id" => transcript_id = Some(value.to_string()),
-                "gene_name" => gene_name = Some(value.to_string()),
-                _ => continue,
-            }
-        }
+    (mean_pair_diff - (nf - 1.0) / nf * eta_s) / (u * s + v * s * s).sqrt()
+}
 
-        let transcript_id = match transcript_id {
-            Some(id) => id,
-            None => {
-                malformed_attributes += 1;
-                if malformed_attributes <= 5 {
-                    eprintln!("Warning: Could not find transcript_id in attributes at line {}: {}", 
-                             line_num + 1, attributes);
-                }
-                continue;
-            }
+// Sums of theta_pi and theta_H over sites, each with its own number of called chromosomes
+// Sites without a usable ancestral allele are skipped
+fn fay_wu_thetas(variants: &[Variant], ancestral: AncestralSource) -> (f64, f64) {
+    let mut theta_pi = 0.0;
+    let mut theta_h = 0.0;
+    for variant in variants {
+        let ancestral_allele = match ancestral {
+            AncestralSource::Ref => 0,
+            AncestralSource::AaTag => match variant.ancestral_allele {
+                Some(allele) => allele,
+                None => continue,
+            },
         };
 
-        // Store for later reporting
-        if let Some(gene) = gene_name {
-            transcripts_found.insert(format!("{}:{}", gene, transcript_id));
-        } else {
-            transcripts_found.insert(transcript_id.clone());
+        let alleles: Vec<u8> = variant.genotypes.iter().flatten().flatten().copied().collect();
+        // Use the number of called chromosomes at this site as the sample size
+        let sample_size = alleles.len();
+        if sample_size <= 1 {
+            continue;
+        }
+        let derived = alleles.iter().filter(|&&a| a != ancestral_allele).count();
+        if derived == 0 || derived == sample_size {
+            continue;
         }
 
-        // Store CDS segment with frame exactly as given in GFF
-        transcript_cdss.entry(transcript_id)
-            .or_default()
-            .push((start, end, frame));
+        let i = derived as f64;
+        let m = sample_size as f64;
+        let pairs = m * (m - 1.0);
+        theta_pi += 2.0 * i * (m - i) / pairs;
+        theta_h += 2.0 * i * i / pairs;
     }
+    (theta_pi, theta_h)
+}
 
-    println!("\n{}", "GFF Parsing Statistics:".blue().bold());
-    println!("Total CDS entries processed: {}", processed_lines);
-    println!("Skipped lines: {}", skipped_lines);
-    println!("Unique transcripts found: {}", transcripts_found.len());
-    if malformed_attributes > 0 {
-        println!("{}", format!("Entries with missing transcript IDs: {}", malformed_attributes).yellow());
+// Fay and Wu's H = theta_pi - theta_H, per site
+pub fn calculate_fay_wu_h(variants: &[Variant], n: usize, seq_length: i64, ancestral: AncestralSource) -> f64 {
+    if n <= 1 || seq_length <= 0 {
+        return f64::NAN;
     }
+    let (theta_pi, theta_h) = fay_wu_thetas(variants, ancestral);
+    (theta_pi - theta_h) / seq_length as f64
+}
 
-    println!("\n{}", "Processing CDS regions by transcript...".green().bold());
-    let mut cds_regions = Vec::new();
-    let transcripts_processed = 0;
-
-    #[derive(Default)]
-    struct TranscriptStats {
-        total_transcripts: usize,
-        non_divisible_by_three: usize,
-        total_cds_segments: usize,
-        single_cds_transcripts: usize,
-        multi_cds_transcripts: usize,
-        total_coding_length: i64,
-        shortest_transcript_length: Option<i64>,
-        longest_transcript_length: Option<i64>,
-        transcripts_with_gaps: usize,
+// Fay and Wu's theta_H = sum of 2 k^2 / (n (n - 1)) over sites with k derived alleles, per site
+pub fn calculate_theta_h(variants: &[Variant], n: usize, seq_length: i64, ancestral: AncestralSource) -> f64 {
+    if n <= 1 || seq_length <= 0 {
+        return f64::NAN;
     }
+    fay_wu_thetas(variants, ancestral).1 / seq_length as f64
+}
 
-    let mut stats = TranscriptStats::default();
-
-    for (transcript_id, mut segments) in transcript_cdss {
-        segments.sort_by_key(|&(start, _, _)| start);
-        
-        println!("\nProcessing transcript: {}", transcript_id);
-        println!("Found {} CDS segments", segments.len());
-
-        stats.total_transcripts += 1;
-        stats.total_cds_segments += segments.len();
-
-        if segments.len() == 1 {
-            stats.single_cds_transcripts += 1;
-        } else {
-            stats.multi_cds_transcripts += 1;
+// Number of distinct haplotypes among variants projected to one haplotype per genotype
+// A missing allele counts as its own state, so it is never merged with a called one
+pub fn count_distinct_haplotypes(variants: &[Variant], n: usize) -> usize {
+    let mut haplotypes: Vec<Vec<u8>> = vec![Vec::with_capacity(variants.len()); n];
+    for variant in variants {
+        for (h, haplotype) in haplotypes.iter_mut().enumerate() {
+            let allele = variant
+                .genotypes
+                .get(h)
+                .and_then(|gt| gt.as_ref())
+                .and_then(|alleles| alleles.first())
+                .copied()
+                .unwrap_or(u8::MAX);
+            haplotype.push(allele);
         }
+    }
+    haplotypes.into_iter().collect::<HashSet<_>>().len()
+}
 
-        // Check for gaps between segments
-        let has_gaps = segments.windows(2)
-            .any(|w| w[1].0 - w[0].1 > 1);
-        if has_gaps {
-            stats.transcripts_with_gaps += 1;
-        }
+// ln(e^a + e^b) without overflow
+fn log_add_exp(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
 
-        // Calculate total coding length and check individual segments
-        let mut coding_segments = Vec::new();
-        for (i, &(start, end, frame)) in segments.iter().enumerate() {
-            let segment_length = end - start + 1;
-            println!("  Segment {}: {}-{} (length: {}, frame: {})", 
-                    i + 1, start, end, segment_length, frame);
-            coding_segments.push((start, end));
+// ln |s(n, k)| for k = 0..=n, unsigned Stirling numbers of the first kind
+fn log_stirling_first_kind(n: usize) -> Vec<f64> {
+    let mut row = vec![f64::NEG_INFINITY; n + 1];
+    row[0] = 0.0;
+    for m in 1..=n {
+        let log_factor = ((m - 1) as f64).ln();
+        for k in (1..=m).rev() {
+            row[k] = log_add_exp(row[k - 1], log_factor + row[k]);
         }
+        row[0] = f64::NEG_INFINITY;
+    }
+    row
+}
 
-        if segments.is_empty() {
-            println!("  {} No valid segments for transcript {}", "!".red(), transcript_id);
-            continue;
-        }
+// Fu's Fs = ln(S' / (1 - S')), where S' = P(K >= k | theta_pi) under the Ewens sampling formula (Fu 1997)
+// theta_pi is the mean number of pairwise differences, not a per-site value
+pub fn calculate_fu_fs(k: usize, n: usize, theta_pi: f64) -> f64 {
+    // Undefined without diversity or with an impossible haplotype count
+    if n < 2 || k == 0 || k > n || !theta_pi.is_finite() || theta_pi <= 0.0 {
+        return f64::NAN;
+    }
 
-        let min_start = segments.iter().map(|&(s, _, _)| s).min().unwrap();
-        let max_end = segments.iter().map(|&(_, e, _)| e).max().unwrap();
-        let transcript_span = max_end - min_start + 1;
-        
-        // Calculate actual coding length (sum of CDS lengths)
-        let total_coding_length: i64 = segments.iter()
-            .map(|&(s, e, _)| e - s + 1)
-            .sum();
-
-        stats.total_coding_length += total_coding_length;
-
-        // Update length stats
-        match stats.shortest_transcript_length {
-            None => stats.shortest_transcript_length = Some(total_coding_length),
-            Some(current) => if total_coding_length < current {
-                stats.shortest_transcript_length = Some(total_coding_length)
-            }
-        }
-        
-        match stats.longest_transcript_length {
-            None => stats.longest_transcript_length = Some(total_coding_length),
-            Some(current) => if total_coding_length > current {
-                stats.longest_transcript_length = Some(total_coding_length)
-            }
-        }
+    // P(K = j) is proportional to |s(n, j)| theta^j; the normaliser Gamma(theta + n) / Gamma(theta) cancels in the ratio
+    let log_theta = theta_pi.ln();
+    let log_terms: Vec<f64> = log_stirling_first_kind(n)
+        .into_iter()
+        .enumerate()
+        .map(|(j, log_s)| log_s + j as f64 * log_theta)
+        .collect();
+    let log_at_least_k = log_terms[k..].iter().fold(f64::NEG_INFINITY, |acc, &t| log_add_exp(acc, t));
+    let log_below_k = log_terms[1..k].iter().fold(f64::NEG_INFINITY, |acc, &t| log_add_exp(acc, t));
 
-        if total_coding_length % 3 != 0 {
-            stats.non_divisible_by_three += 1;
-            println!("  {} Warning: Total CDS length {} not divisible by 3", 
-                    "!".yellow(), total_coding_length);
-            println!("    Remainder when divided by 3: {}", total_coding_length % 3);
-            println!("    Individual segment lengths: {:?}", 
-                    segments.iter().map(|&(s, e, _)| e - s + 1).collect::<Vec<_>>());
-        }
+    // For k = 1, S' is exactly 1 and Fs is +inf
+    log_at_least_k - log_below_k
+}
 
-        let segs: Vec<(i64,i64)> = segments.iter().map(|&(s,e,_)| (s,e)).collect();
-        let cds_region = CdsRegion {
-            transcript_id: transcript_id.clone(),
-            segments: segs,
+// Segregating sites and the sum of theta_L over them: i / (n - 1) for a site with i derived alleles
+// Sites without a usable ancestral allele are skipped
+fn theta_l_sum(variants: &[Variant], ancestral: AncestralSource) -> (usize, f64) {
+    let mut seg_sites = 0;
+    let mut theta_l = 0.0;
+    for variant in variants {
+        let ancestral_allele = match ancestral {
+            AncestralSource::Ref => 0,
+            AncestralSource::AaTag => match variant.ancestral_allele {
+                Some(allele) => allele,
+                None => continue,
+            },
         };
 
-        let cds_start = cds_region.segments.iter().map(|(s, _)| *s).min().unwrap();
-        let cds_end = cds_region.segments.iter().map(|(_, e)| *e).max().unwrap();
-
-        // Create a local variable holding the cloned segments
-        let cloned_segments = cds_region.segments.clone();
-        
-        let min_start_for_print = cloned_segments.iter().map(|(s,_)| s).min().unwrap();
-        let max_end_for_print = cloned_segments.iter().map(|(_,e)| e).max().unwrap();
-        
-        println!("  CDS region: {}-{}", min_start_for_print, max_end_for_print);
-
-        // Print before pushing:
-        println!("  CDS region: {}-{}", min_start_for_print, max_end_for_print);
-
-        // Now push after printing, so no borrow occurs after move:
-        cds_regions.push(cds_region);
-
-        println!("  CDS region: {}-{}", min_start_for_print, max_end_for_print);
-        println!("    Genomic span: {}", transcript_span);
-        println!("    Total coding length: {}", total_coding_length); 
-    }
-
-    if stats.total_transcripts > 0 {
-        println!("\n{}", "CDS Processing Summary:".blue().bold());
-        println!("Total transcripts processed: {}", stats.total_transcripts);
-        println!("Total CDS segments: {}", stats.total_cds_segments);
-        println!("Average segments per transcript: {:.2}", 
-                 stats.total_cds_segments as f64 / stats.total_transcripts as f64);
-        println!("Single-cds transcripts: {} ({:.1}%)", 
-                 stats.single_cds_transcripts,
-                 100.0 * stats.single_cds_transcripts as f64 / stats.total_transcripts as f64);
-        println!("Multi-cds transcripts: {} ({:.1}%)", 
-                 stats.multi_cds_transcripts,
-                 100.0 * stats.multi_cds_transcripts as f64 / stats.total_transcripts as f64);
-        println!("Transcripts with gaps: {} ({:.1}%)",
-                 stats.transcripts_with_gaps,
-                 100.0 * stats.transcripts_with_gaps as f64 / stats.total_transcripts as f64);
-        println!("Non-divisible by three: {} ({:.1}%)", 
-                 stats.non_divisible_by_three,
-                 100.0 * stats.non_divisible_by_three as f64 / stats.total_transcripts as f64);
-        println!("Total coding bases: {}", stats.total_coding_length);
-        
-        if let Some(shortest) = stats.shortest_transcript_length {
-            println!("Shortest transcript: {} bp", shortest);
+        let alleles: Vec<u8> = variant.genotypes.iter().flatten().flatten().copied().collect();
+        // Use the number of called chromosomes at this site as the sample size
+        let sample_size = alleles.len();
+        if sample_size <= 1 {
+            continue;
         }
-        if let Some(longest) = stats.longest_transcript_length {
-            println!("Longest transcript: {} bp", longest);
+        let derived = alleles.iter().filter(|&&a| a != ancestral_allele).count();
+        if derived == 0 || derived == sample_size {
+            continue;
         }
-        println!("Average transcript length: {:.1} bp",
-                 stats.total_coding_length as f64 / stats.total_transcripts as f64);
-    }
 
-    if cds_regions.is_empty() {
-        println!("{}", "No valid CDS regions found!".red());
-    }
-
-    Ok(cds_regions)
-}
-
-
-// Struct to hold CDS region information
-struct CdsRegion {
-    transcript_id: String,
-    segments: Vec<(i64, i64)>,
-}
-
-// IN PROGRESS
-// Write sequences to PHYLIP file
-fn write_phylip_file(
-    output_file: &str,
-    hap_sequences: &HashMap<String, Vec<char>>,
-) -> Result<(), VcfError> {
-    let file = File::create(output_file).map_err(|e| {
-        VcfError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to create PHYLIP file '{}': {:?}", output_file, e),
-        ))
-    })?;
-    let mut writer = BufWriter::new(file);
-
-    // Process and write each sequence
-    for (sample_name, seq_chars) in hap_sequences {
-        let padded_name = format!("{:<10}", sample_name);
-        let sequence: String = seq_chars.iter().collect();
-        
-        // Write sequence to PHYLIP file
-        writeln!(writer, "{}{}", padded_name, sequence).map_err(|e| {
-            VcfError::Io(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to write to PHYLIP file '{}': {:?}", output_file, e),
-            ))
-        })?;
-    }
-
-    writer.flush().map_err(|e| {
-        VcfError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to flush PHYLIP file '{}': {:?}", output_file, e),
-        ))
-    })?;
-
-    println!("PHYLIP file '{}' written successfully.", output_file);
-    Ok(())
-}
-
-
-// Function to parse a variant line
-fn parse_variant(
-    line: &str,
-    chr: &str,
-    start: i64,
-    end: i64,
-    missing_data_info: &mut MissingDataInfo,
-    sample_names: &[String],
-    min_gq: u16,
-    _filtering_stats: &mut FilteringStats,
-    allow_regions: Option<&HashMap<String, Vec<(i64, i64)>>>,
-    mask_regions: Option<&HashMap<String, Vec<(i64, i64)>>>,
-    position_allele_map: &Mutex<HashMap<i64, (char, char)>>,
-) -> Result<Option<(Variant, bool)>, VcfError> {
-    let fields: Vec<&str> = line.split('\t').collect();
-
-    let required_fixed_fields = 9;
-    if fields.len() < required_fixed_fields + sample_names.len() {
-        return Err(VcfError::Parse(format!(
-            "Invalid VCF line format: expected at least {} fields, found {}",
-            required_fixed_fields + sample_names.len(),
-            fields.len()
-        )));
-    }
-
-    let vcf_chr = fields[0].trim().trim_start_matches("chr");
-
-    if vcf_chr != chr.trim_start_matches("chr") {
-        return Ok(None);
-    }
-
-    let pos: i64 = fields[1]
-        .parse()
-        .map_err(|_| VcfError::Parse("Invalid position".to_string()))?;
-    if pos < start || pos > end {
-        return Ok(None);
-    }
-
-    _filtering_stats.total_variants += 1; // DO NOT MOVE THIS LINE ABOVE THE CHECK FOR WITHIN RANGE
-    // Only variants within the range get passed the collector which increments statistics.
-    // For variants outside the range, the consumer thread does not send any result to the collector.
-    // If this line is moved above the early return return Ok(None) in the range check, then it would increment all variants, not just those in the regions
-    // This would mean that the maximum number of variants filtered could be below the maximum number of variants, in the case that there are variants outside of the ranges (which would not even get far enough to need to be filtered, but would be included in the total).
-
-    let adjusted_pos = pos - 1; // Adjust VCF position (one-based) to zero-based
-
-    // Check allow regions
-    if let Some(allow_regions_chr) = allow_regions.and_then(|ar| ar.get(vcf_chr)) {
-        if !position_in_regions(adjusted_pos, allow_regions_chr) {
-            _filtering_stats._filtered_variants += 1;
-            _filtering_stats.filtered_due_to_allow += 1;
-            _filtering_stats.filtered_positions.insert(pos);
-            _filtering_stats.add_example(format!("{}: Filtered due to allow", line.trim()));
-            return Ok(None);
-        }
-    } else if allow_regions.is_some() {
-        // If allow_regions is provided, but there are no allowed regions for this chromosome, filter it
-        _filtering_stats._filtered_variants += 1;
-        _filtering_stats.filtered_due_to_allow += 1;
-        _filtering_stats.filtered_positions.insert(pos);
-        _filtering_stats.add_example(format!("{}: Filtered due to allow", line.trim()));
-        return Ok(None);
-    }
-
-    // Check mask regions
-    if let Some(mask_regions_chr) = mask_regions.and_then(|mr| mr.get(vcf_chr)) {
-        if position_in_regions(adjusted_pos, mask_regions_chr) {
-            _filtering_stats._filtered_variants += 1;
-            _filtering_stats.filtered_due_to_mask += 1;
-            _filtering_stats.filtered_positions.insert(pos);
-            _filtering_stats.add_example(format!("{}: Filtered due to mask", line.trim()));
-            return Ok(None);
-        }
-    } else if mask_regions.is_some() {
-        // If mask_regions is provided but there are no mask regions for this chromosome,
-        // we do not filter the variant since it's not masked.
-        // This is separate from the allow file behavior, which restricts anything not explicitly allowed.
-        // No action needed here; we proceed with processing.
+        seg_sites += 1;
+        theta_l += derived as f64 / (sample_size - 1) as f64;
     }
+    (seg_sites, theta_l)
+}
 
-    // Store reference and alternate alleles
-    if !fields[3].is_empty() && !fields[4].is_empty() {
-        let ref_allele = fields[3].chars().next().unwrap_or('N');
-        let alt_allele = fields[4].chars().next().unwrap_or('N');
-        position_allele_map.lock().insert(pos, (ref_allele, alt_allele));
+// Zeng's theta_L = sum over k of k * xi_k / (n - 1), per site, where xi_k is the number of sites with k
+// derived alleles; each site uses its own number of called chromosomes as n (Zeng et al. 2006)
+pub fn calculate_theta_l(variants: &[Variant], n: usize, seq_length: i64, ancestral: AncestralSource) -> f64 {
+    if n <= 1 || seq_length <= 0 {
+        return f64::NAN;
     }
+    theta_l_sum(variants, ancestral).1 / seq_length as f64
+}
 
-    let alt_alleles: Vec<&str> = fields[4].split(',').collect();
-    let is_multiallelic = alt_alleles.len() > 1;
-    if is_multiallelic {
-        _filtering_stats.multi_allelic_variants += 1;
-        eprintln!("{}", format!("Warning: Multi-allelic site detected at position {}, which is not fully supported.", pos).yellow());
-        _filtering_stats.add_example(format!("{}: Filtered due to multi-allelic variant", line.trim()));
+// Zeng's E = (theta_L - theta_W) / sqrt(Var(theta_L - theta_W)) (Zeng et al. 2006)
+pub fn calculate_zeng_e(variants: &[Variant], n: usize, seq_length: i64, ancestral: AncestralSource) -> f64 {
+    // Undefined for fewer than 3 haplotypes
+    if n < 3 || seq_length <= 0 {
+        return f64::NAN;
     }
 
-    // Parse the FORMAT field to get the indices of the subfields
-    let format_fields: Vec<&str> = fields[8].split(':').collect();
-
-    // Find the index of GQ
-    let gq_index = format_fields.iter().position(|&s| s == "GQ");
+    let (seg_sites, theta_l) = theta_l_sum(variants, ancestral);
 
-    if gq_index.is_none() {
-        return Err(VcfError::Parse("GQ field not found in FORMAT".to_string()));
+    // Undefined without segregating sites
+    if seg_sites == 0 {
+        return f64::NAN;
     }
 
-    let gq_index = gq_index.unwrap();
+    let nf = n as f64;
+    let s = seg_sites as f64;
+    let (an, bn, _) = fu_li_coefficients(n);
+    let bn1 = bn + 1.0 / (nf * nf);
+    let theta_w = s / an;
+    let theta_sq = s * (s - 1.0) / (an * an + bn);
 
-    let genotypes: Vec<Option<Vec<u8>>> = fields[9..].iter()
-        .map(|gt| {
-            missing_data_info.total_data_points += 1;
-            let alleles_str = gt.split(':').next().unwrap_or(".");
-            if alleles_str == "." || alleles_str == "./." || alleles_str == ".|." {
-                missing_data_info.missing_data_points += 1;
-                missing_data_info.positions_with_missing.insert(pos);
-                return None;
-            }
-            let alleles = alleles_str.split(|c| c == '|' || c == '/')
-                .map(|allele| allele.parse::<u8>().ok())
-                .collect::<Option<Vec<u8>>>();
-            if alleles.is_none() {
-                missing_data_info.missing_data_points += 1;
-                missing_data_info.positions_with_missing.insert(pos);
-            }
-            alleles
-        })
+    let ratio = nf / (nf - 1.0);
+    let variance = (nf / (2.0 * (nf - 1.0)) - 1.0 / an) * theta_w
+        + (bn / (an * an) + 2.0 * ratio * ratio * bn1
+            - 2.0 * (nf * bn1 - nf + 1.0) / ((nf - 1.0) * an)
+            - (3.0 * nf + 1.0) / (nf - 1.0))
+            * theta_sq;
+
+    (theta_l - theta_w) / variance.sqrt()
+}
+
+// Hudson's FST = 1 - pi_within / pi_between (Hudson, Slatkin & Maddison 1992)
+// Differences are summed over all sites before taking the ratio (ratio of averages)
+// Both groups must be projected from the same variant list so positions line up
+pub fn calculate_fst_hudson(
+    variants_group0: &[Variant],
+    n0: usize,
+    variants_group1: &[Variant],
+    n1: usize,
+    seq_length: i64,
+) -> f64 {
+    if n0 <= 1 || n1 <= 1 || seq_length <= 0 {
+        return f64::NAN;
+    }
+
+    let group1_by_position: HashMap<i64, &Variant> = variants_group1
+        .iter()
+        .map(|v| (v.position, v))
         .collect();
 
-    let mut sample_has_low_gq = false;
-    let mut _num_samples_below_gq = 0;
-
-    for gt_field in fields[9..].iter() {
-        let gt_subfields: Vec<&str> = gt_field.split(':').collect();
-        
-        // Check if GQ index is within the subfields
-        if gq_index >= gt_subfields.len() {
-            return Err(VcfError::Parse(format!(
-                "GQ value missing in sample genotype field at chr{}:{}",
-                chr, pos
-            )));
-        }
-        
-        let gq_str = gt_subfields[gq_index];
-        
-        // Attempt to parse GQ value as u16
-        // Parse GQ value, treating '.' or empty string as 0
-        let gq_value: u16 = match gq_str {
-            "." | "" => 0,
-            _ => match gq_str.parse() {
-                Ok(val) => val,
-                Err(_) => {
-                    eprintln!("Missing GQ value '{}' at {}:{}. Treating as 0.", gq_str, chr, pos);
-                    0
-                },
-            },
+    let mut pi_within = 0.0;
+    let mut pi_between = 0.0;
+    for variant0 in variants_group0 {
+        let variant1 = match group1_by_position.get(&variant0.position) {
+            Some(v) => v,
+            None => continue,
         };
-    
-        // Check if GQ value is below the minimum threshold
-        if gq_value < min_gq {
-            sample_has_low_gq = true;
-            _num_samples_below_gq += 1;
+
+        let alleles0: Vec<u8> = variant0.genotypes.iter().flatten().flatten().copied().collect();
+        let alleles1: Vec<u8> = variant1.genotypes.iter().flatten().flatten().copied().collect();
+        // Each group needs a pair of called chromosomes for a within-group comparison
+        if alleles0.len() <= 1 || alleles1.len() <= 1 {
+            continue;
         }
-    }
 
-    if sample_has_low_gq {
-        // Skip this variant
-        _filtering_stats.low_gq_variants += 1;
-        _filtering_stats._filtered_variants += 1;
-        _filtering_stats.filtered_positions.insert(pos);
-        _filtering_stats.add_example(format!("{}: Filtered due to low GQ", line.trim()));
-    
-        let has_missing_genotypes = genotypes.iter().any(|gt| gt.is_none());
-        let passes_filters = !sample_has_low_gq && !has_missing_genotypes && !is_multiallelic;
+        // A monomorphic group simply contributes zero within-group diversity
+        pi_within += (mean_pairwise_difference(&alleles0) + mean_pairwise_difference(&alleles1)) / 2.0;
 
-        let variant = Variant {
-            position: pos,
-            genotypes: genotypes.clone(),
-        };
+        let between_diffs = alleles0
+            .iter()
+            .map(|a| alleles1.iter().filter(|b| *b != a).count())
+            .sum::<usize>();
+        pi_between += between_diffs as f64 / (alleles0.len() * alleles1.len()) as f64;
+    }
 
-        return Ok(Some((variant, passes_filters)));
+    if pi_between == 0.0 {
+        // No differences between the groups at all: FST is undefined
+        return f64::NAN;
     }
-    
-    // Do not exclude the variant; update the missing data info
-    if genotypes.iter().any(|gt| gt.is_none()) {
-        _filtering_stats.missing_data_variants += 1;
-        _filtering_stats.add_example(format!("{}: Filtered due to missing data", line.trim()));
-        // Continue processing
+
+    let pi_within = pi_within / seq_length as f64;
+    let pi_between = pi_between / seq_length as f64;
+    1.0 - pi_within / pi_between
+}
+
+// Dxy (Nei 1987): mean number of differences per site over all n0 * n1 cross-group pairs
+// As in calculate_fst_hudson, both groups must be projected from the same variant list
+pub fn calculate_dxy(
+    variants_group0: &[Variant],
+    n0: usize,
+    variants_group1: &[Variant],
+    n1: usize,
+    seq_length: i64,
+) -> f64 {
+    if n0 == 0 || n1 == 0 || seq_length <= 0 {
+        return f64::NAN;
     }
 
-    let has_missing_genotypes = genotypes.iter().any(|gt| gt.is_none());
-    let passes_filters = !sample_has_low_gq && !has_missing_genotypes && !is_multiallelic;
-    
-    // Update filtering stats if variant is filtered out
-    if !passes_filters {
-        _filtering_stats._filtered_variants += 1;
-        _filtering_stats.filtered_positions.insert(pos);
-        
-        if sample_has_low_gq {
-            _filtering_stats.low_gq_variants += 1;
-            _filtering_stats.add_example(format!("{}: Filtered due to low GQ", line.trim()));
-        }
-        
-        if genotypes.iter().any(|gt| gt.is_none()) {
-            _filtering_stats.missing_data_variants += 1;
-            _filtering_stats.add_example(format!("{}: Filtered due to missing data", line.trim()));
-        }
-        
-        if is_multiallelic {
-            _filtering_stats.multi_allelic_variants += 1;
-            _filtering_stats.add_example(format!("{}: Filtered due to multi-allelic variant", line.trim()));
+    let group1_by_position: HashMap<i64, &Variant> = variants_group1
+        .iter()
+        .map(|v| (v.position, v))
+        .collect();
+
+    let mut total = 0.0;
+    for variant0 in variants_group0 {
+        let variant1 = match group1_by_position.get(&variant0.position) {
+            Some(v) => v,
+            None => continue,
+        };
+        let alleles0: Vec<u8> = variant0.genotypes.iter().flatten().flatten().copied().collect();
+        let alleles1: Vec<u8> = variant1.genotypes.iter().flatten().flatten().copied().collect();
+        // Missing calls shrink the number of cross-group pairs at this site
+        if alleles0.is_empty() || alleles1.is_empty() {
+            continue;
         }
+        let between_diffs = alleles0
+            .iter()
+            .map(|a| alleles1.iter().filter(|b| *b != a).count())
+            .sum::<usize>();
+        total += between_diffs as f64 / (alleles0.len() * alleles1.len()) as f64;
     }
 
-    let variant = Variant {
-        position: pos,
-        genotypes: genotypes.clone(),
-    };
-    
-    // Return the parsed variant and whether it passes filters
-    Ok(Some((variant, passes_filters)))
+    total / seq_length as f64
 }
 
+// The allele carried by every haplotype of a group at a site, if they all agree.
+// Missing calls disqualify the site unless allow_missing, which needs at least one call.
+fn fixed_allele(variant: &Variant, n: usize, allow_missing: bool) -> Option<u8> {
+    let mut allele = None;
+    for genotype in variant.genotypes.iter().take(n) {
+        match genotype.as_ref().and_then(|alleles| alleles.first()) {
+            Some(&a) if allele.is_some_and(|b| b != a) => return None,
+            Some(&a) => allele = Some(a),
+            None if allow_missing => {}
+            None => return None,
+        }
+    }
+    if !allow_missing && variant.genotypes.len() < n {
+        return None;
+    }
+    allele
+}
 
-fn count_segregating_sites(variants: &[Variant]) -> usize {
-    variants
-        .par_iter()
-        .filter(|v| {
-            let alleles: HashSet<_> = v.genotypes
-                .iter()
-                .flatten()
-                .flatten()
-                .collect();
-            alleles.len() > 1
+// Sites where group 0 is fixed for one allele and group 1 for another
+pub fn count_fixed_differences(
+    variants_g0: &[Variant],
+    n0: usize,
+    variants_g1: &[Variant],
+    n1: usize,
+    allow_missing: bool,
+) -> usize {
+    if n0 == 0 || n1 == 0 {
+        return 0;
+    }
+    let group1_by_position: HashMap<i64, &Variant> = variants_g1.iter().map(|v| (v.position, v)).collect();
+    variants_g0
+        .iter()
+        .filter(|variant0| {
+            let Some(variant1) = group1_by_position.get(&variant0.position) else {
+                return false;
+            };
+            match (fixed_allele(variant0, n0, allow_missing), fixed_allele(variant1, n1, allow_missing)) {
+                (Some(a), Some(b)) => a != b,
+                _ => false,
+            }
         })
         .count()
 }
 
-fn calculate_pairwise_differences(
-    variants: &[Variant],
-    n: usize,
-) -> Vec<((usize, usize), usize, Vec<i64>)> {
-    let variants = Arc::new(variants);
+// McDonald-Kreitman counts of one gene: non-synonymous and synonymous fixed differences (dn, ds)
+// and polymorphisms (pn, ps)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MkTable {
+    pub dn: usize,
+    pub ds: usize,
+    pub pn: usize,
+    pub ps: usize,
+}
 
-    // Iterate over all sample indices from 0 to n - 1
-    (0..n).into_par_iter().flat_map(|i| {
-        let variants = Arc::clone(&variants);
+impl MkTable {
+    pub fn fisher_p_value(&self) -> f64 {
+        fisher_exact_test(self.dn, self.ds, self.pn, self.ps)
+    }
+}
 
-        // For each i, iterate over j from i + 1 to n - 1
-        (i+1..n).into_par_iter().map(move |j| {
-            let mut diff_count = 0;
-            let mut diff_positions = Vec::new();
+// Two-sided Fisher's exact test of the 2x2 table [[a, b], [c, d]]: the total probability,
+// given the margins, of the tables no more likely than the observed one
+pub fn fisher_exact_test(a: usize, b: usize, c: usize, d: usize) -> f64 {
+    let n = a + b + c + d;
+    if n == 0 {
+        return 1.0;
+    }
+    let mut ln_factorial = vec![0.0; n + 1];
+    for i in 1..=n {
+        ln_factorial[i] = ln_factorial[i - 1] + (i as f64).ln();
+    }
+    let (row0, row1, col0) = (a + b, c + d, a + c);
+    // Hypergeometric probability of the table with x in the top-left cell
+    let ln_probability = |x: usize| {
+        ln_factorial[row0] + ln_factorial[row1] + ln_factorial[col0] + ln_factorial[n - col0]
+            - ln_factorial[n]
+            - ln_factorial[x]
+            - ln_factorial[row0 - x]
+            - ln_factorial[col0 - x]
+            - ln_factorial[row1 + x - col0]
+    };
+    let observed = ln_probability(a);
+    let p: f64 = (col0.saturating_sub(row1)..=row0.min(col0))
+        .map(ln_probability)
+        .filter(|&ln_p| ln_p <= observed + 1e-7)
+        .map(f64::exp)
+        .sum();
+    p.min(1.0)
+}
 
-            // For each variant, compare genotypes of samples i and j
-            for v in variants.iter() {
-                if let (Some(gi), Some(gj)) = (&v.genotypes[i], &v.genotypes[j]) {
-                    if gi != gj {
-                        diff_count += 1;
-                        diff_positions.push(v.position);
-                    }
-                } else {
-                    // Skip if either genotype is missing
-                    continue;
-                }
-            }
+// MK table of every --annotation_gff gene with coding sites in the two haplotype groups.
+// A site fixed for different alleles in the groups is a fixed difference; a site where either group
+// carries both alleles is a polymorphism.
+pub fn tally_mk_tables(
+    variants_g0: &[Variant],
+    n0: usize,
+    variants_g1: &[Variant],
+    n1: usize,
+    annotation: &GffIndex,
+    allow_missing: bool,
+) -> BTreeMap<String, MkTable> {
+    let mut tables: BTreeMap<String, MkTable> = BTreeMap::new();
+    if n0 == 0 || n1 == 0 {
+        return tables;
+    }
+    let group1_by_position: HashMap<i64, &Variant> = variants_g1.iter().map(|v| (v.position, v)).collect();
+    let is_polymorphic = |variant: &Variant, n: usize| {
+        let called: HashSet<u8> = variant
+            .genotypes
+            .iter()
+            .take(n)
+            .filter_map(|genotype| genotype.as_ref().and_then(|alleles| alleles.first().copied()))
+            .collect();
+        called.len() > 1
+    };
+    for variant0 in variants_g0 {
+        let Some(variant1) = group1_by_position.get(&variant0.position) else {
+            continue;
+        };
+        let Some(transcript) = annotation.transcript_at(variant0.position) else {
+            continue;
+        };
+        let class = classify_site(
+            variant0.position,
+            &(variant0.ref_allele as char).to_string(),
+            &(variant0.alt_allele as char).to_string(),
+            annotation,
+        );
+        let fixed_difference = matches!(
+            (fixed_allele(variant0, n0, allow_missing), fixed_allele(variant1, n1, allow_missing)),
+            (Some(a), Some(b)) if a != b
+        );
+        let polymorphic = is_polymorphic(variant0, n0) || is_polymorphic(variant1, n1);
+        if class == SiteClass::NonCoding || !(fixed_difference || polymorphic) {
+            continue;
+        }
+        let table = tables.entry(transcript.gene.clone()).or_default();
+        match (class, fixed_difference) {
+            (SiteClass::Nonsynonymous, true) => table.dn += 1,
+            (SiteClass::Synonymous, true) => table.ds += 1,
+            (SiteClass::Nonsynonymous, false) => table.pn += 1,
+            _ => table.ps += 1,
+        }
+    }
+    tables
+}
 
-            // Return the pair of sample indices, difference count, and positions
-            ((i, j), diff_count, diff_positions)
-        }).collect::<Vec<_>>()
-    }).collect()
+// Randomly split n0 + n1 pooled haplotype indices into groups of the original sizes
+pub fn permute_groups(n0: usize, n1: usize, rng: &mut impl Rng) -> (Vec<usize>, Vec<usize>) {
+    let mut indices: Vec<usize> = (0..n0 + n1).collect();
+    indices.shuffle(rng);
+    let group1 = indices.split_off(n0);
+    (indices, group1)
 }
 
+// Empirical p-value of Hudson's FST: the fraction of `permutations` random reassignments of the pooled
+// haplotypes whose FST is at least the observed one. NaN when disabled or when the observed FST is undefined.
+// As with bootstrapping, each permutation gets its own seed drawn from `rng`.
+pub fn fst_permutation_p_value(
+    variants_group0: &[Variant],
+    n0: usize,
+    variants_group1: &[Variant],
+    n1: usize,
+    seq_length: i64,
+    permutations: usize,
+    rng: &mut impl Rng,
+) -> f64 {
+    if permutations == 0 {
+        return f64::NAN;
+    }
+    let observed = calculate_fst_hudson(variants_group0, n0, variants_group1, n1, seq_length);
+    if !observed.is_finite() {
+        return f64::NAN;
+    }
+
+    // Both groups' haplotypes at each shared site, group 0 first
+    let group1_by_position: HashMap<i64, &Variant> = variants_group1
+        .iter()
+        .map(|v| (v.position, v))
+        .collect();
+    let pooled: Vec<Variant> = variants_group0
+        .iter()
+        .filter_map(|variant0| {
+            let variant1 = group1_by_position.get(&variant0.position)?;
+            let mut variant = variant0.clone();
+            variant.genotypes.extend(variant1.genotypes.iter().cloned());
+            Some(variant)
+        })
+        .collect();
 
+    let pick = |indices: &[usize]| -> Vec<Variant> {
+        pooled
+            .iter()
+            .map(|variant| Variant {
+                genotypes: indices.iter().map(|&i| variant.genotypes[i].clone()).collect(),
+                ..variant.clone()
+            })
+            .collect()
+    };
 
-fn extract_sample_id(name: &str) -> &str {
-    name.rsplit('_').next().unwrap_or(name)
+    let seeds: Vec<u64> = (0..permutations).map(|_| rng.gen()).collect();
+    let at_least_observed = seeds
+        .into_par_iter()
+        .filter(|&seed| {
+            let (indices0, indices1) = permute_groups(n0, n1, &mut StdRng::seed_from_u64(seed));
+            calculate_fst_hudson(&pick(&indices0), n0, &pick(&indices1), n1, seq_length) >= observed
+        })
+        .count();
+    at_least_observed as f64 / permutations as f64
 }
 
-fn harmonic(n: usize) -> f64 {
-    (1..=n).map(|i| 1.0 / i as f64).sum()
-}
+// Weir & Cockerham (1984) FST for any number of populations, as a ratio of sums over sites
+// Each population lists genotype indices into the variants; every called allele is one haploid
+// sample, so the within-individual term drops out and unequal sample sizes enter through n_c
+pub fn calculate_weir_cockerham_fst(populations: &[Vec<usize>], variants: &[Variant]) -> f64 {
+    let mut numerator = 0.0;
+    let mut denominator = 0.0;
 
-fn calculate_watterson_theta(seg_sites: usize, n: usize, seq_length: i64) -> f64 {
-    // Handle edge cases
-    if n <= 1 || seq_length == 0 {
-        return f64::INFINITY; // Return infinity if only 1 or fewer haplotypes or if sequence length is zero
-    }
-    
-    let harmonic_value = harmonic(n - 1);
-    if harmonic_value == 0.0 {
-        return f64::INFINITY; // Return infinity to avoid division by zero
+    for variant in variants {
+        // Called alleles of each population, leaving out populations with no data at this site
+        let pop_alleles: Vec<Vec<u8>> = populations
+            .iter()
+            .map(|indices| {
+                indices
+                    .iter()
+                    .filter_map(|&i| variant.genotypes.get(i))
+                    .flatten()
+                    .flatten()
+                    .copied()
+                    .collect::<Vec<u8>>()
+            })
+            .filter(|alleles| !alleles.is_empty())
+            .collect();
+
+        let r = pop_alleles.len();
+        let sizes: Vec<f64> = pop_alleles.iter().map(|a| a.len() as f64).collect();
+        let total: f64 = sizes.iter().sum();
+        if r < 2 || total <= r as f64 {
+            continue;
+        }
+        let n_c = (total - sizes.iter().map(|n| n * n).sum::<f64>() / total) / (r - 1) as f64;
+
+        let distinct: HashSet<u8> = pop_alleles.iter().flatten().copied().collect();
+        if distinct.len() < 2 {
+            continue;
+        }
+
+        for allele in distinct {
+            let freqs: Vec<f64> = pop_alleles
+                .iter()
+                .map(|a| a.iter().filter(|&&x| x == allele).count() as f64 / a.len() as f64)
+                .collect();
+            let p_bar = sizes.iter().zip(&freqs).map(|(n, p)| n * p).sum::<f64>() / total;
+            let msp = sizes.iter().zip(&freqs).map(|(n, p)| n * (p - p_bar).powi(2)).sum::<f64>()
+                / (r - 1) as f64;
+            let msg = sizes.iter().zip(&freqs).map(|(n, p)| n * p * (1.0 - p)).sum::<f64>()
+                / (total - r as f64);
+            numerator += msp - msg;
+            denominator += msp + (n_c - 1.0) * msg;
+        }
     }
 
-    seg_sites as f64 / harmonic_value / seq_length as f64
+    if denominator == 0.0 {
+        return f64::NAN;
+    }
+    numerator / denominator
 }
 
-fn calculate_pi(tot_pair_diff: usize, n: usize, seq_length: i64) -> f64 {
-    // Handle edge cases
-    if n <= 1 || seq_length == 0 {
-        return f64::INFINITY; // Return infinity if only 1 or fewer haplotypes or if sequence length is zero
+// Fraction of distinct pairs of alleles at a site that differ
+pub fn mean_pairwise_difference(alleles: &[u8]) -> f64 {
+    let m = alleles.len();
+    let mut diffs = 0;
+    for i in 0..m {
+        for j in (i + 1)..m {
+            if alleles[i] != alleles[j] {
+                diffs += 1;
+            }
+        }
     }
+    diffs as f64 / (m * (m - 1) / 2) as f64
+}
 
-    let num_comparisons = n * (n - 1) / 2;
-    if num_comparisons == 0 {
-        return f64::NAN; // Return NaN if there's somehow no valid pairwise comparison
+// The statistics compared across regions by --flag_outliers; NaN where a region lacks one
+fn outlier_statistics(stats: &RegionStats) -> Vec<f64> {
+    let mut values = Vec::new();
+    for set in [&stats.all, &stats.group_0, &stats.group_1] {
+        let group = set.as_ref().map(|set| &set.unfiltered);
+        values.extend([
+            group.map_or(f64::NAN, |g| g.w_theta),
+            group.map_or(f64::NAN, |g| g.pi),
+            group.map_or(f64::NAN, |g| g.tajima_d),
+        ]);
     }
-
-    tot_pair_diff as f64 / num_comparisons as f64 / seq_length as f64
+    values.push(stats.fst.unwrap_or(f64::NAN));
+    values.push(stats.dxy.unwrap_or(f64::NAN));
+    values
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    include!("tests/stats_tests.rs");
-    include!("tests/filter_tests.rs");
+// Mark each region with a statistic more than z_threshold standard deviations from that
+// statistic's mean over all regions. Non-finite values are left out of the mean and never flagged.
+pub fn flag_outliers(results: &[RegionStats], z_threshold: f64) -> Vec<bool> {
+    let values: Vec<Vec<f64>> = results.iter().map(outlier_statistics).collect();
+    let mut flags = vec![false; results.len()];
+    let num_statistics = values.first().map_or(0, Vec::len);
+    for column in 0..num_statistics {
+        let finite: Vec<f64> = values.iter().map(|v| v[column]).filter(|x| x.is_finite()).collect();
+        if finite.len() < 2 {
+            continue;
+        }
+        let mean = finite.iter().sum::<f64>() / finite.len() as f64;
+        let sd = (finite.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / finite.len() as f64).sqrt();
+        if sd == 0.0 {
+            continue;
+        }
+        for (flag, v) in flags.iter_mut().zip(&values) {
+            if v[column].is_finite() && ((v[column] - mean) / sd).abs() > z_threshold {
+                *flag = true;
+            }
+        }
+    }
+    flags
 }
@@ -4,19 +4,24 @@ mod tests {
     use tempfile::NamedTempFile;
     use std::fs::File;
     use std::collections::HashMap;
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::sync::Arc;
     use parking_lot::Mutex;
     use std::path::PathBuf;
 
     // Helper function to create a Variant for testing
     fn create_variant(position: i64, genotypes: Vec<Option<Vec<u8>>>) -> Variant {
-        Variant { position, genotypes }
+        Variant { position, genotypes, ancestral_allele: None, ref_allele: b'N', alt_allele: b'N', variant_type: VariantType::Snp }
+    }
+
+    // Helper function to create the A>T SNP that the test VCF lines parse to
+    fn create_snp_variant(position: i64, genotypes: Vec<Option<Vec<u8>>>) -> Variant {
+        Variant { position, genotypes, ancestral_allele: None, ref_allele: b'A', alt_allele: b'T', variant_type: VariantType::Snp }
     }
 
     // Helper function to create a Variant for testing with specific number of haplotypes
     fn create_variant_with_genotypes(position: i64, genotypes: Vec<Option<Vec<u8>>>) -> Variant {
-        Variant { position, genotypes }
+        Variant { position, genotypes, ancestral_allele: None, ref_allele: b'N', alt_allele: b'N', variant_type: VariantType::Snp }
     }
 
     #[test]
@@ -31,6 +36,16 @@ mod tests {
         assert_eq!(count_segregating_sites(&variants), 3);
     }
 
+    #[test]
+    fn test_count_segregating_sites_multiallelic() {
+        // Three alleles at one site still make one segregating site
+        let variants = vec![
+            create_variant(1, haplotypes(&[0, 1, 2, 2])),
+            create_variant(2, vec![Some(vec![2]), None, Some(vec![2])]),
+        ];
+        assert_eq!(count_segregating_sites(&variants), 1);
+    }
+
     #[test]
     fn test_count_segregating_sites_no_variants() {
         assert_eq!(count_segregating_sites(&[]), 0);
@@ -89,6 +104,37 @@ mod tests {
         assert_eq!(extract_sample_id("EAS_JPT_NA18939"), "NA18939");
     }
 
+    #[test]
+    fn test_find_duplicate_sample_ids() {
+        let names: Vec<String> = ["EAS_NA18939", "HG00096", "rep_NA18939", "AFR_HG00096", "NA12878"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(
+            find_duplicate_sample_ids(&names),
+            vec![("NA18939".to_string(), 0, 2), ("HG00096".to_string(), 1, 3)]
+        );
+        assert!(find_duplicate_sample_ids(&names[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_check_sample_consistency() {
+        let names = |list: &[&str]| -> Vec<String> { list.iter().map(|name| name.to_string()).collect() };
+        // Order and population prefixes do not matter
+        assert!(check_sample_consistency(&names(&["EAS_NA18939", "HG00096"]), &names(&["HG00096", "NA18939"])).is_ok());
+        let err = check_sample_consistency(&names(&["NA18939", "HG00096"]), &names(&["NA18939", "NA12878"]));
+        assert!(matches!(&err, Err(VcfError::Parse(msg)) if msg.contains("HG00096") && msg.contains("NA12878")));
+    }
+
+    #[test]
+    fn test_genotype_concordance() {
+        let v1 = vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0]), None, Some(vec![0, 1])];
+        let v2 = vec![Some(vec![1, 0]), Some(vec![0, 1]), Some(vec![0, 0]), Some(vec![1, 1]), None];
+        // Three sites are called in both; 0|1 and 1|0 agree
+        assert!((genotype_concordance(&v1, &v2) - 2.0 / 3.0).abs() < 1e-12);
+        assert!(genotype_concordance(&v1[3..4], &v2[4..5]).is_nan());
+    }
+
     #[test]
     fn test_extract_sample_id_complex_names_amr() {
         assert_eq!(extract_sample_id("AMR_PEL_HG02059"), "HG02059");
@@ -104,6 +150,31 @@ mod tests {
         assert_eq!(extract_sample_id("triple_part_name_789"), "789");
     }
 
+    #[test]
+    fn test_extract_sample_id_leading_underscore() {
+        assert_eq!(extract_sample_id("_HG00096"), "HG00096");
+    }
+
+    #[test]
+    fn test_extract_sample_id_numeric_suffix() {
+        // Only the suffix survives, not the sample name in front of it
+        assert_eq!(extract_sample_id("HG00096_1"), "1");
+        assert_eq!(extract_sample_id("HG00096_1_2"), "2");
+    }
+
+    #[test]
+    fn test_extract_sample_id_collisions() {
+        assert_eq!(extract_sample_id("HG00096_1"), extract_sample_id("NA12878_1"));
+        assert_eq!(extract_sample_id("EUR_GBR_HG00096"), extract_sample_id("HG00096"));
+        assert_ne!(extract_sample_id("EUR_GBR_HG00096"), extract_sample_id("EUR_GBR_HG00097"));
+
+        // Colliding VCF columns are not reported: the config sample matches the last one
+        let sample_names = vec!["HG00096_1".to_string(), "NA12878_1".to_string()];
+        let mut sample_filter = HashMap::new();
+        sample_filter.insert("1".to_string(), (0, 1));
+        assert_eq!(collect_haplotype_indices(&sample_names, 0, &sample_filter), vec![(1, 0)]);
+    }
+
     #[test]
     fn test_harmonic_single() {
         assert_eq!(harmonic(1), 1.0);
@@ -127,313 +198,1539 @@ mod tests {
     }
 
     #[test]
-    fn test_calculate_pairwise_differences_basic() {
-        let variants = vec![
-            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
-            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
-            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
-        ];
+    fn test_effective_population_size() {
+        // theta = 4 Ne mu: a per-site theta of 0.001 with mu = 1.25e-8 gives Ne = 20000
+        assert!((effective_population_size(0.001, 1.25e-8) - 20000.0).abs() < 1e-6);
+        assert!(effective_population_size(f64::NAN, 1.25e-8).is_nan());
+    }
 
-        let result = calculate_pairwise_differences(&variants, 3);
+    #[test]
+    fn test_harmonic_asymptotic() {
+        // The expansion takes over above n = 30
+        assert!((harmonic(31) - harmonic_exact(31)).abs() < 1e-10);
+        assert!((harmonic(1000) - harmonic_exact(1000)).abs() < 1e-10);
+        assert_eq!(harmonic(30), harmonic_exact(30));
+    }
 
-        assert_eq!(result.len(), 3);
+    #[test]
+    fn test_count_singletons_basic() {
+        let variants = vec![
+            create_variant(1, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![0, 0])]), // singleton
+            create_variant(2, vec![Some(vec![0, 1]), Some(vec![0, 1]), Some(vec![0, 0])]), // doubleton
+            create_variant(3, vec![Some(vec![1, 1]), Some(vec![1, 1]), Some(vec![1, 0])]), // singleton (REF)
+            create_variant(4, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 0])]), // monomorphic
+        ];
+        assert_eq!(count_singletons(&variants), 2);
     }
 
     #[test]
-    fn test_calculate_pairwise_differences_pair_0_1() {
+    fn test_count_singletons_with_missing_data() {
         let variants = vec![
-            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
-            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
-            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+            create_variant(1, vec![Some(vec![0, 1]), None, Some(vec![0, 0])]),
+            create_variant(2, vec![None, None, Some(vec![1, 1])]),
         ];
+        assert_eq!(count_singletons(&variants), 1);
+    }
 
-        let result = calculate_pairwise_differences(&variants, 3);
+    #[test]
+    fn test_minor_allele_frequency() {
+        let rare = create_variant(1, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![0, 0]), Some(vec![0, 0])]);
+        assert!((minor_allele_frequency(&rare) - 0.125).abs() < 1e-10);
+        // The minor allele can be REF
+        let common_alt = create_variant(2, vec![Some(vec![1, 1]), Some(vec![1, 0])]);
+        assert!((minor_allele_frequency(&common_alt) - 0.25).abs() < 1e-10);
+        let monomorphic = create_variant(3, vec![Some(vec![0, 0]), Some(vec![0, 0])]);
+        assert_eq!(minor_allele_frequency(&monomorphic), 0.0);
+        // At a multi-allelic site everything but the most common allele counts as minor
+        let multiallelic = create_variant(4, vec![Some(vec![0, 1]), Some(vec![1, 2]), Some(vec![2, 2]), None]);
+        assert_eq!(called_allele_counts(&multiallelic), (5, 6));
+        assert!((minor_allele_frequency(&multiallelic) - 0.5).abs() < 1e-10);
+    }
 
-        for &((i, j), count, ref positions) in &result {
-            if (i, j) == (0, 1) {
-                assert_eq!(count, 2);
-                assert_eq!(positions, &vec![1000, 3000]);
-            }
-        }
+    #[test]
+    fn test_clr_score() {
+        // Neutral proportions for n = 4: 1/i over 1 + 1/2 + 1/3; folded, (4/3, 1/2) over 11/6
+        let expected = neutral_sfs(4, false);
+        assert!((expected[0] - 6.0 / 11.0).abs() < 1e-12);
+        assert!((neutral_sfs(4, true)[1] - 3.0 / 11.0).abs() < 1e-12);
+
+        // A spectrum in neutral proportions scores 0; an excess of high-frequency alleles scores higher
+        assert!(clr_score(&[6, 3, 2], &expected).abs() < 1e-12);
+        assert!(clr_score(&[1, 1, 9], &expected) > clr_score(&[6, 2, 3], &expected));
+        assert_eq!(clr_score(&[0, 0, 0], &expected), 0.0);
+        assert!(clr_score(&[1, 0], &[0.0, 1.0]).is_infinite());
     }
 
     #[test]
-    fn test_calculate_pairwise_differences_pair_0_2() {
-        let variants = vec![
-            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
-            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
-            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
-        ];
+    fn test_variant_allele_count() {
+        let variant = create_variant(1, vec![Some(vec![0, 1]), Some(vec![2, 1]), None, Some(vec![1])]);
+        assert_eq!(variant.allele_count(1), (3, 5));
+        assert_eq!(variant.allele_count(0), (1, 5));
+        assert_eq!(variant.allele_count(3), (0, 5));
+
+        // Large sample sets are counted in parallel with the same result
+        let many = create_variant(2, (0..3000).map(|i| if i % 3 == 0 { None } else { Some(vec![0, (i % 2) as u8]) }).collect());
+        assert_eq!(many.allele_count(1), (1000, 4000));
+    }
 
-        let result = calculate_pairwise_differences(&variants, 3);
+    #[test]
+    fn test_hwe_exact_test() {
+        // One of each homozygote: 0 hets has probability 1/3, 2 hets 2/3
+        assert!((hwe_exact_test(1, 0, 1) - 1.0 / 3.0).abs() < 1e-12);
+        assert!((hwe_exact_test(0, 2, 0) - 1.0).abs() < 1e-12);
+        // No heterozygotes among 100 samples at a common allele is far from equilibrium
+        assert!(hwe_exact_test(50, 0, 50) < 1e-20);
+        assert!(hwe_exact_test(25, 50, 25) > 0.5);
+        assert_eq!(hwe_exact_test(10, 0, 0), 1.0);
+        assert_eq!(hwe_exact_test(0, 0, 0), 1.0);
+    }
 
-        for &((i, j), count, ref positions) in &result {
-            if (i, j) == (0, 2) {
-                assert_eq!(count, 3);
-                assert_eq!(positions, &vec![1000, 2000, 3000]);
-            }
-        }
+    #[test]
+    fn test_count_genotype_classes() {
+        let variant = create_variant(1, vec![Some(vec![0, 0]), Some(vec![1, 0]), Some(vec![2, 1]), None, Some(vec![1])]);
+        assert_eq!(count_genotype_classes(&variant), (1, 1, 1));
     }
 
     #[test]
-    fn test_calculate_pairwise_differences_with_missing_data() {
-        let missing_data_variants = vec![
-            create_variant(1, vec![Some(vec![0]), None, Some(vec![1])]),
-            create_variant(2, vec![Some(vec![1]), Some(vec![1]), None]),
-        ];
-        let missing_data_result = calculate_pairwise_differences(&missing_data_variants, 3);
+    fn test_minor_allele_frequency_ignores_missing() {
+        let variant = create_variant(1, vec![Some(vec![0, 1]), None, None]);
+        assert!((minor_allele_frequency(&variant) - 0.5).abs() < 1e-10);
+        let all_missing = create_variant(2, vec![None, None]);
+        assert_eq!(minor_allele_frequency(&all_missing), 0.0);
+    }
 
-        assert_eq!(missing_data_result.len(), 3);
+    #[test]
+    fn test_calculate_fu_li_d_star_typical() {
+        let d_star = calculate_fu_li_d_star(10, 4, 10);
+        assert!((d_star - (-0.0268151378)).abs() < 1e-8);
+    }
 
-        for &((i, j), count, _) in &missing_data_result {
-            match (i, j) {
-                (0, 1) => assert_eq!(count, 0),
-                (0, 2) => assert_eq!(count, 1),
-                (1, 2) => assert_eq!(count, 0),
-                _ => panic!("Unexpected pair: ({}, {})", i, j),
-            }
-        }
+    #[test]
+    fn test_calculate_fu_li_d_star_excess_singletons_negative() {
+        assert!(calculate_fu_li_d_star(10, 9, 20) < 0.0);
+        assert!(calculate_fu_li_d_star(10, 0, 20) > 0.0);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_case1() {
-        let epsilon = 1e-6;
-        assert!((calculate_watterson_theta(10, 5, 1000) - 0.0048).abs() < epsilon);
+    fn test_calculate_fu_li_d_star_undefined() {
+        assert!(calculate_fu_li_d_star(5, 1, 2).is_nan());
+        assert!(calculate_fu_li_d_star(0, 0, 10).is_nan());
     }
 
     #[test]
-    fn test_calculate_watterson_theta_case2() {
-        let epsilon = 1e-6;
-        assert!((calculate_watterson_theta(5, 2, 1000) - 0.005).abs() < epsilon);
+    fn test_calculate_fu_li_f_star_typical() {
+        let f_star = calculate_fu_li_f_star(10, 4, 3.5, 10);
+        assert!((f_star - (-0.0348526355)).abs() < 1e-8);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_large_values() {
-        let epsilon = 1e-6;
-        assert!((calculate_watterson_theta(100, 10, 1_000_000) - 0.00003534).abs() < epsilon);
+    fn test_calculate_fu_li_f_star_undefined() {
+        assert!(calculate_fu_li_f_star(5, 1, 2.0, 2).is_nan());
+        assert!(calculate_fu_li_f_star(0, 0, 0.0, 10).is_nan());
     }
 
     #[test]
-    fn test_calculate_watterson_theta_n1() {
-        let theta_n1 = calculate_watterson_theta(100, 1, 1000);
-        assert!(theta_n1.is_infinite());
+    fn test_calculate_fay_wu_h_ref_ancestral() {
+        // 4 haplotypes; derived counts 1 and 3
+        let variants = vec![
+            create_variant(1, vec![Some(vec![1]), Some(vec![0]), Some(vec![0]), Some(vec![0])]),
+            create_variant(2, vec![Some(vec![1]), Some(vec![1]), Some(vec![1]), Some(vec![0])]),
+        ];
+        // theta_pi = (2*1*3 + 2*3*1) / 12 = 1.0, theta_H = (2*1 + 2*9) / 12 = 5/3
+        let expected = (1.0 - 5.0 / 3.0) / 100.0;
+        let h = calculate_fay_wu_h(&variants, 4, 100, AncestralSource::Ref);
+        assert!((h - expected).abs() < 1e-12);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_n0() {
-        let theta_n0 = calculate_watterson_theta(0, 0, 1000);
-        assert!(theta_n0.is_infinite());
+    fn test_calculate_fay_wu_h_aa_tag() {
+        let mut high_freq = create_variant(1, vec![Some(vec![1]), Some(vec![1]), Some(vec![1]), Some(vec![0])]);
+        high_freq.ancestral_allele = Some(1); // ALT is ancestral, so the derived allele is a singleton
+        let no_tag = create_variant(2, vec![Some(vec![1]), Some(vec![0]), Some(vec![0]), Some(vec![0])]);
+        let h = calculate_fay_wu_h(&[high_freq, no_tag], 4, 1, AncestralSource::AaTag);
+        // Only the tagged site counts: theta_pi = 0.5, theta_H = 1/6
+        assert!((h - (0.5 - 1.0 / 6.0)).abs() < 1e-12);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_seq_zero() {
-        let theta_seq_zero = calculate_watterson_theta(10, 5, 0);
-        assert!(theta_seq_zero.is_infinite());
+    fn test_calculate_theta_h() {
+        let variants = vec![
+            create_variant(1, vec![Some(vec![1]), Some(vec![0]), Some(vec![0]), Some(vec![0])]),
+            create_variant(2, vec![Some(vec![1]), Some(vec![1]), Some(vec![1]), Some(vec![0])]),
+        ];
+        // theta_H = (2*1 + 2*9) / 12 = 5/3, and H = theta_pi - theta_H
+        let theta_h = calculate_theta_h(&variants, 4, 100, AncestralSource::Ref);
+        assert!((theta_h - 5.0 / 300.0).abs() < 1e-12);
+        let h = calculate_fay_wu_h(&variants, 4, 100, AncestralSource::Ref);
+        assert!((h - (1.0 / 100.0 - theta_h)).abs() < 1e-12);
+        assert!(calculate_theta_h(&variants, 1, 100, AncestralSource::Ref).is_nan());
+        assert!(calculate_theta_h(&variants, 4, 0, AncestralSource::Ref).is_nan());
+        assert!(calculate_theta_h(&variants, 4, -5, AncestralSource::Ref).is_nan());
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_typical() {
-        let epsilon = 1e-10;
-        let pi = calculate_pi(15, 5, 1000);
-        let expected = 15f64 / ((5 * (5 - 1) / 2) as f64 * 1000.0);
-        assert!((pi - expected).abs() < epsilon);
+    fn test_calculate_fay_wu_h_undefined() {
+        assert!(calculate_fay_wu_h(&[], 1, 100, AncestralSource::Ref).is_nan());
+        assert!(calculate_fay_wu_h(&[], 4, 0, AncestralSource::Ref).is_nan());
+        assert!(calculate_fay_wu_h(&[], 4, -5, AncestralSource::Ref).is_nan());
+    }
+
+    fn haplotypes(alleles: &[u8]) -> Vec<Option<Vec<u8>>> {
+        alleles.iter().map(|&a| Some(vec![a])).collect()
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_no_differences() {
-        let pi = calculate_pi(0, 5, 1000);
-        assert_eq!(pi, 0.0);
+    fn test_count_distinct_haplotypes() {
+        let variants = vec![
+            create_variant(1, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2, haplotypes(&[0, 1, 1, 1])),
+        ];
+        // 00, 11, 01, 11
+        assert_eq!(count_distinct_haplotypes(&variants, 4), 3);
+        assert_eq!(count_distinct_haplotypes(&[], 4), 1);
+
+        // A missing allele is its own state
+        let with_missing = vec![create_variant(1, vec![Some(vec![0]), None, Some(vec![0])])];
+        assert_eq!(count_distinct_haplotypes(&with_missing, 3), 2);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_min_sample_size() {
-        let epsilon = 1e-10;
-        let pi = calculate_pi(5, 2, 1000);
-        let expected = 5f64 / ((2 * (2 - 1) / 2) as f64 * 1000.0);
-        assert!((pi - expected).abs() < epsilon);
+    fn test_calculate_fu_fs() {
+        // n = 4, theta = 1: |s(4, k)| = 6, 11, 6, 1 over 4! = 24, so P(K >= 3) = 7/24
+        assert!((calculate_fu_fs(3, 4, 1.0) - (7.0f64 / 17.0).ln()).abs() < 1e-12);
+        // P(K >= 2) = 18/24 at theta = 1
+        assert!((calculate_fu_fs(2, 4, 1.0) - 3.0f64.ln()).abs() < 1e-12);
+        // theta = 2: terms 6*2, 11*4, 6*8, 1*16 -> P(K >= 4) = 16 / 120
+        assert!((calculate_fu_fs(4, 4, 2.0) - (16.0f64 / 104.0).ln()).abs() < 1e-12);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_n1() {
-        let pi_n1 = calculate_pi(100, 1, 1000);
-        assert!(pi_n1.is_infinite());
+    fn test_calculate_neutrality_index() {
+        assert_eq!(calculate_neutrality_index(0.002, 0.001), Some(2.0));
+        assert_eq!(calculate_neutrality_index(0.001, 0.004), Some(0.25));
+        // Undefined without segregating sites
+        assert_eq!(calculate_neutrality_index(0.0, 0.0), None);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_seq_zero() {
-        let pi_seq_zero = calculate_pi(100, 10, 0);
-        assert!(pi_seq_zero.is_infinite());
+    fn test_calculate_fu_fs_large_sample() {
+        // Stirling numbers for n = 500 overflow f64, the log-space sums must not
+        let fs = calculate_fu_fs(60, 500, 5.0);
+        assert!(fs.is_finite());
+        // Many more haplotypes than theta predicts gives a strongly negative Fs
+        assert!(fs < -10.0);
     }
 
     #[test]
-    fn test_calculate_watterson_theta_pi_large_values() {
-        let epsilon = 1e-10;
-        let pi = calculate_pi(10000, 100, 10000);
-        let expected = 10000f64 / ((100 * (100 - 1) / 2) as f64 * 10000.0);
-        assert!((pi - expected).abs() < epsilon);
+    fn test_calculate_fu_fs_undefined() {
+        assert!(calculate_fu_fs(1, 4, 0.0).is_nan());
+        assert!(calculate_fu_fs(0, 4, 1.0).is_nan());
+        assert!(calculate_fu_fs(5, 4, 1.0).is_nan());
+        assert!(calculate_fu_fs(1, 1, 1.0).is_nan());
+        assert_eq!(calculate_fu_fs(1, 4, 1.0), f64::INFINITY);
     }
 
     #[test]
-    fn test_calculate_pi_typical_values() {
-        let epsilon = 1e-6;
-        assert!((calculate_pi(15, 5, 1000) - 0.0015).abs() < epsilon);
+    fn test_calculate_zeng_e() {
+        // n = 4 with derived counts 1, 1, 2, 3: theta_L = 7/3, theta_W = 4/a_4
+        let variants = vec![
+            create_variant(1, haplotypes(&[1, 0, 0, 0])),
+            create_variant(2, haplotypes(&[0, 1, 0, 0])),
+            create_variant(3, haplotypes(&[1, 1, 0, 0])),
+            create_variant(4, haplotypes(&[1, 1, 1, 0])),
+        ];
+        let e = calculate_zeng_e(&variants, 4, 1000, AncestralSource::Ref);
+        assert!((e - 0.187259196).abs() < 1e-6);
     }
 
     #[test]
-    fn test_calculate_pi_no_pairwise_differences() {
-        assert_eq!(calculate_pi(0, 5, 1000), 0.0);
+    fn test_calculate_theta_l_neutral_sfs() {
+        // Under the neutral SFS xi_k = theta / k, so with theta = 6 and 4 haplotypes: 6, 3 and 2 sites
+        // with 1, 2 and 3 derived alleles. Then theta_L = (6 + 6 + 6) / 3 = 6 = 11 / a_4 = theta_W.
+        let mut variants = Vec::new();
+        for (derived, sites) in [(1, 6), (2, 3), (3, 2)] {
+            for _ in 0..sites {
+                let alleles: Vec<u8> = (0..4).map(|h| u8::from(h < derived)).collect();
+                variants.push(create_variant(variants.len() as i64 + 1, haplotypes(&alleles)));
+            }
+        }
+        let theta_l = calculate_theta_l(&variants, 4, 1000, AncestralSource::Ref);
+        let theta_w = calculate_watterson_theta(11, 4, 1000);
+        assert!((theta_l - 0.006).abs() < 1e-12);
+        assert!((theta_l - theta_w).abs() < 1e-12);
+        assert!(calculate_theta_l(&variants, 1, 1000, AncestralSource::Ref).is_nan());
+        assert!(calculate_theta_l(&variants, 4, 0, AncestralSource::Ref).is_nan());
+        assert!(calculate_theta_l(&variants, 4, -10, AncestralSource::Ref).is_nan());
     }
 
     #[test]
-    fn test_calculate_pi_large_pairwise_differences() {
-        let epsilon = 1e-6;
-        assert!((calculate_pi(10000, 100, 10000) - 0.00020202).abs() < epsilon);
+    fn test_calculate_zeng_e_sign() {
+        // An excess of singletons pulls E negative, high-frequency derived alleles push it positive
+        let singletons: Vec<Variant> = (0..4u8)
+            .map(|i| create_variant(i as i64, haplotypes(&[u8::from(i == 0), u8::from(i == 1), u8::from(i == 2), u8::from(i == 3)])))
+            .collect();
+        assert!((calculate_zeng_e(&singletons, 4, 1000, AncestralSource::Ref) + 1.048651499).abs() < 1e-6);
+
+        let high_frequency: Vec<Variant> = (0..3)
+            .map(|i| create_variant(i, haplotypes(&[1, 1, 1, 0])))
+            .collect();
+        assert!((calculate_zeng_e(&high_frequency, 4, 1000, AncestralSource::Ref) - 2.173962287).abs() < 1e-6);
     }
 
     #[test]
-    fn test_calculate_pi_min_sample_size() {
-        let epsilon = 1e-6;
-        assert!((calculate_pi(5, 2, 1000) - 0.005).abs() < epsilon);
+    fn test_calculate_zeng_e_aa_tag() {
+        // With ALT ancestral the single derived allele is REF, a singleton
+        let mut variant = create_variant(1, haplotypes(&[1, 1, 1, 0]));
+        variant.ancestral_allele = Some(1);
+        let e = calculate_zeng_e(&[variant.clone()], 4, 1000, AncestralSource::AaTag);
+        assert!((e + 0.824957911).abs() < 1e-6);
+
+        // Sites without an AA tag are skipped
+        variant.ancestral_allele = None;
+        assert!(calculate_zeng_e(&[variant], 4, 1000, AncestralSource::AaTag).is_nan());
     }
 
     #[test]
-    fn test_calculate_pi_very_large_sequence_length() {
-        let epsilon = 1e-9;
-        assert!((calculate_pi(1000, 10, 1_000_000) - 0.0000222222).abs() < epsilon);
+    fn test_calculate_zeng_e_undefined() {
+        assert!(calculate_zeng_e(&[create_variant(1, haplotypes(&[0, 1]))], 2, 100, AncestralSource::Ref).is_nan());
+        assert!(calculate_zeng_e(&[create_variant(1, haplotypes(&[0, 0, 0, 0]))], 4, 100, AncestralSource::Ref).is_nan());
+        assert!(calculate_zeng_e(&[], 4, 100, AncestralSource::Ref).is_nan());
     }
 
     #[test]
-    fn test_calculate_pi_n1_infinite() {
-        let pi_n1 = calculate_pi(100, 1, 1000);
-        assert!(pi_n1.is_infinite());
+    fn test_calculate_fst_hudson_fixed_difference() {
+        let group_0 = vec![create_variant(1000, haplotypes(&[0, 0, 0, 0]))];
+        let group_1 = vec![create_variant(1000, haplotypes(&[1, 1, 1]))];
+        let fst = calculate_fst_hudson(&group_0, 4, &group_1, 3, 100);
+        assert!((fst - 1.0).abs() < 1e-10);
     }
 
     #[test]
-    fn test_calculate_pi_n0_infinite() {
-        let pi_n0 = calculate_pi(0, 0, 1000);
-        assert!(pi_n0.is_infinite());
+    fn test_calculate_fst_hudson_monomorphic_group() {
+        // pi_within = (0 + 4/6) / 2, pi_between = 8/16
+        let group_0 = vec![
+            create_variant(1000, haplotypes(&[0, 0, 0, 0])),
+            create_variant(2000, haplotypes(&[0, 0, 0, 0])),
+        ];
+        let group_1 = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2000, haplotypes(&[0, 0, 0, 0])),
+        ];
+        let fst = calculate_fst_hudson(&group_0, 4, &group_1, 4, 100);
+        assert!((fst - 1.0 / 3.0).abs() < 1e-10);
     }
 
     #[test]
-    fn test_parse_region_valid_small() {
-        assert_eq!(parse_region("1-1000").unwrap(), (1, 1000));
+    fn test_calculate_dxy() {
+        // Site 1000: 2 of 4 cross pairs differ; site 2000: all 4 differ; site 3000 only in group 0
+        let group_0 = vec![
+            create_variant(1000, haplotypes(&[0, 1])),
+            create_variant(2000, haplotypes(&[0, 0])),
+            create_variant(3000, haplotypes(&[1, 1])),
+        ];
+        let group_1 = vec![
+            create_variant(1000, haplotypes(&[0, 1])),
+            create_variant(2000, haplotypes(&[1, 1])),
+        ];
+        let dxy = calculate_dxy(&group_0, 2, &group_1, 2, 10);
+        assert!((dxy - 1.5 / 10.0).abs() < 1e-12);
+
+        // A missing call leaves fewer pairs at that site
+        let group_1 = vec![create_variant(2000, vec![Some(vec![1]), None])];
+        assert!((calculate_dxy(&group_0, 2, &group_1, 2, 10) - 0.1).abs() < 1e-12);
+        assert!(calculate_dxy(&group_0, 0, &group_1, 2, 10).is_nan());
+        assert!(calculate_dxy(&group_0, 2, &group_1, 2, 0).is_nan());
     }
 
     #[test]
-    fn test_parse_region_valid_large() {
-        assert_eq!(parse_region("1000000-2000000").unwrap(), (1000000, 2000000));
+    fn test_count_fixed_differences() {
+        // Site 1000 is polymorphic, 2000 fixed for different alleles, 3000 fixed for the same allele
+        let group_0 = vec![
+            create_variant(1000, haplotypes(&[0, 1])),
+            create_variant(2000, haplotypes(&[0, 0])),
+            create_variant(3000, haplotypes(&[1, 1])),
+            create_variant(4000, vec![Some(vec![0]), None]),
+        ];
+        let group_1 = vec![
+            create_variant(1000, haplotypes(&[1, 1])),
+            create_variant(2000, haplotypes(&[1, 1])),
+            create_variant(3000, haplotypes(&[1, 1])),
+            create_variant(4000, haplotypes(&[1, 1])),
+        ];
+        assert_eq!(count_fixed_differences(&group_0, 2, &group_1, 2, false), 1);
+        // Site 4000 only counts when missing calls are allowed
+        assert_eq!(count_fixed_differences(&group_0, 2, &group_1, 2, true), 2);
+        assert_eq!(count_fixed_differences(&group_0, 0, &group_1, 2, true), 0);
     }
 
     #[test]
-    fn test_parse_region_invalid_missing_end() {
-        assert!(matches!(parse_region("1000"), Err(VcfError::InvalidRegion(_))));
+    fn test_calculate_fst_hudson_undefined() {
+        let group_0 = vec![create_variant(1000, haplotypes(&[0, 0]))];
+        let group_1 = vec![create_variant(1000, haplotypes(&[0, 0]))];
+        assert!(calculate_fst_hudson(&group_0, 2, &group_1, 2, 100).is_nan());
+        assert!(calculate_fst_hudson(&group_0, 1, &group_1, 2, 100).is_nan());
+        assert!(calculate_fst_hudson(&group_0, 2, &group_1, 2, 0).is_nan());
     }
 
     #[test]
-    fn test_parse_region_invalid_missing_start() {
-        assert!(matches!(parse_region("1000-"), Err(VcfError::InvalidRegion(_))));
+    fn test_permute_groups() {
+        let mut rng = bootstrap_rng(Some(7));
+        let (group_0, group_1) = permute_groups(3, 5, &mut rng);
+        assert_eq!(group_0.len(), 3);
+        assert_eq!(group_1.len(), 5);
+        let mut all: Vec<usize> = group_0.iter().chain(&group_1).copied().collect();
+        all.sort();
+        assert_eq!(all, (0..8).collect::<Vec<_>>());
+        assert_eq!(permute_groups(3, 5, &mut bootstrap_rng(Some(7))), (group_0, group_1));
     }
 
     #[test]
-    fn test_parse_region_invalid_negative_start() {
-        assert!(matches!(parse_region("-1000"), Err(VcfError::InvalidRegion(_))));
+    fn test_fst_permutation_p_value() {
+        // Only 2 of the 70 ways to split 8 haplotypes 4/4 reproduce the fixed difference
+        let group_0 = vec![create_variant(1000, haplotypes(&[0, 0, 0, 0]))];
+        let group_1 = vec![create_variant(1000, haplotypes(&[1, 1, 1, 1]))];
+        let p = fst_permutation_p_value(&group_0, 4, &group_1, 4, 100, 2000, &mut bootstrap_rng(Some(1)));
+        assert!(p > 0.0 && p < 0.1, "p = {}", p);
+
+        // Identical groups: every permutation is at least as differentiated
+        let group_1 = vec![create_variant(1000, haplotypes(&[0, 1, 0, 1]))];
+        let p = fst_permutation_p_value(&group_1, 4, &group_1, 4, 100, 200, &mut bootstrap_rng(Some(1)));
+        assert!(p > 0.5, "p = {}", p);
+
+        assert!(fst_permutation_p_value(&group_0, 4, &group_1, 4, 100, 0, &mut bootstrap_rng(Some(1))).is_nan());
+        assert!(fst_permutation_p_value(&group_0, 4, &group_0, 4, 100, 100, &mut bootstrap_rng(Some(1))).is_nan());
     }
 
     #[test]
-    fn test_parse_region_invalid_non_numeric_start() {
-        assert!(matches!(parse_region("a-1000"), Err(VcfError::InvalidRegion(_))));
+    fn test_calculate_weir_cockerham_fst() {
+        // n = 3 and 3, p = 1/3 and 1: MSP = 2/3, MSG = 1/6, n_c = 3, so each allele gives 1/2 over 1
+        let variants = vec![create_variant(1000, haplotypes(&[0, 0, 1, 1, 1, 1]))];
+        let fst = calculate_weir_cockerham_fst(&[vec![0, 1, 2], vec![3, 4, 5]], &variants);
+        assert!((fst - 0.5).abs() < 1e-10);
+
+        // Fixed differences give 1 whatever the sample sizes
+        let variants = vec![create_variant(1000, haplotypes(&[0, 0, 0, 0, 1, 1]))];
+        let fst = calculate_weir_cockerham_fst(&[vec![0, 1, 2, 3], vec![4, 5]], &variants);
+        assert!((fst - 1.0).abs() < 1e-10);
     }
 
     #[test]
-    fn test_parse_region_invalid_non_numeric_end() {
-        assert!(matches!(parse_region("1000-b"), Err(VcfError::InvalidRegion(_))));
+    fn test_calculate_weir_cockerham_fst_three_populations() {
+        let variants = vec![create_variant(1000, haplotypes(&[0, 0, 1, 1, 0, 1]))];
+        let populations = vec![vec![0, 1], vec![2, 3], vec![4, 5]];
+        // r = 3, n = 2 each, p = 0, 1, 1/2: MSP = 1/2, MSG = 1/6, n_c = 2
+        let expected = (0.5 - 1.0 / 6.0) / (0.5 + 1.0 / 6.0);
+        assert!((calculate_weir_cockerham_fst(&populations, &variants) - expected).abs() < 1e-10);
     }
 
     #[test]
-    fn test_parse_region_invalid_start_equals_end() {
-        assert!(matches!(parse_region("1000-1000"), Err(VcfError::InvalidRegion(_))));
+    fn test_calculate_weir_cockerham_fst_undefined() {
+        let monomorphic = vec![create_variant(1000, haplotypes(&[0, 0, 0, 0]))];
+        assert!(calculate_weir_cockerham_fst(&[vec![0, 1], vec![2, 3]], &monomorphic).is_nan());
+        let variants = vec![create_variant(1000, haplotypes(&[0, 1, 0, 1]))];
+        assert!(calculate_weir_cockerham_fst(&[vec![0, 1, 2, 3]], &variants).is_nan());
+        // A population without any called allele leaves only one
+        let missing = vec![create_variant(1000, vec![Some(vec![0]), Some(vec![1]), None, None])];
+        assert!(calculate_weir_cockerham_fst(&[vec![0, 1], vec![2, 3]], &missing).is_nan());
     }
 
     #[test]
-    fn test_parse_region_invalid_start_greater_than_end() {
-        assert!(matches!(parse_region("2000-1000"), Err(VcfError::InvalidRegion(_))));
+    fn test_pairwise_weir_cockerham_fst() {
+        let sample_names = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let mut sample_filter = HashMap::new();
+        sample_filter.insert("A".to_string(), (0, 0));
+        sample_filter.insert("B".to_string(), (1, 1));
+        sample_filter.insert("C".to_string(), (2, 2));
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![1, 1]), Some(vec![0, 0])]),
+            create_variant(5000, vec![Some(vec![0, 1]), Some(vec![0, 1]), Some(vec![0, 1])]),
+        ];
+        let pairs = pairwise_weir_cockerham_fst(&variants, &sample_names, &[0, 1, 2], &sample_filter, 1, 2000);
+        let labels: Vec<(u8, u8)> = pairs.iter().map(|&(a, b, _)| (a, b)).collect();
+        assert_eq!(labels, vec![(0, 1), (0, 2), (1, 2)]);
+        assert!((pairs[0].2 - 1.0).abs() < 1e-10);
+        assert!(pairs[1].2.is_nan());
+        assert!((pairs[2].2 - 1.0).abs() < 1e-10);
     }
 
     #[test]
-    fn test_validate_vcf_header_valid() {
-        let valid_header = "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\tSAMPLE2";
-        assert!(validate_vcf_header(valid_header).is_ok());
+    fn test_extract_group_variants() {
+        let sample_names = vec!["SAMPLE_A".to_string(), "SAMPLE_B".to_string()];
+        let mut sample_filter = HashMap::new();
+        sample_filter.insert("A".to_string(), (0, 1));
+        sample_filter.insert("B".to_string(), (1, 1));
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 1]), Some(vec![1, 0])]),
+            create_variant(5000, vec![Some(vec![0, 1]), Some(vec![1, 0])]),
+        ];
+
+        let (group_0, n0) = extract_group_variants(&variants, &sample_names, 0, &sample_filter, 500, 2000);
+        assert_eq!(n0, 1);
+        assert_eq!(group_0.len(), 1);
+        assert_eq!(group_0[0].genotypes, vec![Some(vec![0])]);
+
+        let (group_1, n1) = extract_group_variants(&variants, &sample_names, 1, &sample_filter, 500, 2000);
+        assert_eq!(n1, 3);
+        let mut alleles: Vec<u8> = group_1[0].genotypes.iter().flatten().flatten().copied().collect();
+        alleles.sort();
+        assert_eq!(alleles, vec![0, 1, 1]);
     }
 
     #[test]
-    fn test_validate_vcf_header_invalid_missing_fields() {
-        let invalid_header = "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO";
-        assert!(matches!(validate_vcf_header(invalid_header), Err(VcfError::InvalidVcfFormat(_))));
+    fn test_calculate_tajima_d() {
+        // S = 10, k = 3.5, n = 10
+        let d = calculate_tajima_d(10, 3.5, 10);
+        assert!((d - -0.0437085303).abs() < 1e-8);
     }
 
     #[test]
-    fn test_validate_vcf_header_invalid_order() {
-        let invalid_order = "POS\t#CHROM\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT";
-        assert!(matches!(validate_vcf_header(invalid_order), Err(VcfError::InvalidVcfFormat(_))));
+    fn test_calculate_tajima_d_undefined() {
+        assert!(calculate_tajima_d(0, 0.0, 10).is_nan());
+        assert!(calculate_tajima_d(5, 2.0, 2).is_nan());
     }
-    
 
+    #[test]
+    fn test_total_pairwise_differences() {
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2000, vec![Some(vec![0]), None, Some(vec![1]), Some(vec![1])]),
+        ];
+        let expected: usize = calculate_pairwise_differences(&variants, 4)
+            .iter()
+            .map(|&(_, count, _)| count)
+            .sum();
+        assert_eq!(total_pairwise_differences(&variants), expected);
+        assert_eq!(expected, 6);
+    }
 
     #[test]
-    fn test_parse_variant_valid_all_gq_above_threshold() {
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut missing_data_info = MissingDataInfo::default();  
-        let mut _filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
+    fn test_calculate_per_site_pi() {
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2000, haplotypes(&[0, 0, 0, 1])),
+            create_variant(3000, haplotypes(&[1, 1, 1, 1])),
+        ];
+        let per_site = calculate_per_site_pi(&variants, 4);
+        assert_eq!(per_site.len(), 3);
+        // 4 of 6 pairs differ, then 3 of 6, then none
+        assert_eq!(per_site[0], (1000, 4.0 / 6.0));
+        assert_eq!(per_site[1], (2000, 0.5));
+        assert_eq!(per_site[2], (3000, 0.0));
+        let total: f64 = per_site.iter().map(|&(_, pi)| pi).sum();
+        assert!((total / 10.0 - calculate_pi(total_pairwise_differences(&variants), 4, 10)).abs() < 1e-12);
+    }
 
-        let valid_line = "chr1\t1500\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
-        let allow_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let mask_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let result = parse_variant(
-            valid_line,
-            "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut _filtering_stats,
-            allow_regions,
-            mask_regions,
-            &position_allele_map,
-        );
+    #[test]
+    fn test_compute_sfs_folded() {
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 0])),
+            create_variant(2000, haplotypes(&[1, 1, 1, 0])),
+            create_variant(3000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(4000, haplotypes(&[1, 1, 1, 1])),
+            // Not called in every haplotype
+            create_variant(5000, vec![Some(vec![0]), None, Some(vec![1]), Some(vec![1])]),
+        ];
+        assert_eq!(compute_sfs(&variants, 4, true), vec![2, 1]);
+        assert!(compute_sfs(&variants, 1, true).is_empty());
+    }
 
-        assert!(result.is_ok());
-        let some_variant = result.unwrap();
-        assert!(some_variant.is_some());
+    #[test]
+    fn test_compute_sfs_unfolded() {
+        let mut variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 0])),
+            create_variant(2000, haplotypes(&[1, 1, 1, 0])),
+            create_variant(3000, haplotypes(&[0, 1, 0, 1])),
+        ];
+        variants[0].ancestral_allele = Some(0);
+        variants[1].ancestral_allele = Some(0);
+        // Site 3000 has no AA tag and is skipped
+        assert_eq!(compute_sfs(&variants, 4, false), vec![1, 0, 1]);
+        variants[1].ancestral_allele = Some(1);
+        assert_eq!(compute_sfs(&variants, 4, false), vec![2, 0, 0]);
+    }
 
-        let (variant, is_valid) = some_variant.unwrap();
-        assert!(is_valid);
-        assert_eq!(variant.position, 1500);
-        assert_eq!(variant.genotypes, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]);
+    #[test]
+    fn test_normalize_sfs() {
+        assert_eq!(normalize_sfs(&[2, 1, 1]), vec![0.5, 0.25, 0.25]);
+        assert_eq!(normalize_sfs(&[0, 0]), vec![0.0, 0.0]);
+        assert!(normalize_sfs(&[]).is_empty());
     }
 
     #[test]
-    fn test_parse_variant_one_gq_below_threshold() {
-        let sample_names = vec![
-            "SAMPLE1".to_string(),
-            "SAMPLE2".to_string(),
-            "SAMPLE3".to_string(),
+    fn test_project_sfs() {
+        // Complete data is not projected at all
+        let complete = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 0])),
+            create_variant(2000, haplotypes(&[1, 1, 1, 0])),
+            create_variant(3000, haplotypes(&[0, 1, 0, 1])),
         ];
-        let mut missing_data_info = MissingDataInfo::default();
-        let mut filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
-    
-        // Define the expected variant using the helper function
-        let expected_variant = create_variant(
-            1000,
+        assert_eq!(project_sfs(&complete, 4, true), (vec![2.0, 1.0], 4));
+
+        // With three calls at 2000, n_proj = 3: the singleton at 1000 stays a singleton with probability 3/4
+        let mut variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 0])),
+            create_variant(2000, vec![Some(vec![0]), None, Some(vec![1]), Some(vec![0])]),
+            // Called in fewer than half of the haplotypes
+            create_variant(3000, vec![Some(vec![1]), None, None, None]),
+        ];
+        let (sfs, n_proj) = project_sfs(&variants, 4, true);
+        assert_eq!(n_proj, 3);
+        assert!((sfs[0] - 1.75).abs() < 1e-12);
+        for variant in &mut variants {
+            variant.ancestral_allele = Some(0);
+        }
+        let (sfs, _) = project_sfs(&variants, 4, false);
+        assert!((sfs[0] - 1.75).abs() < 1e-12 && sfs[1].abs() < 1e-12);
+        assert_eq!(project_sfs(&[], 4, true), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn test_write_sfs() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = sfs_output_path(&temp_dir.path().join("stats.csv"), "_1_1_100_group0");
+        assert_eq!(path.file_name().unwrap(), "stats_1_1_100_group0.sfs");
+        let variants = vec![create_variant(10, haplotypes(&[0, 1, 1, 1, 1]))];
+        write_sfs(&path, &variants, 5, true, false, false, 6).expect("Failed to write SFS");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1 0\n");
+        write_sfs(&path, &variants, 5, true, true, false, 6).expect("Failed to write SFS");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1.000000 0.000000\n");
+        write_sfs(&path, &variants, 5, true, true, false, 2).expect("Failed to write SFS");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1.00 0.00\n");
+    }
+
+    #[test]
+    fn test_output_path_with_suffix() {
+        assert_eq!(output_path_with_suffix(Path::new("out/stats.csv"), "_per_site"), PathBuf::from("out/stats_per_site.csv"));
+        assert_eq!(output_path_with_suffix(Path::new("stats"), "_ld"), PathBuf::from("stats_ld"));
+    }
+
+    #[test]
+    fn test_compressed_output_path() {
+        assert_eq!(compressed_output_path(Path::new("out/stats.csv"), true), PathBuf::from("out/stats.csv.gz"));
+        assert_eq!(compressed_output_path(Path::new("stats.csv.gz"), true), PathBuf::from("stats.csv.gz"));
+        assert_eq!(compressed_output_path(Path::new("stats"), true), PathBuf::from("stats.gz"));
+        assert_eq!(compressed_output_path(Path::new("stats.csv"), false), PathBuf::from("stats.csv"));
+    }
+
+    #[test]
+    fn test_open_output_writer_compressed() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("stats.csv");
+        let mut writer = open_output_writer(&path, true).expect("Failed to open output");
+        writer.write_all(b"chr,pi\n1,0.5\n").unwrap();
+        writer.finish().expect("Failed to finish output");
+        assert!(!path.exists());
+        let mut contents = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(File::open(temp_dir.path().join("stats.csv.gz")).unwrap()),
+            &mut contents,
+        )
+            .unwrap();
+        assert_eq!(contents, "chr,pi\n1,0.5\n");
+    }
+
+    #[test]
+    fn test_format_f64() {
+        assert_eq!(format_f64(0.123456789, 6), "0.123457");
+        assert_eq!(format_f64(2.0, 0), "2");
+        assert_eq!(format_f64(-1.5, 3), "-1.500");
+        assert_eq!(format_f64(f64::NAN, 6), "NA");
+        assert_eq!(format_f64(f64::INFINITY, 6), "NA");
+        assert_eq!(format_f64(f64::NEG_INFINITY, 6), "NA");
+    }
+
+    #[test]
+    fn test_write_per_site_pi_includes_invariant_sites() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out_per_site.csv");
+        let variants = vec![create_variant(3, haplotypes(&[0, 1]))];
+        write_per_site_pi(&path, "1", &[(1, 4)], &variants, 2, 6).expect("Failed to write per-site pi");
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines, vec!["chr,pos,pi_site", "1,1,0.000000", "1,2,0.000000", "1,3,1.000000", "1,4,0.000000"]);
+
+        // Only the positions inside the intervals are written
+        write_per_site_pi(&path, "1", &[(1, 1), (3, 4)], &variants, 2, 6).expect("Failed to write per-site pi");
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines, vec!["chr,pos,pi_site", "1,1,0.000000", "1,3,1.000000", "1,4,0.000000"]);
+    }
+
+    #[test]
+    fn test_clopper_pearson_ci() {
+        let (lower, upper) = clopper_pearson_ci(5, 10, 0.05);
+        assert!((lower - 0.187086).abs() < 1e-4);
+        assert!((upper - 0.812914).abs() < 1e-4);
+        // No successes: the lower bound is 0 and the upper bound is 1 - (alpha/2)^(1/n)
+        let (lower, upper) = clopper_pearson_ci(0, 10, 0.05);
+        assert_eq!(lower, 0.0);
+        assert!((upper - (1.0 - 0.025f64.powf(0.1))).abs() < 1e-4);
+        let (lower, upper) = clopper_pearson_ci(10, 10, 0.05);
+        assert!((lower - 0.025f64.powf(0.1)).abs() < 1e-4);
+        assert_eq!(upper, 1.0);
+        assert_eq!(clopper_pearson_ci(0, 0, 0.05), (0.0, 1.0));
+    }
+
+    #[test]
+    fn test_write_allele_frequencies() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("af.csv");
+        let variants = vec![
+            create_variant(3, vec![Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(7, vec![Some(vec![0, 0]), None]),
+        ];
+        write_allele_frequencies(&path, "1", &variants, 3).expect("Failed to write allele frequencies");
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "chr,pos,ac,an,af,ci_lower,ci_upper");
+        assert!(lines[1].starts_with("1,3,3,4,0.750,"));
+        // Missing genotypes do not count towards AN
+        assert_eq!(lines[2], "1,7,0,2,0.000,0.000,0.842");
+    }
+
+    #[test]
+    fn test_calculate_r_squared_perfect_linkage() {
+        let v1 = create_variant(100, haplotypes(&[0, 0, 1, 1]));
+        let v2 = create_variant(200, haplotypes(&[0, 0, 1, 1]));
+        assert!((calculate_r_squared(&v1, &v2, 4).unwrap() - 1.0).abs() < 1e-12);
+        assert!((calculate_d_prime(&v1, &v2, 4).unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_calculate_r_squared_partial_linkage() {
+        // pA = pB = 0.5, pAB = 0.25 + 1/8, so D = 0.125
+        let v1 = create_variant(100, haplotypes(&[1, 1, 1, 1, 0, 0, 0, 0]));
+        let v2 = create_variant(200, haplotypes(&[1, 1, 1, 0, 1, 0, 0, 0]));
+        assert!((calculate_r_squared(&v1, &v2, 8).unwrap() - 0.25).abs() < 1e-12);
+        assert!((calculate_d_prime(&v1, &v2, 8).unwrap() - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_calculate_r_squared_undefined() {
+        let v1 = create_variant(100, haplotypes(&[0, 0, 0, 0]));
+        let v2 = create_variant(200, haplotypes(&[0, 1, 0, 1]));
+        assert_eq!(calculate_r_squared(&v1, &v2, 4), None);
+
+        // One haplotype missing at the second site
+        let v3 = create_variant(300, vec![Some(vec![0]), Some(vec![0]), Some(vec![1]), None]);
+        let v4 = create_variant(400, haplotypes(&[0, 1, 1, 1]));
+        assert_eq!(calculate_r_squared(&v3, &v4, 4), None);
+        assert!(calculate_r_squared(&v3, &v4, 3).is_some());
+    }
+
+    #[test]
+    fn test_calculate_ld_pairs_window() {
+        let variants = vec![
+            create_variant(300, haplotypes(&[0, 1, 0, 1])),
+            create_variant(100, haplotypes(&[0, 0, 1, 1])),
+            create_variant(150, haplotypes(&[0, 0, 1, 1])),
+        ];
+        let mut pairs = calculate_ld_pairs(&variants, 150, 4);
+        pairs.sort_by_key(|&(pos1, pos2, _, _)| (pos1, pos2));
+        let positions: Vec<(i64, i64)> = pairs.iter().map(|&(p1, p2, _, _)| (p1, p2)).collect();
+        assert_eq!(positions, vec![(100, 150), (150, 300)]);
+        assert!((pairs[0].2 - 1.0).abs() < 1e-12);
+        assert!(pairs[1].2.abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_log_spaced_bins() {
+        assert_eq!(log_spaced_bins(3, 999), vec![(1, 10), (10, 100), (100, 1000)]);
+        // Rounding collapses the narrowest bins instead of producing empty ones
+        let bins = log_spaced_bins(10, 5);
+        assert_eq!(bins.first().unwrap().0, 1);
+        assert_eq!(bins.last().unwrap().1, 6);
+        assert!(bins.iter().all(|&(start, end)| start < end));
+        assert!(log_spaced_bins(0, 1000).is_empty());
+    }
+
+    #[test]
+    fn test_ld_decay_bins() {
+        let ld_values = vec![((100, 105), 0.8), ((100, 108), 0.6), ((100, 150), 0.2), ((100, 400), 0.1)];
+        let decay = ld_decay_bins(&ld_values, &[(1, 10), (10, 100), (100, 200)]);
+        assert_eq!(decay[0].0, 5);
+        assert!((decay[0].1 - 0.7).abs() < 1e-12);
+        assert_eq!(decay[0].2, 2);
+        assert!((decay[1].1 - 0.2).abs() < 1e-12);
+        assert_eq!(decay[1].2, 1);
+        // The 300 bp pair falls in no bin
+        assert!(decay[2].1.is_nan());
+        assert_eq!(decay[2].2, 0);
+    }
+
+    #[test]
+    fn test_count_ts_tv() {
+        let snp = |position, ref_allele, alt_allele, genotypes| Variant {
+            position,
+            genotypes,
+            ancestral_allele: None,
+            ref_allele,
+            alt_allele,
+            variant_type: VariantType::Snp,
+        };
+        let variants = vec![
+            snp(100, b'A', b'G', vec![Some(vec![0, 1]), Some(vec![0, 0])]), // transition
+            snp(200, b'C', b'T', vec![Some(vec![1, 1]), Some(vec![0, 0])]), // transition
+            snp(300, b'A', b'C', vec![Some(vec![0, 1]), None]),             // transversion
+            snp(400, b'G', b'T', vec![Some(vec![0, 0]), Some(vec![0, 0])]), // monomorphic
+            snp(500, b'A', b'N', vec![Some(vec![0, 1]), Some(vec![0, 0])]), // not a SNP
+        ];
+        assert_eq!(count_ts_tv(&variants), (2, 1));
+    }
+
+    #[test]
+    fn test_snp_base() {
+        assert_eq!(snp_base("a"), b'A');
+        assert_eq!(snp_base("T"), b'T');
+        assert_eq!(snp_base("AT"), b'N');
+        assert_eq!(snp_base("<DEL>"), b'N');
+        assert_eq!(snp_base("."), b'N');
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 50.0), 3.0);
+        assert!((percentile(&values, 2.5) - 1.1).abs() < 1e-10);
+        assert!(percentile(&[], 50.0).is_nan());
+    }
+
+    #[test]
+    fn test_bootstrap_statistic_reproducible() {
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2000, haplotypes(&[0, 0, 0, 1])),
+            create_variant(3000, haplotypes(&[0, 0, 0, 0])),
+            create_variant(4000, haplotypes(&[1, 1, 0, 0])),
+        ];
+        let first = bootstrap_pi(&variants, 4, 100, 200, &mut bootstrap_rng(Some(42)));
+        let second = bootstrap_pi(&variants, 4, 100, 200, &mut bootstrap_rng(Some(42)));
+        assert_eq!(first, second);
+
+        let pi = calculate_pi(total_pairwise_differences(&variants), 4, 100);
+        assert!(first.0 <= pi && pi <= first.1);
+    }
+
+    #[test]
+    fn test_bootstrap_statistic_identical_sites() {
+        // Every resample is the same, so the interval collapses onto the point estimate
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(2000, haplotypes(&[0, 1, 0, 1])),
+        ];
+        let (lower, upper) = bootstrap_theta(&variants, 4, 100, 50, &mut bootstrap_rng(Some(1)));
+        let theta = calculate_watterson_theta(2, 4, 100);
+        assert!((lower - theta).abs() < 1e-12);
+        assert!((upper - theta).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_bootstrap_statistic_disabled() {
+        let variants = vec![create_variant(1000, haplotypes(&[0, 1, 0, 1]))];
+        let (lower, upper) = bootstrap_pi(&variants, 4, 100, 0, &mut bootstrap_rng(Some(1)));
+        assert!(lower.is_nan() && upper.is_nan());
+    }
+
+    #[test]
+    fn test_sliding_windows_non_overlapping() {
+        let windows: Vec<_> = sliding_windows(1, 250, 100, 100).collect();
+        assert_eq!(windows, vec![(1, 100), (101, 200), (201, 250)]);
+    }
+
+    #[test]
+    fn test_sliding_windows_overlapping() {
+        let windows: Vec<_> = sliding_windows(1, 200, 100, 50).collect();
+        assert_eq!(windows, vec![(1, 100), (51, 150), (101, 200)]);
+    }
+
+    #[test]
+    fn test_sliding_windows_invalid() {
+        assert_eq!(sliding_windows(1, 200, 0, 50).count(), 0);
+        assert_eq!(sliding_windows(1, 200, 100, 0).count(), 0);
+        assert_eq!(sliding_windows(300, 200, 100, 50).count(), 0);
+    }
+
+    #[test]
+    fn test_calculate_window_stats() {
+        let variants = vec![
+            create_variant(1000, haplotypes(&[0, 1, 0, 1])),
+            create_variant(1010, haplotypes(&[0, 0, 0, 0])),
+        ];
+        let stats = calculate_window_stats(&variants, 4, 100);
+        assert_eq!(stats.segregating_sites, 1);
+        assert_eq!(stats.num_haplotypes, 4);
+        // 4 of 6 pairs differ at the single segregating site
+        assert!((stats.pi - 4.0 / 6.0 / 100.0).abs() < 1e-10);
+        assert!((stats.w_theta - 1.0 / harmonic(3) / 100.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_subsample_variants() {
+        let mut unfiltered: Vec<Variant> =
+            (1..=10).map(|pos| create_variant(pos * 100, haplotypes(&[0, 1, 0, 1]))).collect();
+        let mut filtered: Vec<Variant> = unfiltered.iter().filter(|v| v.position % 200 == 0).cloned().collect();
+        let mut rng = bootstrap_rng(Some(7));
+        assert!(!subsample_variants(&mut unfiltered, &mut filtered, 0, &mut rng));
+        assert!(!subsample_variants(&mut unfiltered, &mut filtered, 10, &mut rng));
+        assert_eq!(unfiltered.len(), 10);
+
+        assert!(subsample_variants(&mut unfiltered, &mut filtered, 4, &mut rng));
+        assert_eq!(unfiltered.len(), 4);
+        assert!(unfiltered.windows(2).all(|pair| pair[0].position < pair[1].position));
+        // Filtered variants follow the kept unfiltered positions
+        let kept: Vec<i64> = unfiltered.iter().map(|v| v.position).filter(|pos| pos % 200 == 0).collect();
+        assert_eq!(filtered.iter().map(|v| v.position).collect::<Vec<_>>(), kept);
+    }
+
+    #[test]
+    fn test_calculate_inbreeding_f() {
+        // Two samples, both heterozygous: p = 0.5, H_exp = 1, H_obs = 2
+        let outbred = vec![create_variant(1000, haplotypes(&[0, 1, 1, 0]))];
+        assert!((calculate_inbreeding_f(&outbred, 4) + 1.0).abs() < 1e-12);
+        // Two homozygous samples of different alleles: no heterozygotes at all
+        let inbred = vec![create_variant(1000, haplotypes(&[0, 0, 1, 1]))];
+        assert!((calculate_inbreeding_f(&inbred, 4) - 1.0).abs() < 1e-12);
+        // Monomorphic sites and too few haplotypes are undefined
+        let monomorphic = vec![create_variant(1000, haplotypes(&[0, 0, 0, 0]))];
+        assert!(calculate_inbreeding_f(&monomorphic, 4).is_nan());
+        assert!(calculate_inbreeding_f(&inbred, 1).is_nan());
+        // Config mode reads the same F from unprojected genotypes; uncalled samples are skipped
+        let diploid = vec![create_variant(1000, vec![Some(vec![0, 1]), Some(vec![1, 0]), None])];
+        assert!((calculate_genotype_inbreeding_f(&diploid) + 1.0).abs() < 1e-12);
+        let homozygous = vec![create_variant(1000, vec![Some(vec![0, 0]), Some(vec![1, 1])])];
+        assert!((calculate_genotype_inbreeding_f(&homozygous) - 1.0).abs() < 1e-12);
+    }
+
+    fn example_set_stats(pi: f64) -> HaplotypeSetStats {
+        let stats = GroupStats { segregating_sites: 2, pi, num_haplotypes: 4, ..Default::default() };
+        HaplotypeSetStats {
+            unfiltered: stats.clone(),
+            filtered: stats,
+            pi_lower: f64::NAN,
+            pi_upper: f64::NAN,
+            theta_lower: f64::NAN,
+            theta_upper: f64::NAN,
+            ne_theta_w: None,
+            ne_pi: None,
+        }
+    }
+
+    #[test]
+    fn test_write_json_output_groups_nested() {
+        let region = RegionStats {
+            label: None,
+            chr: "1".to_string(),
+            region_start: 100,
+            region_end: 200,
+            sequence_length: 101,
+            sequence_length_adjusted: 90,
+            sequence_length_accessible: None,
+            gc_content: None,
+            all: None,
+            group_0: Some(example_set_stats(0.01)),
+            group_1: Some(example_set_stats(0.02)),
+            inversion_freq_no_filter: Some(0.5),
+            inversion_freq_filter: Some(0.5),
+            fst: Some(0.1),
+            fst_filtered: Some(0.1),
+            dxy: Some(0.02),
+            dxy_filtered: Some(0.02),
+            inbreeding_f: None,
+            inbreeding_f_filtered: None,
+            fst_p_value: Some(0.01),
+            fixed_diffs: None,
+            fixed_diffs_filtered: None,
+            pairwise_fst: Vec::new(),
+            subsampled: false,
+            outlier: None,
+        };
+        assert_eq!(config_stats_record(&region, 6).len(), 80);
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out.json");
+        write_json_output(&[region], &path).expect("Failed to write JSON");
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let region = &json[0];
+        assert_eq!(region["chr"], "1");
+        assert_eq!(region["group_0"]["unfiltered"]["pi"], 0.01);
+        assert_eq!(region["group_1"]["filtered"]["segregating_sites"], 2);
+        // NaN bounds are written as null, and no flat per-group fields appear
+        assert!(region["group_0"]["pi_lower"].is_null());
+        assert!(region.get("0_pi").is_none());
+        assert!(region.get("unfiltered").is_none());
+    }
+
+    #[test]
+    fn test_write_json_output_all_haplotypes_flat() {
+        let region = RegionStats {
+            label: None,
+            chr: "1".to_string(),
+            region_start: 1,
+            region_end: 300,
+            sequence_length: 300,
+            sequence_length_adjusted: 300,
+            sequence_length_accessible: None,
+            gc_content: None,
+            all: Some(example_set_stats(0.03)),
+            group_0: None,
+            group_1: None,
+            inversion_freq_no_filter: None,
+            inversion_freq_filter: None,
+            fst: None,
+            fst_filtered: None,
+            dxy: None,
+            dxy_filtered: None,
+            inbreeding_f: None,
+            inbreeding_f_filtered: None,
+            fst_p_value: None,
+            fixed_diffs: None,
+            fixed_diffs_filtered: None,
+            pairwise_fst: Vec::new(),
+            subsampled: false,
+            outlier: None,
+        };
+        assert_eq!(region_stats_record(&region, 6).len(), 3 + REGION_STATS_COLUMNS.len());
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out.json");
+        write_json_output(&[region], &path).expect("Failed to write JSON");
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json[0]["unfiltered"]["pi"], 0.03);
+        assert!(json[0].get("group_0").is_none());
+        assert!(json[0].get("fst").is_none());
+    }
+
+    #[test]
+    fn test_flag_outliers() {
+        let region = |pi: f64| RegionStats {
+            label: None,
+            chr: "1".to_string(),
+            region_start: 1,
+            region_end: 300,
+            sequence_length: 300,
+            sequence_length_adjusted: 300,
+            sequence_length_accessible: None,
+            gc_content: None,
+            all: Some(example_set_stats(pi)),
+            group_0: None,
+            group_1: None,
+            inversion_freq_no_filter: None,
+            inversion_freq_filter: None,
+            fst: None,
+            fst_filtered: None,
+            dxy: None,
+            dxy_filtered: None,
+            inbreeding_f: None,
+            inbreeding_f_filtered: None,
+            fst_p_value: None,
+            fixed_diffs: None,
+            fixed_diffs_filtered: None,
+            pairwise_fst: Vec::new(),
+            subsampled: false,
+            outlier: None,
+        };
+        // Ten similar regions and one with a far higher pi: |Z| of the odd one is about 3.16
+        let mut results: Vec<RegionStats> = (0..10).map(|i| region(0.01 + i as f64 * 0.0001)).collect();
+        results.push(region(0.5));
+        let flags = flag_outliers(&results, 3.0);
+        assert_eq!(flags.iter().filter(|&&flag| flag).count(), 1);
+        assert!(flags[10]);
+        assert!(flag_outliers(&results, 3.5).iter().all(|&flag| !flag));
+        // Identical regions have no spread, so nothing is flagged
+        assert_eq!(flag_outliers(&[region(0.01), region(0.01)], 0.0), vec![false, false]);
+    }
+
+    #[test]
+    fn test_parse_ancestral_allele() {
+        assert_eq!(parse_ancestral_allele("AA=C;VT=SNP", "C", &["T"]), Some(0));
+        assert_eq!(parse_ancestral_allele("VT=SNP;AA=t|||", "C", &["T"]), Some(1));
+        assert_eq!(parse_ancestral_allele("AA=G", "C", &["T"]), None);
+        assert_eq!(parse_ancestral_allele("AA=.", "C", &["T"]), None);
+        assert_eq!(parse_ancestral_allele(".", "C", &["T"]), None);
+    }
+
+    #[test]
+    fn test_calculate_pairwise_differences_basic() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+        ];
+
+        let result = calculate_pairwise_differences(&variants, 3);
+
+        assert_eq!(result.len(), 3);
+    }
+
+    #[test]
+    fn test_calculate_pairwise_differences_pair_0_1() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+        ];
+
+        let result = calculate_pairwise_differences(&variants, 3);
+
+        for &((i, j), count, ref positions) in &result {
+            if (i, j) == (0, 1) {
+                assert_eq!(count, 2);
+                assert_eq!(positions, &vec![1000, 3000]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_calculate_pairwise_differences_pair_0_2() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+        ];
+
+        let result = calculate_pairwise_differences(&variants, 3);
+
+        for &((i, j), count, ref positions) in &result {
+            if (i, j) == (0, 2) {
+                assert_eq!(count, 3);
+                assert_eq!(positions, &vec![1000, 2000, 3000]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_calculate_pairwise_differences_with_missing_data() {
+        let missing_data_variants = vec![
+            create_variant(1, vec![Some(vec![0]), None, Some(vec![1])]),
+            create_variant(2, vec![Some(vec![1]), Some(vec![1]), None]),
+        ];
+        let missing_data_result = calculate_pairwise_differences(&missing_data_variants, 3);
+
+        assert_eq!(missing_data_result.len(), 3);
+
+        for &((i, j), count, _) in &missing_data_result {
+            match (i, j) {
+                (0, 1) => assert_eq!(count, 0),
+                (0, 2) => assert_eq!(count, 1),
+                (1, 2) => assert_eq!(count, 0),
+                _ => panic!("Unexpected pair: ({}, {})", i, j),
+            }
+        }
+    }
+
+    #[test]
+    fn test_calculate_pairwise_differences_multiallelic() {
+        let variants = vec![create_variant(1, vec![Some(vec![1]), Some(vec![2]), Some(vec![2]), None])];
+        for &((i, j), count, _) in &calculate_pairwise_differences(&variants, 4) {
+            match (i, j) {
+                // Two different ALT alleles count as a difference
+                (0, 1) | (0, 2) => assert_eq!(count, 1),
+                (1, 2) => assert_eq!(count, 0),
+                _ => assert_eq!(count, 0, "pair ({}, {}) has a missing call", i, j),
+            }
+        }
+        let mut accumulator = StreamingDiversityAccumulator::new(4);
+        accumulator.add(&variants[0]);
+        assert_eq!(accumulator.segregating_sites(), 1);
+    }
+
+    #[test]
+    fn test_write_pairwise_matrix() {
+        let variants = vec![
+            create_variant(1, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+        ];
+        let diffs = calculate_pairwise_differences(&variants, 3);
+        let names = vec!["EUR_GBR_HG00096".to_string(), "NA18939".to_string(), "NA18940".to_string()];
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+
+        let csv_path = temp_dir.path().join("matrix.csv");
+        write_pairwise_matrix(&diffs, &names, &csv_path, MatrixFormat::Csv).unwrap();
+        assert_eq!(
+            fs::read_to_string(&csv_path).unwrap(),
+            "sample,HG00096,NA18939,NA18940\nHG00096,0,1,2\nNA18939,1,0,2\nNA18940,2,2,0\n"
+        );
+
+        let phylip_path = temp_dir.path().join("matrix.phy");
+        write_pairwise_matrix(&diffs, &names, &phylip_path, MatrixFormat::Phylip).unwrap();
+        assert_eq!(
+            fs::read_to_string(&phylip_path).unwrap(),
+            "3\nHG00096    0 1 2\nNA18939    1 0 2\nNA18940    2 2 0\n"
+        );
+    }
+
+    #[test]
+    fn test_write_diff_positions() {
+        let variants = vec![
+            create_variant(1, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+        ];
+        let diffs = calculate_pairwise_differences(&variants, 3);
+        let names = vec!["EUR_GBR_HG00096".to_string(), "NA18939".to_string(), "NA18940".to_string()];
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("diff_positions.csv");
+
+        write_diff_positions(&diffs, &names, 0, &path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "sample_i,sample_j,diff_count,positions\nHG00096,NA18939,1,1\nHG00096,NA18940,2,1;2\nNA18939,NA18940,2,1;2\n"
+        );
+
+        // Pairs at or below the minimum are left out
+        write_diff_positions(&diffs, &names, 1, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_streaming_diversity_accumulator_matches_pairwise() {
+        let variants = vec![
+            create_variant(1, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 1])]),
+            create_variant(2, vec![Some(vec![0, 1]), None, Some(vec![0, 1]), Some(vec![0, 0])]),
+            create_variant(3, vec![Some(vec![0, 0]), Some(vec![0, 0]), None, Some(vec![0, 0])]),
+            create_variant(4, vec![Some(vec![1, 1]), Some(vec![1, 0]), Some(vec![0, 1]), None]),
+        ];
+        let mut accumulator = StreamingDiversityAccumulator::new(4);
+        for variant in variants.iter().cloned() {
+            accumulator.push(variant);
+        }
+        let tot_pair_diff: usize = calculate_pairwise_differences(&variants, 4)
+            .iter()
+            .map(|&(_, count, _)| count)
+            .sum();
+
+        assert_eq!(accumulator.variant_count(), 4);
+        assert_eq!(accumulator.segregating_sites(), count_segregating_sites(&variants));
+        assert_eq!(accumulator.total_pairwise_differences(), tot_pair_diff);
+        assert_eq!(accumulator.pi(100), calculate_pi(tot_pair_diff, 4, 100));
+        assert_eq!(accumulator.watterson_theta(100), calculate_watterson_theta(3, 4, 100));
+    }
+
+    #[test]
+    fn test_streaming_chromosome_stats_matches_batch() {
+        let mut variants = vec![
+            create_variant(10, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+            create_variant(20, vec![Some(vec![0, 0]), None, Some(vec![0, 1])]),
+            create_variant(30, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 0])]),
+            create_variant(40, vec![Some(vec![1, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+        ];
+        variants[0].ref_allele = b'A';
+        variants[0].alt_allele = b'G';
+        variants[3].ref_allele = b'C';
+        variants[3].alt_allele = b'A';
+        let mut stats = StreamingChromosomeStats::new(3, AncestralSource::Ref);
+        for variant in variants.iter().cloned() {
+            stats.push(variant);
+        }
+        assert_eq!(stats.diversity().variant_count(), 4);
+        assert_eq!(stats.diversity().segregating_sites(), count_segregating_sites(&variants));
+        assert_eq!(stats.theta_h(6, 100), calculate_theta_h(&variants, 6, 100, AncestralSource::Ref));
+        assert_eq!(stats.theta_l(6, 100), calculate_theta_l(&variants, 6, 100, AncestralSource::Ref));
+        // A negative length (an empty region after masking) is as undefined as a zero one
+        assert!(stats.theta_h(6, -5).is_nan());
+        assert_eq!(stats.ts_tv(), count_ts_tv(&variants));
+        assert_eq!(stats.ts_tv(), (1, 1));
+        let expected = calculate_per_sample_heterozygosity(&variants, 3);
+        for (streamed, batch) in stats.per_sample_heterozygosity().iter().zip(&expected) {
+            assert!(streamed == batch || (streamed.is_nan() && batch.is_nan()));
+        }
+        assert_eq!(stats.last_position(), Some(40));
+        assert!(stats.theta_l(1, 100).is_nan());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_case1() {
+        let epsilon = 1e-6;
+        assert!((calculate_watterson_theta(10, 5, 1000) - 0.0048).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_case2() {
+        let epsilon = 1e-6;
+        assert!((calculate_watterson_theta(5, 2, 1000) - 0.005).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_large_values() {
+        let epsilon = 1e-6;
+        assert!((calculate_watterson_theta(100, 10, 1_000_000) - 0.00003534).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_n1() {
+        let theta_n1 = calculate_watterson_theta(100, 1, 1000);
+        assert!(theta_n1.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_n0() {
+        let theta_n0 = calculate_watterson_theta(0, 0, 1000);
+        assert!(theta_n0.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_seq_zero() {
+        let theta_seq_zero = calculate_watterson_theta(10, 5, 0);
+        assert!(theta_seq_zero.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_typical() {
+        let epsilon = 1e-10;
+        let pi = calculate_pi(15, 5, 1000);
+        let expected = 15f64 / ((5 * (5 - 1) / 2) as f64 * 1000.0);
+        assert!((pi - expected).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_no_differences() {
+        let pi = calculate_pi(0, 5, 1000);
+        assert_eq!(pi, 0.0);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_min_sample_size() {
+        let epsilon = 1e-10;
+        let pi = calculate_pi(5, 2, 1000);
+        let expected = 5f64 / ((2 * (2 - 1) / 2) as f64 * 1000.0);
+        assert!((pi - expected).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_n1() {
+        let pi_n1 = calculate_pi(100, 1, 1000);
+        assert!(pi_n1.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_seq_zero() {
+        let pi_seq_zero = calculate_pi(100, 10, 0);
+        assert!(pi_seq_zero.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_watterson_theta_pi_large_values() {
+        let epsilon = 1e-10;
+        let pi = calculate_pi(10000, 100, 10000);
+        let expected = 10000f64 / ((100 * (100 - 1) / 2) as f64 * 10000.0);
+        assert!((pi - expected).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_pi_typical_values() {
+        let epsilon = 1e-6;
+        assert!((calculate_pi(15, 5, 1000) - 0.0015).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_pi_no_pairwise_differences() {
+        assert_eq!(calculate_pi(0, 5, 1000), 0.0);
+    }
+
+    #[test]
+    fn test_calculate_pi_large_pairwise_differences() {
+        let epsilon = 1e-6;
+        assert!((calculate_pi(10000, 100, 10000) - 0.00020202).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_pi_min_sample_size() {
+        let epsilon = 1e-6;
+        assert!((calculate_pi(5, 2, 1000) - 0.005).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_pi_very_large_sequence_length() {
+        let epsilon = 1e-9;
+        assert!((calculate_pi(1000, 10, 1_000_000) - 0.0000222222).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_calculate_pi_n1_infinite() {
+        let pi_n1 = calculate_pi(100, 1, 1000);
+        assert!(pi_n1.is_infinite());
+    }
+
+    #[test]
+    fn test_calculate_pi_n0_infinite() {
+        let pi_n0 = calculate_pi(0, 0, 1000);
+        assert!(pi_n0.is_infinite());
+    }
+
+    #[test]
+    fn test_parse_region_valid_small() {
+        assert_eq!(parse_region("1-1000").unwrap(), vec![(1, 1000)]);
+    }
+
+    #[test]
+    fn test_parse_region_valid_large() {
+        assert_eq!(parse_region("1000000-2000000").unwrap(), vec![(1000000, 2000000)]);
+    }
+
+    #[test]
+    fn test_parse_region_multiple_intervals() {
+        assert_eq!(parse_region("5000-6000,1000-2000").unwrap(), vec![(1000, 2000), (5000, 6000)]);
+        // Overlapping intervals are merged, touching ones are kept apart
+        assert_eq!(parse_region("1000-2000,1500-2500,2501-3000").unwrap(), vec![(1000, 2500), (2501, 3000)]);
+        assert_eq!(parse_region("1000-2000,500-3000").unwrap(), vec![(500, 3000)]);
+        assert!(matches!(parse_region("1000-2000,"), Err(VcfError::InvalidRegion(_))));
+        assert!(matches!(parse_region("1000-2000,3000-2500"), Err(VcfError::InvalidRegion(_))));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_missing_end() {
+        assert!(matches!(parse_region("1000"), Err(VcfError::InvalidRegion(_))));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_missing_start() {
+        assert!(matches!(parse_region("1000-"), Err(VcfError::MissingField { line: 0, field: "end" })));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_negative_start() {
+        assert!(matches!(parse_region("-1000"), Err(VcfError::MissingField { line: 0, field: "start" })));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_non_numeric_start() {
+        assert!(matches!(parse_region("a-1000"), Err(VcfError::BadValue { field: "start", .. })));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_non_numeric_end() {
+        assert!(matches!(parse_region("1000-b"), Err(VcfError::BadValue { field: "end", .. })));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_start_equals_end() {
+        assert!(matches!(parse_region("1000-1000"), Err(VcfError::InvalidRegion(_))));
+    }
+
+    #[test]
+    fn test_parse_region_invalid_start_greater_than_end() {
+        assert!(matches!(parse_region("2000-1000"), Err(VcfError::InvalidRegion(_))));
+    }
+
+    #[test]
+    fn test_validate_vcf_header_valid() {
+        let valid_header = "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\tSAMPLE2";
+        assert!(validate_vcf_header(valid_header).is_ok());
+    }
+
+    #[test]
+    fn test_validate_vcf_header_invalid_missing_fields() {
+        let invalid_header = "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO";
+        assert!(matches!(validate_vcf_header(invalid_header), Err(VcfError::InvalidVcfFormat(_))));
+    }
+
+    #[test]
+    fn test_validate_vcf_header_invalid_order() {
+        let invalid_order = "POS\t#CHROM\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT";
+        assert!(matches!(validate_vcf_header(invalid_order), Err(VcfError::InvalidVcfFormat(_))));
+    }
+    
+
+
+    #[test]
+    fn test_parse_variant_valid_all_gq_above_threshold() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();  
+        let mut _filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+
+        let valid_line = "chr1\t1500\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
+        let result = parse_variant(
+            valid_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
+        );
+
+        assert!(result.is_ok());
+        let some_variant = result.unwrap();
+        assert!(some_variant.is_some());
+
+        let (variant, is_valid) = some_variant.unwrap();
+        assert!(is_valid);
+        assert_eq!(variant.position, 1500);
+        assert_eq!(variant.genotypes, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]);
+        assert_eq!((variant.ref_allele, variant.alt_allele), (b'A', b'T'));
+    }
+
+    #[test]
+    fn test_parse_variant_require_phased() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let line = "chr1\t1500\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0/1:40\t1|1:45";
+
+        for require_phased in [false, true] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let (variant, passes_filters) = parse_variant(
+                line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, require_phased, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(filtering_stats.unphased_variants, 1);
+            if require_phased {
+                // The unphased call becomes missing, which keeps the site out of the filtered set
+                assert_eq!(variant.genotypes[1], None);
+                assert_eq!(missing_data_info.missing_data_points, 1);
+                assert!(!passes_filters);
+            } else {
+                assert_eq!(variant.genotypes[1], Some(vec![0, 1]));
+                assert!(passes_filters);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_one_gq_below_threshold() {
+        let sample_names = vec![
+            "SAMPLE1".to_string(),
+            "SAMPLE2".to_string(),
+            "SAMPLE3".to_string(),
+        ];
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+    
+        // Define the expected variant using the helper function
+        let expected_variant = create_snp_variant(
+            1000,
             vec![
                 Some(vec![0, 0]), // SAMPLE1: 0|0:35
-                Some(vec![0, 1]), // SAMPLE2: 0|1:25
+                None,             // SAMPLE2: 0|1:25, masked below the GQ threshold
                 Some(vec![1, 1]), // SAMPLE3: 1|1:45
             ],
         );
@@ -441,332 +1738,2483 @@ mod tests {
         // VCF line with one genotype below the GQ threshold
         let invalid_gq_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:25\t1|1:45";
         let result = parse_variant(
-            invalid_gq_line,
-            "1",
+            invalid_gq_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        );
+    
+        // The function executed without errors
+        assert!(result.is_ok());
+    
+        // Assert that the variant is returned but marked as invalid (filtered out)
+        assert_eq!(result.unwrap(), Some((expected_variant, false)));
+    }
+
+    #[test]
+    fn test_parse_variant_valid_variant_details() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();  
+        let mut _filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+
+        let valid_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
+        let result = parse_variant(
+            valid_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
+        );
+
+        assert!(result.is_ok());
+        if let Some((variant, is_valid)) = result.unwrap() {
+            assert!(is_valid);
+            assert_eq!(variant.position, 1000);
+            assert_eq!(variant.genotypes, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]);
+        } else {
+            panic!("Expected Some variant, got None");
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_low_gq_variant() {
+        let sample_names = vec![
+            "SAMPLE1".to_string(),
+            "SAMPLE2".to_string(),
+            "SAMPLE3".to_string(),
+        ];
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+    
+        // Define the expected variant using the helper function
+        let expected_variant = create_snp_variant(
+            1000,
+            vec![
+                Some(vec![0, 0]), // SAMPLE1: 0|0:35
+                None,             // SAMPLE2: 0|1:20, masked below the GQ threshold
+                Some(vec![1, 1]), // SAMPLE3: 1|1:45
+            ],
+        );
+    
+        // VCF line with one genotype below the GQ threshold
+        let low_gq_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:20\t1|1:45";
+        let result = parse_variant(
+            low_gq_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        );
+    
+        // the function executed without errors
+        assert!(result.is_ok());
+    
+        // The low-GQ genotype is missing, so the variant is left out of the filtered set
+        assert_eq!(result.unwrap(), Some((expected_variant, false)));
+        assert_eq!(missing_data_info.low_gq_count, 1);
+        assert_eq!(missing_data_info.missing_data_points, 1);
+        assert!(missing_data_info.positions_with_missing.contains(&1000));
+    }
+
+    #[test]
+    fn test_parse_variant_without_gq_field() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT\t0|0\t0|1";
+        // Without GQ every genotype has an unknown quality, which only matters with a threshold
+        for (min_gq, called) in [(0, true), (30, false)] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let result = parse_variant(
+                line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            );
+            let expected = if called {
+                create_snp_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1])])
+            } else {
+                create_snp_variant(1000, vec![None, None])
+            };
+            assert_eq!(result.unwrap(), Some((expected, called)), "min_gq {}", min_gq);
+            assert_eq!(missing_data_info.low_gq_count, if called { 0 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_unknown_gq_masked() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        // A bare ./. without its trailing FORMAT fields, and a '.' GQ
+        let line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t./.\t0|1:.\t1|1:40";
+        let result = parse_variant(
+            line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        )
+        .expect("An unknown GQ is missing, not an error");
+        let expected = create_snp_variant(1000, vec![None, None, Some(vec![1, 1])]);
+        assert_eq!(result, Some((expected, false)));
+        assert_eq!(missing_data_info.low_gq_count, 1);
+        assert_eq!(missing_data_info.missing_data_points, 2);
+        assert_eq!(filtering_stats.low_gq_variants, 1);
+    }
+
+    #[test]
+    fn test_parse_variant_depth_bounds() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        let line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ:DP\t0|0:35:4\t0|1:40:20\t1|1:45:500";
+        let result = parse_variant(
+            line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq: 30, min_dp: 10, max_dp: 100, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        )
+        .unwrap();
+        let expected = create_snp_variant(1000, vec![None, Some(vec![0, 1]), None]);
+        assert_eq!(result, Some((expected, false)));
+        assert_eq!(missing_data_info.low_dp_count, 1);
+        assert_eq!(missing_data_info.high_dp_count, 1);
+        assert_eq!(missing_data_info.missing_data_points, 2);
+    }
+
+    #[test]
+    fn test_is_symbolic_allele() {
+        assert!(is_symbolic_allele("<DEL>"));
+        assert!(is_symbolic_allele("<NON_REF>"));
+        assert!(is_symbolic_allele("*"));
+        assert!(!is_symbolic_allele("T"));
+        assert!(!is_symbolic_allele("TA"));
+    }
+
+    #[test]
+    fn test_parse_variant_symbolic_alleles() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let line = "chr1\t1000\t.\tA\tT,<DEL>\t.\tPASS\t.\tGT:GQ\t0|2:35\t1|0:40";
+        for include_symbolic in [false, true] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let result = parse_variant(
+                line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, include_symbolic, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            if include_symbolic {
+                let mut expected = create_variant(1000, vec![Some(vec![0, 1]), Some(vec![1, 0])]);
+                expected.ref_allele = b'A';
+                // Collapsed to one ALT, so not dropped as multi-allelic
+                assert_eq!(result, Some((expected, true)));
+                assert_eq!(filtering_stats.structural_variant_skipped, 0);
+            } else {
+                assert_eq!(result, None);
+                assert_eq!(filtering_stats.structural_variant_skipped, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_snps_only() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let cases = [
+            ("A", "T", true, true),
+            ("AT", "A", true, false),
+            ("A", "T,AG", true, false),
+            ("AT", "A", false, true),
+        ];
+        for (reference, alt, snps_only, kept) in cases {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let line = format!("chr1\t1000\t.\t{}\t{}\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40", reference, alt);
+            let result = parse_variant(
+                &line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, snps_only, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "{} > {} (snps_only {})", reference, alt, snps_only);
+            assert_eq!(filtering_stats.indels_skipped, usize::from(!kept));
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_repeat_mask_counted_apart() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40";
+        // The repeats are already merged into the mask, as run does
+        let mask = HashMap::from([("1".to_string(), vec![(0, 10), (990, 1010)])]);
+        let cases = [(vec![(990, 1010)], 1, 0), (vec![(0, 10)], 0, 1)];
+        for (repeats, repeat_masked, masked) in cases {
+            let repeats = HashMap::from([("1".to_string(), repeats)]);
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let result = parse_variant(
+                line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks { mask: Some(Arc::new(mask.clone())), repeat: Some(Arc::new(repeats)), ..RegionMasks::default() },
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert!(result.is_none());
+            assert_eq!(filtering_stats.repeat_masked_count, repeat_masked);
+            assert_eq!(filtering_stats.filtered_due_to_mask, masked);
+        }
+    }
+
+    #[test]
+    fn test_classify_variant() {
+        assert_eq!(classify_variant("A", "T"), VariantType::Snp);
+        assert_eq!(classify_variant("AC", "GT"), VariantType::Mnp);
+        assert_eq!(classify_variant("A", "AG"), VariantType::Insertion);
+        assert_eq!(classify_variant("AT", "A"), VariantType::Deletion);
+        assert_eq!(classify_variant("A", "<DEL>"), VariantType::Sv);
+        assert_eq!(classify_variant("A", "*"), VariantType::Sv);
+    }
+
+    #[test]
+    fn test_parse_variant_variant_type_filter() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let cases = [
+            ("A", "T", VariantTypeFilter::Snp, true),
+            ("AT", "A", VariantTypeFilter::Snp, false),
+            ("AT", "A", VariantTypeFilter::Deletion, true),
+            ("A", "AG", VariantTypeFilter::Deletion, false),
+            ("AC", "GT", VariantTypeFilter::Mnp, true),
+            // Every ALT must match
+            ("A", "AG,T", VariantTypeFilter::Insertion, false),
+            ("A", "AG,T", VariantTypeFilter::All, true),
+        ];
+        for (reference, alt, variant_type, kept) in cases {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let line = format!("chr1\t1000\t.\t{}\t{}\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40", reference, alt);
+            let result = parse_variant(
+                &line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, multiallelic: MultiallelicMode::Keep, variant_type, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "{} > {} ({:?})", reference, alt, variant_type);
+            assert_eq!(filtering_stats.variant_type_skipped, usize::from(!kept));
+            let site_type = classify_variant(reference, alt.split(',').next().unwrap());
+            assert_eq!(filtering_stats.variant_type_counts.get(&site_type), Some(&1));
+            if let Some((variant, _)) = result {
+                assert_eq!(variant.variant_type, site_type);
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_variant_type_counts() {
+        let mut counts = BTreeMap::new();
+        assert_eq!(format_variant_type_counts(&counts), "none");
+        counts.insert(VariantType::Deletion, 4);
+        counts.insert(VariantType::Snp, 120);
+        assert_eq!(format_variant_type_counts(&counts), "snp 120, deletion 4");
+    }
+
+    #[test]
+    fn test_build_cpg_set() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let fasta_path = temp_dir.path().join("ref.fa");
+        // Positions:   1234567890
+        fs::write(&fasta_path, ">chr1\nACGTTcgAAC\nGT\n").unwrap();
+        fs::write(temp_dir.path().join("ref.fa.fai"), "chr1\t12\t6\t10\t11\n").unwrap();
+
+        let all = build_cpg_set(&fasta_path, "1", 1, 12).unwrap();
+        assert_eq!(all, HashSet::from([2, 3, 6, 7, 10, 11]));
+
+        // The CG straddling position 10/11 is still seen when only its G is in range
+        let partial = build_cpg_set(&fasta_path, "chr1", 11, 12).unwrap();
+        assert_eq!(partial, HashSet::from([11]));
+
+        assert!(build_cpg_set(&fasta_path, "2", 1, 12).is_err());
+
+        // A reference using Ensembl names is found from UCSC-style requests, and vice versa
+        let ensembl_path = temp_dir.path().join("ensembl.fa");
+        fs::write(&ensembl_path, ">1\nACGTTcgAAC\nGT\n>MT\nCGCG\n").unwrap();
+        fs::write(temp_dir.path().join("ensembl.fa.fai"), "1\t12\t3\t10\t11\nMT\t4\t21\t4\t5\n").unwrap();
+        assert_eq!(build_cpg_set(&ensembl_path, "chr1", 1, 12).unwrap(), all);
+        assert_eq!(build_cpg_set(&ensembl_path, "chrM", 1, 4).unwrap(), HashSet::from([1, 2, 3, 4]));
+        assert_eq!(build_cpg_set(&fasta_path, "chr1", 1, 12).unwrap(), all);
+    }
+
+    #[test]
+    fn test_parse_variant_exclude_cpg() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let cpg_sites = HashSet::from([1000]);
+        for (pos, kept) in [(1000, false), (1001, true)] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let line = format!("chr1\t{}\t.\tC\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40", pos);
+            let result = parse_variant(
+                &line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: Some(&cpg_sites),
+                    options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "position {}", pos);
+            assert_eq!(filtering_stats.cpg_excluded, usize::from(!kept));
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_out_of_range_region() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();  
+        let mut _filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+
+        let out_of_range = "chr1\t3000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
+        let result = parse_variant(
+            out_of_range,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
+        );
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_variant_different_chromosome() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();  
+        let mut _filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+
+        let diff_chr = "chr2\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
+        let result = parse_variant(
+            diff_chr,
             1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut filtering_stats,
-            None, 
-            None,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
             &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
         );
-    
-        // The function executed without errors
+
         assert!(result.is_ok());
-    
-        // Assert that the variant is returned but marked as invalid (filtered out)
-        assert_eq!(result.unwrap(), Some((expected_variant, false)));
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_variant_invalid_format() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();  
+        let mut _filtering_stats = FilteringStats::default();
+        let min_gq = 30;
+        let _mask: Option<&[(i64, i64)]> = None;
+        let position_allele_map = Mutex::new(HashMap::new());
+
+        let invalid_format = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35"; // Only 10 fields, expecting 12 for 3 samples
+        assert!(parse_variant(
+            invalid_format,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
+        ).is_err());
+
+        // A line cut short in the fixed columns names the first missing one
+        let truncated = "chr1\t1000\t.\tA\tT";
+        let result = parse_variant(
+            truncated,
+            42,
+            &RecordContext {
+                chr: "1",
+                start: 1,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut _filtering_stats,
+        );
+        let err = result.unwrap_err();
+        assert!(matches!(err, VcfError::MissingField { line: 42, field: "QUAL" }));
+        assert_eq!(err.to_string(), "line 42: missing QUAL field");
+    }
+
+    #[test]
+    fn test_locate_indexed_record_error() {
+        // Record numbers within an indexed region are not file lines, so the error names CHROM:POS
+        let line = "chr1\t1500\t.\tA\tT\t.\tPASS\t.\tGT:GQ\tx|1:40\n";
+        let err = locate_indexed_record_error(VcfError::MissingField { line: 3, field: "QUAL" }, line);
+        assert_eq!(err.to_string(), "Parse error: chr1:1500: missing QUAL field");
+        let err = locate_indexed_record_error(
+            VcfError::BadValue { line: 3, field: "GT", value: "x|1".to_string() },
+            line,
+        );
+        assert_eq!(err.to_string(), "Parse error: chr1:1500: invalid GT value 'x|1'");
+        assert!(matches!(locate_indexed_record_error(VcfError::NoVcfFiles, line), VcfError::NoVcfFiles));
+    }
+
+    #[test]
+    fn test_process_variants_with_invalid_haplotype_group() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
+            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+        ];
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut sample_filter = HashMap::new();
+        sample_filter.insert("SAMPLE1".to_string(), (0, 1));
+        sample_filter.insert("SAMPLE2".to_string(), (0, 1));
+        sample_filter.insert("SAMPLE3".to_string(), (0, 1));
+        let adjusted_sequence_length: Option<i64> = None;
+        let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
+        let position_allele_map = Arc::new(Mutex::new(HashMap::new()));
+        let chromosome = "1".to_string();
+
+        // Read reference sequence
+        let (fasta_file, cds_regions) = setup_test_data();
+        let reference_sequence = read_reference_sequence(fasta_file.path(), "1", 1000, 3000)
+            .expect("Failed to read reference sequence");
+
+        // Manually populate the position_allele_map
+        {
+            let mut pam = position_allele_map.lock();
+            pam.insert(1000, ('A', 'T'));
+            pam.insert(2000, ('C', 'G'));
+            pam.insert(3000, ('G', 'A'));
+        }
+
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let invalid_group = process_variants(
+            &variants,
+            2, // haplotype_group=2 (invalid, since only 0 and 1)
+            &sample_filter,
+            adjusted_sequence_length,
+            false, // is_filtered_set
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
+        );
+        assert!(invalid_group.unwrap_or(None).is_none(), "Expected None for invalid haplotype group");
+    }
+
+    #[test]
+    fn test_count_genotyped_samples() {
+        let variant = create_variant(1000, vec![Some(vec![0, 1]), None, Some(vec![1, 1])]);
+        let both_haplotypes = vec![(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)];
+        assert_eq!(count_genotyped_samples(&variant, &both_haplotypes), 2);
+        assert_eq!(count_genotyped_samples(&variant, &[(1, 0)]), 0);
+    }
+
+    #[test]
+    fn test_missing_fraction() {
+        assert_eq!(missing_fraction(&MissingDataInfo::default()), 0.0);
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 1]), None, Some(vec![1, 1])]),
+            create_variant(2000, vec![None, None, Some(vec![0, 0])]),
+        ];
+        let info = missing_data_in_variants(&variants);
+        assert_eq!(info.total_data_points, 6);
+        assert_eq!(info.missing_data_points, 3);
+        assert_eq!(info.positions_with_missing.len(), 2);
+        assert!((missing_fraction(&info) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_write_missing_positions() {
+        let mut info = MissingDataInfo::default();
+        info.positions_with_missing.extend([3000, 1000, 2000, 1000]);
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = output_path_with_suffix(&temp_dir.path().join("out.csv"), "_missing_positions").with_extension("txt");
+        assert_eq!(path, temp_dir.path().join("out_missing_positions.txt"));
+        write_missing_positions(&path, &info.positions_with_missing).expect("Failed to write missing positions");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1000\n2000\n3000\n");
+    }
+
+    #[test]
+    fn test_per_sample_missing() {
+        let names = vec!["S1".to_string(), "S2".to_string(), "S3".to_string()];
+        let info = missing_data_in_variants(&[
+            create_variant(1000, vec![Some(vec![0, 1]), None, Some(vec![1, 1])]),
+            create_variant(2000, vec![None, None, Some(vec![0, 0])]),
+        ]);
+        assert_eq!(info.sample_sites, vec![2, 2, 2]);
+        assert_eq!(info.sample_missing, vec![1, 2, 0]);
+
+        // A second VCF with a different sample order adds to the same rows
+        let mut totals = SampleTotals::default();
+        add_sample_missing(&mut totals, &names, &info);
+        let other = missing_data_in_variants(&[create_variant(500, vec![None, Some(vec![0, 0])])]);
+        add_sample_missing(&mut totals, &["S3".to_string(), "S4".to_string()], &other);
+        assert_eq!(
+            totals.rows,
+            vec![
+                ("S1".to_string(), 2, 1),
+                ("S2".to_string(), 2, 2),
+                ("S3".to_string(), 3, 1),
+                ("S4".to_string(), 1, 0),
+            ]
+        );
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("missing.csv");
+        write_per_sample_missing(&path, &totals.rows, 6).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "sample_name,total_sites,missing_sites,missing_fraction");
+        assert_eq!(lines[2], "S2,2,2,1.000000");
+        assert_eq!(lines[3], "S3,3,1,0.333333");
+        write_per_sample_missing(&path, &totals.rows, 3).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().nth(3), Some("S3,3,1,0.333"));
+    }
+
+    #[test]
+    fn test_per_sample_heterozygosity() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 1]), Some(vec![1, 1]), None]),
+            create_variant(2000, vec![Some(vec![1, 0]), Some(vec![0, 0]), None]),
+            create_variant(3000, vec![Some(vec![0, 0]), Some(vec![1, 0]), None]),
+        ];
+        assert_eq!(count_per_sample_heterozygous(&variants, 3), vec![(2, 3), (1, 3), (0, 0)]);
+        let heterozygosity = calculate_per_sample_heterozygosity(&variants, 3);
+        assert!((heterozygosity[0] - 2.0 / 3.0).abs() < 1e-12);
+        assert!((heterozygosity[1] - 1.0 / 3.0).abs() < 1e-12);
+        assert!(heterozygosity[2].is_nan());
+
+        // Counts from a second chromosome add to the same rows
+        let names = vec!["S1".to_string(), "S2".to_string(), "S3".to_string()];
+        let mut totals = SampleTotals::default();
+        add_sample_heterozygosity(&mut totals, &names, &count_per_sample_heterozygous(&variants, 3));
+        add_sample_heterozygosity(&mut totals, &names[..1], &[(1, 1)]);
+        assert_eq!(
+            totals.rows,
+            vec![("S1".to_string(), 3, 4), ("S2".to_string(), 1, 3), ("S3".to_string(), 0, 0)]
+        );
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("sample_stats.csv");
+        write_sample_stats(&path, &totals.rows, 4).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines, vec!["sample_name,heterozygous_sites,called_sites,heterozygosity", "S1,3,4,0.7500", "S2,1,3,0.3333", "S3,0,0,NA"]);
+    }
+
+    #[test]
+    fn test_process_config_entries_max_missing() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = temp_dir.path();
+
+        fs::write(dir.join("ref.fa"), format!(">1\n{}\n", "A".repeat(1000))).unwrap();
+        fs::write(dir.join("ref.fa.fai"), "1\t1000\t3\t1000\t1001\n").unwrap();
+        fs::write(dir.join("empty.gff"), "").unwrap();
+        // S2 is missing at the only site of the first region
+        fs::write(
+            dir.join("chr1.vcf"),
+            "##fileformat=VCFv4.2\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\n\
+             chr1\t100\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:40\t.:40\n\
+             chr1\t900\t.\tA\tG\t.\tPASS\t.\tGT:GQ\t0|0:40\t0|1:40\n",
+        )
+        .unwrap();
+        let config_path = dir.join("config.tsv");
+        fs::write(
+            &config_path,
+            "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tS1\tS2\n\
+             chr1\t50\t150\t.\t.\t.\t.\t0|1\t0|1\n\
+             chr1\t850\t950\t.\t.\t.\t.\t0|1\t0|1\n",
+        )
+        .unwrap();
+
+        let output_path = dir.join("out.csv");
+        let args = Args::parse_from([
+            "vcf_stats",
+            "--vcf_folder", dir.to_str().unwrap(),
+            "--config_file", config_path.to_str().unwrap(),
+            "--reference", dir.join("ref.fa").to_str().unwrap(),
+            "--gff", dir.join("empty.gff").to_str().unwrap(),
+            "--output_file", output_path.to_str().unwrap(),
+            "--max_missing", "0.25",
+            "--write_params",
+        ]);
+        run(args).expect("Config run failed");
+        // run() writes the parameters itself, so every binary honours --write_params
+        let params: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("out.params.json")).unwrap()).unwrap();
+        assert!(params["args"]["threads"].as_u64().unwrap() > 0);
+
+        let mut reader = csv::Reader::from_path(&output_path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let mut rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        rows.sort_by_key(|r| r[column("region_start")].parse::<i64>().unwrap());
+        assert_eq!(rows.len(), 2);
+        // Half the genotypes of the first region are missing: coordinates kept, statistics NA
+        assert_eq!(&rows[0][column("0_sequence_length")], "101");
+        assert_eq!(&rows[0][column("0_segregating_sites")], "NA");
+        assert_eq!(&rows[0][column("fst")], "NA");
+        assert_eq!(rows[0].len(), headers.len());
+        assert_eq!(&rows[1][column("1_segregating_sites")], "1");
+    }
+
+    #[test]
+    fn test_process_config_entries_min_seq_length() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = temp_dir.path();
+
+        fs::write(dir.join("ref.fa"), format!(">1\n{}\n", "A".repeat(1000))).unwrap();
+        fs::write(dir.join("ref.fa.fai"), "1\t1000\t3\t1000\t1001\n").unwrap();
+        fs::write(dir.join("empty.gff"), "").unwrap();
+        fs::write(
+            dir.join("chr1.vcf"),
+            "##fileformat=VCFv4.2\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\n\
+             chr1\t100\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:40\t0|0:40\n\
+             chr1\t900\t.\tA\tG\t.\tPASS\t.\tGT:GQ\t0|0:40\t0|1:40\n",
+        )
+        .unwrap();
+        // The second region is only 11 bp long
+        let config_path = dir.join("config.tsv");
+        fs::write(
+            &config_path,
+            "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tS1\tS2\n\
+             chr1\t50\t150\t.\t.\t.\t.\t0|1\t0|1\n\
+             chr1\t895\t905\t.\t.\t.\t.\t0|1\t0|1\n",
+        )
+        .unwrap();
+
+        let output_path = dir.join("out.csv");
+        let args = Args::parse_from([
+            "vcf_stats",
+            "--vcf_folder", dir.to_str().unwrap(),
+            "--config_file", config_path.to_str().unwrap(),
+            "--reference", dir.join("ref.fa").to_str().unwrap(),
+            "--gff", dir.join("empty.gff").to_str().unwrap(),
+            "--output_file", output_path.to_str().unwrap(),
+            "--min_seq_length", "50",
+        ]);
+        run(args).expect("Config run failed");
+
+        let mut reader = csv::Reader::from_path(&output_path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][column("1_segregating_sites")], "1");
+        assert_eq!(&rows[1][column("0_sequence_length")], "11");
+        assert_eq!(&rows[1][column("0_segregating_sites")], "NA");
+        assert_eq!(&rows[1][column("0_pi")], "NA");
+    }
+
+    #[test]
+    fn test_process_variants_min_samples() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
+            create_variant(2000, vec![Some(vec![0, 1]), None, None]),
+        ];
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
+        let mut sample_filter = HashMap::new();
+        sample_filter.insert("SAMPLE1".to_string(), (0, 0));
+        sample_filter.insert("SAMPLE2".to_string(), (0, 0));
+        sample_filter.insert("SAMPLE3".to_string(), (0, 0));
+        let position_allele_map = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut pam = position_allele_map.lock();
+            pam.insert(1000, ('A', 'T'));
+            pam.insert(2000, ('C', 'G'));
+        }
+
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let run = |min_samples| {
+            process_variants(
+                &variants,
+                0,
+                &sample_filter,
+                None,
+                false,
+                &ConfigRegion {
+                    chromosome: "1",
+                    start: 1000,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    reference_sequence: &[],
+                    cds_regions: &[],
+                    seqinfo_storage: &Mutex::new(Vec::new()),
+                    position_allele_map: &position_allele_map,
+                    ancestral_source: AncestralSource::Ref,
+                    min_samples,
+                    phylip_dir: phylip_dir.path(),
+                },
+            )
+            .unwrap()
+            .unwrap()
+        };
+
+        // The second site is only genotyped in SAMPLE1
+        assert_eq!(run(1).segregating_sites, 2);
+        assert_eq!(run(2).segregating_sites, 1);
+        assert_eq!(run(4).segregating_sites, 0);
+    }
+
+    #[test]
+    fn test_parse_config_file_with_noreads() {
+        let config_content = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tSAMPLE1\tSAMPLE2\n\
+                              chr1\t1000\t2000\t1500\ttest_id\tpass\tinv\t0|1_lowconf\t1|1\n\
+                              chr1\t3000\t4000\t.\t.\t.\t.\t0|0\t0|1\n";
+        let path = NamedTempFile::new().expect("Failed to process variants");
+        write!(path.as_file(), "{}", config_content).expect("Failed to process variants");
+
+        let config_entries = parse_config_file(path.path()).expect("Failed to process variants");
+        assert_eq!(config_entries.len(), 2);
+    }
+
+    #[test]
+    fn test_vcf_error_is_std_error() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<VcfError>();
+
+        let err = VcfError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, "missing.vcf"));
+        assert_eq!(std::error::Error::source(&err).map(|source| source.to_string()), Some("missing.vcf".to_string()));
+        assert!(std::error::Error::source(&VcfError::NoVcfFiles).is_none());
+
+        let wrapped = anyhow::Error::from(VcfError::Parse("bad header".to_string()));
+        assert_eq!(wrapped.to_string(), "Parse error: bad header");
+        let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(VcfError::NoVcfFiles);
+        assert_eq!(boxed.to_string(), "No VCF files found");
+    }
+
+    #[test]
+    fn test_parse_config_file_with_label() {
+        let config_content = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tlabel\tSAMPLE1\n\
+                              chr1\t1000\t2000\t.\t.\t.\t.\tinv_A\t0|1\n\
+                              chr1\t3000\t4000\t.\t.\t.\t.\t\t1|1\n";
+        let path = NamedTempFile::new().expect("Failed to create config file");
+        write!(path.as_file(), "{}", config_content).expect("Failed to write config file");
+
+        let config_entries = parse_config_file(path.path()).expect("Failed to parse config");
+        assert_eq!(config_entries[0].label.as_deref(), Some("inv_A"));
+        assert_eq!(config_entries[1].label, None);
+        // The label column is not taken for a sample
+        assert_eq!(config_entries[0].samples_unfiltered.len(), 1);
+        assert_eq!(config_entries[1].samples_unfiltered.get("SAMPLE1"), Some(&(1, 1)));
+    }
+
+    #[test]
+    fn test_parse_config_file_bad_fields() {
+        let header = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tSAMPLE1\n";
+        let path = NamedTempFile::new().expect("Failed to create config file");
+
+        fs::write(path.path(), format!("{}chr1\t1000\t2000\t.\t.\t.\t.\t0|1\nchr1\t1x00\t4000\t.\t.\t.\t.\t0|1\n", header)).unwrap();
+        let err = parse_config_file(path.path()).unwrap_err();
+        assert!(matches!(&err, VcfError::BadValue { line: 3, field: "start", value } if value == "1x00"));
+        assert_eq!(err.to_string(), "line 3: invalid start value '1x00'");
+
+        fs::write(path.path(), format!("{}chr1\t1000\t\t.\t.\t.\t.\t0|1\n", header)).unwrap();
+        assert!(matches!(parse_config_file(path.path()), Err(VcfError::MissingField { line: 2, field: "end" })));
+    }
+
+    #[test]
+    fn test_parse_config_file_more_groups() {
+        let config_content = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tSAMPLE1\tSAMPLE2\n\
+                              chr1\t1000\t2000\t1500\ttest_id\tpass\tinv\t0|2\t3|1_lowconf\n";
+        let path = NamedTempFile::new().expect("Failed to create config file");
+        write!(path.as_file(), "{}", config_content).expect("Failed to write config file");
+
+        let config_entries = parse_config_file(path.path()).expect("Failed to parse config");
+        assert_eq!(config_entries[0].samples_unfiltered.get("SAMPLE2"), Some(&(3, 1)));
+        assert_eq!(config_entries[0].samples_filtered.get("SAMPLE1"), Some(&(0, 2)));
+        assert!(!config_entries[0].samples_filtered.contains_key("SAMPLE2"));
+        assert_eq!(config_group_labels(&config_entries), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_parse_yaml_config() {
+        let config_content = "regions:\n\
+                              \x20 - seqname: chr1\n\
+                              \x20   start: 1000\n\
+                              \x20   end: 2000\n\
+                              \x20   samples:\n\
+                              \x20     SAMPLE1: \"0|1\"\n\
+                              \x20     SAMPLE2: \"1|1_lowconf\"\n\
+                              \x20 - seqname: \"2\"\n\
+                              \x20   start: 10\n\
+                              \x20   end: 20\n\
+                              \x20   samples:\n\
+                              \x20     SAMPLE1: \"bad\"\n";
+        let path = NamedTempFile::new().expect("Failed to create config file");
+        write!(path.as_file(), "{}", config_content).expect("Failed to write config file");
+
+        // The second region has no valid genotype and is skipped, as in the TSV format
+        let config_entries = parse_yaml_config(path.path()).expect("Failed to parse YAML config");
+        assert_eq!(config_entries.len(), 1);
+        assert_eq!(config_entries[0].seqname, "1");
+        assert_eq!((config_entries[0].start, config_entries[0].end), (1000, 2000));
+        assert_eq!(config_entries[0].samples_unfiltered.get("SAMPLE2"), Some(&(1, 1)));
+        assert_eq!(config_entries[0].samples_filtered.get("SAMPLE1"), Some(&(0, 1)));
+        assert!(!config_entries[0].samples_filtered.contains_key("SAMPLE2"));
+    }
+
+    #[test]
+    fn test_parse_yaml_config_invalid() {
+        let path = NamedTempFile::new().expect("Failed to create config file");
+        write!(path.as_file(), "regions:\n  - seqname: chr1\n    start: one\n").expect("Failed to write config file");
+        assert!(matches!(parse_yaml_config(path.path()), Err(VcfError::Parse(_))));
+    }
+
+    fn config_entry(seqname: &str, start: i64, end: i64) -> ConfigEntry {
+        ConfigEntry {
+            seqname: seqname.to_string(),
+            start,
+            end,
+            label: None,
+            samples_unfiltered: HashMap::new(),
+            samples_filtered: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_find_overlapping_entries() {
+        let entries = vec![
+            config_entry("1", 500, 600),
+            config_entry("1", 100, 1000),
+            config_entry("2", 100, 200),
+            config_entry("1", 1001, 1100),
+            config_entry("1", 900, 950),
+            config_entry("2", 201, 300),
+        ];
+        let mut overlaps = find_overlapping_entries(&entries);
+        overlaps.sort();
+        // Both 500-600 and 900-950 lie inside 100-1000; touching ends (200/201, 1000/1001) do not overlap
+        assert_eq!(overlaps, vec![(0, 1), (1, 4)]);
+    }
+
+    #[test]
+    fn test_check_overlapping_entries_strict() {
+        let entries = vec![config_entry("1", 100, 200), config_entry("1", 200, 300)];
+        assert!(check_overlapping_entries(&entries, false).is_ok());
+        assert!(matches!(check_overlapping_entries(&entries, true), Err(VcfError::Parse(_))));
+
+        let disjoint = vec![config_entry("1", 100, 200), config_entry("2", 100, 200)];
+        assert!(check_overlapping_entries(&disjoint, true).is_ok());
+    }
+
+    #[test]
+    fn test_parse_config_file_gzipped() {
+        let config_content = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tSAMPLE1\tSAMPLE2\n\
+                              chr1\t1000\t2000\t1500\ttest_id\tpass\tinv\t0|1\t1|1\n";
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("config.tsv.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        write!(encoder, "{}", config_content).unwrap();
+        encoder.finish().unwrap();
+
+        let config_entries = parse_config_file(&path).expect("Failed to parse gzipped config");
+        assert_eq!(config_entries.len(), 1);
+        assert_eq!(config_entries[0].seqname, "1");
+        assert_eq!(config_entries[0].samples_filtered.get("SAMPLE2"), Some(&(1, 1)));
+    }
+
+    #[test]
+    fn test_resolve_thread_count() {
+        // The flag wins over the environment variable
+        assert_eq!(resolve_thread_count(3, Some("8")), 3);
+        assert_eq!(resolve_thread_count(0, Some("8")), 8);
+        assert_eq!(resolve_thread_count(0, Some(" 2\n")), 2);
+        // Unset, unparsable or zero values fall back to all logical CPUs
+        assert_eq!(resolve_thread_count(0, None), num_cpus::get());
+        assert_eq!(resolve_thread_count(0, Some("many")), num_cpus::get());
+        assert_eq!(resolve_thread_count(0, Some("0")), num_cpus::get());
+    }
+
+    #[test]
+    fn test_log_level_parsing() {
+        let base = ["vcf_stats", "--vcf_folder", "vcfs", "--reference", "ref.fa", "--gff", "a.gff"];
+        let args = Args::parse_from(base);
+        assert_eq!(args.log_level, LogLevel::Info);
+        let args = Args::parse_from(base.iter().chain(&["--log_level", "debug"]));
+        assert_eq!(tracing::Level::from(args.log_level), tracing::Level::DEBUG);
+        assert!(Args::try_parse_from(base.iter().chain(&["--log_level", "loud"])).is_err());
+    }
+
+    #[test]
+    fn test_whole_chromosome_length() {
+        // The contig length wins even when the last variant is far from the end
+        assert_eq!(whole_chromosome_length(1, 40000, 1500), (40000, true));
+        assert_eq!(whole_chromosome_length(1, 0, 1500), (1500, false));
+    }
+
+    #[test]
+    fn test_stdin_args() {
+        let base = ["vcf_stats", "--reference", "ref.fa", "--gff", "a.gff"];
+        // --vcf_folder is only needed without --stdin, and --stdin needs --chr
+        assert!(Args::try_parse_from(base).is_err());
+        assert!(Args::try_parse_from(base.iter().chain(&["--stdin"])).is_err());
+        let args = Args::parse_from(base.iter().chain(&["--stdin", "--chr", "chr1"]));
+        assert!(args.stdin);
+        assert_eq!(resolve_vcf_file(&args, "chr1").unwrap(), Path::new(STDIN_PATH));
+        assert!(Args::try_parse_from(base.iter().chain(&["--stdin", "--chr", "1", "--bed_file", "a.bed"])).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("run.checkpoint");
+        save_checkpoint(&path, &checkpoint_key("1", 100, 200)).unwrap();
+        save_checkpoint(&path, &checkpoint_key("X", 5, 50)).unwrap();
+        let finished = load_checkpoint(&path).unwrap();
+        assert_eq!(finished.len(), 2);
+        assert!(finished.contains("1:100:200"));
+        assert!(finished.contains("X:5:50"));
+        assert!(!finished.contains("1:100:201"));
+    }
+
+    #[test]
+    fn test_recover_region() {
+        let mut errors = Vec::new();
+        assert_eq!(recover_region(Ok(5), "1:1-100", false, &mut errors).unwrap(), Some(5));
+        let failed: Result<i32, VcfError> = Err(VcfError::NoVcfFiles);
+        assert_eq!(recover_region(failed, "1:1-100", false, &mut errors).unwrap(), None);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "1:1-100");
+        // --fail_on_region_error stops at the error
+        let failed: Result<i32, VcfError> = Err(VcfError::NoVcfFiles);
+        assert!(matches!(recover_region(failed, "2:1-100", true, &mut errors), Err(VcfError::NoVcfFiles)));
+        assert_eq!(errors.len(), 1);
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out_errors.csv");
+        write_region_errors(&path, &errors).expect("Failed to write region errors");
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "region,error");
+        assert!(lines[1].starts_with("1:1-100,"));
+    }
+
+    #[test]
+    fn test_write_run_params() {
+        let args = Args::parse_from([
+            "vcf_stats", "--vcf_folder", "vcfs", "--reference", "ref.fa", "--gff", "a.gff",
+            "--output_file", "results/out.csv", "--ancestral_source", "aa_tag", "--write_params",
+        ]);
+        assert_eq!(params_output_path(Path::new("results/out.csv")), Path::new("results/out.params.json"));
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out.params.json");
+        write_run_params(&RunParams::new(&args), &path).expect("Failed to write run parameters");
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["ferromic_version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["timestamp"].as_u64().unwrap() > 0);
+        // Defaults are written out along with the arguments that were given
+        assert_eq!(json["args"]["output_file"], "results/out.csv");
+        assert_eq!(json["args"]["ancestral_source"], "aa_tag");
+        assert_eq!(json["args"]["min_gq"], 0);
+        assert_eq!(json["args"]["multiallelic"], "skip");
+        assert!(json["args"]["mask_file"].is_null());
+    }
+
+    #[test]
+    fn test_process_config_entries_distant_regions_same_chromosome() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = temp_dir.path();
+
+        fs::write(dir.join("ref.fa"), format!(">1\n{}\n", "A".repeat(1000))).unwrap();
+        fs::write(dir.join("ref.fa.fai"), "1\t1000\t3\t1000\t1001\n").unwrap();
+        fs::write(dir.join("empty.gff"), "").unwrap();
+        fs::write(
+            dir.join("chr1.vcf"),
+            "##fileformat=VCFv4.2\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\n\
+             chr1\t100\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:40\t0|0:40\n\
+             chr1\t900\t.\tA\tG\t.\tPASS\t.\tGT:GQ\t0|0:40\t0|1:40\n",
+        )
+        .unwrap();
+        // The second region lies well past the end of the first
+        let config_path = dir.join("config.tsv");
+        fs::write(
+            &config_path,
+            "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tS1\tS2\n\
+             chr1\t50\t150\t.\t.\t.\t.\t0|1\t0|1\n\
+             chr1\t850\t950\t.\t.\t.\t.\t0|1\t0|1\n",
+        )
+        .unwrap();
+
+        let output_path = dir.join("out.csv");
+        let args = Args::parse_from([
+            "vcf_stats",
+            "--vcf_folder", dir.to_str().unwrap(),
+            "--config_file", config_path.to_str().unwrap(),
+            "--reference", dir.join("ref.fa").to_str().unwrap(),
+            "--gff", dir.join("empty.gff").to_str().unwrap(),
+            "--output_file", output_path.to_str().unwrap(),
+        ]);
+        run(args).expect("Config run failed");
+
+        let mut reader = csv::Reader::from_path(&output_path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let mut rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        rows.sort_by_key(|r| r[column("region_start")].parse::<i64>().unwrap());
+        assert_eq!(rows.len(), 2);
+        // Each region sees its own variant: S1's ALT at 100 (group 1), S2's ALT at 900 (group 1)
+        for row in &rows {
+            assert_eq!(&row[column("1_segregating_sites")], "1");
+            assert_eq!(&row[column("0_segregating_sites")], "0");
+        }
+    }
+
+    #[test]
+    fn test_process_config_entries_seed_reproducible_across_chromosomes() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let dir = temp_dir.path();
+
+        fs::write(dir.join("ref.fa"), format!(">1\n{}\n>2\n{}\n", "A".repeat(1000), "A".repeat(1000))).unwrap();
+        fs::write(dir.join("ref.fa.fai"), "1\t1000\t3\t1000\t1001\n2\t1000\t1007\t1000\t1001\n").unwrap();
+        fs::write(dir.join("empty.gff"), "").unwrap();
+        for chr in ["1", "2"] {
+            let mut vcf = "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\tS3\n".to_string();
+            for pos in (10..1000).step_by(37) {
+                let genotypes = ["0|1\t0|0\t1|1", "1|0\t0|1\t0|0", "0|0\t1|1\t0|1"][pos % 3];
+                vcf.push_str(&format!("chr{}\t{}\t.\tA\tT\t.\tPASS\t.\tGT\t{}\n", chr, pos, genotypes));
+            }
+            fs::write(dir.join(format!("chr{}.vcf", chr)), vcf).unwrap();
+        }
+        let config_path = dir.join("config.tsv");
+        fs::write(
+            &config_path,
+            "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tS1\tS2\tS3\n\
+             chr1\t10\t900\t.\t.\t.\t.\t0|1\t0|1\t1|0\n\
+             chr2\t10\t900\t.\t.\t.\t.\t0|1\t1|0\t0|1\n",
+        )
+        .unwrap();
+
+        // The bootstrap draws come from one generator, so the chromosomes have to be visited in a fixed order
+        let run_once = |name: &str| {
+            let output_path = dir.join(name);
+            let args = Args::parse_from([
+                "vcf_stats",
+                "--vcf_folder", dir.to_str().unwrap(),
+                "--config_file", config_path.to_str().unwrap(),
+                "--reference", dir.join("ref.fa").to_str().unwrap(),
+                "--gff", dir.join("empty.gff").to_str().unwrap(),
+                "--output_file", output_path.to_str().unwrap(),
+                "--bootstrap", "50",
+                "--seed", "7",
+            ]);
+            run(args).expect("Config run failed");
+            fs::read_to_string(&output_path).unwrap()
+        };
+        let first = run_once("first.csv");
+        assert_eq!(first.lines().count(), 3);
+        for name in ["second.csv", "third.csv", "fourth.csv"] {
+            assert_eq!(run_once(name), first);
+        }
+    }
+
+    #[test]
+    fn test_find_vcf_file_existing_vcfs() {
+        use std::fs::File;
+
+        // Create a temporary directory for testing
+        let temp_dir = tempfile::tempdir().expect("Failed to process variants");
+        let temp_path = temp_dir.path();
+
+        // Create some test VCF files
+        File::create(temp_path.join("chr1.vcf")).expect("Failed to process variants");
+        File::create(temp_path.join("chr2.vcf.gz")).expect("Failed to process variants");
+        File::create(temp_path.join("chr10.vcf")).expect("Failed to process variants");
+
+        // Test finding existing VCF files
+        let vcf1 = find_vcf_file(temp_path.to_str().unwrap(), "1").expect("Failed to process variants");
+        assert!(vcf1.ends_with("chr1.vcf"));
+
+        let vcf2 = find_vcf_file(temp_path.to_str().unwrap(), "2").expect("Failed to process variants");
+        assert!(vcf2.ends_with("chr2.vcf.gz"));
+
+        let vcf10 = find_vcf_file(temp_path.to_str().unwrap(), "10").expect("Failed to process variants");
+        assert!(vcf10.ends_with("chr10.vcf"));
+    }
+
+    #[test]
+    fn test_find_vcf_file_bcf() {
+        use std::fs::File;
+
+        let temp_dir = tempfile::tempdir().expect("Failed to process variants");
+        let temp_path = temp_dir.path();
+        File::create(temp_path.join("chr1.vcf")).expect("Failed to process variants");
+        File::create(temp_path.join("chr3.bcf")).expect("Failed to process variants");
+
+        let vcf3 = find_vcf_file(temp_path.to_str().unwrap(), "3").expect("Failed to process variants");
+        assert!(vcf3.ends_with("chr3.bcf"));
     }
 
     #[test]
-    fn test_parse_variant_valid_variant_details() {
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut missing_data_info = MissingDataInfo::default();  
-        let mut _filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
+    fn test_find_vcf_file_bgz_and_ties() {
+        use std::fs::File;
 
-        let valid_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
-        let allow_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let mask_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let result = parse_variant(
-            valid_line,
-            "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut _filtering_stats,
-            allow_regions,
-            mask_regions,
-            &position_allele_map,
-        );
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let temp_path = temp_dir.path();
+        File::create(temp_path.join("chr4.bgz")).unwrap();
+        for name in ["chr5.vcf", "chr5.bcf", "chr5.vcf.gz", "5.vcf.bgz", "chr6.vcf", "chr6.bcf"] {
+            File::create(temp_path.join(name)).unwrap();
+        }
 
-        assert!(result.is_ok());
-        if let Some((variant, is_valid)) = result.unwrap() {
-            assert!(is_valid);
-            assert_eq!(variant.position, 1000);
-            assert_eq!(variant.genotypes, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]);
-        } else {
-            panic!("Expected Some variant, got None");
+        let vcf4 = find_vcf_file(temp_path.to_str().unwrap(), "4").unwrap();
+        assert!(vcf4.ends_with("chr4.bgz"));
+
+        // Several candidates resolve to the .vcf.gz, and otherwise to the first by name
+        for _ in 0..3 {
+            assert!(find_vcf_file(temp_path.to_str().unwrap(), "5").unwrap().ends_with("chr5.vcf.gz"));
+            assert!(find_vcf_file(temp_path.to_str().unwrap(), "6").unwrap().ends_with("chr6.bcf"));
         }
     }
 
     #[test]
-    fn test_parse_variant_low_gq_variant() {
-        let sample_names = vec![
-            "SAMPLE1".to_string(),
-            "SAMPLE2".to_string(),
-            "SAMPLE3".to_string(),
+    fn test_find_vcf_file_non_existent_chromosome() {
+        use std::fs::File;
+
+        // Create a temporary directory for testing
+        let temp_dir = tempfile::tempdir().expect("Failed to process variants");
+        let temp_path = temp_dir.path();
+
+        // Create some test VCF files
+        File::create(temp_path.join("chr1.vcf")).expect("Failed to process variants");
+        File::create(temp_path.join("chr2.vcf.gz")).expect("Failed to process variants");
+        File::create(temp_path.join("chr10.vcf")).expect("Failed to process variants");
+
+        // Test with non-existent chromosome "3"
+        let result = find_vcf_file(temp_path.to_str().unwrap(), "3");
+        assert!(matches!(result, Err(VcfError::NoVcfFiles)));
+    }
+
+    #[test]
+    fn test_find_vcf_file_non_existent_directory() {
+        // Test with a non-existent directory path
+        let result = find_vcf_file("/non/existent/path", "1");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_open_vcf_reader_non_existent_file() {
+        let path = PathBuf::from("/non/existent/file.vcf");
+        let result = open_vcf_reader(&path);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_open_vcf_reader_uncompressed_file() {
+        // Create a temporary uncompressed VCF file
+        let temp_file = NamedTempFile::new().expect("Failed to process variants");
+        let path = temp_file.path();
+        let mut file = File::create(path).expect("Failed to process variants");
+        writeln!(file, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1").expect("Failed to process variants");
+
+        let reader = open_vcf_reader(&path);
+        assert!(reader.is_ok());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open_mmap_reader() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("chr1.vcf");
+        fs::write(&path, "##fileformat=VCFv4.2\nchr1\t100\t.\tA\tT\n").unwrap();
+
+        let lines: Vec<String> = open_mmap_reader(&path).unwrap().lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines, vec!["##fileformat=VCFv4.2", "chr1\t100\t.\tA\tT"]);
+
+        // A directory is not a regular file, so it cannot be mapped
+        assert!(open_mmap_reader(temp_dir.path()).is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open_vcf_reader_mmap_empty_file_falls_back() {
+        let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        let mut reader = open_vcf_reader(temp_file.path()).expect("Empty file should still open");
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_open_vcf_reader_gzipped_file() {
+        // Create a temporary gzipped VCF file
+        let gzipped_file = NamedTempFile::new().expect("Failed to process variants");
+        let path = gzipped_file.path();
+        let mut encoder = flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::default());
+        writeln!(encoder, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1").expect("Failed to process variants");
+        encoder.finish().expect("Failed to process variants");
+
+        let reader = open_vcf_reader(&path);
+        assert!(reader.is_ok());
+    }
+
+    // One BGZF block holding data, laid out as bgzip writes it
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        // BSIZE is the total block size minus 1: 18 header bytes, the deflate stream and 8 trailer bytes
+        let bsize = (18 + compressed.len() + 8 - 1) as u16;
+        let mut block = vec![31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0];
+        block.extend_from_slice(&bsize.to_le_bytes());
+        block.extend_from_slice(&compressed);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_open_vcf_reader_bgzf_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("chr1.vcf.gz");
+        let text: String = (1..=300).map(|pos| format!("1\t{}\t.\tA\tT\t.\tPASS\t.\tGT\t0|1\n", pos)).collect();
+        // More blocks than one batch, split mid-line, ending with the empty end-of-file block
+        let mut contents: Vec<u8> = text.as_bytes().chunks(37).flat_map(bgzf_block).collect();
+        contents.extend(bgzf_block(b""));
+        fs::write(&path, &contents).unwrap();
+        assert!(is_bgzf(&path).unwrap());
+
+        let mut decoded = String::new();
+        open_vcf_reader(&path).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // Plain gzip is read with the sequential decoder
+        let gzip_path = temp_dir.path().join("chr2.vcf.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&gzip_path).unwrap(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        assert!(!is_bgzf(&gzip_path).unwrap());
+    }
+
+    #[test]
+    fn test_bgz_extension() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("chr4.vcf.bgz");
+        let text = "##fileformat=VCFv4.2\n#CHROM\tPOS\n4\t100\n";
+        let mut contents = bgzf_block(text.as_bytes());
+        contents.extend(bgzf_block(b""));
+        fs::write(&path, &contents).unwrap();
+        assert!(has_gzip_extension(&path));
+        assert!(!has_gzip_extension(Path::new("chr4.vcf")));
+
+        // Found in the folder and decompressed rather than read as plain text
+        let found = find_vcf_file(temp_dir.path().to_str().unwrap(), "4").unwrap();
+        assert!(found.ends_with("chr4.vcf.bgz"));
+        let mut decoded = String::new();
+        open_vcf_reader(&found).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn test_bgzf_reader_truncated_block() {
+        let mut contents = bgzf_block(b"1\t100\n");
+        contents.truncate(contents.len() - 4);
+        let mut reader = BgzfParallelReader::new(contents.as_slice());
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_open_vcf_reader_bcf_file() {
+        use rust_htslib::bcf::{self, Format, Read as BcfRead, Writer};
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let vcf_path = temp_dir.path().join("chr1.vcf");
+        let bcf_path = temp_dir.path().join("chr1.bcf");
+        let record_line = "1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35\t1|1:40";
+        {
+            let mut file = File::create(&vcf_path).expect("Failed to create VCF");
+            writeln!(file, "##fileformat=VCFv4.2").unwrap();
+            writeln!(file, "##contig=<ID=1,length=40000>").unwrap();
+            writeln!(file, "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">").unwrap();
+            writeln!(file, "##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Genotype quality\">").unwrap();
+            writeln!(file, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\tSAMPLE2").unwrap();
+            writeln!(file, "{}", record_line).unwrap();
+        }
+
+        // Convert the VCF to BCF with htslib
+        {
+            let mut vcf = bcf::Reader::from_path(&vcf_path).expect("Failed to read VCF");
+            let header = bcf::Header::from_template(vcf.header());
+            let mut writer = Writer::from_path(&bcf_path, &header, false, Format::Bcf)
+                .expect("Failed to create BCF");
+            for record in vcf.records() {
+                writer.write(&record.expect("Failed to read record")).expect("Failed to write record");
+            }
+        }
+
+        let reader = open_vcf_reader(&bcf_path).expect("Failed to open BCF");
+        let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+        assert!(lines.iter().any(|l| l == "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\tSAMPLE2"));
+        assert_eq!(lines.last().unwrap(), record_line);
+    }
+
+    fn write_bgzf_vcf(path: &Path, positions: &[i64]) {
+        let mut writer = rust_htslib::bgzf::Writer::from_path(path).expect("Failed to create BGZF file");
+        writeln!(writer, "##fileformat=VCFv4.2").unwrap();
+        writeln!(writer, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1").unwrap();
+        for pos in positions {
+            writeln!(writer, "chr1\t{}\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35", pos).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_try_open_tabix_reader_indexed() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let vcf_path = temp_dir.path().join("chr1.vcf.gz");
+        write_bgzf_vcf(&vcf_path, &[100, 1000, 1500, 2000, 5000]);
+        let c_path = std::ffi::CString::new(vcf_path.to_str().unwrap()).unwrap();
+        let ret = unsafe { rust_htslib::htslib::tbx_index_build(c_path.as_ptr(), 0, &rust_htslib::htslib::tbx_conf_vcf) };
+        assert_eq!(ret, 0);
+
+        // Config-style chromosome name without the "chr" prefix
+        let lines = try_open_tabix_reader(&vcf_path, "1", 1000, 2000)
+            .expect("Failed to query index")
+            .expect("Index should be used");
+        let positions: Vec<String> = lines
+            .map(|l| l.unwrap().split('\t').nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(positions, vec!["1000", "1500", "2000"]);
+
+        // Unknown chromosome falls back to a linear scan
+        assert!(try_open_tabix_reader(&vcf_path, "22", 1000, 2000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_try_open_tabix_reader_without_index() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let vcf_path = temp_dir.path().join("chr1.vcf.gz");
+        write_bgzf_vcf(&vcf_path, &[100, 1000]);
+
+        assert!(try_open_tabix_reader(&vcf_path, "1", 1, 2000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fails_filter_column() {
+        let exclude = vec!["VQSR_FAIL".to_string()];
+        assert!(!fails_filter_column("PASS", true, &[]));
+        assert!(!fails_filter_column(".", true, &[]));
+        assert!(fails_filter_column("LowQual", true, &[]));
+        assert!(!fails_filter_column("LowQual", false, &[]));
+        assert!(fails_filter_column("LowQual;VQSR_FAIL", false, &exclude));
+        assert!(!fails_filter_column("PASS", false, &exclude));
+    }
+
+    #[test]
+    fn test_parse_variant_filter_column() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let exclude = vec!["LowQual".to_string()];
+        let cases: [(&str, bool, &[String], bool); 4] = [
+            ("PASS", true, &[], true),
+            ("LowQual", false, &[], true),
+            ("LowQual", true, &[], false),
+            ("LowQual", false, &exclude, false),
         ];
-        let mut missing_data_info = MissingDataInfo::default();
-        let mut filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
+        for (filter, pass_only, exclude_filters, kept) in cases {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let variant_line = format!("chr1\t1000\t.\tA\tT\t.\t{}\t.\tGT:GQ\t0|0:35\t0|1:40", filter);
+            let result = parse_variant(
+                &variant_line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1000,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, filter_pass_only: pass_only, exclude_filters: exclude_filters.to_vec(), ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "FILTER {} (pass_only {})", filter, pass_only);
+            assert_eq!(missing_data_info.filtered_by_qual, usize::from(!kept));
+        }
+    }
+
+    #[test]
+    fn test_parse_info_filter() {
+        let cases = [
+            ("DP>=10", "DP", InfoOp::Ge, "10"),
+            ("DP<=10", "DP", InfoOp::Le, "10"),
+            ("AF>0.05", "AF", InfoOp::Gt, "0.05"),
+            ("AF<0.5", "AF", InfoOp::Lt, "0.5"),
+            ("TYPE == snp", "TYPE", InfoOp::Eq, "snp"),
+            ("TYPE!=indel", "TYPE", InfoOp::Ne, "indel"),
+        ];
+        for (expr, key, op, value) in cases {
+            let filter = parse_info_filter(expr).unwrap();
+            assert_eq!(filter, InfoFilter { key: key.to_string(), op, value: value.to_string() }, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parse_info_filter_invalid() {
+        for expr in ["DP", "DP=10", ">=10", "DP>=", "DP!10", "D P>=1"] {
+            assert!(matches!(parse_info_filter(expr), Err(VcfError::Parse(_))), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_apply_info_filter() {
+        let info = "DP=25;AF=0.1,0.3;TYPE=snp;DB";
+        let passes = |expr: &str| apply_info_filter(info, &parse_info_filter(expr).unwrap());
+        assert!(passes("DP>=25"));
+        assert!(!passes("DP>25"));
+        assert!(passes("DP==25.0"));
+        assert!(passes("AF<0.2"));
+        assert!(passes("TYPE==snp"));
+        assert!(!passes("TYPE!=snp"));
+        assert!(!passes("TYPE>snp"));
+        assert!(!passes("MQ>=30"));
+        assert!(!passes("DB>=1"));
+    }
+
+    #[test]
+    fn test_parse_variant_info_filter() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
         let position_allele_map = Mutex::new(HashMap::new());
-    
-        // Define the expected variant using the helper function
-        let expected_variant = create_variant(
-            1000,
-            vec![
-                Some(vec![0, 0]), // SAMPLE1: 0|0:35
-                Some(vec![0, 1]), // SAMPLE2: 0|1:20 (below threshold)
-                Some(vec![1, 1]), // SAMPLE3: 1|1:45
-            ],
+        let filters = vec![parse_info_filter("DP>=10").unwrap()];
+        for (info, kept) in [("DP=12", true), ("DP=5", false), (".", false)] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let variant_line = format!("chr1\t1000\t.\tA\tT\t.\tPASS\t{}\tGT:GQ\t0|0:35\t0|1:40", info);
+            let result = parse_variant(
+                &variant_line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1000,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, info_filters: filters.clone(), ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "INFO {}", info);
+            assert_eq!(filtering_stats.info_filtered_count, usize::from(!kept));
+        }
+    }
+
+    #[test]
+    fn test_parse_variant_qual_threshold() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        for (qual, threshold, kept) in [("45.2", 30.0, true), ("12", 30.0, false), (".", 30.0, false), (".", 0.0, true)] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let variant_line = format!("chr1\t1000\t.\tA\tT\t{}\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40", qual);
+            let result = parse_variant(
+                &variant_line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1000,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, qual_threshold: threshold, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap();
+            assert_eq!(result.is_some(), kept, "QUAL {} with threshold {}", qual, threshold);
+            assert_eq!(filtering_stats.qual_filtered_count, usize::from(!kept));
+            assert_eq!(filtering_stats.missing_qual_count, usize::from(qual == "." && threshold > 0.0));
+        }
+    }
+
+    #[test]
+    fn test_parse_bed_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let bed_path = temp_dir.path().join("regions.bed");
+        fs::write(&bed_path, "track name=test\n# comment\nchr1\t0\t100\tname\n\n2\t999\t2000\n").unwrap();
+        let regions = parse_bed_file(&bed_path).unwrap();
+        assert_eq!(regions, vec![("1".to_string(), 1, 100), ("2".to_string(), 1000, 2000)]);
+    }
+
+    #[test]
+    fn test_parse_bed_file_invalid() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let bed_path = temp_dir.path().join("regions.bed");
+        fs::write(&bed_path, "chr1\t100\t100\n").unwrap();
+        assert!(parse_bed_file(&bed_path).is_err());
+        fs::write(&bed_path, "chr1\t100\n").unwrap();
+        assert!(parse_bed_file(&bed_path).is_err());
+    }
+
+    #[test]
+    fn test_merge_bed_regions() {
+        let mut regions = HashMap::new();
+        regions.insert("1".to_string(), vec![(0, 10)]);
+        let bed = vec![
+            ("1".to_string(), 6, 20),   // BED 5-20, overlaps the existing mask
+            ("1".to_string(), 101, 200),
+            ("2".to_string(), 1, 5),
+        ];
+        merge_bed_regions(&mut regions, &bed);
+        assert_eq!(regions["1"], vec![(0, 20), (100, 200)]);
+        assert_eq!(regions["2"], vec![(0, 5)]);
+        assert!(position_in_regions(15, &regions["1"]));
+        assert!(!position_in_regions(20, &regions["1"]));
+        assert!(position_in_regions(100, &regions["1"]));
+    }
+
+    #[test]
+    fn test_vcf_audit() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let vcf_path = temp_dir.path().join("audit.vcf");
+        fs::write(
+            &vcf_path,
+            "##fileformat=VCFv4.2\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\n\
+             chr1\t100\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:40\t./.:40\n\
+             chr1\t200\t.\tA\tG,C\t.\tPASS\t.\tGT:GQ\t1|2:40\t0|.:40\n\
+             chr2\t300\t.\tC\tT\t.\tPASS\t.\tGT:GQ\t.:40\t0|0:40\n",
+        )
+        .unwrap();
+
+        let report = vcf_audit(&vcf_path).expect("Audit failed");
+        assert_eq!(report.total_lines, 5);
+        assert_eq!(report.header_lines, 2);
+        assert_eq!(report.variant_lines, 3);
+        assert_eq!(report.variants_per_chromosome.get("chr1"), Some(&2));
+        assert_eq!(report.variants_per_chromosome.get("chr2"), Some(&1));
+        assert_eq!(report.multiallelic_sites, 1);
+        assert_eq!(report.sample_names, vec!["S1".to_string(), "S2".to_string()]);
+        assert_eq!(report.missing_per_sample, vec![1, 2]);
+        assert!((report.missing_rate(1) - 2.0 / 3.0).abs() < 1e-12);
+
+        // A file without a #CHROM line is rejected
+        fs::write(&vcf_path, "##fileformat=VCFv4.2\n").unwrap();
+        assert!(vcf_audit(&vcf_path).is_err());
+    }
+
+    #[test]
+    fn test_cli_check_subcommand() {
+        let cli = Cli::parse_from(["vcf_stats", "check", "input.vcf"]);
+        assert!(matches!(cli.command, Some(crate::process::Command::Check { ref vcf }) if vcf == Path::new("input.vcf")));
+        assert!(cli.args.is_none());
+
+        let cli = Cli::parse_from(["vcf_stats", "--vcf_folder", "vcfs", "--reference", "ref.fa", "--gff", "a.gff"]);
+        assert!(cli.command.is_none());
+        assert_eq!(cli.args.unwrap().vcf_folder, "vcfs");
+    }
+
+    #[test]
+    fn test_cli_compare_subcommand() {
+        let cli = Cli::parse_from(["vcf_stats", "compare", "--file1", "a.csv", "--file2", "b.csv", "--tolerance", "0.05"]);
+        match cli.command {
+            Some(crate::process::Command::Compare { file1, file2, tolerance }) => {
+                assert_eq!(file1, PathBuf::from("a.csv"));
+                assert_eq!(file2, PathBuf::from("b.csv"));
+                assert_eq!(tolerance, 0.05);
+            }
+            other => panic!("Expected compare, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_relative_change() {
+        assert_eq!(relative_change(2.0, 2.2), 0.10000000000000009);
+        assert_eq!(relative_change(-2.0, -1.0), 0.5);
+        assert_eq!(relative_change(0.0, 0.0), 0.0);
+        assert!(relative_change(0.0, 1.0).is_infinite());
+        assert_eq!(relative_change(f64::NAN, f64::NAN), 0.0);
+        assert!(relative_change(f64::NAN, 1.0).is_nan());
+    }
+
+    #[test]
+    fn test_compare_csv_files() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let old = temp_dir.path().join("old.csv");
+        let new = temp_dir.path().join("new.csv");
+        fs::write(&old, "chr,region_start,region_end,pi,label\n1,1,100,0.010000,NA\n1,101,200,0.020000,x\n2,1,50,0.5,x\n").unwrap();
+        fs::write(&new, "chr,region_start,region_end,label,pi\n1,1,100,NA,0.010050\n1,101,200,y,0.030000\n").unwrap();
+
+        let diffs = compare_csv_files(&old, &new, 0.01).unwrap();
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0].region, "1:101-200");
+        assert_eq!(diffs[0].column, "pi");
+        assert_eq!((diffs[0].old_value, diffs[0].new_value), (0.02, 0.03));
+        assert!((diffs[0].relative_change - 0.5).abs() < 1e-12);
+        // Changed text is reported with NaN values
+        assert_eq!(diffs[1].column, "label");
+        assert!(diffs[1].relative_change.is_nan());
+
+        // Within a 0.6 tolerance only the text change remains
+        assert_eq!(compare_csv_files(&old, &new, 0.6).unwrap().len(), 1);
+
+        fs::write(&new, "pi\n0.1\n").unwrap();
+        assert!(matches!(compare_csv_files(&old, &new, 0.01), Err(VcfError::Parse(_))));
+    }
+
+    #[test]
+    fn test_gc_fraction() {
+        assert!((gc_fraction(b"ACGTGGNN") - 4.0 / 6.0).abs() < 1e-12);
+        assert!((gc_fraction(b"acgt") - 0.5).abs() < 1e-12);
+        assert!(gc_fraction(b"NNNN").is_nan());
+
+        let (fasta_file, _) = setup_test_data();
+        assert!((calculate_gc_content(fasta_file.path(), "1", 1, 100).unwrap() - 0.5).abs() < 1e-12);
+        assert!(calculate_gc_content(fasta_file.path(), "2", 1, 100).is_err());
+        // Windows are sliced from one read of the span; parts past its end are left out
+        let span = ReferenceSpan::read(fasta_file.path(), "1", 101, 200).unwrap();
+        assert!((span.gc_content(101, 104).unwrap() - 0.5).abs() < 1e-12);
+        assert!((span.gc_content(102, 103).unwrap() - 1.0).abs() < 1e-12);
+        assert!((span.gc_content(199, 250).unwrap() - 0.5).abs() < 1e-12);
+        assert!(span.gc_content(50, 60).is_none());
+        assert!(span.gc_content(201, 300).is_none());
+    }
+
+    #[test]
+    fn test_fisher_exact_test() {
+        // Fisher's tea-tasting style table; R's fisher.test gives 0.002759
+        assert!((fisher_exact_test(1, 9, 11, 3) - 0.002759).abs() < 1e-6);
+        assert!((fisher_exact_test(3, 1, 1, 3) - 0.485714).abs() < 1e-6);
+        assert_eq!(fisher_exact_test(0, 0, 0, 0), 1.0);
+        assert!((MkTable { dn: 2, ds: 2, pn: 2, ps: 2 }.fisher_p_value() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mk_table_from_annotation() {
+        let (fasta_file, _) = setup_test_data();
+        let mut gff = NamedTempFile::new().expect("Failed to create temp file");
+        writeln!(gff, "##gff-version 3").unwrap();
+        writeln!(gff, "1\ttest\tCDS\t1\t12\t.\t+\t0\tID=cds1;Parent=tx1;gene_name=G1").unwrap();
+        writeln!(gff, "1\ttest\tCDS\t1\t6\t.\t+\t0\tParent=tx1b;gene_name=G1").unwrap();
+        writeln!(gff, "chr1\ttest\tCDS\t21\t26\t.\t-\t0\tParent=tx2;gene_name=G2").unwrap();
+        let annotation = parse_annotation_gff(gff.path(), fasta_file.path(), "1", 1, 100).unwrap();
+        // The shorter transcript of G1 is dropped
+        assert_eq!(annotation.transcripts.len(), 2);
+        assert_eq!(annotation.transcripts[0].sequence, b"ACGTACGTACGT".to_vec());
+        assert_eq!(annotation.transcripts[1].sequence, b"GTACGT".to_vec());
+
+        assert_eq!(translate_codon(b"ACG"), Some(b'T'));
+        assert_eq!(translate_codon(b"TGA"), Some(b'*'));
+        assert_eq!(translate_codon(b"ANG"), None);
+        // ACG -> ACA (Thr -> Thr), ACG -> TCG (Thr -> Ser)
+        assert_eq!(classify_site(3, "G", "A", &annotation), SiteClass::Synonymous);
+        assert_eq!(classify_site(1, "A", "T", &annotation), SiteClass::Nonsynonymous);
+        // Minus strand: GTA -> GTG (Val -> Val) and GTA -> TTA (Val -> Leu)
+        assert_eq!(classify_site(24, "T", "C", &annotation), SiteClass::Synonymous);
+        assert_eq!(classify_site(26, "C", "A", &annotation), SiteClass::Nonsynonymous);
+        assert_eq!(classify_site(50, "A", "G", &annotation), SiteClass::NonCoding);
+        assert_eq!(classify_site(1, "A", "TT", &annotation), SiteClass::NonCoding);
+
+        let site = |position: i64, ref_allele: u8, alt_allele: u8, alleles: [u8; 2]| Variant {
+            position,
+            genotypes: alleles.iter().map(|&a| Some(vec![a])).collect(),
+            ancestral_allele: None,
+            ref_allele,
+            alt_allele,
+            variant_type: VariantType::Snp,
+        };
+        let group_0 = vec![site(1, b'A', b'T', [0, 1]), site(3, b'G', b'A', [0, 0]), site(6, b'C', b'T', [0, 0]), site(50, b'A', b'G', [0, 0])];
+        let group_1 = vec![site(1, b'A', b'T', [0, 0]), site(3, b'G', b'A', [1, 1]), site(6, b'C', b'T', [0, 0]), site(50, b'A', b'G', [1, 1])];
+        let tables = tally_mk_tables(&group_0, 2, &group_1, 2, &annotation, false);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables["G1"], MkTable { dn: 0, ds: 1, pn: 1, ps: 0 });
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("out_mk_table.csv");
+        let rows: Vec<(String, i64, i64, String, MkTable)> = tables
+            .into_iter()
+            .map(|(gene, table)| ("1".to_string(), 1, 100, gene, table))
+            .collect();
+        write_mk_tables(&path, &rows, 6).expect("Failed to write MK table");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "chr,region_start,region_end,gene,dn,ds,pn,ps,fisher_p\n1,1,100,G1,0,1,1,0,1.000000\n"
         );
-    
-        // VCF line with one genotype below the GQ threshold
-        let low_gq_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:20\t1|1:45";
-        let result = parse_variant(
-            low_gq_line,
-            "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut filtering_stats,
-            None, 
-            None,
-            &position_allele_map,
+    }
+
+    #[test]
+    fn test_count_accessible_bases() {
+        let mut bed = NamedTempFile::new().expect("Failed to create temp file");
+        writeln!(bed, "chr1\t0\t50\nchr1\t40\t60\nchr1\t90\t200\nchr2\t0\t1000").unwrap();
+        let regions = parse_accessible_mask(bed.path()).unwrap();
+        // Overlapping intervals are merged: 1-60 and 91-200 (1-based)
+        assert_eq!(count_accessible_bases(&regions, "1", 1, 100), 70);
+        assert_eq!(count_accessible_bases(&regions, "chr1", 51, 95), 15);
+        assert_eq!(count_accessible_bases(&regions, "1", 61, 90), 0);
+        assert_eq!(count_accessible_bases(&regions, "3", 1, 100), 0);
+        assert_eq!(accessible_length(&[(0, 10)], 1, 10), 10);
+    }
+
+    #[test]
+    fn test_write_fasta_haplotypes() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let out = temp_dir.path().join("haplotypes.fa");
+        let mut snp = create_variant(102, vec![Some(vec![0, 1]), Some(vec![1, 1])]);
+        snp.alt_allele = b'T';
+        let missing = create_variant(105, vec![None, Some(vec![0, 2])]);
+        let names = vec!["EUR_S1".to_string(), "S2".to_string()];
+        let indices = [(0, 0), (0, 1), (1, 1)];
+
+        write_fasta_haplotypes(&[snp, missing], &indices, &names, b"acgtacgt", 101, &out).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            ">S1_hap1\nACGTNCGT\n>S1_hap2\nATGTNCGT\n>S2_hap2\nATGTNCGT\n"
         );
-    
-        // the function executed without errors
-        assert!(result.is_ok());
-    
-        // Assert that the variant is returned but marked as invalid (filtered out)
-        assert_eq!(result.unwrap(), Some((expected_variant, false)));
     }
 
     #[test]
-    fn test_parse_variant_out_of_range_region() {
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut missing_data_info = MissingDataInfo::default();  
-        let mut _filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
+    fn test_region_sort_key() {
+        let mut regions = vec![("X", 5, 10), ("10", 1, 5), ("2", 300, 400), ("2", 100, 200), ("MT", 1, 2)];
+        regions.sort_by_key(|&(chr, start, end)| region_sort_key(chr, start, end));
+        assert_eq!(regions, vec![("2", 100, 200), ("2", 300, 400), ("10", 1, 5), ("MT", 1, 2), ("X", 5, 10)]);
+    }
+
+    #[test]
+    fn test_normalize_chr() {
+        assert_eq!(canonical_chr("chr1"), "1");
+        assert_eq!(canonical_chr("chrM"), "MT");
+        assert_eq!(canonical_chr("M"), canonical_chr("MT"));
+        assert_eq!(chr_aliases("chrM"), vec!["MT".to_string(), "M".to_string()]);
+
+        assert_eq!(normalize_chr("chr1", ChrPrefixMode::Auto), "chr1");
+        assert_eq!(normalize_chr("1", ChrPrefixMode::Auto), "1");
+        assert_eq!(normalize_chr("1", ChrPrefixMode::Ucsc), "chr1");
+        assert_eq!(normalize_chr("MT", ChrPrefixMode::Ucsc), "chrM");
+        assert_eq!(normalize_chr("chrX", ChrPrefixMode::Ensembl), "X");
+        assert_eq!(normalize_chr("chrM", ChrPrefixMode::Ensembl), "MT");
+    }
 
-        let out_of_range = "chr1\t3000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
-        let allow_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let mask_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let result = parse_variant(
-            out_of_range,
-            "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut _filtering_stats,
-            allow_regions,
-            mask_regions,
-            &position_allele_map,
+    #[test]
+    fn test_find_vcf_file_mitochondrial_alias() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("chrM.vcf"), "").unwrap();
+        fs::write(temp_dir.path().join("chr1.vcf"), "").unwrap();
+        fs::write(temp_dir.path().join("chr10.vcf"), "").unwrap();
+        let folder = temp_dir.path().to_str().unwrap();
+
+        assert_eq!(find_vcf_file(folder, "MT").unwrap(), temp_dir.path().join("chrM.vcf"));
+        assert_eq!(find_vcf_file(folder, "chrM").unwrap(), temp_dir.path().join("chrM.vcf"));
+        assert_eq!(find_vcf_file(folder, "chr1").unwrap(), temp_dir.path().join("chr1.vcf"));
+    }
+
+    #[test]
+    fn test_merge_csv_files() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let chr1 = temp_dir.path().join("chr1.csv");
+        let chr2 = temp_dir.path().join("chr2.csv");
+        let merged = temp_dir.path().join("merged.csv");
+        fs::write(&chr1, "chr,region_start,region_end,pi\n1,1,100,0.01\n1,101,200,0.02\n").unwrap();
+        fs::write(&chr2, "chr,region_start,region_end,pi\n2,1,50,NA\n").unwrap();
+
+        let pattern = temp_dir.path().join("chr*.csv").to_string_lossy().into_owned();
+        let inputs = expand_input_patterns(&[pattern]).unwrap();
+        assert_eq!(inputs, vec![chr1.clone(), chr2.clone()]);
+        assert_eq!(merge_csv_files(&inputs, &merged).unwrap(), 3);
+        assert_eq!(
+            fs::read_to_string(&merged).unwrap(),
+            "chr,region_start,region_end,pi\n1,1,100,0.01\n1,101,200,0.02\n2,1,50,NA\n"
         );
 
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+        fs::write(&chr2, "chr,window_start,window_end,pi\n2,1,50,NA\n").unwrap();
+        assert!(matches!(merge_csv_files(&inputs, &merged), Err(VcfError::Parse(_))));
+        let missing = temp_dir.path().join("none*.csv").to_string_lossy().into_owned();
+        assert!(matches!(expand_input_patterns(&[missing]), Err(VcfError::Parse(_))));
     }
 
     #[test]
-    fn test_parse_variant_different_chromosome() {
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut missing_data_info = MissingDataInfo::default();  
-        let mut _filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
+    fn test_parse_contig_length() {
+        assert_eq!(
+            parse_contig_length("##contig=<ID=chr1,length=248956422,assembly=GRCh38>\n"),
+            Some(("chr1".to_string(), 248956422))
+        );
+        assert_eq!(parse_contig_length("##contig=<ID=chr1>"), None);
+        assert_eq!(parse_contig_length("##fileformat=VCFv4.2"), None);
+    }
 
-        let diff_chr = "chr2\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\t1|1:45";
-        let allow_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let mask_regions: Option<&HashMap<String, Vec<(i64, i64)>>> = None;
-        let result = parse_variant(
-            diff_chr,
+    fn run_process_vcf(vcf_path: &Path, reference_path: &Path) -> i64 {
+        process_vcf(
+            vcf_path,
             "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut _filtering_stats,
-            allow_regions,
-            mask_regions,
-            &position_allele_map,
-        );
+            &[(1, 2000)],
+            &RegionMasks::default(),
+            &VcfOptions {
+                reference_path: reference_path.to_path_buf(),
+                min_gq: 30,
+                ..VcfOptions::default()
+            },
+            Arc::new(Mutex::new(Vec::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .expect("Failed to process VCF")
+        .3
+    }
 
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+    #[test]
+    fn test_process_vcf_contig_length() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let (fasta_file, _) = setup_test_data();
+        let body = "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\n\
+                    chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35\n";
+
+        // The header length wins over the reference index
+        let vcf_path = temp_dir.path().join("with_contig.vcf");
+        fs::write(&vcf_path, format!("##fileformat=VCFv4.2\n##contig=<ID=chr1,length=123456>\n{}", body)).unwrap();
+        assert_eq!(run_process_vcf(&vcf_path, fasta_file.path()), 123456);
+
+        // Without a ##contig line the reference index supplies the length
+        let vcf_path = temp_dir.path().join("without_contig.vcf");
+        fs::write(&vcf_path, format!("##fileformat=VCFv4.2\n{}", body)).unwrap();
+        assert_eq!(run_process_vcf(&vcf_path, fasta_file.path()), 40000);
     }
 
     #[test]
-    fn test_parse_variant_invalid_format() {
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut missing_data_info = MissingDataInfo::default();  
-        let mut _filtering_stats = FilteringStats::default();
-        let min_gq = 30;
-        let _mask: Option<&[(i64, i64)]> = None;
-        let position_allele_map = Mutex::new(HashMap::new());
+    fn test_process_vcf_sorted_by_position() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let (fasta_file, _) = setup_test_data();
+        let mut vcf = "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\n".to_string();
+        for pos in 1..=1500 {
+            vcf.push_str(&format!("chr1\t{}\t.\tA\tT,C\t.\tPASS\t.\tGT:GQ\t1|2:35\n", pos));
+        }
+        let vcf_path = temp_dir.path().join("many.vcf");
+        fs::write(&vcf_path, vcf).unwrap();
 
-        let invalid_format = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35"; // Only 10 fields, expecting 12 for 3 samples
-        assert!(parse_variant(
-            invalid_format,
+        // Split mode skips the dedup pass, so this only holds if the collected variants are sorted
+        let (unfiltered, filtered, ..) = process_vcf(
+            &vcf_path,
             "1",
-            1,
-            2000,
-            &mut missing_data_info,
-            &sample_names,
-            min_gq,
-            &mut _filtering_stats,
-            None, 
-            None,
-            &position_allele_map,
-        ).is_err());
+            &[(1, 2000)],
+            &RegionMasks::default(),
+            &VcfOptions {
+                reference_path: fasta_file.path().to_path_buf(),
+                min_gq: 30,
+                multiallelic: MultiallelicMode::Split,
+                ..VcfOptions::default()
+            },
+            Arc::new(Mutex::new(Vec::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+        )
+        .expect("Failed to process VCF");
+        assert_eq!(unfiltered.len(), 3000);
+        assert!(unfiltered.windows(2).all(|pair| pair[0].position <= pair[1].position));
+        assert!(filtered.windows(2).all(|pair| pair[0].position <= pair[1].position));
+        // Records split from one line keep their ALT order
+        assert_eq!(unfiltered[0].genotypes[0], Some(vec![1, 0]));
+        assert_eq!(unfiltered[1].genotypes[0], Some(vec![0, 1]));
     }
 
     #[test]
-    fn test_process_variants_with_invalid_haplotype_group() {
-        let variants = vec![
-            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1]), Some(vec![1, 1])]),
-            create_variant(2000, vec![Some(vec![0, 0]), Some(vec![0, 0]), Some(vec![0, 1])]),
-            create_variant(3000, vec![Some(vec![0, 1]), Some(vec![1, 1]), Some(vec![0, 0])]),
+    fn test_dedup_by_position() {
+        // (line number, FILTER passes, variant) as the collector gathers them, out of file order
+        let records = vec![
+            (4, true, create_variant(2000, vec![Some(vec![0, 0])])),
+            (1, true, create_variant(1000, vec![Some(vec![1, 1])])),
+            (5, true, create_variant(3000, vec![Some(vec![0, 1])])),
+            (2, true, create_variant(2000, vec![Some(vec![0, 1])])),
+            // A failing FILTER loses to a passing record even when it comes first in the file
+            (3, false, create_variant(3000, vec![Some(vec![1, 1])])),
         ];
-        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string(), "SAMPLE3".to_string()];
-        let mut sample_filter = HashMap::new();
-        sample_filter.insert("SAMPLE1".to_string(), (0, 1));
-        sample_filter.insert("SAMPLE2".to_string(), (0, 1));
-        sample_filter.insert("SAMPLE3".to_string(), (0, 1));
-        let adjusted_sequence_length: Option<i64> = None;
-        let seqinfo_storage = Arc::new(Mutex::new(Vec::new()));
-        let position_allele_map = Arc::new(Mutex::new(HashMap::new()));
-        let chromosome = "1".to_string();
-
-        // Read reference sequence
-        let (fasta_file, cds_regions) = setup_test_data();
-        let reference_sequence = read_reference_sequence(fasta_file.path(), "1", 1000, 3000)
-            .expect("Failed to read reference sequence");
+        let mut variants = sort_collected_variants(records);
+        assert_eq!(dedup_by_position(&mut variants), 2);
+        let positions: Vec<i64> = variants.iter().map(|v| v.position).collect();
+        assert_eq!(positions, vec![1000, 2000, 3000]);
+        // The first record in the file at a repeated position is the one kept
+        assert_eq!(variants[1].genotypes, vec![Some(vec![0, 1])]);
+        assert_eq!(variants[2].genotypes, vec![Some(vec![0, 1])]);
+        assert_eq!(dedup_by_position(&mut variants), 0);
+
+        assert!(filter_column_passes("chr1\t100\t.\tA\tT\t.\tPASS\t.\tGT\t0|1\n"));
+        assert!(filter_column_passes("chr1\t100\t.\tA\tT\t.\t.\t.\tGT\t0|1\n"));
+        assert!(!filter_column_passes("chr1\t100\t.\tA\tT\t.\tLowQual\t.\tGT\t0|1\n"));
+    }
 
-        // Manually populate the position_allele_map
-        {
-            let mut pam = position_allele_map.lock();
-            pam.insert(1000, ('A', 'T'));
-            pam.insert(2000, ('C', 'G'));
-            pam.insert(3000, ('G', 'A'));
+    #[test]
+    fn test_unfiltered_stream_matches_sort_and_dedup() {
+        // (line number, FILTER passes, variant) in arrival order, with line 3 failing its FILTER
+        let records = vec![
+            (4, true, create_variant(2000, vec![Some(vec![0, 0])])),
+            (2, true, create_variant(1000, vec![Some(vec![1, 1])])),
+            (5, true, create_variant(2000, vec![Some(vec![1, 1])])),
+            (3, false, create_variant(2000, vec![Some(vec![0, 1])])),
+            (6, true, create_variant(3000, vec![Some(vec![0, 1])])),
+        ];
+        let mut stream = UnfilteredStream::new(StreamingChromosomeStats::new(1, AncestralSource::Ref), true, 1);
+        // Line 1 is a record outside the region and arrives last
+        for (line_num, filter_pass, variant) in records.iter().cloned() {
+            stream.receive(line_num, filter_pass, vec![variant]);
         }
-
-        let invalid_group = process_variants(
-            &variants,
-            &sample_names,
-            2, // haplotype_group=2 (invalid, since only 0 and 1)
-            &sample_filter,
-            1000,
-            3000,
-            adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
-            false, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
-        );
-        assert!(invalid_group.unwrap_or(None).is_none(), "Expected None for invalid haplotype group");
+        assert_eq!(stream.stats.diversity().variant_count(), 0);
+        stream.receive(1, true, Vec::new());
+        let (stats, duplicates, unsorted) = stream.finish();
+
+        let mut kept = sort_collected_variants(records);
+        assert_eq!(duplicates, dedup_by_position(&mut kept));
+        assert!(!unsorted);
+        let mut expected = StreamingChromosomeStats::new(1, AncestralSource::Ref);
+        for variant in &kept {
+            expected.add(variant);
+        }
+        // The passing record at 2000 on line 4 is kept over the failing line 3 and the later line 5
+        assert_eq!(kept[1].genotypes, vec![Some(vec![0, 0])]);
+        assert_eq!(stats.diversity().variant_count(), 3);
+        assert_eq!(stats.per_sample_heterozygosity(), expected.per_sample_heterozygosity());
+        assert_eq!(stats.last_position(), Some(3000));
     }
 
     #[test]
-    fn test_parse_config_file_with_noreads() {
-        let config_content = "seqnames\tstart\tend\tPOS\torig_ID\tverdict\tcateg\tSAMPLE1\tSAMPLE2\n\
-                              chr1\t1000\t2000\t1500\ttest_id\tpass\tinv\t0|1_lowconf\t1|1\n\
-                              chr1\t3000\t4000\t.\t.\t.\t.\t0|0\t0|1\n";
-        let path = NamedTempFile::new().expect("Failed to process variants");
-        write!(path.as_file(), "{}", config_content).expect("Failed to process variants");
+    fn test_process_vcf_duplicate_positions_deterministic() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let (fasta_file, _) = setup_test_data();
+        // Every position appears three times: a failing FILTER record first, then two passing ones
+        let mut vcf = "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\n".to_string();
+        for pos in 1..=500 {
+            vcf.push_str(&format!("chr1\t{}\t.\tA\tT\t.\tLowQual\t.\tGT:GQ\t1|1:35\n", pos));
+            vcf.push_str(&format!("chr1\t{}\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35\n", pos));
+            vcf.push_str(&format!("chr1\t{}\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\n", pos));
+        }
+        let vcf_path = temp_dir.path().join("duplicates.vcf");
+        fs::write(&vcf_path, vcf).unwrap();
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for round in 0..10 {
+            // Every other run sums the unfiltered variants as they arrive instead of keeping them
+            let stream = (round % 2 == 1).then_some(AncestralSource::Ref);
+            // process_vcf sizes its consumer threads to the current pool
+            let (unfiltered, filtered, .., streamed) = pool
+                .install(|| {
+                    process_vcf(
+                        &vcf_path,
+                        "1",
+                        &[(1, 2000)],
+                        &RegionMasks::default(),
+                        &VcfOptions {
+                            reference_path: fasta_file.path().to_path_buf(),
+                            min_gq: 30,
+                            stream_unfiltered: stream,
+                            channel_buffer: 1,
+                            result_buffer: 1,
+                            ..VcfOptions::default()
+                        },
+                        Arc::new(Mutex::new(Vec::new())),
+                        Arc::new(Mutex::new(HashMap::new())),
+                    )
+                })
+                .expect("Failed to process VCF");
+            assert_eq!(filtered.len(), 500);
+            assert!(filtered.iter().all(|v| v.genotypes[0] == Some(vec![0, 1])));
+            match streamed {
+                Some(stats) => {
+                    assert!(unfiltered.is_empty());
+                    assert_eq!(stats.diversity().variant_count(), 500);
+                    // Only the kept 0|1 records are heterozygous
+                    assert_eq!(stats.per_sample_heterozygosity(), vec![1.0]);
+                }
+                None => {
+                    assert_eq!(unfiltered.len(), 500);
+                    assert!(unfiltered.iter().all(|v| v.genotypes[0] == Some(vec![0, 1])));
+                }
+            }
+        }
+    }
 
-        let config_entries = parse_config_file(path.path()).expect("Failed to process variants");
-        assert_eq!(config_entries.len(), 2);
+    #[test]
+    fn test_process_vcf_skips_region_gaps() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let (fasta_file, _) = setup_test_data();
+        let header = "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1\tSAMPLE2\n";
+        let kept = "chr1\t50\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35\t0|0:35\n\
+                    chr1\t250\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t1|1:35\t0|1:35\n";
+        // Missing, low GQ and failing FILTER: it would show up in every count if it were read
+        let gap = "chr1\t150\t.\tA\tT\t.\tLowQual\t.\tGT:GQ\t.|.:10\t0|1:10\n";
+        let run = |body: &str| {
+            let vcf_path = temp_dir.path().join("gaps.vcf");
+            fs::write(&vcf_path, format!("{}{}", header, body)).unwrap();
+            process_vcf(
+                &vcf_path,
+                "1",
+                &[(1, 100), (201, 300)],
+                &RegionMasks::default(),
+                &VcfOptions {
+                    reference_path: fasta_file.path().to_path_buf(),
+                    min_gq: 30,
+                    ..VcfOptions::default()
+                },
+                Arc::new(Mutex::new(Vec::new())),
+                Arc::new(Mutex::new(HashMap::new())),
+            )
+            .expect("Failed to process VCF")
+        };
+        let with_gap = run(&format!("{}{}", kept, gap));
+        let without_gap = run(kept);
+
+        let positions = |variants: &[Variant]| variants.iter().map(|v| v.position).collect::<Vec<_>>();
+        assert_eq!(positions(&with_gap.0), vec![50, 250]);
+        assert_eq!(positions(&with_gap.0), positions(&without_gap.0));
+        assert_eq!(positions(&with_gap.1), positions(&without_gap.1));
+        let (missing, expected) = (&with_gap.4, &without_gap.4);
+        assert_eq!(missing.total_data_points, expected.total_data_points);
+        assert_eq!(missing.missing_data_points, expected.missing_data_points);
+        assert_eq!(missing.positions_with_missing, expected.positions_with_missing);
+        assert_eq!(missing.filtered_by_qual, expected.filtered_by_qual);
+        assert_eq!(missing.low_gq_count, expected.low_gq_count);
+        assert_eq!(missing.sample_sites, expected.sample_sites);
+        assert_eq!(missing.sample_missing, expected.sample_missing);
+        let (stats, expected) = (&with_gap.5, &without_gap.5);
+        assert_eq!(stats.total_variants, 2);
+        assert_eq!(stats.total_variants, expected.total_variants);
+        assert_eq!(stats._filtered_variants, expected._filtered_variants);
+        assert_eq!(stats.missing_data_variants, expected.missing_data_variants);
+        assert_eq!(stats.low_gq_variants, expected.low_gq_variants);
     }
 
     #[test]
-    fn test_find_vcf_file_existing_vcfs() {
-        use std::fs::File;
+    fn test_parse_variant_trailing_newline() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        let position_allele_map = Mutex::new(HashMap::new());
 
-        // Create a temporary directory for testing
-        let temp_dir = tempfile::tempdir().expect("Failed to process variants");
-        let temp_path = temp_dir.path();
+        // Lines read from a file end in a newline right after the last sample's GQ
+        let variant_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40\n";
+        let result = parse_variant(
+            variant_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1000,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        )
+        .unwrap();
 
-        // Create some test VCF files
-        File::create(temp_path.join("chr1.vcf")).expect("Failed to process variants");
-        File::create(temp_path.join("chr2.vcf.gz")).expect("Failed to process variants");
-        File::create(temp_path.join("chr10.vcf")).expect("Failed to process variants");
+        let expected_variant = create_snp_variant(1000, vec![Some(vec![0, 0]), Some(vec![0, 1])]);
+        assert_eq!(result, Some((expected_variant, true)));
+        assert_eq!(filtering_stats.low_gq_variants, 0);
+    }
 
-        // Test finding existing VCF files
-        let vcf1 = find_vcf_file(temp_path.to_str().unwrap(), "1").expect("Failed to process variants");
-        assert!(vcf1.ends_with("chr1.vcf"));
+    #[test]
+    fn test_parse_variant_sample_subset() {
+        let sample_names = vec!["SAMPLE3".to_string(), "SAMPLE1".to_string()];
+        let mut missing_data_info = MissingDataInfo::default();
+        let mut filtering_stats = FilteringStats::default();
+        let position_allele_map = Mutex::new(HashMap::new());
 
-        let vcf2 = find_vcf_file(temp_path.to_str().unwrap(), "2").expect("Failed to process variants");
-        assert!(vcf2.ends_with("chr2.vcf.gz"));
+        // SAMPLE2 has a low GQ but is not in the subset, so the site still passes
+        let variant_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|1:35\t1|1:5\t0|0:40\n";
+        let result = parse_variant(
+            variant_line,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1000,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: Some(&[0, 2]),
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+            },
+            &position_allele_map,
+            &mut missing_data_info,
+            &mut filtering_stats,
+        )
+        .unwrap();
 
-        let vcf10 = find_vcf_file(temp_path.to_str().unwrap(), "10").expect("Failed to process variants");
-        assert!(vcf10.ends_with("chr10.vcf"));
+        let expected_variant = create_snp_variant(1000, vec![Some(vec![0, 1]), Some(vec![0, 0])]);
+        assert_eq!(result, Some((expected_variant, true)));
+        assert_eq!(missing_data_info.total_data_points, 2);
     }
 
     #[test]
-    fn test_find_vcf_file_non_existent_chromosome() {
-        use std::fs::File;
+    fn test_select_sample_columns() {
+        let vcf_samples = vec!["EUR_GBR_HG00096".to_string(), "SAMPLE2".to_string(), "HG00097".to_string()];
+        let list = vec!["HG00096".to_string(), "HG00097".to_string(), "NOT_IN_VCF".to_string()];
+        assert_eq!(select_sample_columns(&vcf_samples, &list).unwrap(), vec![0, 2]);
+        assert!(select_sample_columns(&vcf_samples, &["NOT_IN_VCF".to_string()]).is_err());
+    }
 
-        // Create a temporary directory for testing
-        let temp_dir = tempfile::tempdir().expect("Failed to process variants");
-        let temp_path = temp_dir.path();
+    #[test]
+    fn test_exclude_sample_columns() {
+        let vcf_samples = vec!["EUR_GBR_HG00096".to_string(), "SAMPLE2".to_string(), "HG00097".to_string()];
+        let exclude = vec!["HG00096".to_string(), "NOT_IN_VCF".to_string()];
+        assert_eq!(exclude_sample_columns(&vcf_samples, vec![0, 1, 2], &exclude).unwrap(), vec![1, 2]);
+        assert_eq!(missing_excluded_samples(&vcf_samples, &exclude), vec!["NOT_IN_VCF"]);
+        // Applied to the --sample_list columns, exclusions win
+        let list = vec!["HG00096".to_string(), "HG00097".to_string()];
+        let columns = select_sample_columns(&vcf_samples, &list).unwrap();
+        assert_eq!(exclude_sample_columns(&vcf_samples, columns, &exclude).unwrap(), vec![2]);
+        assert!(exclude_sample_columns(&vcf_samples, vec![0], &exclude).is_err());
+    }
 
-        // Create some test VCF files
-        File::create(temp_path.join("chr1.vcf")).expect("Failed to process variants");
-        File::create(temp_path.join("chr2.vcf.gz")).expect("Failed to process variants");
-        File::create(temp_path.join("chr10.vcf")).expect("Failed to process variants");
+    #[test]
+    fn test_parse_sample_list() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("samples.txt");
+        fs::write(&path, "# cohort\nHG00096\n\n  HG00097 \n").unwrap();
+        assert_eq!(parse_sample_list(&path).unwrap(), vec!["HG00096", "HG00097"]);
+        fs::write(&path, "\n# nothing\n").unwrap();
+        assert!(parse_sample_list(&path).is_err());
+    }
 
-        // Test with non-existent chromosome "3"
-        let result = find_vcf_file(temp_path.to_str().unwrap(), "3");
-        assert!(matches!(result, Err(VcfError::NoVcfFiles)));
+    #[test]
+    fn test_parse_group_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("groups.tsv");
+        fs::write(&path, "sample_id\tgroup_label\n# comment\nHG00096\tEUR\n\nNA18939\tEAS\nHG00096\tEUR\n").unwrap();
+        let groups = parse_group_file(&path).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["HG00096"], "EUR");
+        assert_eq!(groups["NA18939"], "EAS");
+
+        // Conflicting labels, a missing label and an empty file are errors
+        fs::write(&path, "HG00096\tEUR\nHG00096\tAFR\n").unwrap();
+        assert!(parse_group_file(&path).is_err());
+        fs::write(&path, "HG00096\n").unwrap();
+        assert!(parse_group_file(&path).is_err());
+        fs::write(&path, "sample_id\tgroup_label\n").unwrap();
+        assert!(parse_group_file(&path).is_err());
     }
 
     #[test]
-    fn test_find_vcf_file_non_existent_directory() {
-        // Test with a non-existent directory path
-        let result = find_vcf_file("/non/existent/path", "1");
-        assert!(result.is_err());
+    fn test_parse_fam_file() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("samples.fam");
+        fs::write(&path, "FAM1 HG00096 0 0 1 1\nFAM1\tHG00097\t0\t0\t2\t2\nFAM2 NA18939 0 0 2 -9\nFAM3 NA18940 0 0 1 0\n").unwrap();
+        let groups = parse_fam_file(&path).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["HG00096"], 0);
+        assert_eq!(groups["HG00097"], 1);
+
+        // Quantitative phenotypes, short lines and files with no cases or controls are errors
+        fs::write(&path, "FAM1 HG00096 0 0 1 3.7\n").unwrap();
+        assert!(parse_fam_file(&path).is_err());
+        fs::write(&path, "FAM1 HG00096 0 0 1\n").unwrap();
+        assert!(parse_fam_file(&path).is_err());
+        fs::write(&path, "FAM1 HG00096 0 0 1 -9\n").unwrap();
+        assert!(parse_fam_file(&path).is_err());
     }
 
     #[test]
-    fn test_open_vcf_reader_non_existent_file() {
-        let path = PathBuf::from("/non/existent/file.vcf");
-        let result = open_vcf_reader(&path);
-        assert!(result.is_err());
+    fn test_group_region_stats() {
+        let sample_names = vec!["EUR_GBR_HG00096".to_string(), "NA18939".to_string(), "UNGROUPED".to_string()];
+        let groups: HashMap<String, String> = [("HG00096", "EUR"), ("NA18939", "EAS")]
+            .into_iter()
+            .map(|(s, g)| (s.to_string(), g.to_string()))
+            .collect();
+        let labels = vec!["EAS".to_string(), "EUR".to_string()];
+        let group_indices = group_haplotype_indices(&sample_names, &groups, &labels);
+        assert_eq!(group_indices, vec![vec![2, 3], vec![0, 1]]);
+
+        // One site where EUR carries 0|0 and EAS 1|1
+        let site = create_variant(150, vec![Some(vec![0]), Some(vec![0]), Some(vec![1]), Some(vec![1]), Some(vec![0]), Some(vec![0])]);
+        let haplotypes = HaplotypeSet {
+            sample_names,
+            unfiltered: vec![site.clone()],
+            filtered: vec![site],
+            num_haplotypes: 6,
+            chr_length: 1000,
+            subsampled: false,
+            accessible: None,
+        };
+        let stats = calculate_group_region_stats(&haplotypes, &labels, &group_indices, "1", 101, 200, 100);
+        assert_eq!(stats.groups[0].unfiltered.segregating_sites, 0);
+        assert_eq!(stats.pairs.len(), 1);
+        assert!((stats.pairs[0].dxy - 0.01).abs() < 1e-12);
+        assert!((stats.pairs[0].fst - 1.0).abs() < 1e-12);
+        assert_eq!(group_region_record(&stats, 6).len(), 3 + group_region_columns(&labels).len());
+        assert_eq!(stats.sequence_length_accessible, None);
+
+        // With --accessible_mask the unfiltered statistics use the 50 callable bases of the region
+        let haplotypes = HaplotypeSet { accessible: Some(vec![(100, 150)]), ..haplotypes };
+        let stats = calculate_group_region_stats(&haplotypes, &labels, &group_indices, "1", 101, 200, 100);
+        assert_eq!(stats.sequence_length_accessible, Some(50));
+        assert!((stats.pairs[0].dxy - 0.02).abs() < 1e-12);
+        assert_eq!(group_region_record(&stats, 6)[5], "50");
     }
 
     #[test]
-    fn test_open_vcf_reader_uncompressed_file() {
-        // Create a temporary uncompressed VCF file
-        let temp_file = NamedTempFile::new().expect("Failed to process variants");
-        let path = temp_file.path();
-        let mut file = File::create(path).expect("Failed to process variants");
-        writeln!(file, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1").expect("Failed to process variants");
+    fn test_split_multiallelic() {
+        let variant = Variant {
+            position: 1000,
+            genotypes: vec![Some(vec![0, 2]), Some(vec![1, 1]), Some(vec![2, 0]), None],
+            ancestral_allele: Some(2),
+            ref_allele: b'A',
+            alt_allele: b'N',
+            variant_type: VariantType::Snp,
+        };
+        let split = split_multiallelic(&variant, 2);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].genotypes, vec![Some(vec![0, 0]), Some(vec![1, 1]), Some(vec![0, 0]), None]);
+        assert_eq!(split[1].genotypes, vec![Some(vec![0, 1]), Some(vec![0, 0]), Some(vec![1, 0]), None]);
+        // The ancestral allele is ALT 2, so it only has an index in the second record
+        assert_eq!(split[0].ancestral_allele, None);
+        assert_eq!(split[1].ancestral_allele, Some(1));
+    }
 
-        let reader = open_vcf_reader(&path);
-        assert!(reader.is_ok());
+    #[test]
+    fn test_expand_multiallelic_modes() {
+        let variant = create_variant(1000, vec![Some(vec![0, 2]), Some(vec![1, 0])]);
+
+        let kept = expand_multiallelic(variant.clone(), 2, MultiallelicMode::Keep);
+        assert_eq!(kept, vec![create_variant(1000, vec![Some(vec![0, 1]), Some(vec![1, 0])])]);
+
+        let skipped = expand_multiallelic(variant.clone(), 2, MultiallelicMode::Skip);
+        assert_eq!(skipped, vec![variant.clone()]);
+
+        assert_eq!(expand_multiallelic(variant.clone(), 2, MultiallelicMode::Split).len(), 2);
+        // Biallelic sites are never touched
+        assert_eq!(expand_multiallelic(variant.clone(), 1, MultiallelicMode::Split), vec![variant]);
     }
 
     #[test]
-    fn test_open_vcf_reader_gzipped_file() {
-        // Create a temporary gzipped VCF file
-        let gzipped_file = NamedTempFile::new().expect("Failed to process variants");
-        let path = gzipped_file.path();
-        let mut encoder = flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::default());
-        writeln!(encoder, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tSAMPLE1").expect("Failed to process variants");
-        encoder.finish().expect("Failed to process variants");
+    fn test_parse_variant_multiallelic_modes() {
+        let sample_names = vec!["SAMPLE1".to_string(), "SAMPLE2".to_string()];
+        let position_allele_map = Mutex::new(HashMap::new());
+        let variant_line = "chr1\t1000\t.\tA\tT,G\t.\tPASS\t.\tGT:GQ\t0|2:35\t1|0:40";
 
-        let reader = open_vcf_reader(&path);
-        assert!(reader.is_ok());
+        let mut passes = Vec::new();
+        for mode in [MultiallelicMode::Skip, MultiallelicMode::Split, MultiallelicMode::Keep] {
+            let mut missing_data_info = MissingDataInfo::default();
+            let mut filtering_stats = FilteringStats::default();
+            let result = parse_variant(
+                variant_line,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1000,
+                    end: 2000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, multiallelic: mode, ..VcfOptions::default() },
+                },
+                &position_allele_map,
+                &mut missing_data_info,
+                &mut filtering_stats,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(filtering_stats.multi_allelic_variants, 1);
+            passes.push(result.1);
+        }
+        assert_eq!(passes, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_allele_indices_beyond_one() {
+        let variants = vec![
+            create_variant(1000, vec![Some(vec![0, 0]), Some(vec![2, 2])]),
+            create_variant(2000, vec![Some(vec![2, 2]), Some(vec![2, 2])]),
+        ];
+        assert_eq!(count_segregating_sites(&variants), 1);
+        let diffs = calculate_pairwise_differences(&variants, 2);
+        assert_eq!(diffs[0].1, 1);
+        assert_eq!(diffs[0].2, vec![1000]);
     }
 
     #[test]
@@ -782,10 +4230,10 @@ mod tests {
         let position_allele_map = Mutex::new(HashMap::new());
     
         // Define the expected variant using the helper function
-        let expected_variant = create_variant(
+        let expected_variant = create_snp_variant(
             1000,
             vec![
-                Some(vec![0, 0]), // SAMPLE1: 0|0:20 (below threshold)
+                None,             // SAMPLE1: 0|0:20, masked below the GQ threshold
                 Some(vec![0, 1]), // SAMPLE2: 0|1:40
             ],
         );
@@ -794,16 +4242,20 @@ mod tests {
         let variant_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:20\t0|1:40";
         let result = parse_variant(
             variant_line,
-            "1",
-            1000,
-            2000,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1000,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
             &mut missing_data_info,
-            &sample_names,
-            min_gq,
             &mut filtering_stats,
-            None, 
-            None,
-            &position_allele_map,
         );
     
         // the function executed without errors
@@ -825,16 +4277,20 @@ mod tests {
         let valid_variant_line = "chr1\t1000\t.\tA\tT\t.\tPASS\t.\tGT:GQ\t0|0:35\t0|1:40";
         let result = parse_variant(
             valid_variant_line,
-            "1",
-            1000,
-            2000,
+            1,
+            &RecordContext {
+                chr: "1",
+                start: 1000,
+                end: 2000,
+                sample_names: &sample_names,
+                sample_columns: None,
+                masks: &RegionMasks::default(),
+                cpg_sites: None,
+                options: &VcfOptions { min_gq, ..VcfOptions::default() },
+            },
+            &position_allele_map,
             &mut missing_data_info,
-            &sample_names,
-            min_gq,
             &mut _filtering_stats,
-            None, 
-            None,
-            &position_allele_map,
         ).expect("Failed to process variants");
     
         // Variant should be Some because all samples have GQ >= min_gq
@@ -857,11 +4313,15 @@ mod tests {
         writeln!(fasta_file, ">1").expect("Failed to write FASTA header");
         writeln!(fasta_file, "{}", "ACGT".repeat(10000)).expect("Failed to write sequence");
         fasta_file.flush().expect("Failed to flush file");
+
+        // IndexedReader requires a .fai next to the FASTA
+        let fai_path = format!("{}.fai", fasta_file.path().display());
+        std::fs::write(&fai_path, "1\t40000\t3\t40000\t40001\n").expect("Failed to write FASTA index");
     
         let cds_regions = vec![
-            CdsRegion { start: 1200, end: 1901 },
-            CdsRegion { start: 1950, end: 2113 },
-            CdsRegion { start: 2600, end: 2679 },
+            CdsRegion { transcript_id: "T1".to_string(), segments: vec![(1200, 1901)] },
+            CdsRegion { transcript_id: "T2".to_string(), segments: vec![(1950, 2113)] },
+            CdsRegion { transcript_id: "T3".to_string(), segments: vec![(2600, 2679)] },
         ];
     
         (fasta_file, cds_regions)
@@ -1022,16 +4482,20 @@ mod tests {
             let mut filtering_stats = FilteringStats::default();
             let result = parse_variant(
                 line,
-                "1",
-                1000,
-                3000,
+                1,
+                &RecordContext {
+                    chr: "1",
+                    start: 1000,
+                    end: 3000,
+                    sample_names: &sample_names,
+                    sample_columns: None,
+                    masks: &RegionMasks::default(),
+                    cpg_sites: None,
+                    options: &VcfOptions { min_gq: 30, ..VcfOptions::default() },
+                },
+                &position_allele_map,
                 &mut missing_data_info,
-                &sample_names,
-                30,
                 &mut filtering_stats,
-                None,
-                None,
-                &position_allele_map,
             );
             assert!(result.is_ok());
         }
@@ -1051,20 +4515,26 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let result = process_variants(
             &variants,
-            &sample_names,
             0, // haplotype_group is irrelevant now
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             false, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
         // Calculate allele frequency globally
@@ -1085,7 +4555,7 @@ mod tests {
         );
     
         // Verify segregating sites
-        assert_eq!(result.unwrap().0, 2, "Number of segregating sites should be 2");
+        assert_eq!(result.unwrap().segregating_sites, 2, "Number of segregating sites should be 2");
     }
 
     #[test]
@@ -1106,26 +4576,38 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             false, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
-        let (_segsites, _w_theta, _pi, n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (_segsites, _w_theta, _pi, n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
     
         // Number of haplotypes for group1 should be 3
         let expected_num_hap_group1 = 3;
@@ -1165,27 +4647,39 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
 
         // Correctly unwrap the Option to access the inner tuple
-        let (segsites, _w_theta, _pi, _n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (segsites, _w_theta, _pi, _n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
 
         let expected_segsites_group1 = 2;
         println!(
@@ -1232,26 +4726,38 @@ mod tests {
             pam.insert(2000, ('T', 'C'));
         }
     
+       let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
        let result = process_variants(
-           &variants,
-           &sample_names,
-           1,  // haplotype_group=1
-           &sample_filter,
-           1000,
-           2000,
-           Some(100),  // sequence_length=100 for some reason
-           Arc::clone(&seqinfo_storage),
-           Arc::clone(&position_allele_map),
-           chromosome,
-           false,
-           &reference_sequence,
-           &cds_regions[..],
-       ).unwrap();
-    
-       let (segsites, w_theta, _pi, n_hap) = match result {
+            &variants,
+            1, // haplotype_group=1
+            &sample_filter,
+            Some(100), // sequence_length=100 for some reason
+            false,
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 2000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
+        ).unwrap();
+    
+       let group_stats = match result {
            Some(data) => data,
            None => panic!("Expected Some variant data"),
        };
+       let (segsites, w_theta, _pi, n_hap) = (
+           group_stats.segregating_sites,
+           group_stats.w_theta,
+           group_stats.pi,
+           group_stats.num_haplotypes,
+       );
     
        // n=4 haplotypes means we sum 1/1 + 1/2 + 1/3 = 11/6
        // theta = 2 / (11/6) / 100 = 12/11/100
@@ -1282,27 +4788,39 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             false, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
         // Correctly unwrap the Option to access the inner tuple
-        let (_segsites, w_theta, _pi, _n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (_segsites, w_theta, _pi, _n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
     
         // Calculate expected Watterson's theta
         let harmonic_value = harmonic(2); // n-1 =2
@@ -1340,20 +4858,26 @@ mod tests {
             pam.insert(3000, ('A', 'T'));
         }
 
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let result = process_variants(
             &variants,
-            &sample_names,
             0, // haplotype_group is irrelevant now
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
 
         // Calculate global allele frequency
@@ -1374,7 +4898,7 @@ mod tests {
         );
 
         // Number of segregating sites
-        assert_eq!(result.unwrap().0, 2, "Number of segregating sites should be 2");
+        assert_eq!(result.unwrap().segregating_sites, 2, "Number of segregating sites should be 2");
     }
 
     #[test]
@@ -1397,26 +4921,38 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
-        let (_segsites, _w_theta, _pi, n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (_segsites, _w_theta, _pi, n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
     
         // Number of haplotypes after filtering should be same as before if no filtering applied
         let expected_num_hap_group1_filtered = 3;
@@ -1452,27 +4988,39 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
         // Correctly unwrap the Option to access the inner tuple
-        let (segsites, _w_theta, _pi, _n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (segsites, _w_theta, _pi, _n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
     
         let expected_segsites_group1_filtered = 2;
         println!(
@@ -1507,27 +5055,39 @@ mod tests {
             pam.insert(3000, ('G', 'A'));
         }
     
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter,
-            1000,
-            3000,
             adjusted_sequence_length,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).unwrap();
     
         // Correctly unwrap the Option to access the inner tuple
-        let (_segsites, w_theta, _pi, _n_hap) = match _result_group1 {
+        let group_stats = match _result_group1 {
             Some(data) => data,
             None => panic!("Expected Some variant data"),
         };
+        let (_segsites, w_theta, _pi, _n_hap) = (
+            group_stats.segregating_sites,
+            group_stats.w_theta,
+            group_stats.pi,
+            group_stats.num_haplotypes,
+        );
     
         // Calculate expected Watterson's theta after filtering
         let harmonic_value = harmonic(2); // n-1 =2
@@ -1618,20 +5178,26 @@ mod tests {
         }
     
         // Process variants for haplotype_group=1 (Group 1)
+        let phylip_dir = tempfile::tempdir().expect("Failed to create temp dir");
         let _result_group1 = process_variants(
             &variants,
-            &sample_names,
             1, // haplotype_group=1
             &sample_filter_unfiltered,
-            1000,
-            3000,
             None,
-            Arc::clone(&seqinfo_storage),
-            Arc::clone(&position_allele_map),
-            chromosome.clone(),
             true, // is_filtered_set
-            &reference_sequence,
-            &cds_regions[..],
+            &ConfigRegion {
+                chromosome: &chromosome,
+                start: 1000,
+                end: 3000,
+                sample_names: &sample_names,
+                reference_sequence: &reference_sequence,
+                cds_regions: &cds_regions[..],
+                seqinfo_storage: &seqinfo_storage,
+                position_allele_map: &position_allele_map,
+                ancestral_source: AncestralSource::Ref,
+                min_samples: 1,
+                phylip_dir: phylip_dir.path(),
+            },
         ).expect("Failed to process variants");
     
         // Calculate global allele frequency using the revised function (no haplotype_group parameter)